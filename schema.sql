@@ -1,17 +1,22 @@
 CREATE TABLE IF NOT EXISTS category (
   uuid blob NOT NULL PRIMARY KEY,
   name text NOT NULL,
-  supercategory blob
+  supercategory blob,
+  FOREIGN KEY (supercategory) REFERENCES category (uuid) ON DELETE RESTRICT
 );
 
+CREATE UNIQUE INDEX IF NOT EXISTS category_name ON category (name);
+
 
 CREATE TABLE IF NOT EXISTS instance (
   uuid blob NOT NULL PRIMARY KEY,
   identifier text NOT NULL,
   product blob NOT NULL,
-  FOREIGN KEY (product) REFERENCES product (uuid)
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
 );
 
+CREATE UNIQUE INDEX IF NOT EXISTS instance_product_identifier ON instance (product, identifier);
+
 
 CREATE TABLE IF NOT EXISTS loan (
   uuid blob NOT NULL PRIMARY KEY,
@@ -19,8 +24,11 @@ CREATE TABLE IF NOT EXISTS loan (
   date_start text NOT NULL,
   date_end text NOT NULL,
   accepted boolean NOT NULL,
+  rejected boolean NOT NULL DEFAULT 0,
   description text,
-  FOREIGN KEY (user) REFERENCES user (uuid)
+  price numeric NOT NULL DEFAULT 0,
+  CHECK (julianday(date_start) < julianday(date_end)),
+  FOREIGN KEY (user) REFERENCES user (uuid) ON DELETE RESTRICT
 );
 
 
@@ -28,9 +36,11 @@ CREATE TABLE IF NOT EXISTS product (
   uuid blob NOT NULL PRIMARY KEY,
   name text NOT NULL,
   category blob NOT NULL,
-  FOREIGN KEY (category) REFERENCES category (uuid)
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE RESTRICT
 );
 
+CREATE UNIQUE INDEX IF NOT EXISTS product_name ON product (name);
+
 
 CREATE TABLE IF NOT EXISTS user (
   uuid blob NOT NULL PRIMARY KEY,
@@ -45,23 +55,345 @@ CREATE TABLE IF NOT EXISTS membership_payments (
   price numeric NOT NULL,
   date_start text NOT NULL,
   date_end text NOT NULL,
-  FOREIGN KEY (user) REFERENCES user (uuid),
-  FOREIGN KEY (membership_type) REFERENCES membership_type (uuid)
+  FOREIGN KEY (user) REFERENCES user (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (membership_type) REFERENCES membership_type (uuid) ON DELETE RESTRICT
 );
 
 CREATE TABLE IF NOT EXISTS membership_type (
   uuid blob NOT NULL PRIMARY KEY,
-  type text NOT NULL
+  type text NOT NULL,
+  rental_discount integer NOT NULL DEFAULT 0,
+  CHECK (rental_discount BETWEEN 0 AND 100)
 );
 
 CREATE TABLE IF NOT EXISTS loan_instances (
   loan blob NOT NULL,
   instance blob NOT NULL,
+  date_returned text,
+  PRIMARY KEY (loan, instance),
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE RESTRICT
+);
+
+
+-- Rental prices in cents. Products without a row are free.
+CREATE TABLE IF NOT EXISTS product_price (
+  product blob NOT NULL PRIMARY KEY,
+  per_day numeric NOT NULL,
+  per_weekend numeric,
+  per_week numeric,
+  CHECK (per_day >= 0 AND per_weekend >= 0 AND per_week >= 0),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Price of each instance agreed when the loan was made, in cents
+CREATE TABLE IF NOT EXISTS loan_price (
+  loan blob NOT NULL,
+  instance blob NOT NULL,
+  description text NOT NULL,
+  price numeric NOT NULL,
+  discount numeric NOT NULL,
+  PRIMARY KEY (loan, instance),
+  FOREIGN KEY (loan, instance) REFERENCES loan_instances (loan, instance) ON DELETE CASCADE
+);
+
+-- Kits are booked as one unit. Each item is either a number of instances of
+-- a product or a specific instance.
+CREATE TABLE IF NOT EXISTS kit (
+  uuid blob NOT NULL PRIMARY KEY,
+  name text NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS kit_item (
+  kit blob NOT NULL,
+  position integer NOT NULL,
+  product blob,
+  instance blob,
+  quantity integer NOT NULL DEFAULT 1,
+  CHECK ((product IS NULL) <> (instance IS NULL)),
+  CHECK (quantity > 0),
+  PRIMARY KEY (kit, position),
+  FOREIGN KEY (kit) REFERENCES kit (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE RESTRICT
+);
+
+-- Instances of a loan that were booked as part of a kit.
+CREATE TABLE IF NOT EXISTS loan_kit (
+  loan blob NOT NULL,
+  instance blob NOT NULL,
+  kit blob NOT NULL,
   PRIMARY KEY (loan, instance),
-  FOREIGN KEY (loan) REFERENCES loan (uuid),
-  FOREIGN KEY (instance) REFERENCES instance (uuid)
+  FOREIGN KEY (loan, instance) REFERENCES loan_instances (loan, instance) ON DELETE CASCADE,
+  FOREIGN KEY (kit) REFERENCES kit (uuid) ON DELETE RESTRICT
 );
 
+-- Attributes of the products of a category and its subcategories. Options
+-- are a JSON array of the values an enum can have.
+CREATE TABLE IF NOT EXISTS attribute (
+  uuid blob NOT NULL PRIMARY KEY,
+  category blob NOT NULL,
+  name text NOT NULL,
+  kind text NOT NULL,
+  options text NOT NULL DEFAULT '[]',
+  required integer NOT NULL,
+  CHECK (kind IN ('text', 'number', 'enum', 'bool')),
+  UNIQUE (category, name),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS product_attribute (
+  product blob NOT NULL,
+  attribute blob NOT NULL,
+  value text NOT NULL,
+  PRIMARY KEY (product, attribute),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (attribute) REFERENCES attribute (uuid) ON DELETE CASCADE
+);
+
+-- Compatible products are related both ways, whichever comes first.
+CREATE TABLE IF NOT EXISTS product_relation (
+  product blob NOT NULL,
+  related blob NOT NULL,
+  kind text NOT NULL,
+  CHECK (kind IN ('compatible_with', 'requires', 'recommended_with')),
+  CHECK (product <> related),
+  PRIMARY KEY (product, related, kind),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (related) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Deposits in cents. Product deposits override the inherited category ones.
+CREATE TABLE IF NOT EXISTS product_deposit (
+  product blob NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  CHECK (amount >= 0),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS category_deposit (
+  category blob NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  CHECK (amount >= 0),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Deposit taken for a loan when it was handed out
+CREATE TABLE IF NOT EXISTS loan_deposit (
+  loan blob NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  date_received text NOT NULL,
+  status text NOT NULL,
+  date_settled text,
+  reason text,
+  CHECK (amount > 0),
+  CHECK (status IN ('held', 'refunded', 'withheld')),
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+-- Damage or wear found on an instance, optionally during a loan. An instance
+-- with an unresolved report that put it out of service can't be booked.
+CREATE TABLE IF NOT EXISTS condition_report (
+  uuid blob NOT NULL PRIMARY KEY,
+  instance blob NOT NULL,
+  loan blob,
+  severity text NOT NULL,
+  description text NOT NULL,
+  reporter text NOT NULL,
+  date text NOT NULL,
+  out_of_service integer NOT NULL,
+  date_resolved text,
+  resolution text,
+  CHECK (severity IN ('minor', 'major', 'critical')),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS condition_report_instance ON condition_report (instance);
+
+-- Service of an instance on known dates. Instances can't be booked during it.
+CREATE TABLE IF NOT EXISTS maintenance_window (
+  uuid blob NOT NULL PRIMARY KEY,
+  instance blob NOT NULL,
+  date_start text NOT NULL,
+  date_end text NOT NULL,
+  reason text NOT NULL,
+  CHECK (julianday(date_start) < julianday(date_end)),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS maintenance_window_instance ON maintenance_window (instance);
+
+-- Service intervals of a product. Whichever limit is reached first makes an
+-- instance due for maintenance.
+CREATE TABLE IF NOT EXISTS maintenance_rule (
+  product blob NOT NULL PRIMARY KEY,
+  loans integer,
+  days_out integer,
+  months integer,
+  CHECK (loans > 0 AND days_out > 0 AND months > 0),
+  CHECK (loans IS NOT NULL OR days_out IS NOT NULL OR months IS NOT NULL),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Readings of counters not derived from loans, e.g. shutter count
+CREATE TABLE IF NOT EXISTS usage_reading (
+  uuid blob NOT NULL PRIMARY KEY,
+  instance blob NOT NULL,
+  counter text NOT NULL,
+  value integer NOT NULL,
+  date text NOT NULL,
+  CHECK (value >= 0),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS usage_reading_instance ON usage_reading (instance);
+
+-- Lifecycle of an instance. The latest change is its status, instances
+-- without changes are available.
+CREATE TABLE IF NOT EXISTS instance_status (
+  uuid blob NOT NULL PRIMARY KEY,
+  instance blob NOT NULL,
+  status text NOT NULL,
+  date text NOT NULL,
+  loan blob,
+  note text,
+  CHECK (status IN ('available', 'in_repair', 'lost', 'stolen', 'retired')),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS instance_status_instance ON instance_status (instance);
+
+-- Inventory details of an instance, prices in cents
+CREATE TABLE IF NOT EXISTS instance_asset (
+  instance blob NOT NULL PRIMARY KEY,
+  serial_number text UNIQUE,
+  purchase_date text,
+  purchase_price numeric,
+  current_value numeric,
+  supplier text,
+  warranty_end text,
+  CHECK (purchase_price >= 0 AND current_value >= 0),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+-- Where an instance is kept, for insurance
+CREATE TABLE IF NOT EXISTS instance_location (
+  instance blob NOT NULL PRIMARY KEY,
+  location text NOT NULL,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+-- Depreciation of purchase prices, inherited by subcategories. The rate is a
+-- yearly percentage of the purchase price or of the remaining value.
+CREATE TABLE IF NOT EXISTS depreciation (
+  category blob NOT NULL PRIMARY KEY,
+  method text NOT NULL,
+  rate integer NOT NULL,
+  CHECK (method IN ('straight_line', 'declining_balance')),
+  CHECK (rate > 0 AND rate <= 100),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Invoices are numbered sequentially, totals are in cents
+CREATE TABLE IF NOT EXISTS invoice (
+  number integer NOT NULL PRIMARY KEY,
+  loan blob NOT NULL,
+  date text NOT NULL,
+  total numeric NOT NULL,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+-- Lines of an invoice as it was issued, in print order. Later changes to the
+-- loan or the ledger don't alter them.
+CREATE TABLE IF NOT EXISTS invoice_line (
+  invoice integer NOT NULL,
+  position integer NOT NULL,
+  description text NOT NULL,
+  amount numeric NOT NULL,
+  PRIMARY KEY (invoice, position),
+  FOREIGN KEY (invoice) REFERENCES invoice (number) ON DELETE CASCADE
+);
+
+-- Daily late fee in cents, inherited by subcategories
+CREATE TABLE IF NOT EXISTS late_fee_rate (
+  category blob NOT NULL PRIMARY KEY,
+  daily_rate numeric NOT NULL,
+  CHECK (daily_rate >= 0),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Charges (positive) and payments (negative) in cents
+CREATE TABLE IF NOT EXISTS ledger (
+  uuid blob NOT NULL PRIMARY KEY,
+  user blob NOT NULL,
+  date text NOT NULL,
+  kind text NOT NULL,
+  amount numeric NOT NULL,
+  loan blob,
+  description text,
+  CHECK (kind IN ('late_fee', 'damage', 'adjustment', 'payment')),
+  FOREIGN KEY (user) REFERENCES user (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS user_email (
+  user blob NOT NULL PRIMARY KEY,
+  email text NOT NULL,
+  FOREIGN KEY (user) REFERENCES user (uuid) ON DELETE CASCADE
+);
+
+-- Outbox of messages to users. reference is the loan or membership payment
+-- the message is about and may outlive it.
+CREATE TABLE IF NOT EXISTS notification (
+  uuid blob NOT NULL PRIMARY KEY,
+  user blob NOT NULL,
+  kind text NOT NULL,
+  reference blob,
+  subject text NOT NULL,
+  body text NOT NULL,
+  created text NOT NULL,
+  status text NOT NULL,
+  attempts integer NOT NULL DEFAULT 0,
+  next_attempt text NOT NULL,
+  last_error text,
+  date_sent text,
+  CHECK (kind IN ('loan_created', 'loan_approved', 'loan_rejected', 'loan_due_soon',
+    'loan_overdue', 'membership_expiring')),
+  CHECK (status IN ('pending', 'sent', 'failed')),
+  FOREIGN KEY (user) REFERENCES user (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notification_status ON notification (status, next_attempt);
+
+-- Append-only log of changes. entity is not a foreign key, entries outlive
+-- what they are about. date is in UTC with microseconds so that it sorts as
+-- text, before and after are JSON.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id integer NOT NULL PRIMARY KEY AUTOINCREMENT,
+  date text NOT NULL,
+  actor text NOT NULL,
+  operation text NOT NULL,
+  entity_type text NOT NULL,
+  entity blob NOT NULL,
+  before text,
+  after text
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_entity ON audit_log (entity);
+CREATE INDEX IF NOT EXISTS audit_log_actor ON audit_log (actor, date);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_update BEFORE UPDATE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_delete BEFORE DELETE ON audit_log
+BEGIN
+  SELECT RAISE(ABORT, 'audit log is append-only');
+END;
+
 
 CREATE VIEW IF NOT EXISTS loan_view AS
 SELECT
@@ -78,7 +410,10 @@ SELECT
   product.name AS product_name,
   category.uuid AS category_uuid,
   category.name AS category_name,
-  category.supercategory AS category_supercategory
+  category.supercategory AS category_supercategory,
+  loan_instances.date_returned AS instance_date_returned,
+  loan.price AS loan_price,
+  loan.rejected AS loan_rejected
 FROM loan_instances
   JOIN loan ON loan_instances.loan = loan.uuid
   JOIN instance ON loan_instances.instance = instance.uuid
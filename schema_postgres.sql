@@ -0,0 +1,447 @@
+-- PostgreSQL version of schema.sql. Table and column names are the same,
+-- "user" has to be quoted as it is a reserved word.
+
+CREATE EXTENSION IF NOT EXISTS btree_gist;
+
+CREATE TABLE IF NOT EXISTS category (
+  uuid uuid NOT NULL PRIMARY KEY,
+  name text NOT NULL,
+  supercategory uuid,
+  FOREIGN KEY (supercategory) REFERENCES category (uuid) ON DELETE RESTRICT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS category_name ON category (name);
+
+
+CREATE TABLE IF NOT EXISTS product (
+  uuid uuid NOT NULL PRIMARY KEY,
+  name text NOT NULL,
+  category uuid NOT NULL,
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE RESTRICT
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS product_name ON product (name);
+
+
+CREATE TABLE IF NOT EXISTS instance (
+  uuid uuid NOT NULL PRIMARY KEY,
+  identifier text NOT NULL,
+  product uuid NOT NULL,
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS instance_product_identifier ON instance (product, identifier);
+
+
+CREATE TABLE IF NOT EXISTS "user" (
+  uuid uuid NOT NULL PRIMARY KEY,
+  name text NOT NULL
+);
+
+
+CREATE TABLE IF NOT EXISTS loan (
+  uuid uuid NOT NULL PRIMARY KEY,
+  "user" uuid NOT NULL,
+  date_start timestamptz NOT NULL,
+  date_end timestamptz NOT NULL,
+  accepted boolean NOT NULL,
+  rejected boolean NOT NULL DEFAULT false,
+  description text,
+  price numeric NOT NULL DEFAULT 0,
+  CHECK (date_start < date_end),
+  FOREIGN KEY ("user") REFERENCES "user" (uuid) ON DELETE RESTRICT
+);
+
+
+CREATE TABLE IF NOT EXISTS membership_type (
+  uuid uuid NOT NULL PRIMARY KEY,
+  type text NOT NULL,
+  rental_discount integer NOT NULL DEFAULT 0,
+  CHECK (rental_discount BETWEEN 0 AND 100)
+);
+
+CREATE TABLE IF NOT EXISTS membership_payments (
+  uuid uuid NOT NULL PRIMARY KEY,
+  "user" uuid NOT NULL,
+  membership_type uuid NOT NULL,
+  price numeric NOT NULL,
+  date_start date NOT NULL,
+  date_end date NOT NULL,
+  FOREIGN KEY ("user") REFERENCES "user" (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (membership_type) REFERENCES membership_type (uuid) ON DELETE RESTRICT
+);
+
+
+-- Rental prices in cents. Products without a row are free.
+CREATE TABLE IF NOT EXISTS product_price (
+  product uuid NOT NULL PRIMARY KEY,
+  per_day numeric NOT NULL,
+  per_weekend numeric,
+  per_week numeric,
+  CHECK (per_day >= 0 AND per_weekend >= 0 AND per_week >= 0),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Kits are booked as one unit. Each item is either a number of instances of
+-- a product or a specific instance.
+CREATE TABLE IF NOT EXISTS kit (
+  uuid uuid NOT NULL PRIMARY KEY,
+  name text NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS kit_item (
+  kit uuid NOT NULL,
+  position bigint NOT NULL,
+  product uuid,
+  instance uuid,
+  quantity bigint NOT NULL DEFAULT 1,
+  CHECK ((product IS NULL) <> (instance IS NULL)),
+  CHECK (quantity > 0),
+  PRIMARY KEY (kit, position),
+  FOREIGN KEY (kit) REFERENCES kit (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE RESTRICT
+);
+
+-- Attributes of the products of a category and its subcategories. Options
+-- are a JSON array of the values an enum can have.
+CREATE TABLE IF NOT EXISTS attribute (
+  uuid uuid NOT NULL PRIMARY KEY,
+  category uuid NOT NULL,
+  name text NOT NULL,
+  kind text NOT NULL,
+  options text NOT NULL DEFAULT '[]',
+  required boolean NOT NULL,
+  CHECK (kind IN ('text', 'number', 'enum', 'bool')),
+  UNIQUE (category, name),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS product_attribute (
+  product uuid NOT NULL,
+  attribute uuid NOT NULL,
+  value text NOT NULL,
+  PRIMARY KEY (product, attribute),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (attribute) REFERENCES attribute (uuid) ON DELETE CASCADE
+);
+
+-- Compatible products are related both ways, whichever comes first.
+CREATE TABLE IF NOT EXISTS product_relation (
+  product uuid NOT NULL,
+  related uuid NOT NULL,
+  kind text NOT NULL,
+  CHECK (kind IN ('compatible_with', 'requires', 'recommended_with')),
+  CHECK (product <> related),
+  PRIMARY KEY (product, related, kind),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (related) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Deposits in cents. Product deposits override the inherited category ones.
+CREATE TABLE IF NOT EXISTS product_deposit (
+  product uuid NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  CHECK (amount >= 0),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS category_deposit (
+  category uuid NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  CHECK (amount >= 0),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Deposit taken for a loan when it was handed out
+CREATE TABLE IF NOT EXISTS loan_deposit (
+  loan uuid NOT NULL PRIMARY KEY,
+  amount numeric NOT NULL,
+  date_received timestamptz NOT NULL,
+  status text NOT NULL,
+  date_settled timestamptz,
+  reason text,
+  CHECK (amount > 0),
+  CHECK (status IN ('held', 'refunded', 'withheld')),
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+-- Damage or wear found on an instance, optionally during a loan. An instance
+-- with an unresolved report that put it out of service can't be booked.
+CREATE TABLE IF NOT EXISTS condition_report (
+  uuid uuid NOT NULL PRIMARY KEY,
+  instance uuid NOT NULL,
+  loan uuid,
+  severity text NOT NULL,
+  description text NOT NULL,
+  reporter text NOT NULL,
+  date timestamptz NOT NULL,
+  out_of_service boolean NOT NULL,
+  date_resolved timestamptz,
+  resolution text,
+  CHECK (severity IN ('minor', 'major', 'critical')),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS condition_report_instance ON condition_report (instance);
+
+-- Service of an instance on known dates. Instances can't be booked during it.
+CREATE TABLE IF NOT EXISTS maintenance_window (
+  uuid uuid NOT NULL PRIMARY KEY,
+  instance uuid NOT NULL,
+  date_start timestamptz NOT NULL,
+  date_end timestamptz NOT NULL,
+  reason text NOT NULL,
+  CHECK (date_start < date_end),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS maintenance_window_instance ON maintenance_window (instance);
+
+-- Service intervals of a product. Whichever limit is reached first makes an
+-- instance due for maintenance.
+CREATE TABLE IF NOT EXISTS maintenance_rule (
+  product uuid NOT NULL PRIMARY KEY,
+  loans bigint,
+  days_out bigint,
+  months bigint,
+  CHECK (loans > 0 AND days_out > 0 AND months > 0),
+  CHECK (loans IS NOT NULL OR days_out IS NOT NULL OR months IS NOT NULL),
+  FOREIGN KEY (product) REFERENCES product (uuid) ON DELETE CASCADE
+);
+
+-- Readings of counters not derived from loans, e.g. shutter count
+CREATE TABLE IF NOT EXISTS usage_reading (
+  uuid uuid NOT NULL PRIMARY KEY,
+  instance uuid NOT NULL,
+  counter text NOT NULL,
+  value bigint NOT NULL,
+  date timestamptz NOT NULL,
+  CHECK (value >= 0),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS usage_reading_instance ON usage_reading (instance);
+
+-- Lifecycle of an instance. The latest change is its status, instances
+-- without changes are available.
+CREATE TABLE IF NOT EXISTS instance_status (
+  uuid uuid NOT NULL PRIMARY KEY,
+  instance uuid NOT NULL,
+  status text NOT NULL,
+  date timestamptz NOT NULL,
+  loan uuid,
+  note text,
+  CHECK (status IN ('available', 'in_repair', 'lost', 'stolen', 'retired')),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE INDEX IF NOT EXISTS instance_status_instance ON instance_status (instance);
+
+-- Inventory details of an instance, prices in cents
+CREATE TABLE IF NOT EXISTS instance_asset (
+  instance uuid NOT NULL PRIMARY KEY,
+  serial_number text UNIQUE,
+  purchase_date timestamptz,
+  purchase_price numeric,
+  current_value numeric,
+  supplier text,
+  warranty_end timestamptz,
+  CHECK (purchase_price >= 0 AND current_value >= 0),
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+-- Where an instance is kept, for insurance
+CREATE TABLE IF NOT EXISTS instance_location (
+  instance uuid NOT NULL PRIMARY KEY,
+  location text NOT NULL,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE CASCADE
+);
+
+-- Depreciation of purchase prices, inherited by subcategories. The rate is a
+-- yearly percentage of the purchase price or of the remaining value.
+CREATE TABLE IF NOT EXISTS depreciation (
+  category uuid NOT NULL PRIMARY KEY,
+  method text NOT NULL,
+  rate bigint NOT NULL,
+  CHECK (method IN ('straight_line', 'declining_balance')),
+  CHECK (rate > 0 AND rate <= 100),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Invoices are numbered sequentially, totals are in cents
+CREATE TABLE IF NOT EXISTS invoice (
+  number bigint NOT NULL PRIMARY KEY,
+  loan uuid NOT NULL,
+  date timestamptz NOT NULL,
+  total numeric NOT NULL,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+-- Lines of an invoice as it was issued, in print order. Later changes to the
+-- loan or the ledger don't alter them.
+CREATE TABLE IF NOT EXISTS invoice_line (
+  invoice bigint NOT NULL,
+  position integer NOT NULL,
+  description text NOT NULL,
+  amount numeric NOT NULL,
+  PRIMARY KEY (invoice, position),
+  FOREIGN KEY (invoice) REFERENCES invoice (number) ON DELETE CASCADE
+);
+
+-- Daily late fee in cents, inherited by subcategories
+CREATE TABLE IF NOT EXISTS late_fee_rate (
+  category uuid NOT NULL PRIMARY KEY,
+  daily_rate numeric NOT NULL,
+  CHECK (daily_rate >= 0),
+  FOREIGN KEY (category) REFERENCES category (uuid) ON DELETE CASCADE
+);
+
+-- Charges (positive) and payments (negative) in cents
+CREATE TABLE IF NOT EXISTS ledger (
+  uuid uuid NOT NULL PRIMARY KEY,
+  "user" uuid NOT NULL,
+  date timestamptz NOT NULL,
+  kind text NOT NULL,
+  amount numeric NOT NULL,
+  loan uuid,
+  description text,
+  CHECK (kind IN ('late_fee', 'damage', 'adjustment', 'payment')),
+  FOREIGN KEY ("user") REFERENCES "user" (uuid) ON DELETE RESTRICT,
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE RESTRICT
+);
+
+CREATE TABLE IF NOT EXISTS user_email (
+  "user" uuid NOT NULL PRIMARY KEY,
+  email text NOT NULL,
+  FOREIGN KEY ("user") REFERENCES "user" (uuid) ON DELETE CASCADE
+);
+
+-- Outbox of messages to users. reference is the loan or membership payment
+-- the message is about and may outlive it.
+CREATE TABLE IF NOT EXISTS notification (
+  uuid uuid NOT NULL PRIMARY KEY,
+  "user" uuid NOT NULL,
+  kind text NOT NULL,
+  reference uuid,
+  subject text NOT NULL,
+  body text NOT NULL,
+  created timestamptz NOT NULL,
+  status text NOT NULL,
+  attempts integer NOT NULL DEFAULT 0,
+  next_attempt timestamptz NOT NULL,
+  last_error text,
+  date_sent timestamptz,
+  CHECK (kind IN ('loan_created', 'loan_approved', 'loan_rejected', 'loan_due_soon',
+    'loan_overdue', 'membership_expiring')),
+  CHECK (status IN ('pending', 'sent', 'failed')),
+  FOREIGN KEY ("user") REFERENCES "user" (uuid) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS notification_status ON notification (status, next_attempt);
+
+-- Append-only log of changes. entity is not a foreign key, entries outlive
+-- what they are about.
+CREATE TABLE IF NOT EXISTS audit_log (
+  id bigint GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+  date timestamptz NOT NULL,
+  actor text NOT NULL,
+  operation text NOT NULL,
+  entity_type text NOT NULL,
+  entity uuid NOT NULL,
+  before jsonb,
+  after jsonb
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_entity ON audit_log (entity);
+CREATE INDEX IF NOT EXISTS audit_log_actor ON audit_log (actor, date);
+
+CREATE OR REPLACE FUNCTION audit_log_append_only() RETURNS trigger AS $$
+BEGIN
+  RAISE EXCEPTION 'audit log is append-only' USING ERRCODE = 'integrity_constraint_violation';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER audit_log_append_only
+BEFORE UPDATE OR DELETE ON audit_log
+FOR EACH ROW EXECUTE FUNCTION audit_log_append_only();
+
+
+-- accepted and period are copies of the loan columns, so that the exclusion
+-- constraint can keep accepted loans of an instance from overlapping.
+CREATE TABLE IF NOT EXISTS loan_instances (
+  loan uuid NOT NULL,
+  instance uuid NOT NULL,
+  accepted boolean NOT NULL,
+  period tstzrange NOT NULL,
+  date_returned timestamptz,
+  PRIMARY KEY (loan, instance),
+  FOREIGN KEY (loan) REFERENCES loan (uuid) ON DELETE CASCADE,
+  FOREIGN KEY (instance) REFERENCES instance (uuid) ON DELETE RESTRICT,
+  EXCLUDE USING gist (instance WITH =, period WITH &&) WHERE (accepted)
+);
+
+CREATE OR REPLACE FUNCTION loan_instances_sync() RETURNS trigger AS $$
+BEGIN
+  UPDATE loan_instances
+  SET accepted = NEW.accepted,
+    period = tstzrange(NEW.date_start, NEW.date_end, '[]')
+  WHERE loan = NEW.uuid;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE TRIGGER loan_instances_sync
+AFTER UPDATE OF accepted, date_start, date_end ON loan
+FOR EACH ROW EXECUTE FUNCTION loan_instances_sync();
+
+
+-- Price of each instance agreed when the loan was made, in cents
+CREATE TABLE IF NOT EXISTS loan_price (
+  loan uuid NOT NULL,
+  instance uuid NOT NULL,
+  description text NOT NULL,
+  price numeric NOT NULL,
+  discount numeric NOT NULL,
+  PRIMARY KEY (loan, instance),
+  FOREIGN KEY (loan, instance) REFERENCES loan_instances (loan, instance) ON DELETE CASCADE
+);
+
+-- Instances of a loan that were booked as part of a kit.
+CREATE TABLE IF NOT EXISTS loan_kit (
+  loan uuid NOT NULL,
+  instance uuid NOT NULL,
+  kit uuid NOT NULL,
+  PRIMARY KEY (loan, instance),
+  FOREIGN KEY (loan, instance) REFERENCES loan_instances (loan, instance) ON DELETE CASCADE,
+  FOREIGN KEY (kit) REFERENCES kit (uuid) ON DELETE RESTRICT
+);
+
+
+CREATE OR REPLACE VIEW loan_view AS
+SELECT
+  loan.uuid AS loan_uuid,
+  loan.date_start AS loan_date_start,
+  loan.date_end AS loan_date_end,
+  loan.accepted AS loan_accepted,
+  loan.description AS loan_description,
+  "user".uuid AS user_uuid,
+  "user".name AS user_name,
+  instance.uuid AS instance_uuid,
+  instance.identifier AS instance_identifier,
+  product.uuid AS product_uuid,
+  product.name AS product_name,
+  category.uuid AS category_uuid,
+  category.name AS category_name,
+  category.supercategory AS category_supercategory,
+  loan_instances.date_returned AS instance_date_returned,
+  loan.price AS loan_price,
+  loan.rejected AS loan_rejected
+FROM loan_instances
+  JOIN loan ON loan_instances.loan = loan.uuid
+  JOIN instance ON loan_instances.instance = instance.uuid
+  JOIN product ON instance.product = product.uuid
+  JOIN category ON product.category = category.uuid
+  JOIN "user" ON loan."user" = "user".uuid;
@@ -0,0 +1,192 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::database::DatabaseError;
+
+/// Kind of value an attribute holds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "options", rename_all = "snake_case")]
+pub enum AttributeType {
+    Text,
+    Number,
+    /// One of the options.
+    Enum(Vec<String>),
+    Bool,
+}
+
+impl AttributeType {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AttributeType::Text => "text",
+            AttributeType::Number => "number",
+            AttributeType::Enum(_) => "enum",
+            AttributeType::Bool => "bool",
+        }
+    }
+
+    /// Options are only used for enums.
+    pub fn parse(kind: &str, options: Vec<String>) -> Option<Self> {
+        match kind {
+            "text" => Some(AttributeType::Text),
+            "number" => Some(AttributeType::Number),
+            "enum" => Some(AttributeType::Enum(options)),
+            "bool" => Some(AttributeType::Bool),
+            _ => None,
+        }
+    }
+
+    pub fn options(&self) -> &[String] {
+        match self {
+            AttributeType::Enum(options) => options,
+            _ => &[],
+        }
+    }
+
+    /// The value as it is stored, e.g. `true` for `True` and the option as
+    /// it is spelled for enums. Fails with `Invalid` if it isn't of the type.
+    pub fn validate(&self, value: &str) -> Result<String, DatabaseError> {
+        let value = value.trim();
+        let invalid = || {
+            DatabaseError::Invalid(format!(
+                "\"{}\" is not a valid {} value.",
+                value,
+                self.as_str()
+            ))
+        };
+        match self {
+            AttributeType::Text if !value.is_empty() => Ok(value.to_string()),
+            AttributeType::Number if value.parse::<f64>().is_ok_and(f64::is_finite) => {
+                Ok(value.to_string())
+            }
+            AttributeType::Enum(options) => options
+                .iter()
+                .find(|option| option.eq_ignore_ascii_case(value))
+                .cloned()
+                .ok_or_else(invalid),
+            AttributeType::Bool => match value.to_ascii_lowercase().as_str() {
+                "true" => Ok("true".to_string()),
+                "false" => Ok("false".to_string()),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Attribute that products of a category and its subcategories have, e.g.
+/// the mount of lenses.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributeDefinition {
+    pub uuid: Uuid,
+    pub category: Uuid,
+    /// Unique within the category tree it applies to.
+    pub name: String,
+    pub kind: AttributeType,
+    /// Products must have a value for it.
+    pub required: bool,
+}
+
+/// Value of an attribute of a product, as stored.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributeValue {
+    pub product: Uuid,
+    pub attribute: Uuid,
+    pub value: String,
+}
+
+/// Attribute of a product with its value, if it has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProductAttribute {
+    pub attribute: AttributeDefinition,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// Operators as they are written in filters. Longer ones come first so
+    /// that `<=` is not read as `<`.
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("=", Comparison::Equal),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+}
+
+/// Condition on an attribute value, e.g. `mount = RF` or
+/// `focal length >= 50`. Numbers are compared as numbers, other values
+/// ignoring case.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeFilter {
+    pub name: String,
+    pub comparison: Comparison,
+    pub value: String,
+}
+
+impl AttributeFilter {
+    pub fn new(name: &str, comparison: Comparison, value: &str) -> Self {
+        Self {
+            name: name.trim().to_string(),
+            comparison,
+            value: value.trim().to_string(),
+        }
+    }
+
+    /// Reads a filter written as `<name> <operator> <value>`.
+    pub fn parse(filter: &str) -> Result<Self, DatabaseError> {
+        let invalid =
+            || DatabaseError::Invalid(format!("Invalid attribute filter \"{}\".", filter));
+        let (index, operator, comparison) = Comparison::OPERATORS
+            .iter()
+            .filter_map(|(operator, comparison)| {
+                filter
+                    .find(operator)
+                    .map(|index| (index, *operator, *comparison))
+            })
+            .min_by_key(|(index, _, _)| *index)
+            .ok_or_else(invalid)?;
+        let name = &filter[..index];
+        let value = &filter[index + operator.len()..];
+        if name.trim().is_empty() || value.trim().is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self::new(name, comparison, value))
+    }
+
+    /// Whether a stored value of the type passes the filter.
+    pub fn matches(&self, kind: &AttributeType, value: &str) -> bool {
+        let ordering = match kind {
+            AttributeType::Number => {
+                let (Ok(value), Ok(wanted)) = (value.parse::<f64>(), self.value.parse::<f64>())
+                else {
+                    return false;
+                };
+                value.partial_cmp(&wanted)
+            }
+            _ => Some(value.to_lowercase().cmp(&self.value.to_lowercase())),
+        };
+        let Some(ordering) = ordering else {
+            return false;
+        };
+        match self.comparison {
+            Comparison::Equal => ordering.is_eq(),
+            Comparison::NotEqual => ordering.is_ne(),
+            Comparison::Less => ordering.is_lt(),
+            Comparison::LessOrEqual => ordering.is_le(),
+            Comparison::Greater => ordering.is_gt(),
+            Comparison::GreaterOrEqual => ordering.is_ge(),
+        }
+    }
+}
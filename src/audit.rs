@@ -0,0 +1,117 @@
+use chrono::DateTime;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Actor of changes made through a `Database` that was not given one with
+/// `acting_as`.
+pub const SYSTEM_ACTOR: &str = "system";
+
+/// Kind of row an audit entry is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityType {
+    User,
+    Category,
+    Product,
+    Instance,
+    Loan,
+    MembershipType,
+    MembershipPayment,
+    LedgerEntry,
+    /// Identified by the UUID of its loan.
+    Invoice,
+    /// Identified by the UUID of its loan.
+    Deposit,
+    ConditionReport,
+    MaintenanceWindow,
+    /// Identified by the UUID of its product.
+    MaintenanceRule,
+    UsageReading,
+    Attribute,
+    Kit,
+}
+
+impl EntityType {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityType::User => "user",
+            EntityType::Category => "category",
+            EntityType::Product => "product",
+            EntityType::Instance => "instance",
+            EntityType::Loan => "loan",
+            EntityType::MembershipType => "membership_type",
+            EntityType::MembershipPayment => "membership_payment",
+            EntityType::LedgerEntry => "ledger_entry",
+            EntityType::Invoice => "invoice",
+            EntityType::Deposit => "deposit",
+            EntityType::ConditionReport => "condition_report",
+            EntityType::MaintenanceWindow => "maintenance_window",
+            EntityType::MaintenanceRule => "maintenance_rule",
+            EntityType::UsageReading => "usage_reading",
+            EntityType::Attribute => "attribute",
+            EntityType::Kit => "kit",
+        }
+    }
+
+    pub fn parse(entity_type: &str) -> Option<Self> {
+        match entity_type {
+            "user" => Some(EntityType::User),
+            "category" => Some(EntityType::Category),
+            "product" => Some(EntityType::Product),
+            "instance" => Some(EntityType::Instance),
+            "loan" => Some(EntityType::Loan),
+            "membership_type" => Some(EntityType::MembershipType),
+            "membership_payment" => Some(EntityType::MembershipPayment),
+            "ledger_entry" => Some(EntityType::LedgerEntry),
+            "invoice" => Some(EntityType::Invoice),
+            "deposit" => Some(EntityType::Deposit),
+            "condition_report" => Some(EntityType::ConditionReport),
+            "maintenance_window" => Some(EntityType::MaintenanceWindow),
+            "maintenance_rule" => Some(EntityType::MaintenanceRule),
+            "usage_reading" => Some(EntityType::UsageReading),
+            "attribute" => Some(EntityType::Attribute),
+            "kit" => Some(EntityType::Kit),
+            _ => None,
+        }
+    }
+}
+
+/// Change made by a mutating `Database` method. Entries are never changed
+/// or deleted, not even when the entity is.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Increases with every entry, in the order they were made.
+    pub id: i64,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+    pub actor: String,
+    /// Name of the `Database` method, e.g. `remove_product`.
+    pub operation: String,
+    pub entity_type: EntityType,
+    pub entity: Uuid,
+    /// The entity, or the part of it that changed, as JSON. None when it was
+    /// created.
+    pub before: Option<serde_json::Value>,
+    /// None when it was deleted.
+    pub after: Option<serde_json::Value>,
+}
+
+/// Filters for the audit log. Entries must match every filter given.
+#[derive(Default, Debug, Clone)]
+pub struct AuditQueryParams {
+    pub entity_type: Option<EntityType>,
+    pub entity: Option<Uuid>,
+    pub actor: Option<String>,
+    /// Entries made at or after this time.
+    pub date_start: Option<DateTime<Tz>>,
+    /// Entries made before this time.
+    pub date_end: Option<DateTime<Tz>>,
+}
+
+impl AuditQueryParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
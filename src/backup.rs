@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono_tz::Europe::Helsinki;
+use chrono_tz::Tz;
+
+use crate::database::{Database, DatabaseError};
+
+/// Names of rotated backups, e.g. `loaner-20240610-120000-000.db`. They sort
+/// in the order they were taken.
+const FILE_NAME_FORMAT: &str = "loaner-%Y%m%d-%H%M%S-%3f.db";
+/// Names of rotated backups before they had milliseconds, which are still
+/// rotated.
+const OLD_FILE_NAME_FORMAT: &str = "loaner-%Y%m%d-%H%M%S.db";
+
+pub fn file_name(date: DateTime<Tz>) -> String {
+    date.format(FILE_NAME_FORMAT).to_string()
+}
+
+/// Path for a new backup in the directory taken at `date`. Should a backup
+/// of the same millisecond exist, the next free millisecond is used instead
+/// so that it isn't overwritten.
+pub fn new_backup_path(directory: &Path, date: DateTime<Tz>) -> PathBuf {
+    let mut date = date;
+    let mut path = directory.join(file_name(date));
+    while path.exists() {
+        date += chrono::Duration::milliseconds(1);
+        path = directory.join(file_name(date));
+    }
+    path
+}
+
+fn backup_date(name: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(name, FILE_NAME_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(name, OLD_FILE_NAME_FORMAT))
+        .ok()
+}
+
+/// Rotated backups in the directory, oldest first. Other files are ignored.
+pub fn list_backups(directory: &Path) -> Result<Vec<PathBuf>, DatabaseError> {
+    let entries = fs::read_dir(directory).map_err(|e| DatabaseError::Internal(e.to_string()))?;
+    let mut backups = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| DatabaseError::Internal(e.to_string()))?;
+        let date = entry.file_name().to_str().and_then(backup_date);
+        if let Some(date) = date {
+            if entry.path().is_file() {
+                backups.push((date, entry.path()));
+            }
+        }
+    }
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Deletes all but the newest `keep` backups in the directory and returns
+/// the deleted files.
+pub fn remove_old_backups(directory: &Path, keep: usize) -> Result<Vec<PathBuf>, DatabaseError> {
+    let mut backups = list_backups(directory)?;
+    let count = backups.len().saturating_sub(keep);
+    let removed: Vec<PathBuf> = backups.drain(..count).collect();
+    for path in removed.iter() {
+        fs::remove_file(path).map_err(|e| DatabaseError::Internal(e.to_string()))?;
+    }
+    Ok(removed)
+}
+
+/// Takes a rotating backup at a fixed interval on a thread of its own, until
+/// stopped or dropped. Failed backups are reported on standard error and
+/// tried again at the next interval.
+pub struct BackupSchedule {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl BackupSchedule {
+    /// The first backup is taken one interval after starting.
+    pub fn start(db: Arc<Database>, directory: &str, interval: Duration, keep: usize) -> Self {
+        let directory = directory.to_string();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            // Anything else means stopped, or the schedule was dropped
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let now = Utc::now().with_timezone(&Helsinki);
+                if let Err(e) = db.backup_rotating(&directory, keep, now) {
+                    eprintln!("Backup to {} failed: {:?}", directory, e);
+                }
+            }
+        });
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Waits for a backup in progress to finish.
+    pub fn stop(mut self) {
+        self.shut_down();
+    }
+
+    fn shut_down(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for BackupSchedule {
+    fn drop(&mut self) {
+        self.shut_down();
+    }
+}
@@ -0,0 +1,145 @@
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::database::{Loan, MaintenanceWindow};
+
+/// Loans as a named iCalendar feed, one event per loan and maintenance window.
+#[derive(Debug, Clone)]
+pub struct Calendar {
+    pub name: String,
+    pub loans: Vec<Loan>,
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// Whether loans are shown with their items and borrower. Otherwise
+    /// they are only shown as booked, with the items in the description.
+    pub borrowers: bool,
+}
+
+/// Lines longer than this many octets are folded.
+const MAX_LINE_LENGTH: usize = 75;
+
+fn format_date<Z: TimeZone>(date: &DateTime<Z>) -> String {
+    date.with_timezone(&Utc)
+        .format("%Y%m%dT%H%M%SZ")
+        .to_string()
+}
+
+fn escape_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\;"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => {}
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Appends a content line, folded so that no line exceeds the limit and no
+/// character is split.
+fn push_line(ics: &mut String, line: &str) {
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > MAX_LINE_LENGTH {
+            ics.push_str("\r\n ");
+            // The leading space counts towards the limit
+            length = 1;
+        }
+        ics.push(c);
+        length += c.len_utf8();
+    }
+    ics.push_str("\r\n");
+}
+
+impl Calendar {
+    pub fn to_ics(&self) -> String {
+        let stamp = format_date(&Utc::now());
+
+        let mut ics = String::new();
+        push_line(&mut ics, "BEGIN:VCALENDAR");
+        push_line(&mut ics, "VERSION:2.0");
+        push_line(&mut ics, "PRODID:-//Loaner//Loaner//EN");
+        push_line(&mut ics, "CALSCALE:GREGORIAN");
+        push_line(&mut ics, "METHOD:PUBLISH");
+        push_line(
+            &mut ics,
+            &format!("X-WR-CALNAME:{}", escape_text(&self.name)),
+        );
+        for loan in self.loans.iter() {
+            let items: Vec<String> = loan
+                .instaces
+                .iter()
+                .map(|instance| format!("{} {}", instance.product.name, instance.identifier))
+                .collect();
+            let (status, state) = if loan.rejected {
+                ("CANCELLED", "rejected")
+            } else if loan.accepted {
+                ("CONFIRMED", "approved")
+            } else {
+                ("TENTATIVE", "waiting for approval")
+            };
+            let (summary, description) = if self.borrowers {
+                (
+                    items.join(", "),
+                    format!("Borrower: {}\nLoan {}", loan.user.name, state),
+                )
+            } else {
+                (
+                    "Booked".to_string(),
+                    format!("{}\nLoan {}", items.join(", "), state),
+                )
+            };
+
+            push_line(&mut ics, "BEGIN:VEVENT");
+            // Loan UUIDs are unique and never change, so calendar apps can
+            // update events they have seen before.
+            push_line(&mut ics, &format!("UID:{}@loaner", loan.uuid));
+            push_line(&mut ics, &format!("DTSTAMP:{}", stamp));
+            push_line(
+                &mut ics,
+                &format!("DTSTART:{}", format_date(&loan.date_start)),
+            );
+            push_line(&mut ics, &format!("DTEND:{}", format_date(&loan.date_end)));
+            push_line(&mut ics, &format!("SUMMARY:{}", escape_text(&summary)));
+            push_line(
+                &mut ics,
+                &format!("DESCRIPTION:{}", escape_text(&description)),
+            );
+            push_line(&mut ics, &format!("STATUS:{}", status));
+            push_line(&mut ics, "END:VEVENT");
+        }
+        for window in self.maintenance.iter() {
+            push_line(&mut ics, "BEGIN:VEVENT");
+            push_line(&mut ics, &format!("UID:{}@loaner", window.uuid));
+            push_line(&mut ics, &format!("DTSTAMP:{}", stamp));
+            push_line(
+                &mut ics,
+                &format!("DTSTART:{}", format_date(&window.date_start)),
+            );
+            push_line(
+                &mut ics,
+                &format!("DTEND:{}", format_date(&window.date_end)),
+            );
+            push_line(
+                &mut ics,
+                &format!(
+                    "SUMMARY:{}",
+                    escape_text(&format!(
+                        "Maintenance: {} {}",
+                        window.instance.product.name, window.instance.identifier
+                    ))
+                ),
+            );
+            push_line(
+                &mut ics,
+                &format!("DESCRIPTION:{}", escape_text(&window.reason)),
+            );
+            push_line(&mut ics, "STATUS:CONFIRMED");
+            push_line(&mut ics, "END:VEVENT");
+        }
+        push_line(&mut ics, "END:VCALENDAR");
+        ics
+    }
+}
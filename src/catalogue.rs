@@ -0,0 +1,167 @@
+use crate::database::DatabaseError;
+
+/// Columns of catalogue CSV files, in order.
+pub const HEADER: [&str; 3] = ["category", "product", "identifier"];
+
+/// Separates category names in paths, e.g. `Catalogue/Cameras/Digital`.
+/// Separators in the names are escaped with a backslash, as are backslashes.
+pub const PATH_SEPARATOR: char = '/';
+const ESCAPE: char = '\\';
+
+/// Joins category names into a path, escaping separators in the names.
+pub fn join_path(names: &[String]) -> String {
+    let mut path = String::new();
+    for (i, name) in names.iter().enumerate() {
+        if i > 0 {
+            path.push(PATH_SEPARATOR);
+        }
+        for c in name.chars() {
+            if c == PATH_SEPARATOR || c == ESCAPE {
+                path.push(ESCAPE);
+            }
+            path.push(c);
+        }
+    }
+    path
+}
+
+/// Splits a path written by `join_path` into category names. Names are
+/// trimmed.
+pub fn split_path(path: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut name = String::new();
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ESCAPE => name.push(chars.next().unwrap_or(ESCAPE)),
+            PATH_SEPARATOR => names.push(std::mem::take(&mut name)),
+            _ => name.push(c),
+        }
+    }
+    names.push(name);
+    names.iter().map(|name| name.trim().to_string()).collect()
+}
+
+/// Row of a catalogue CSV file. Rows without a product only describe the
+/// category, rows without an identifier only the product.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatalogueRow {
+    /// Line in the file, starting from 1 for the header.
+    pub line: u64,
+    pub category_path: Vec<String>,
+    pub product: Option<String>,
+    pub identifier: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportError {
+    pub line: u64,
+    pub message: String,
+}
+
+/// What an import adds to the catalogue, or would add in a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Paths of the new categories.
+    pub categories: Vec<String>,
+    pub products: Vec<String>,
+    /// Product name and identifier of the new instances.
+    pub instances: Vec<String>,
+    /// Rows that are already in the catalogue or earlier in the file.
+    pub duplicates: usize,
+    /// Rows that can't be imported. Nothing is imported if there are any.
+    pub errors: Vec<ImportError>,
+}
+
+impl ImportReport {
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+        let sections = [
+            ("New categories", &self.categories),
+            ("New products", &self.products),
+            ("New instances", &self.instances),
+        ];
+        for (title, names) in sections {
+            text.push_str(&format!("{}: {}\n", title, names.len()));
+            for name in names.iter() {
+                text.push_str(&format!("  {}\n", name));
+            }
+        }
+        text.push_str(&format!("Already in the catalogue: {}\n", self.duplicates));
+        if !self.errors.is_empty() {
+            text.push_str(&format!("Errors: {}\n", self.errors.len()));
+            for error in self.errors.iter() {
+                text.push_str(&format!("  Line {}: {}\n", error.line, error.message));
+            }
+        }
+        text
+    }
+}
+
+fn non_empty(field: Option<&str>) -> Option<String> {
+    let field = field?.trim();
+    if field.is_empty() {
+        return None;
+    }
+    Some(field.to_string())
+}
+
+/// Reads the rows of a catalogue CSV file. The header row is required and
+/// fields are trimmed.
+pub fn parse(csv: &str) -> Result<Vec<CatalogueRow>, DatabaseError> {
+    let invalid = |message: String| DatabaseError::Invalid(message);
+
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_reader(csv.as_bytes());
+    let header = reader
+        .headers()
+        .map_err(|e| invalid(e.to_string()))?
+        .iter()
+        .map(|field| field.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    if header != HEADER {
+        return Err(invalid(format!("Header must be {}.", HEADER.join(","))));
+    }
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| invalid(e.to_string()))?;
+        let line = record.position().map_or(0, |position| position.line());
+        if record.len() > HEADER.len() {
+            return Err(invalid(format!(
+                "Line {}: expected at most {} fields.",
+                line,
+                HEADER.len()
+            )));
+        }
+        let category_path = match non_empty(record.get(0)) {
+            Some(path) => split_path(&path),
+            None => Vec::new(),
+        };
+        rows.push(CatalogueRow {
+            line,
+            category_path,
+            product: non_empty(record.get(1)),
+            identifier: non_empty(record.get(2)),
+        });
+    }
+    Ok(rows)
+}
+
+/// Writes the rows with a header. Line numbers are ignored.
+pub fn write(rows: &[CatalogueRow]) -> String {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(HEADER).unwrap();
+    for row in rows {
+        let path = join_path(&row.category_path);
+        writer
+            .write_record([
+                path.as_str(),
+                row.product.as_deref().unwrap_or_default(),
+                row.identifier.as_deref().unwrap_or_default(),
+            ])
+            .unwrap();
+    }
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
@@ -1,12 +1,74 @@
-use rusqlite::params_from_iter;
+// The methods of `Database` end in an explicit `return`, like they have
+// from the start. Other modules return the last expression.
+#![allow(clippy::needless_return)]
+
 use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
 use uuid::Uuid;
 
 pub use chrono::prelude::*;
 use chrono_tz::Europe::Helsinki;
 use chrono_tz::Tz;
-use rusqlite::params;
-use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::attribute::{
+    AttributeDefinition, AttributeFilter, AttributeType, AttributeValue, ProductAttribute,
+};
+use crate::audit::{AuditEntry, AuditQueryParams, EntityType, SYSTEM_ACTOR};
+use crate::backup;
+use crate::calendar::Calendar;
+use crate::catalogue::{self, CatalogueRow, ImportError, ImportReport};
+use crate::invoice::{InvoiceDocument, InvoiceLine};
+use crate::notification::{
+    DeliveryReport, Notification, NotificationKind, NotificationStatus, Templates, Transport,
+};
+use crate::snapshot::{
+    Snapshot, SnapshotCategory, SnapshotInstance, SnapshotInvoice, SnapshotLoan,
+    SnapshotLoanInstance, SnapshotMaintenanceWindow, SnapshotProduct, SnapshotUser,
+    SNAPSHOT_VERSION,
+};
+use crate::storage::{NewAuditEntry, NewLoan, SqliteStorage, Storage};
+use crate::valuation::{self, InsuranceReport, InsuranceRow};
+
+/// Failure kinds returned by mutating `Database` methods.
+///
+/// Constraint violations reported by the storage are mapped to the matching
+/// kind, so callers can tell a missing reference from a duplicate or a
+/// conflict.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DatabaseError {
+    /// A referenced row does not exist.
+    NotFound(String),
+    /// A row with the same unique key already exists.
+    AlreadyExists(String),
+    /// The row is still referenced and can't be removed.
+    InUse(String),
+    /// The request collides with an existing loan, or an instance can't be
+    /// booked.
+    Conflict(String),
+    /// The input violates a rule or CHECK constraint.
+    Invalid(String),
+    /// Any other database failure.
+    Internal(String),
+}
+
+impl std::fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let message = match self {
+            DatabaseError::NotFound(message)
+            | DatabaseError::AlreadyExists(message)
+            | DatabaseError::InUse(message)
+            | DatabaseError::Conflict(message)
+            | DatabaseError::Invalid(message)
+            | DatabaseError::Internal(message) => message,
+        };
+        write!(f, "Error: {}", message)
+    }
+}
+
+impl std::error::Error for DatabaseError {}
 
 #[derive(Default, Debug, Clone)]
 pub struct LoanQueryParams {
@@ -18,6 +80,14 @@ pub struct LoanQueryParams {
     pub category_uuid: Option<Uuid>,
     pub date_start: Option<DateTime<Tz>>,
     pub date_end: Option<DateTime<Tz>>,
+    /// Loans due at or after this time.
+    pub due_after: Option<DateTime<Tz>>,
+    /// Loans due before this time.
+    pub due_before: Option<DateTime<Tz>>,
+    /// Whether the loaned instance has been checked in.
+    pub returned: Option<bool>,
+    /// Rejected loans are left out unless this is set.
+    pub include_rejected: bool,
 }
 
 impl LoanQueryParams {
@@ -26,840 +96,4557 @@ impl LoanQueryParams {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Default, Debug, Clone)]
+pub struct ProductQueryParams {
+    pub category_uuid: Option<Uuid>,
+    /// Products must pass all of them.
+    pub attributes: Vec<AttributeFilter>,
+}
+
+impl ProductQueryParams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
     pub uuid: Uuid,
     pub name: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Category {
     pub uuid: Uuid,
     pub name: String,
     pub supercategory: Option<Uuid>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Product {
     pub uuid: Uuid,
     pub name: String,
     pub category: Category,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Instance {
     pub uuid: Uuid,
     pub identifier: String,
     pub product: Product,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Loan {
     pub uuid: Uuid,
     pub user: User,
+    #[serde(with = "crate::snapshot::date")]
     pub date_start: DateTime<Tz>,
+    #[serde(with = "crate::snapshot::date")]
     pub date_end: DateTime<Tz>,
     pub accepted: bool,
+    /// Rejected loans are kept for the notifications, ledger and audit log
+    /// referring to them.
+    #[serde(default)]
+    pub rejected: bool,
     pub description: Option<String>,
+    #[serde(rename = "instances")]
     pub instaces: Vec<Instance>,
+    /// Price agreed when the loan was made, in cents.
+    pub price: i64,
+    /// Filled in by `Database` from the product relations, not stored.
+    #[serde(skip)]
+    pub compatibility: Compatibility,
+    /// Kits booked on the loan, grouping some of its instances. Filled in
+    /// by `Database`.
+    #[serde(skip)]
+    pub kits: Vec<LoanKit>,
 }
 
-pub struct Database {
-    pub connection: Connection,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipType {
+    pub uuid: Uuid,
+    pub name: String,
+    /// Discount on rental prices in percent.
+    pub rental_discount: i64,
 }
 
-impl Database {
-    pub fn new(file_name: &str) -> Self {
-        if file_name != "" {
-            let db_exists = fs::metadata(file_name).is_ok();
-            if db_exists {
-                return Self {
-                    connection: Connection::open(file_name).unwrap(),
-                };
-            } else {
-                let db = Self {
-                    connection: Connection::open(file_name).unwrap(),
-                };
-                db.initialize_database();
-                return db;
-            }
-        } else {
-            let db = Self {
-                connection: Connection::open(file_name).unwrap(),
-            };
-            db.initialize_database();
-            return db;
-        }
-    }
+/// Membership of a user for a period, both days included.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipPayment {
+    pub uuid: Uuid,
+    pub user: Uuid,
+    pub membership_type: Uuid,
+    /// In cents.
+    pub price: i64,
+    pub date_start: NaiveDate,
+    pub date_end: NaiveDate,
+}
 
-    fn initialize_database(&self) {
-        let schema = include_str!("../schema.sql");
-        self.connection.execute_batch(schema).unwrap();
-    }
+/// Rental prices of a product in cents. Products without prices are free.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProductPrice {
+    pub product: Uuid,
+    pub per_day: i64,
+    /// For loans from Friday or Saturday to the following Monday at most.
+    pub per_weekend: Option<i64>,
+    pub per_week: Option<i64>,
+}
 
-    pub fn get_users(&self) -> Vec<User> {
-        let query = String::from(
-            "SELECT
-                user.uuid,
-                user.name
-            FROM user",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let user_iter = statement
-            .query_map([], |row| {
-                Ok(User {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                })
-            })
-            .unwrap();
-        let mut users = Vec::new();
-        for user in user_iter {
-            users.push(user.unwrap());
+/// Price of one instance on a loan, in cents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuoteLine {
+    pub instance: Uuid,
+    /// How the price was reached, e.g. "1 week, 2 days".
+    pub description: String,
+    /// Price before the discount.
+    pub price: i64,
+    /// Membership discount, subtracted from the price.
+    pub discount: i64,
+}
+
+/// Itemized rental price of a loan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quote {
+    pub lines: Vec<QuoteLine>,
+    /// Sum of the prices less discounts.
+    pub total: i64,
+}
+
+/// Kind of a ledger entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerEntryKind {
+    /// Charged when instances are checked in after the loan has ended.
+    LateFee,
+    Damage,
+    /// Manual correction, either way.
+    Adjustment,
+    Payment,
+}
+
+impl LedgerEntryKind {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LedgerEntryKind::LateFee => "late_fee",
+            LedgerEntryKind::Damage => "damage",
+            LedgerEntryKind::Adjustment => "adjustment",
+            LedgerEntryKind::Payment => "payment",
         }
-        return users;
     }
 
-    pub fn get_user(&self, uuid: Uuid) -> Option<User> {
-        let query = String::from(
-            "SELECT
-                user.uuid,
-                user.name
-            FROM user
-            WHERE user.uuid = ?1",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let mut user_iter = statement
-            .query_map(params![uuid], |row| {
-                Ok(User {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                })
-            })
-            .unwrap();
-        if let Some(user) = user_iter.next() {
-            return Some(user.unwrap());
-        } else {
-            None
+    /// Name printed on invoices.
+    pub fn label(&self) -> &'static str {
+        match self {
+            LedgerEntryKind::LateFee => "Late fee",
+            LedgerEntryKind::Damage => "Damage",
+            LedgerEntryKind::Adjustment => "Adjustment",
+            LedgerEntryKind::Payment => "Payment",
         }
     }
 
-    pub fn get_user_by_name(&self, name: &str) -> Option<User> {
-        let query = String::from(
-            "SELECT
-                user.uuid,
-                user.name
-            FROM user
-            WHERE user.name = ?1",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let mut user_iter = statement
-            .query_map(params![name], |row| {
-                Ok(User {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                })
-            })
-            .unwrap();
-        if let Some(user) = user_iter.next() {
-            return Some(user.unwrap());
-        } else {
-            None
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "late_fee" => Some(LedgerEntryKind::LateFee),
+            "damage" => Some(LedgerEntryKind::Damage),
+            "adjustment" => Some(LedgerEntryKind::Adjustment),
+            "payment" => Some(LedgerEntryKind::Payment),
+            _ => None,
         }
     }
+}
 
-    pub fn add_user(&self, name: &str) -> Result<User, String> {
-        let uuid = Uuid::new_v4();
-        let query = String::from(
-            "INSERT INTO
-                user (uuid, name)
-            VALUES
-                (?1, ?2)",
-        );
-        self.connection
-            .execute(&query, params![uuid, name])
-            .unwrap();
+/// Charge or payment on a user's account.
+///
+/// Amounts are in cents. Charges are positive and payments negative, so the
+/// balance is the sum of the amounts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LedgerEntry {
+    pub uuid: Uuid,
+    pub user: Uuid,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+    pub kind: LedgerEntryKind,
+    pub amount: i64,
+    pub loan: Option<Uuid>,
+    pub description: Option<String>,
+}
 
-        match self.get_user_by_name(name) {
-            Some(user) => Ok(user),
-            None => Err("Error: User not found.".to_string()),
+/// Invoice issued for a loan. Numbers are sequential over all invoices.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invoice {
+    pub number: i64,
+    pub loan: Uuid,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+    /// In cents.
+    pub total: i64,
+}
+
+/// Rules applied when loans are added.
+#[derive(Debug, Clone)]
+pub struct BorrowingPolicy {
+    /// Loans longer than this need manual approval.
+    pub approval_limit: chrono::Duration,
+    /// Refuse new loans while the user owes money.
+    pub block_on_balance: bool,
+}
+
+impl Default for BorrowingPolicy {
+    fn default() -> Self {
+        Self {
+            approval_limit: chrono::Duration::days(7),
+            block_on_balance: false,
         }
     }
+}
 
-    pub fn remove_user(&self, uuid: Uuid) -> Result<(), String> {
-        let query = String::from(
-            "DELETE FROM user
-            WHERE user.uuid = ?1",
-        );
-        self.connection
-            .execute(&query, params![uuid])
-            .map_err(|e| e.to_string())?;
-        Ok(())
-    }
+/// Accepted loan past its end date with instances still out. Only the
+/// instances that have not been checked in are listed.
+#[derive(Debug, Clone)]
+pub struct OverdueLoan {
+    pub loan: Loan,
+    /// Calendar days since the due date, 0 if it was due earlier today.
+    pub days_overdue: i64,
+}
 
-    pub fn get_categories(&self, supercategory: Option<Uuid>) -> Vec<Category> {
-        let mut statement: rusqlite::Statement;
-        if let Some(_) = supercategory {
-            let query = String::from(
-                "SELECT
-                    category.uuid,
-                    category.name,
-                    category.supercategory
-                FROM category
-                WHERE category.supercategory = ?1",
-            );
-            statement = self.connection.prepare(&query).unwrap();
-        } else {
-            let query = String::from(
-                "SELECT
-                    category.uuid,
-                    category.name,
-                    category.supercategory
-                FROM category",
-            );
-            statement = self.connection.prepare(&query).unwrap();
-        }
-        let category_iter = statement
-            .query_map([], |row| {
-                Ok(Category {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    supercategory: row.get(2).unwrap(),
-                })
-            })
-            .unwrap();
+/// Overdue loans of one user, longest overdue first.
+#[derive(Debug, Clone)]
+pub struct UserOverdueLoans {
+    pub user: User,
+    pub loans: Vec<OverdueLoan>,
+}
 
-        let mut categories = Vec::new();
+/// Accepted loans with instances still out that are due today or tomorrow.
+#[derive(Debug, Clone, Default)]
+pub struct DueLoans {
+    pub today: Vec<Loan>,
+    pub tomorrow: Vec<Loan>,
+}
 
-        for category in category_iter {
-            categories.push(category.unwrap());
-        }
-        return categories;
-    }
+/// Whether the deposit of a loan is still held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepositStatus {
+    Held,
+    Refunded,
+    /// Kept by the club, credited to the user's ledger.
+    Withheld,
+}
 
-    pub fn get_category(&self, name: &str) -> Option<Category> {
-        let query = String::from(
-            "SELECT
-                category.uuid,
-                category.name,
-                category.supercategory
-            FROM category
-            WHERE category.name = ?1",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let mut category_iter = statement
-            .query_map(params![name], |row| {
-                Ok(Category {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    supercategory: row.get(2).unwrap(),
-                })
-            })
-            .unwrap();
-        if let Some(category) = category_iter.next() {
-            return Some(category.unwrap());
-        } else {
-            None
+impl DepositStatus {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepositStatus::Held => "held",
+            DepositStatus::Refunded => "refunded",
+            DepositStatus::Withheld => "withheld",
         }
     }
 
-    pub fn add_category(
-        &self,
-        name: &str,
-        supercategory: Option<Uuid>,
-    ) -> Result<Category, String> {
-        // Adding root category
-        if supercategory.is_none() {
-            let categories = self.get_categories(None);
-            if categories.len() > 0 {
-                return Err("Error: Supercategory must be specified.".to_string());
-            } else {
-                let uuid = Uuid::new_v4();
-                self.connection
-                    .execute(
-                        "INSERT INTO category (uuid, name) VALUES (?1, ?2)",
-                        params![uuid, name],
-                    )
-                    .unwrap();
-                return Ok(self.get_category(name).unwrap());
-            }
-        }
-        // Supercategory must exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM category WHERE uuid = ?1")
-            .unwrap();
-        let mut category_iter = statement
-            .query_map(params![supercategory], |row| {
-                Ok(row.get::<usize, Uuid>(0).unwrap())
-            })
-            .unwrap();
-        if category_iter.next().is_none() {
-            return Err("Error: Supercategory does not exist.".to_string());
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "held" => Some(DepositStatus::Held),
+            "refunded" => Some(DepositStatus::Refunded),
+            "withheld" => Some(DepositStatus::Withheld),
+            _ => None,
         }
+    }
+}
 
-        // Category must not already exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM category WHERE name = ?1")
-            .unwrap();
-        let mut category_iter = statement
-            .query_map(params![name], |row| Ok(row.get::<usize, Uuid>(0).unwrap()))
-            .unwrap();
-        if category_iter.next().is_some() {
-            return Err("Error: Category already exists.".to_string());
-        }
+/// Cash deposit taken for a loan, in cents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Deposit {
+    pub loan: Uuid,
+    pub amount: i64,
+    #[serde(with = "crate::snapshot::date")]
+    pub date_received: DateTime<Tz>,
+    pub status: DepositStatus,
+    #[serde(with = "crate::snapshot::optional_date")]
+    pub date_settled: Option<DateTime<Tz>>,
+    /// Why the deposit was withheld.
+    pub reason: Option<String>,
+}
 
-        let uuid = Uuid::new_v4();
-        self.connection
-            .execute(
-                "INSERT INTO category (uuid, name, supercategory) VALUES (?1, ?2, ?3)",
-                params![uuid, name, supercategory],
-            )
-            .unwrap();
+/// How badly a condition report says an instance is damaged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Cosmetic, doesn't affect use.
+    Minor,
+    /// Works with limitations.
+    Major,
+    /// Unusable.
+    Critical,
+}
 
-        Ok(self.get_category(name).unwrap())
+impl Severity {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Minor => "minor",
+            Severity::Major => "major",
+            Severity::Critical => "critical",
+        }
     }
 
-    pub fn remove_category(&self, uuid: Uuid) -> Result<(), String> {
-        let query = String::from(
-            "DELETE FROM category
-            WHERE category.uuid = ?1",
-        );
-        self.connection
-            .execute(&query, params![uuid])
-            .map_err(|e| e.to_string())?;
-        Ok(())
-    }
-
-    pub fn get_products(&self, category_id: Option<Uuid>) -> Vec<Product> {
-        let mut query = String::from(
-            "SELECT
-                product.uuid,
-                product.name,
-                category.uuid,
-                category.name,
-                category.supercategory
-            FROM product
-            INNER JOIN category ON product.category = category.uuid",
-        );
-        if let Some(id) = category_id {
-            query.push_str(&format!(" where category = {}", id));
-        }
-
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let product_iter = statement
-            .query_map([], |row| {
-                Ok(Product {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    category: Category {
-                        uuid: row.get(2).unwrap(),
-                        name: row.get(3).unwrap(),
-                        supercategory: row.get(4).unwrap(),
-                    },
-                })
-            })
-            .unwrap();
-
-        let mut products = Vec::new();
-
-        for product in product_iter {
-            products.push(product.unwrap());
+    pub fn parse(severity: &str) -> Option<Self> {
+        match severity {
+            "minor" => Some(Severity::Minor),
+            "major" => Some(Severity::Major),
+            "critical" => Some(Severity::Critical),
+            _ => None,
         }
-        return products;
     }
+}
 
-    pub fn get_product_by_name(&self, name: &str) -> Option<Product> {
-        let query = String::from(
-            "SELECT
-                product.uuid,
-                product.name,
-                category.uuid,
-                category.name,
-                category.supercategory
-            FROM product
-                INNER join category ON product.category = category.uuid
-            WHERE product.name = ?1",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let mut product_iter = statement
-            .query_map(params![name], |row| {
-                Ok(Product {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    category: Category {
-                        uuid: row.get(2).unwrap(),
-                        name: row.get(3).unwrap(),
-                        supercategory: row.get(4).unwrap(),
-                    },
-                })
-            })
-            .unwrap();
+/// Where an instance is in its life. Only available instances can be
+/// booked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceStatus {
+    Available,
+    InRepair,
+    Lost,
+    Stolen,
+    Retired,
+}
 
-        // There should be only one product with the given name
-        if let Some(product) = product_iter.next() {
-            return Some(product.unwrap());
-        } else {
-            None
+impl InstanceStatus {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InstanceStatus::Available => "available",
+            InstanceStatus::InRepair => "in_repair",
+            InstanceStatus::Lost => "lost",
+            InstanceStatus::Stolen => "stolen",
+            InstanceStatus::Retired => "retired",
         }
     }
 
-    pub fn get_product(&self, product_uuid: Uuid) -> Option<Product> {
-        let query = String::from(
-            "SELECT
-                product.uuid,
-                product.name,
-                category.uuid,
-                category.name,
-                category.supercategory
-            FROM product
-                INNER JOIN category ON product.category = category.uuid
-            WHERE product.uuid = ?1",
-        );
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let mut product_iter = statement
-            .query_map(params![product_uuid], |row| {
-                Ok(Product {
-                    uuid: row.get(0).unwrap(),
-                    name: row.get(1).unwrap(),
-                    category: Category {
-                        uuid: row.get(2).unwrap(),
-                        name: row.get(3).unwrap(),
-                        supercategory: row.get(4).unwrap(),
-                    },
-                })
-            })
-            .unwrap();
-        if let Some(product) = product_iter.next() {
-            return Some(product.unwrap());
-        } else {
-            None
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "available" => Some(InstanceStatus::Available),
+            "in_repair" => Some(InstanceStatus::InRepair),
+            "lost" => Some(InstanceStatus::Lost),
+            "stolen" => Some(InstanceStatus::Stolen),
+            "retired" => Some(InstanceStatus::Retired),
+            _ => None,
         }
     }
 
-    pub fn add_product(&self, name: &str, category_id: Uuid) -> Result<Product, String> {
-        // Category must exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM category WHERE uuid = ?1")
-            .unwrap();
-        let mut category_iter = statement
-            .query_map(params![category_id], |row| {
-                Ok(row.get::<usize, Uuid>(0).unwrap())
-            })
-            .unwrap();
-        if category_iter.next().is_none() {
-            return Err("Error: Category does not exist.".to_string());
-        }
+    /// Lost or stolen.
+    pub fn is_missing(&self) -> bool {
+        matches!(self, InstanceStatus::Lost | InstanceStatus::Stolen)
+    }
+}
 
-        // Product must not already exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM product WHERE name = ?1")
-            .unwrap();
-        let mut product_iter = statement
-            .query_map(params![name], |row| Ok(row.get::<usize, Uuid>(0).unwrap()))
-            .unwrap();
-        if product_iter.next().is_some() {
-            return Err("Error: Product already exists.".to_string());
-        }
+/// Entry in the status history of an instance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusChange {
+    pub uuid: Uuid,
+    pub instance: Uuid,
+    pub status: InstanceStatus,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+    /// Loan the instance went missing from.
+    pub loan: Option<Uuid>,
+    pub note: Option<String>,
+}
 
-        let uuid = Uuid::new_v4();
-        self.connection
-            .execute(
-                "INSERT INTO product (uuid, name, category) VALUES (?1, ?2, ?3)",
-                params![uuid, name, category_id],
-            )
-            .unwrap();
+/// Instance with the change that gave it its current status.
+#[derive(Debug, Clone)]
+pub struct InstanceWithStatus {
+    pub instance: Instance,
+    pub change: StatusChange,
+}
 
-        return Ok(self.get_product(uuid).unwrap());
-    }
+/// Damage or wear found on an instance.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConditionReport {
+    pub uuid: Uuid,
+    pub instance: Uuid,
+    /// Loan during which the damage happened.
+    pub loan: Option<Uuid>,
+    pub severity: Severity,
+    pub description: String,
+    /// Who found the damage.
+    pub reporter: String,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+    /// The instance can't be booked until the report is resolved.
+    pub out_of_service: bool,
+    #[serde(with = "crate::snapshot::optional_date")]
+    pub date_resolved: Option<DateTime<Tz>>,
+    /// What was done about it.
+    pub resolution: Option<String>,
+}
 
-    pub fn remove_product(&self, uuid: Uuid) -> Result<(), String> {
-        let query = String::from(
-            "DELETE FROM product
-            WHERE product.uuid = ?1",
-        );
-        self.connection
-            .execute(&query, params![uuid])
-            .map_err(|e| e.to_string())?;
-        Ok(())
+impl ConditionReport {
+    pub fn resolved(&self) -> bool {
+        self.date_resolved.is_some()
     }
+}
 
-    pub fn get_instances(&self, product_id: Option<Uuid>) -> Vec<Instance> {
-        let mut instances = Vec::new();
-        if product_id.is_some() {
-            let query = String::from(
-                "SELECT
-                    instance.uuid,
-                    instance.identifier,
-                    product.uuid,
-                    product.name,
-                    category.uuid,
-                    category.name,
-                    category.supercategory
-                FROM instance
-                    INNER JOIN product ON instance.product = product.uuid
-                    INNER JOIN category ON product.category = category.uuid
-                WHERE product = ?1",
-            );
-            let mut statement = self.connection.prepare(&query).unwrap();
-            let instance_iter = statement
-                .query_map(params![product_id], |row| {
-                    Ok(Instance {
-                        uuid: row.get(0).unwrap(),
-                        identifier: row.get(1).unwrap(),
-                        product: Product {
-                            uuid: row.get(2).unwrap(),
-                            name: row.get(3).unwrap(),
-                            category: Category {
-                                uuid: row.get(4).unwrap(),
-                                name: row.get(5).unwrap(),
-                                supercategory: row.get(6).unwrap(),
-                            },
-                        },
-                    })
-                })
-                .unwrap();
-            for instance in instance_iter {
-                instances.push(instance.unwrap());
-            }
-            return instances;
-        } else {
-            let query = String::from(
-                "SELECt
-                    instance.uuid,
-                    instance.identifier,
-                    product.uuid,
-                    product.name,
-                    category.uuid,
-                    category.name,
-                    category.supercategory
-                FROM instance
-                    INNER JOIN product ON instance.product = product.uuid
-                    INNER JOIN category ON product.category = category.uuid",
-            );
-            let mut statement = self.connection.prepare(&query).unwrap();
-            let instance_iter = statement
-                .query_map([], |row| {
-                    Ok(Instance {
-                        uuid: row.get(0).unwrap(),
-                        identifier: row.get(1).unwrap(),
-                        product: Product {
-                            uuid: row.get(2).unwrap(),
-                            name: row.get(3).unwrap(),
-                            category: Category {
-                                uuid: row.get(4).unwrap(),
-                                name: row.get(5).unwrap(),
-                                supercategory: row.get(6).unwrap(),
-                            },
-                        },
-                    })
-                })
-                .unwrap();
-            for instance in instance_iter {
-                instances.push(instance.unwrap());
-            }
-            return instances;
+/// Condition report to be filed with `Database::add_condition_report`.
+#[derive(Debug, Clone)]
+pub struct NewConditionReport {
+    pub instance: Uuid,
+    /// Must have the instance on it.
+    pub loan: Option<Uuid>,
+    pub severity: Severity,
+    pub description: String,
+    pub reporter: String,
+    pub date: DateTime<Tz>,
+    pub out_of_service: bool,
+}
+
+/// Time an instance is away for service and can't be booked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub uuid: Uuid,
+    pub instance: Instance,
+    #[serde(with = "crate::snapshot::date")]
+    pub date_start: DateTime<Tz>,
+    #[serde(with = "crate::snapshot::date")]
+    pub date_end: DateTime<Tz>,
+    pub reason: String,
+}
+
+/// Inventory details of an instance. Prices are in cents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetInfo {
+    pub instance: Uuid,
+    /// Unique among all instances.
+    pub serial_number: Option<String>,
+    #[serde(with = "crate::snapshot::optional_date")]
+    pub purchase_date: Option<DateTime<Tz>>,
+    pub purchase_price: Option<i64>,
+    pub current_value: Option<i64>,
+    pub supplier: Option<String>,
+    #[serde(with = "crate::snapshot::optional_date")]
+    pub warranty_end: Option<DateTime<Tz>>,
+}
+
+impl AssetInfo {
+    /// Details of the instance with nothing filled in.
+    pub fn new(instance: Uuid) -> Self {
+        Self {
+            instance,
+            serial_number: None,
+            purchase_date: None,
+            purchase_price: None,
+            current_value: None,
+            supplier: None,
+            warranty_end: None,
         }
     }
+}
 
-    pub fn get_instance(&self, instance_uuid: Uuid) -> Instance {
-        let query = String::from(
-            "SELECT
-                instance.uuid,
-                instance.identifier,
-                product.uuid,
-                product.name,
-                category.uuid,
-                category.name,
-                category.supercategory
-            FROM instance
-                INNER JOIN product ON instance.product = product.uuid
-                INNER JOIN category ON product.category = category.uuid
-            WHERE instance.uuid = ?1",
-        );
-        self.connection
-            .prepare(&query)
-            .unwrap()
-            .query_map(params![instance_uuid], |row| {
-                Ok(Instance {
-                    uuid: row.get(0).unwrap(),
-                    identifier: row.get(1).unwrap(),
-                    product: Product {
-                        uuid: row.get(2).unwrap(),
-                        name: row.get(3).unwrap(),
-                        category: Category {
-                            uuid: row.get(4).unwrap(),
-                            name: row.get(5).unwrap(),
-                            supercategory: row.get(6).unwrap(),
-                        },
-                    },
-                })
-            })
-            .unwrap()
-            .next()
-            .unwrap()
-            .unwrap()
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DepreciationMethod {
+    /// Loses the rate of the purchase price every year until worth nothing.
+    StraightLine,
+    /// Loses the rate of the remaining value every year.
+    DecliningBalance,
+}
 
-    pub fn add_instance(&self, identifier: &str, product_uuid: Uuid) -> Result<Instance, String> {
-        // Product must exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM product WHERE uuid = ?1")
-            .unwrap();
-        let mut product_iter = statement
-            .query_map(params![product_uuid], |row| {
-                Ok(row.get::<usize, Uuid>(0).unwrap())
-            })
-            .unwrap();
-        if product_iter.next().is_none() {
-            return Err("Error: Product does not exist.".to_string());
+impl DepreciationMethod {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DepreciationMethod::StraightLine => "straight_line",
+            DepreciationMethod::DecliningBalance => "declining_balance",
         }
+    }
 
-        // Instance with this product id and identifier must not already exist
-        let mut statement = self
-            .connection
-            .prepare("SELECT uuid FROM instance WHERE product = ?1 AND identifier = ?2")
-            .unwrap();
-        let mut instance_iter = statement
-            .query_map(params![product_uuid, identifier], |row| {
-                Ok(row.get::<usize, Uuid>(0).unwrap())
-            })
-            .unwrap();
-        if instance_iter.next().is_some() {
-            return Err("Error: Instance already exists.".to_string());
+    pub fn parse(method: &str) -> Option<Self> {
+        match method {
+            "straight_line" => Some(DepreciationMethod::StraightLine),
+            "declining_balance" => Some(DepreciationMethod::DecliningBalance),
+            _ => None,
         }
-
-        let uuid = Uuid::new_v4();
-        self.connection
-            .execute(
-                "INSERT INTO instance (uuid, identifier, product) VALUES (?1, ?2, ?3)",
-                params![uuid, identifier, product_uuid],
-            )
-            .unwrap();
-
-        return Ok(self.get_instance(uuid));
     }
+}
 
-    /// Get loans from loan_view
-    /// Transform dates to Helsinki timezone
-    pub fn get_loans(&self, params: LoanQueryParams) -> Vec<Loan> {
-        let mut query = String::from(
-            "SELECT 
-                loan_uuid,
-                loan_date_start,
-                loan_date_end,
-                loan_accepted,
-                loan_description,
-                user_uuid,
-                user_name,
-                instance_uuid,
-                instance_identifier,
-                product_uuid,
-                product_name,
-                category_uuid,
-                category_name,
-                category_supercategory
-            FROM loan_view
-            WHERE 1=1",
-        );
+/// How instances of a category lose value, inherited by subcategories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Depreciation {
+    pub method: DepreciationMethod,
+    /// Yearly percentage, 1 to 100.
+    pub rate: i64,
+}
 
-        let mut query_params: Vec<&(dyn rusqlite::ToSql + Sync)> = Vec::new();
-        let mut date_strings: Vec<String> = Vec::new();
-
-        if let Some(ref id) = params.loan_uuid {
-            query.push_str(" AND loan_uuid = ?");
-            query_params.push(id);
-        }
-        if let Some(ref id) = params.instance_uuid {
-            query.push_str(" AND instance_uuid = ?");
-            query_params.push(id);
-        }
-        if let Some(ref accepted) = params.loan_accepted {
-            query.push_str(" AND loan_accepted = ?");
-            query_params.push(accepted);
-        }
-        if let Some(ref id) = params.user_uuid {
-            query.push_str(" AND user_uuid = ?");
-            query_params.push(id);
-        }
-        if let Some(ref id) = params.product_uuid {
-            query.push_str(" AND product_uuid = ?");
-            query_params.push(id);
-        }
-        if let Some(ref id) = params.category_uuid {
-            query.push_str(" AND category_uuid = ?");
-            query_params.push(id);
-        }
-        if let Some(ref start) = params.date_start {
-            date_strings.push(start.to_rfc3339());
-            query.push_str(" AND loan_date_end >= ?");
-        }
-        if let Some(ref end) = params.date_end {
-            date_strings.push(end.to_rfc3339());
-            query.push_str(" AND loan_date_start <= ?");
-        }
-
-        for date in date_strings.iter() {
-            query_params.push(date);
-        }
-
-        // Combine loans
-        let mut loans: Vec<Loan> = Vec::new();
-
-        let mut statement = self.connection.prepare(&query).unwrap();
-        let rows = statement.query_map(params_from_iter(query_params.iter()), |row| {
-            Ok(Loan {
-                uuid: row.get(0).unwrap(),
-                user: User {
-                    uuid: row.get(5).unwrap(),
-                    name: row.get(6).unwrap(),
-                },
-                date_start: DateTime::parse_from_rfc3339(
-                    &row.get::<usize, String>(1).unwrap().as_str(),
-                )
-                .unwrap()
-                .with_timezone(&Helsinki),
-                date_end: DateTime::parse_from_rfc3339(
-                    &row.get::<usize, String>(2).unwrap().as_str(),
-                )
-                .unwrap()
-                .with_timezone(&Helsinki),
-
-                accepted: row.get(3).unwrap(),
-                description: row.get(4).unwrap(),
-                instaces: vec![Instance {
-                    uuid: row.get(7).unwrap(),
-                    identifier: row.get(8).unwrap(),
-                    product: Product {
-                        uuid: row.get(9).unwrap(),
-                        name: row.get(10).unwrap(),
-                        category: Category {
-                            uuid: row.get(11).unwrap(),
-                            name: row.get(12).unwrap(),
-                            supercategory: row.get(13).unwrap(),
-                        },
-                    },
-                }],
-            })
-        });
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProductRelationKind {
+    /// Works with the related product, and the other way around.
+    CompatibleWith,
+    /// Can't be used without the related product.
+    Requires,
+    /// Goes well with the related product.
+    RecommendedWith,
+}
 
-        // Fill instances for each loan
-        'rows: for row in rows.unwrap() {
-            for loan in loans.iter_mut() {
-                if loan.uuid == row.as_ref().unwrap().uuid {
-                    let instance = row.as_ref().unwrap().instaces[0].clone();
-                    loan.instaces.push(instance);
-                    continue 'rows;
-                }
-            }
-            loans.push(row.unwrap());
+impl ProductRelationKind {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProductRelationKind::CompatibleWith => "compatible_with",
+            ProductRelationKind::Requires => "requires",
+            ProductRelationKind::RecommendedWith => "recommended_with",
         }
-
-        loans
     }
 
-    pub fn get_loan(&self, loan_uuid: Uuid) -> Option<Loan> {
-        let query_params = LoanQueryParams {
-            loan_uuid: Some(loan_uuid),
-            ..Default::default()
-        };
-        let loans = self.get_loans(query_params);
-
-        if !loans.is_empty() {
-            return Some(loans[0].clone());
-        } else {
-            None
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "compatible_with" => Some(ProductRelationKind::CompatibleWith),
+            "requires" => Some(ProductRelationKind::Requires),
+            "recommended_with" => Some(ProductRelationKind::RecommendedWith),
+            _ => None,
         }
     }
+}
 
-    pub fn add_loan(
-        &self,
-        user_id: Uuid,
-        instaces: Vec<Uuid>,
-        date_start: DateTime<Tz>,
-        date_end: DateTime<Tz>,
-    ) -> Result<Loan, String> {
-        // Check overalapping loans
+/// Relation of `product` to `related`, e.g. a lens that requires an
+/// adapter.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProductRelation {
+    pub product: Uuid,
+    pub related: Uuid,
+    pub kind: ProductRelationKind,
+}
 
-        for instance_id in instaces.iter() {
-            let query_params = LoanQueryParams {
-                instance_uuid: Some(*instance_id),
-                date_start: Some(date_start),
-                date_end: Some(date_end),
-                loan_accepted: Some(true),
-                ..Default::default()
+/// Product on a loan that requires one that isn't on it.
+#[derive(Debug, Clone)]
+pub struct MissingRequirement {
+    pub product: Product,
+    pub required: Product,
+}
+
+/// Products on a loan checked against their relations.
+#[derive(Debug, Clone, Default)]
+pub struct Compatibility {
+    pub missing: Vec<MissingRequirement>,
+    /// Compatible and recommended products that aren't on the loan, e.g.
+    /// accessories.
+    pub suggestions: Vec<Product>,
+}
+
+/// Part of a kit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KitItem {
+    /// Any instances of the product that can be booked.
+    Product { product: Uuid, quantity: i64 },
+    /// The instance itself.
+    Instance { instance: Uuid },
+}
+
+/// Products and instances booked together as one unit, e.g. an interview
+/// kit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Kit {
+    pub uuid: Uuid,
+    pub name: String,
+    pub items: Vec<KitItem>,
+}
+
+/// Instance of a loan that was booked as part of a kit.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LoanKitInstance {
+    pub loan: Uuid,
+    pub instance: Uuid,
+    pub kit: Uuid,
+}
+
+/// Kit on a loan with the instances it was booked as.
+#[derive(Debug, Clone)]
+pub struct LoanKit {
+    pub kit: Kit,
+    pub instances: Vec<Instance>,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceAsset {
+    pub instance: Instance,
+    pub asset: AssetInfo,
+}
+
+/// Value of the instances in a category and its subcategories, in cents.
+/// Instances that are lost, stolen or retired are not counted.
+#[derive(Debug, Clone)]
+pub struct InventoryValuation {
+    pub category: Category,
+    pub instances: i64,
+    pub purchase_price: i64,
+    pub current_value: i64,
+    /// Instances counted without a current value.
+    pub unvalued: i64,
+    pub subcategories: Vec<InventoryValuation>,
+}
+
+/// Service intervals of a product. Whichever limit is reached first since
+/// the last maintenance makes an instance due.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceRule {
+    pub product: Uuid,
+    pub loans: Option<i64>,
+    pub days_out: Option<i64>,
+    pub months: Option<i64>,
+}
+
+/// Reading of a counter kept by the instance itself, e.g. shutter count.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReading {
+    pub uuid: Uuid,
+    pub instance: Uuid,
+    pub counter: String,
+    pub value: i64,
+    #[serde(with = "crate::snapshot::date")]
+    pub date: DateTime<Tz>,
+}
+
+/// Use of an instance, derived from the accepted loans that have started.
+#[derive(Debug, Clone)]
+pub struct InstanceUsage {
+    pub instance: Instance,
+    pub loans: i64,
+    /// Whole days, counting loans still out until now.
+    pub days_out: i64,
+    /// Latest reading of each counter, by counter name.
+    pub readings: Vec<UsageReading>,
+    /// End of the latest maintenance window that has ended.
+    pub last_maintenance: Option<DateTime<Tz>>,
+    pub loans_since_maintenance: i64,
+    pub days_out_since_maintenance: i64,
+}
+
+/// Limit of a maintenance rule that has been reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaintenanceDueReason {
+    Loans,
+    DaysOut,
+    Months,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceDue {
+    pub usage: InstanceUsage,
+    pub rule: MaintenanceRule,
+    pub reasons: Vec<MaintenanceDueReason>,
+}
+
+/// Value set on a category or the closest of its supercategories, if any.
+fn inherited<T>(
+    storage: &dyn Storage,
+    category: Uuid,
+    value: impl Fn(Uuid) -> Result<Option<T>, DatabaseError>,
+) -> Result<Option<T>, DatabaseError> {
+    let categories = storage.get_categories(None)?;
+    let mut category = categories.iter().find(|c| c.uuid == category);
+    while let Some(current) = category {
+        if let Some(value) = value(current.uuid)? {
+            return Ok(Some(value));
+        }
+        category = categories
+            .iter()
+            .find(|c| Some(c.uuid) == current.supercategory);
+    }
+    Ok(None)
+}
+
+/// Daily late fee of a category, inherited from the closest supercategory
+/// that has one. 0 if none has.
+fn late_fee_rate(storage: &dyn Storage, category: Uuid) -> Result<i64, DatabaseError> {
+    let rate = inherited(storage, category, |c| storage.get_late_fee_rate(c))?;
+    Ok(rate.unwrap_or(0))
+}
+
+/// Deposit of a product, or else the one inherited from its category. 0 if
+/// neither has one.
+fn deposit_amount(storage: &dyn Storage, product: &Product) -> Result<i64, DatabaseError> {
+    if let Some(amount) = storage.get_product_deposit(product.uuid)? {
+        return Ok(amount);
+    }
+    let amount = inherited(storage, product.category.uuid, |c| {
+        storage.get_category_deposit(c)
+    })?;
+    Ok(amount.unwrap_or(0))
+}
+
+/// Cheapest way to rent a product with `price` from `start` to `end`. Every
+/// started day counts as a whole one.
+fn rental_price(price: &ProductPrice, start: DateTime<Tz>, end: DateTime<Tz>) -> (i64, String) {
+    let seconds = (end - start).num_seconds();
+    let days = ((seconds + 86399) / 86400).max(1);
+    let plural = |n: i64, unit: &str| {
+        if n == 1 {
+            format!("1 {}", unit)
+        } else {
+            format!("{} {}s", n, unit)
+        }
+    };
+
+    let mut best = (days * price.per_day, plural(days, "day"));
+    if let Some(per_week) = price.per_week {
+        let (weeks, rest) = (days / 7, days % 7);
+        let mut options = vec![((weeks + 1) * per_week, plural(weeks + 1, "week"))];
+        if rest == 0 {
+            options.push((weeks * per_week, plural(weeks, "week")));
+        } else if weeks > 0 {
+            options.push((
+                weeks * per_week + rest * price.per_day,
+                format!("{}, {}", plural(weeks, "week"), plural(rest, "day")),
+            ));
+        }
+        for option in options {
+            if option.0 < best.0 {
+                best = option;
+            }
+        }
+    }
+    if let Some(per_weekend) = price.per_weekend {
+        // Friday or Saturday until Monday at the latest
+        let start_day = start.weekday().num_days_from_monday();
+        let last_day = start.date_naive() + chrono::Days::new(7 - start_day as u64);
+        let weekend = (start_day == 4 || start_day == 5) && end.date_naive() <= last_day;
+        if weekend && per_weekend < best.0 {
+            best = (per_weekend, "Weekend".to_string());
+        }
+    }
+    return best;
+}
+
+/// Itemized price of loaning `instances`, with the discount of the best
+/// membership the user has on the first day of the loan.
+fn quote(
+    storage: &dyn Storage,
+    instances: &[Uuid],
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+    user: Uuid,
+) -> Result<Quote, DatabaseError> {
+    if storage.get_user(user)?.is_none() {
+        return Err(DatabaseError::NotFound("User not found.".to_string()));
+    }
+
+    let day = date_start.date_naive();
+    let membership_types = storage.get_membership_types()?;
+    let discount = storage
+        .get_membership_payments(Some(user))?
+        .iter()
+        .filter(|payment| payment.date_start <= day && day <= payment.date_end)
+        .filter_map(|payment| {
+            membership_types
+                .iter()
+                .find(|t| t.uuid == payment.membership_type)
+        })
+        .map(|membership_type| membership_type.rental_discount)
+        .max()
+        .unwrap_or(0);
+
+    let mut lines = Vec::new();
+    for instance_id in instances.iter() {
+        let instance = match storage.get_instance(*instance_id)? {
+            Some(instance) => instance,
+            None => return Err(DatabaseError::NotFound("Instance not found.".to_string())),
+        };
+        let (price, description) = match storage.get_product_price(instance.product.uuid)? {
+            Some(price) => rental_price(&price, date_start, date_end),
+            None => (0, "No charge".to_string()),
+        };
+        lines.push(QuoteLine {
+            instance: *instance_id,
+            description,
+            price,
+            discount: price * discount / 100,
+        });
+    }
+
+    let total = lines.iter().map(|line| line.price - line.discount).sum();
+    Ok(Quote { lines, total })
+}
+
+/// Rental of every instance with its discount, followed by the charges for
+/// the loan made by `date`.
+fn invoice_lines(
+    storage: &dyn Storage,
+    loan: &Loan,
+    date: DateTime<Tz>,
+) -> Result<Vec<InvoiceLine>, DatabaseError> {
+    let price_lines = storage.get_loan_price_lines(loan.uuid)?;
+    let mut lines = Vec::new();
+    for instance in loan.instaces.iter() {
+        let Some(price_line) = price_lines.iter().find(|l| l.instance == instance.uuid) else {
+            continue;
+        };
+        lines.push(InvoiceLine {
+            description: format!(
+                "{} {}, {}",
+                instance.product.name, instance.identifier, price_line.description
+            ),
+            amount: price_line.price,
+        });
+        if price_line.discount > 0 {
+            lines.push(InvoiceLine {
+                description: "Membership discount".to_string(),
+                amount: -price_line.discount,
+            });
+        }
+    }
+
+    let mut charges: Vec<LedgerEntry> = storage
+        .get_ledger_entries(Some(loan.user.uuid))?
+        .into_iter()
+        .filter(|entry| entry.loan == Some(loan.uuid) && entry.date <= date)
+        .filter(|entry| entry.kind != LedgerEntryKind::Payment)
+        .collect();
+    charges.sort_by_key(|entry| entry.date);
+    for charge in charges {
+        let description = match charge.description {
+            Some(description) => format!("{}: {}", charge.kind.label(), description),
+            None => charge.kind.label().to_string(),
+        };
+        lines.push(InvoiceLine {
+            description,
+            amount: charge.amount,
+        });
+    }
+    Ok(lines)
+}
+
+fn balance(storage: &dyn Storage, user: Uuid) -> Result<i64, DatabaseError> {
+    let entries = storage.get_ledger_entries(Some(user))?;
+    Ok(entries.iter().map(|entry| entry.amount).sum())
+}
+
+/// Delivery is given up after this many failed attempts.
+const MAX_DELIVERY_ATTEMPTS: i64 = 5;
+/// Wait before the first retry, doubled after every failure.
+const RETRY_DELAY_MINUTES: i64 = 5;
+/// Memberships ending within this many days are reminded of.
+const MEMBERSHIP_REMINDER_DAYS: u64 = 7;
+
+/// Entity as it is recorded in the audit log.
+fn json<T: Serialize>(entity: Option<T>) -> Option<serde_json::Value> {
+    return entity.map(|entity| serde_json::to_value(entity).unwrap());
+}
+
+/// Fails with `Conflict` if an accepted loan of one of the instances overlaps
+/// the time frame.
+fn check_overlap(
+    storage: &dyn Storage,
+    instances: &[Uuid],
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+) -> Result<(), DatabaseError> {
+    for instance_id in instances.iter() {
+        let query_params = LoanQueryParams {
+            instance_uuid: Some(*instance_id),
+            date_start: Some(date_start),
+            date_end: Some(date_end),
+            loan_accepted: Some(true),
+            ..Default::default()
+        };
+        let loans = storage.get_loans(&query_params)?;
+
+        if let Some(conflict) = loans.first() {
+            let error_message = format!(
+                "Instance is already loaned in the requested time frame.\n\
+                 Conflicting Loan - User ID: {}, Date Start: {}, Date End: {}",
+                conflict.user.uuid, conflict.date_start, conflict.date_end
+            );
+            return Err(DatabaseError::Conflict(error_message));
+        }
+    }
+    Ok(())
+}
+
+/// Fails with `Conflict` if one of the instances has an unresolved condition
+/// report that put it out of service.
+fn check_in_service(storage: &dyn Storage, instances: &[Uuid]) -> Result<(), DatabaseError> {
+    for instance_id in instances.iter() {
+        let reports = storage.get_condition_reports(Some(*instance_id))?;
+        if let Some(report) = reports
+            .iter()
+            .find(|report| report.out_of_service && !report.resolved())
+        {
+            return Err(DatabaseError::Conflict(format!(
+                "Instance is out of service until its condition report is resolved: {}",
+                report.description
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Status of the instance given by its latest status change.
+fn instance_status(storage: &dyn Storage, instance: Uuid) -> Result<InstanceStatus, DatabaseError> {
+    let changes = storage.get_status_changes(Some(instance))?;
+    Ok(changes
+        .last()
+        .map_or(InstanceStatus::Available, |change| change.status))
+}
+
+/// Fails with `Conflict` unless every instance is available.
+fn check_available(storage: &dyn Storage, instances: &[Uuid]) -> Result<(), DatabaseError> {
+    for instance_id in instances.iter() {
+        let status = instance_status(storage, *instance_id)?;
+        if status != InstanceStatus::Available {
+            return Err(DatabaseError::Conflict(format!(
+                "Instance is {}.",
+                status.as_str().replace('_', " ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Fails with `Conflict` if maintenance of one of the instances overlaps the
+/// time frame.
+fn check_maintenance(
+    storage: &dyn Storage,
+    instances: &[Uuid],
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+) -> Result<(), DatabaseError> {
+    for instance_id in instances.iter() {
+        let windows = storage.get_maintenance_windows(Some(*instance_id))?;
+        if let Some(window) = windows
+            .iter()
+            .find(|window| window.date_end >= date_start && window.date_start <= date_end)
+        {
+            return Err(DatabaseError::Conflict(format!(
+                "Instance is in maintenance in the requested time frame.\n\
+                 Maintenance - Date Start: {}, Date End: {}, Reason: {}",
+                window.date_start, window.date_end, window.reason
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Fails with `Conflict` unless every instance can be booked for the time
+/// frame.
+fn check_bookable(
+    storage: &dyn Storage,
+    instances: &[Uuid],
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+) -> Result<(), DatabaseError> {
+    check_available(storage, instances)?;
+    check_in_service(storage, instances)?;
+    check_maintenance(storage, instances, date_start, date_end)?;
+    check_overlap(storage, instances, date_start, date_end)
+}
+
+/// Replaces the kits among `uuids` with instances that can be booked for the
+/// time frame, keeping the other UUIDs as they are. Also returns which kit
+/// each of the added instances belongs to on the loan. Fails with
+/// `Conflict` unless every item of every kit can be booked.
+fn expand_kits(
+    storage: &dyn Storage,
+    loan: Uuid,
+    uuids: &[Uuid],
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+) -> Result<(Vec<Uuid>, Vec<LoanKitInstance>), DatabaseError> {
+    let all_kits = storage.get_kits()?;
+    let mut instances: Vec<Uuid> = Vec::new();
+    let mut kits: Vec<&Kit> = Vec::new();
+    for uuid in uuids.iter() {
+        match all_kits.iter().find(|k| k.uuid == *uuid) {
+            Some(kit) => kits.push(kit),
+            None => instances.push(*uuid),
+        }
+    }
+    let unavailable = |kit: &Kit, reason: &str| {
+        DatabaseError::Conflict(format!("Kit {} can't be booked. {}", kit.name, reason))
+    };
+
+    // Specific instances first so that they aren't taken for product items
+    let mut kit_instances = Vec::new();
+    for kit in kits.iter() {
+        for item in kit.items.iter() {
+            if let KitItem::Instance { instance } = item {
+                if instances.contains(instance) {
+                    return Err(unavailable(kit, "An instance of it is booked twice."));
+                }
+                match check_bookable(storage, &[*instance], date_start, date_end) {
+                    Ok(()) => {}
+                    Err(DatabaseError::Conflict(reason)) => {
+                        return Err(unavailable(kit, &reason));
+                    }
+                    Err(e) => return Err(e),
+                }
+                instances.push(*instance);
+                kit_instances.push(LoanKitInstance {
+                    loan,
+                    instance: *instance,
+                    kit: kit.uuid,
+                });
+            }
+        }
+    }
+    for kit in kits.iter() {
+        for item in kit.items.iter() {
+            let KitItem::Product { product, quantity } = item else {
+                continue;
+            };
+            let mut found = 0;
+            for candidate in storage.get_instances(Some(*product))? {
+                if found == *quantity {
+                    break;
+                }
+                if instances.contains(&candidate.uuid) {
+                    continue;
+                }
+                match check_bookable(storage, &[candidate.uuid], date_start, date_end) {
+                    Ok(()) => {}
+                    Err(DatabaseError::Conflict(_)) => continue,
+                    Err(e) => return Err(e),
+                }
+                instances.push(candidate.uuid);
+                kit_instances.push(LoanKitInstance {
+                    loan,
+                    instance: candidate.uuid,
+                    kit: kit.uuid,
+                });
+                found += 1;
+            }
+            if found < *quantity {
+                let name = storage
+                    .get_product(*product)?
+                    .map_or_else(String::new, |p| p.name);
+                return Err(unavailable(
+                    kit,
+                    &format!("Only {} of {} {} are available.", found, quantity, name),
+                ));
+            }
+        }
+    }
+    Ok((instances, kit_instances))
+}
+
+/// Use of the instance in the accepted loans that have started by `now`,
+/// and since its last maintenance that has ended by then.
+fn instance_usage(
+    storage: &dyn Storage,
+    instance: Instance,
+    now: DateTime<Tz>,
+) -> Result<InstanceUsage, DatabaseError> {
+    let last_maintenance = storage
+        .get_maintenance_windows(Some(instance.uuid))?
+        .iter()
+        .map(|window| window.date_end)
+        .filter(|date_end| *date_end <= now)
+        .max();
+
+    let query_params = LoanQueryParams {
+        instance_uuid: Some(instance.uuid),
+        loan_accepted: Some(true),
+        ..Default::default()
+    };
+    let check_ins = storage.get_check_ins()?;
+    let mut usage = InstanceUsage {
+        instance,
+        loans: 0,
+        days_out: 0,
+        readings: Vec::new(),
+        last_maintenance,
+        loans_since_maintenance: 0,
+        days_out_since_maintenance: 0,
+    };
+    for loan in storage.get_loans(&query_params)? {
+        if loan.date_start > now {
+            continue;
+        }
+        let returned = check_ins
+            .iter()
+            .find(|c| c.loan == loan.uuid && c.instance == usage.instance.uuid)
+            .map_or(now, |c| c.date);
+        let days = days_between(loan.date_start, returned).max(0);
+        usage.loans += 1;
+        usage.days_out += days;
+        if last_maintenance.is_none_or(|date| loan.date_start >= date) {
+            usage.loans_since_maintenance += 1;
+            usage.days_out_since_maintenance += days;
+        }
+    }
+
+    // Readings are oldest first, so the latest of each counter wins
+    for reading in storage.get_usage_readings(Some(usage.instance.uuid))? {
+        usage.readings.retain(|r| r.counter != reading.counter);
+        usage.readings.push(reading);
+    }
+    usage.readings.sort_by(|a, b| a.counter.cmp(&b.counter));
+    Ok(usage)
+}
+
+/// Value of the instance on `date`: its purchase price depreciated by the
+/// rule of its category, or else its current value or purchase price.
+fn insured_value(
+    storage: &dyn Storage,
+    asset: &AssetInfo,
+    category: Uuid,
+    date: DateTime<Tz>,
+) -> Result<i64, DatabaseError> {
+    let depreciation = inherited(storage, category, |c| storage.get_depreciation(c))?;
+    let value = match (asset.purchase_price, asset.purchase_date, depreciation) {
+        (Some(price), Some(purchase_date), Some(depreciation)) => {
+            valuation::depreciated_value(price, purchase_date, &depreciation, date)
+        }
+        _ => asset.current_value.or(asset.purchase_price).unwrap_or(0),
+    };
+    Ok(value)
+}
+
+/// Valuation of the category and its subcategories. `counted` tells which
+/// instances are still in the inventory.
+fn inventory_valuation(
+    storage: &dyn Storage,
+    category: Category,
+    assets: &[AssetInfo],
+    counted: &impl Fn(Uuid) -> bool,
+) -> Result<InventoryValuation, DatabaseError> {
+    let mut valuation = InventoryValuation {
+        category,
+        instances: 0,
+        purchase_price: 0,
+        current_value: 0,
+        unvalued: 0,
+        subcategories: Vec::new(),
+    };
+    for product in storage.get_products(Some(valuation.category.uuid))? {
+        for instance in storage.get_instances(Some(product.uuid))? {
+            if !counted(instance.uuid) {
+                continue;
+            }
+            let asset = assets.iter().find(|a| a.instance == instance.uuid);
+            valuation.instances += 1;
+            valuation.purchase_price += asset.and_then(|a| a.purchase_price).unwrap_or(0);
+            match asset.and_then(|a| a.current_value) {
+                Some(value) => valuation.current_value += value,
+                None => valuation.unvalued += 1,
+            }
+        }
+    }
+    for subcategory in storage.get_categories(Some(valuation.category.uuid))? {
+        let sub = inventory_valuation(storage, subcategory, assets, counted)?;
+        valuation.instances += sub.instances;
+        valuation.purchase_price += sub.purchase_price;
+        valuation.current_value += sub.current_value;
+        valuation.unvalued += sub.unvalued;
+        valuation.subcategories.push(sub);
+    }
+    Ok(valuation)
+}
+
+/// Template values describing the loan.
+fn loan_values(loan: &Loan) -> Vec<(&'static str, String)> {
+    let items: Vec<String> = loan
+        .instaces
+        .iter()
+        .map(|instance| format!("{} {}", instance.product.name, instance.identifier))
+        .collect();
+    return vec![
+        ("user", loan.user.name.clone()),
+        ("items", items.join(", ")),
+        (
+            "start",
+            loan.date_start.format("%Y-%m-%d %H:%M").to_string(),
+        ),
+        ("end", loan.date_end.format("%Y-%m-%d %H:%M").to_string()),
+    ];
+}
+
+/// Renders the message and adds it to the outbox, due right away.
+fn enqueue(
+    storage: &dyn Storage,
+    templates: &Templates,
+    user: Uuid,
+    kind: NotificationKind,
+    reference: Option<Uuid>,
+    values: &[(&str, String)],
+    now: DateTime<Tz>,
+) -> Result<(), DatabaseError> {
+    let (subject, body) = templates.render(kind, values);
+    storage.insert_notification(&Notification {
+        uuid: Uuid::new_v4(),
+        user,
+        kind,
+        reference,
+        subject,
+        body,
+        created: now,
+        status: NotificationStatus::Pending,
+        attempts: 0,
+        next_attempt: now,
+        last_error: None,
+        date_sent: None,
+    })
+}
+
+/// Row to insert for a catalogue import.
+enum ImportAction {
+    Category {
+        uuid: Uuid,
+        name: String,
+        supercategory: Option<Uuid>,
+    },
+    Product {
+        uuid: Uuid,
+        name: String,
+        category: Uuid,
+    },
+    Instance {
+        uuid: Uuid,
+        identifier: String,
+        product: Uuid,
+    },
+}
+
+/// Names of the categories from the root down to `category`.
+fn category_path(categories: &[Category], category: Uuid) -> Vec<String> {
+    let mut path = Vec::new();
+    let mut current = categories.iter().find(|c| c.uuid == category);
+    while let Some(category) = current {
+        path.insert(0, category.name.clone());
+        current = category
+            .supercategory
+            .and_then(|uuid| categories.iter().find(|c| c.uuid == uuid));
+    }
+    return path;
+}
+
+/// Checks the loaned products against the relations. Instances of the same
+/// product count once.
+fn compatibility(
+    products: &[Product],
+    relations: &[ProductRelation],
+    loaned: &[Uuid],
+) -> Compatibility {
+    let product = |uuid: Uuid| products.iter().find(|p| p.uuid == uuid);
+    let mut result = Compatibility::default();
+    let mut suggested = Vec::new();
+    for relation in relations.iter() {
+        let (from, to) = (relation.product, relation.related);
+        match relation.kind {
+            ProductRelationKind::Requires => {
+                if loaned.contains(&from) && !loaned.contains(&to) {
+                    if let (Some(product), Some(required)) = (product(from), product(to)) {
+                        result.missing.push(MissingRequirement {
+                            product: product.clone(),
+                            required: required.clone(),
+                        });
+                    }
+                }
+            }
+            ProductRelationKind::CompatibleWith => {
+                if loaned.contains(&from) && !loaned.contains(&to) {
+                    suggested.push(to);
+                } else if loaned.contains(&to) && !loaned.contains(&from) {
+                    suggested.push(from);
+                }
+            }
+            ProductRelationKind::RecommendedWith => {
+                if loaned.contains(&from) && !loaned.contains(&to) {
+                    suggested.push(to);
+                }
+            }
+        }
+    }
+    // Required products are reported as missing instead
+    for uuid in suggested {
+        if result.missing.iter().any(|m| m.required.uuid == uuid)
+            || result.suggestions.iter().any(|p| p.uuid == uuid)
+        {
+            continue;
+        }
+        if let Some(product) = product(uuid) {
+            result.suggestions.push(product.clone());
+        }
+    }
+    return result;
+}
+
+/// Whether `category` is `ancestor` or one of its subcategories.
+fn is_within(categories: &[Category], category: Uuid, ancestor: Uuid) -> bool {
+    let mut current = categories.iter().find(|c| c.uuid == category);
+    while let Some(category) = current {
+        if category.uuid == ancestor {
+            return true;
+        }
+        current = category
+            .supercategory
+            .and_then(|uuid| categories.iter().find(|c| c.uuid == uuid));
+    }
+    return false;
+}
+
+/// Attributes of products in the category, from those defined on the root
+/// category down to its own.
+fn category_attributes(
+    storage: &dyn Storage,
+    category: Uuid,
+) -> Result<Vec<AttributeDefinition>, DatabaseError> {
+    let categories = storage.get_categories(None)?;
+    let mut attributes: Vec<AttributeDefinition> = storage
+        .get_attributes(None)?
+        .into_iter()
+        .filter(|a| is_within(&categories, category, a.category))
+        .collect();
+    attributes.sort_by_key(|a| category_path(&categories, a.category).len());
+    Ok(attributes)
+}
+
+/// Works out what importing the rows adds to the catalogue without changing
+/// it. Rows that clash with the catalogue are reported as errors.
+fn plan_import(
+    storage: &dyn Storage,
+    rows: &[CatalogueRow],
+) -> Result<(ImportReport, Vec<ImportAction>), DatabaseError> {
+    // Existing rows, and the planned ones as they are added
+    let mut categories = storage.get_categories(None)?;
+    let mut products: Vec<(Uuid, String, Uuid)> = storage
+        .get_products(None)?
+        .into_iter()
+        .map(|product| (product.uuid, product.name, product.category.uuid))
+        .collect();
+    let mut instances: Vec<(Uuid, String)> = storage
+        .get_instances(None)?
+        .into_iter()
+        .map(|instance| (instance.product.uuid, instance.identifier))
+        .collect();
+
+    let mut report = ImportReport::default();
+    let mut actions = Vec::new();
+    'rows: for row in rows.iter() {
+        let mut error = |message: String| {
+            report.errors.push(ImportError {
+                line: row.line,
+                message,
+            });
+        };
+        if row.category_path.is_empty() {
+            error("Category is missing.".to_string());
+            continue;
+        }
+        if row.category_path.iter().any(|name| name.is_empty()) {
+            error("Category path has an empty name.".to_string());
+            continue;
+        }
+        if row.product.is_none() && row.identifier.is_some() {
+            error("Instance has no product.".to_string());
+            continue;
+        }
+
+        let mut added = false;
+        let mut category = None;
+        for (depth, name) in row.category_path.iter().enumerate() {
+            match categories.iter().find(|c| c.name == *name) {
+                Some(existing) if existing.supercategory == category => {
+                    category = Some(existing.uuid);
+                }
+                Some(_) => {
+                    error(format!("Category {} is elsewhere in the catalogue.", name));
+                    continue 'rows;
+                }
+                None => {
+                    // Only the root category can be added without a supercategory
+                    if category.is_none() {
+                        if let Some(root) = categories.iter().find(|c| c.supercategory.is_none()) {
+                            error(format!("Category path must start with {}.", root.name));
+                            continue 'rows;
+                        }
+                    }
+                    let uuid = Uuid::new_v4();
+                    categories.push(Category {
+                        uuid,
+                        name: name.clone(),
+                        supercategory: category,
+                    });
+                    actions.push(ImportAction::Category {
+                        uuid,
+                        name: name.clone(),
+                        supercategory: category,
+                    });
+                    report
+                        .categories
+                        .push(catalogue::join_path(&row.category_path[..=depth]));
+                    category = Some(uuid);
+                    added = true;
+                }
+            }
+        }
+        let category = category.unwrap();
+
+        if let Some(name) = &row.product {
+            let product = match products.iter().find(|(_, n, _)| n == name) {
+                Some((uuid, _, c)) if *c == category => *uuid,
+                Some(_) => {
+                    error(format!("Product {} is in another category.", name));
+                    continue;
+                }
+                None => {
+                    let uuid = Uuid::new_v4();
+                    products.push((uuid, name.clone(), category));
+                    actions.push(ImportAction::Product {
+                        uuid,
+                        name: name.clone(),
+                        category,
+                    });
+                    report.products.push(name.clone());
+                    added = true;
+                    uuid
+                }
+            };
+
+            if let Some(identifier) = &row.identifier {
+                if !instances.contains(&(product, identifier.clone())) {
+                    instances.push((product, identifier.clone()));
+                    actions.push(ImportAction::Instance {
+                        uuid: Uuid::new_v4(),
+                        identifier: identifier.clone(),
+                        product,
+                    });
+                    report.instances.push(format!("{} {}", name, identifier));
+                    added = true;
+                }
+            }
+        }
+
+        if !added {
+            report.duplicates += 1;
+        }
+    }
+
+    Ok((report, actions))
+}
+
+/// Midnight at the start of `date` in the time zone `tz`.
+fn start_of_day(tz: Tz, date: NaiveDate) -> DateTime<Tz> {
+    return tz
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .unwrap();
+}
+
+/// Calendar days from `from` to `to`. Days change at midnight in Helsinki,
+/// whatever the offsets of the dates.
+fn days_between(from: DateTime<Tz>, to: DateTime<Tz>) -> i64 {
+    let from = from.with_timezone(&Helsinki).date_naive();
+    let to = to.with_timezone(&Helsinki).date_naive();
+    return (to - from).num_days();
+}
+
+/// Database handle that can be shared between threads.
+///
+/// Business rules such as loan overlap and approval are applied here, while
+/// reading and writing rows is left to a `Storage` backend.
+pub struct Database {
+    storage: Arc<dyn Storage + Send + Sync>,
+    policy: BorrowingPolicy,
+    templates: Templates,
+    /// Recorded in the audit log as the author of changes.
+    actor: String,
+}
+
+impl Database {
+    /// Opens an SQLite database file, creating the schema if the file is new.
+    /// An empty file name gives a private in-memory database. Panics if the
+    /// file can't be opened, see `open`.
+    pub fn new(file_name: &str) -> Self {
+        Self::with_storage(SqliteStorage::new(file_name))
+    }
+
+    /// Opens an SQLite database file like `new`. Files of an older schema
+    /// version are migrated, files of a newer one are refused with `Invalid`.
+    pub fn open(file_name: &str) -> Result<Self, DatabaseError> {
+        Ok(Self::with_storage(SqliteStorage::open(file_name)?))
+    }
+
+    pub fn with_storage(storage: impl Storage + Send + Sync + 'static) -> Self {
+        Self {
+            storage: Arc::new(storage),
+            policy: BorrowingPolicy::default(),
+            templates: Templates::default(),
+            actor: SYSTEM_ACTOR.to_string(),
+        }
+    }
+
+    pub fn with_policy(mut self, policy: BorrowingPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn policy(&self) -> &BorrowingPolicy {
+        &self.policy
+    }
+
+    /// Replaces the default English notification templates.
+    pub fn with_templates(mut self, templates: Templates) -> Self {
+        self.templates = templates;
+        self
+    }
+
+    /// Handle to the same database that records changes in the audit log as
+    /// made by `actor`, e.g. the name of the staff member using it.
+    pub fn acting_as(&self, actor: &str) -> Database {
+        Database {
+            storage: Arc::clone(&self.storage),
+            policy: self.policy.clone(),
+            templates: self.templates.clone(),
+            actor: actor.to_string(),
+        }
+    }
+
+    pub fn actor(&self) -> &str {
+        &self.actor
+    }
+
+    /// Appends a change to the audit log. Called in the transaction of the
+    /// change, so that either both are stored or neither. Nothing is
+    /// recorded if the entity neither existed nor exists.
+    fn audit(
+        &self,
+        storage: &dyn Storage,
+        operation: &str,
+        entity_type: EntityType,
+        entity: Uuid,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> Result<(), DatabaseError> {
+        if before.is_none() && after.is_none() {
+            return Ok(());
+        }
+        storage.insert_audit_entry(&NewAuditEntry {
+            date: Utc::now().with_timezone(&Helsinki),
+            actor: self.actor.clone(),
+            operation: operation.to_string(),
+            entity_type,
+            entity,
+            before,
+            after,
+        })
+    }
+
+    /// Changes recorded in the audit log, oldest first.
+    ///
+    /// Every method that changes users, the catalogue, loans, memberships,
+    /// the ledger or deposits is recorded. Queuing and delivering
+    /// notifications is not, the outbox keeps its own history.
+    pub fn get_audit_log(&self, params: AuditQueryParams) -> Vec<AuditEntry> {
+        return self.storage.get_audit_entries(&params).unwrap();
+    }
+
+    /// Runs `f` in a storage transaction and returns what it produced. The
+    /// storage may run `f` again if the transaction ran into a concurrent one.
+    fn transaction<T>(
+        &self,
+        mut f: impl FnMut(&dyn Storage) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        let mut result = None;
+        self.storage.transaction(&mut |storage| {
+            result = Some(f(storage)?);
+            Ok(())
+        })?;
+        Ok(result.unwrap())
+    }
+
+    pub fn get_users(&self) -> Vec<User> {
+        return self.storage.get_users().unwrap();
+    }
+
+    pub fn get_user(&self, uuid: Uuid) -> Option<User> {
+        return self.storage.get_user(uuid).unwrap();
+    }
+
+    pub fn get_user_by_name(&self, name: &str) -> Option<User> {
+        return self.storage.get_user_by_name(name).unwrap();
+    }
+
+    pub fn add_user(&self, name: &str) -> Result<User, DatabaseError> {
+        let uuid = Uuid::new_v4();
+        self.transaction(|storage| {
+            storage.insert_user(uuid, name)?;
+            let user = storage.get_user(uuid)?;
+            self.audit(
+                storage,
+                "add_user",
+                EntityType::User,
+                uuid,
+                None,
+                json(user),
+            )
+        })?;
+
+        match self.get_user(uuid) {
+            Some(user) => Ok(user),
+            None => Err(DatabaseError::NotFound("User not found.".to_string())),
+        }
+    }
+
+    /// Fails with `InUse` while the user still has loans or membership payments.
+    pub fn remove_user(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let user = storage.get_user(uuid)?;
+            storage.delete_user(uuid)?;
+            self.audit(
+                storage,
+                "remove_user",
+                EntityType::User,
+                uuid,
+                json(user),
+                None,
+            )
+        })
+    }
+
+    pub fn get_categories(&self, supercategory: Option<Uuid>) -> Vec<Category> {
+        return self.storage.get_categories(supercategory).unwrap();
+    }
+
+    pub fn get_category(&self, name: &str) -> Option<Category> {
+        return self.storage.get_category(name).unwrap();
+    }
+
+    /// Supercategory existence and name uniqueness are enforced by the storage.
+    pub fn add_category(
+        &self,
+        name: &str,
+        supercategory: Option<Uuid>,
+    ) -> Result<Category, DatabaseError> {
+        let uuid = Uuid::new_v4();
+        self.transaction(|storage| {
+            // Only the root category can be added without a supercategory
+            if supercategory.is_none() && !storage.get_categories(None)?.is_empty() {
+                return Err(DatabaseError::Invalid(
+                    "Supercategory must be specified.".to_string(),
+                ));
+            }
+            storage.insert_category(uuid, name, supercategory)?;
+            let category = storage.get_category(name)?;
+            self.audit(
+                storage,
+                "add_category",
+                EntityType::Category,
+                uuid,
+                None,
+                json(category),
+            )
+        })?;
+
+        match self.get_category(name) {
+            Some(category) => Ok(category),
+            None => Err(DatabaseError::NotFound("Category not found.".to_string())),
+        }
+    }
+
+    /// Fails with `InUse` while the category has subcategories or products.
+    pub fn remove_category(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let category = storage
+                .get_categories(None)?
+                .into_iter()
+                .find(|category| category.uuid == uuid);
+            storage.delete_category(uuid)?;
+            self.audit(
+                storage,
+                "remove_category",
+                EntityType::Category,
+                uuid,
+                json(category),
+                None,
+            )
+        })
+    }
+
+    /// Sets the late fee in cents per day for instances in the category and
+    /// its subcategories that don't have a rate of their own.
+    pub fn set_late_fee_rate(
+        &self,
+        category_uuid: Uuid,
+        daily_rate: i64,
+    ) -> Result<(), DatabaseError> {
+        if daily_rate < 0 {
+            return Err(DatabaseError::Invalid(
+                "Late fee can't be negative.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_late_fee_rate(category_uuid)?;
+            storage.set_late_fee_rate(category_uuid, daily_rate)?;
+            self.audit(
+                storage,
+                "set_late_fee_rate",
+                EntityType::Category,
+                category_uuid,
+                Some(serde_json::json!({ "late_fee_rate": before })),
+                Some(serde_json::json!({ "late_fee_rate": daily_rate })),
+            )
+        })
+    }
+
+    pub fn get_product_price(&self, product_uuid: Uuid) -> Option<ProductPrice> {
+        return self.storage.get_product_price(product_uuid).unwrap();
+    }
+
+    /// Sets the rental prices of a product, replacing earlier ones. Loans
+    /// that already exist keep their price.
+    pub fn set_product_price(&self, price: ProductPrice) -> Result<(), DatabaseError> {
+        let prices = [Some(price.per_day), price.per_weekend, price.per_week];
+        if prices.iter().flatten().any(|price| *price < 0) {
+            return Err(DatabaseError::Invalid(
+                "Price can't be negative.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_product_price(price.product)?;
+            storage.set_product_price(&price)?;
+            self.audit(
+                storage,
+                "set_product_price",
+                EntityType::Product,
+                price.product,
+                json(before),
+                json(Some(&price)),
+            )
+        })
+    }
+
+    /// Itemized price for loaning `instances` from `date_start` to
+    /// `date_end`, with the membership discount of the user.
+    pub fn quote_loan(
+        &self,
+        instances: Vec<Uuid>,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        user_uuid: Uuid,
+    ) -> Result<Quote, DatabaseError> {
+        if date_start >= date_end {
+            return Err(DatabaseError::Invalid(
+                "Loan must end after it starts.".to_string(),
+            ));
+        }
+        quote(
+            self.storage.as_ref(),
+            &instances,
+            date_start,
+            date_end,
+            user_uuid,
+        )
+    }
+
+    /// Itemized price agreed when the loan was made.
+    pub fn get_loan_quote(&self, loan_uuid: Uuid) -> Option<Quote> {
+        let loan = self.get_loan(loan_uuid)?;
+        let lines = self.storage.get_loan_price_lines(loan_uuid).unwrap();
+        return Some(Quote {
+            lines,
+            total: loan.price,
+        });
+    }
+
+    /// Issues an invoice for the loan with the next invoice number. It lists
+    /// the rental price and the charges for the loan made by `date`. The
+    /// lines are stored with the invoice and don't change afterwards.
+    pub fn create_invoice(
+        &self,
+        loan_uuid: Uuid,
+        date: DateTime<Tz>,
+    ) -> Result<Invoice, DatabaseError> {
+        self.transaction(|storage| {
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                ..Default::default()
+            };
+            let Some(loan) = storage.get_loans(&query_params)?.pop() else {
+                return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+            };
+            let lines = invoice_lines(storage, &loan, date)?;
+
+            let number = storage
+                .get_invoices(None)?
+                .iter()
+                .map(|invoice| invoice.number)
+                .max()
+                .unwrap_or(0)
+                + 1;
+            let invoice = Invoice {
+                number,
+                loan: loan_uuid,
+                date,
+                total: lines.iter().map(|line| line.amount).sum(),
+            };
+            storage.insert_invoice(&invoice, &lines)?;
+            self.audit(
+                storage,
+                "create_invoice",
+                EntityType::Invoice,
+                loan_uuid,
+                None,
+                json(Some(&invoice)),
+            )?;
+            Ok(invoice)
+        })
+    }
+
+    /// Invoices of the loan, oldest first.
+    pub fn get_invoices(&self, loan_uuid: Uuid) -> Vec<Invoice> {
+        let mut invoices = self.storage.get_invoices(Some(loan_uuid)).unwrap();
+        invoices.sort_by_key(|invoice| invoice.number);
+        return invoices;
+    }
+
+    /// Contents of an invoice, ready to be rendered.
+    pub fn get_invoice_document(&self, number: i64) -> Option<InvoiceDocument> {
+        let invoice = self
+            .storage
+            .get_invoices(None)
+            .unwrap()
+            .into_iter()
+            .find(|invoice| invoice.number == number)?;
+        let loan = self.get_loan(invoice.loan)?;
+        // Printed as issued, whatever has been charged or refunded since
+        let lines = self.storage.get_invoice_lines(invoice.number).unwrap();
+        return Some(InvoiceDocument {
+            invoice,
+            loan,
+            lines,
+        });
+    }
+
+    /// Loans matching the filters in start order, as a calendar feed.
+    fn calendar(
+        &self,
+        name: String,
+        params: LoanQueryParams,
+        maintenance: Vec<MaintenanceWindow>,
+        borrowers: bool,
+    ) -> Calendar {
+        let mut loans = self.get_loans(params);
+        loans.sort_by_key(|loan| loan.date_start);
+        return Calendar {
+            name,
+            loans,
+            maintenance,
+            borrowers,
+        };
+    }
+
+    pub fn get_loan_calendar(&self, loan_uuid: Uuid) -> Option<Calendar> {
+        let loan = self.get_loan(loan_uuid)?;
+        let items: Vec<String> = loan
+            .instaces
+            .iter()
+            .map(|instance| format!("{} {}", instance.product.name, instance.identifier))
+            .collect();
+        return Some(Calendar {
+            name: format!("Loan of {}", items.join(", ")),
+            loans: vec![loan],
+            maintenance: Vec::new(),
+            borrowers: true,
+        });
+    }
+
+    /// Every loan of the user, including ones waiting for approval.
+    pub fn get_user_calendar(&self, user_uuid: Uuid) -> Option<Calendar> {
+        let user = self.get_user(user_uuid)?;
+        let query_params = LoanQueryParams {
+            user_uuid: Some(user_uuid),
+            ..Default::default()
+        };
+        return Some(self.calendar(
+            format!("Loans of {}", user.name),
+            query_params,
+            Vec::new(),
+            true,
+        ));
+    }
+
+    /// Every booking and maintenance window of the instance. Bookings don't
+    /// name their borrower.
+    pub fn get_instance_calendar(&self, instance_uuid: Uuid) -> Option<Calendar> {
+        let instance = self.storage.get_instance(instance_uuid).unwrap()?;
+        let query_params = LoanQueryParams {
+            instance_uuid: Some(instance_uuid),
+            ..Default::default()
+        };
+        let name = format!("{} {} bookings", instance.product.name, instance.identifier);
+        let maintenance = self.get_maintenance_windows(Some(instance_uuid));
+        return Some(self.calendar(name, query_params, maintenance, false));
+    }
+
+    /// Every booking and maintenance window of any instance of the product.
+    /// Bookings only list the instances of the product and don't name their
+    /// borrower.
+    pub fn get_product_calendar(&self, product_uuid: Uuid) -> Option<Calendar> {
+        let product = self.get_product(product_uuid)?;
+        let query_params = LoanQueryParams {
+            product_uuid: Some(product_uuid),
+            ..Default::default()
+        };
+        let maintenance = self
+            .get_maintenance_windows(None)
+            .into_iter()
+            .filter(|window| window.instance.product.uuid == product_uuid)
+            .collect();
+        return Some(self.calendar(
+            format!("{} bookings", product.name),
+            query_params,
+            maintenance,
+            false,
+        ));
+    }
+
+    pub fn get_membership_types(&self) -> Vec<MembershipType> {
+        return self.storage.get_membership_types().unwrap();
+    }
+
+    /// `rental_discount` is a percentage of rental prices.
+    pub fn add_membership_type(
+        &self,
+        name: &str,
+        rental_discount: i64,
+    ) -> Result<MembershipType, DatabaseError> {
+        if !(0..=100).contains(&rental_discount) {
+            return Err(DatabaseError::Invalid(
+                "Discount must be between 0 and 100 percent.".to_string(),
+            ));
+        }
+        let membership_type = MembershipType {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            rental_discount,
+        };
+        self.transaction(|storage| {
+            storage.insert_membership_type(&membership_type)?;
+            self.audit(
+                storage,
+                "add_membership_type",
+                EntityType::MembershipType,
+                membership_type.uuid,
+                None,
+                json(Some(&membership_type)),
+            )
+        })?;
+        return Ok(membership_type);
+    }
+
+    pub fn get_membership_payments(&self, user_uuid: Uuid) -> Vec<MembershipPayment> {
+        return self
+            .storage
+            .get_membership_payments(Some(user_uuid))
+            .unwrap();
+    }
+
+    /// Records a paid membership from `date_start` to `date_end`, both
+    /// included. `price` is in cents.
+    pub fn add_membership_payment(
+        &self,
+        user_uuid: Uuid,
+        membership_type: Uuid,
+        price: i64,
+        date_start: NaiveDate,
+        date_end: NaiveDate,
+    ) -> Result<MembershipPayment, DatabaseError> {
+        if date_start > date_end {
+            return Err(DatabaseError::Invalid(
+                "Membership must end after it starts.".to_string(),
+            ));
+        }
+        if price < 0 {
+            return Err(DatabaseError::Invalid(
+                "Price can't be negative.".to_string(),
+            ));
+        }
+        let payment = MembershipPayment {
+            uuid: Uuid::new_v4(),
+            user: user_uuid,
+            membership_type,
+            price,
+            date_start,
+            date_end,
+        };
+        self.transaction(|storage| {
+            storage.insert_membership_payment(&payment)?;
+            self.audit(
+                storage,
+                "add_membership_payment",
+                EntityType::MembershipPayment,
+                payment.uuid,
+                None,
+                json(Some(&payment)),
+            )
+        })?;
+        return Ok(payment);
+    }
+
+    /// Adds the categories, products and instances of a catalogue CSV file
+    /// with the columns `category`, `product` and `identifier`. Categories
+    /// are given as paths from the root, e.g. `Catalogue/Cameras/Digital`,
+    /// and missing ones are created. Rows already in the catalogue are
+    /// skipped.
+    ///
+    /// Either every row is imported or, if any of them clashes with the
+    /// catalogue, none. A dry run only reports what would be imported,
+    /// including the errors.
+    pub fn import_catalogue(
+        &self,
+        csv: &str,
+        dry_run: bool,
+    ) -> Result<ImportReport, DatabaseError> {
+        let rows = catalogue::parse(csv)?;
+        if dry_run {
+            let (report, _) = plan_import(self.storage.as_ref(), &rows)?;
+            return Ok(report);
+        }
+
+        self.transaction(|storage| {
+            let (report, actions) = plan_import(storage, &rows)?;
+            if !report.errors.is_empty() {
+                let errors: Vec<String> = report
+                    .errors
+                    .iter()
+                    .map(|error| format!("Line {}: {}", error.line, error.message))
+                    .collect();
+                return Err(DatabaseError::Invalid(errors.join("\n")));
+            }
+            for action in actions {
+                let (entity_type, uuid, after) = match action {
+                    ImportAction::Category {
+                        uuid,
+                        name,
+                        supercategory,
+                    } => {
+                        storage.insert_category(uuid, &name, supercategory)?;
+                        (
+                            EntityType::Category,
+                            uuid,
+                            json(storage.get_category(&name)?),
+                        )
+                    }
+                    ImportAction::Product {
+                        uuid,
+                        name,
+                        category,
+                    } => {
+                        storage.insert_product(uuid, &name, category)?;
+                        (EntityType::Product, uuid, json(storage.get_product(uuid)?))
+                    }
+                    ImportAction::Instance {
+                        uuid,
+                        identifier,
+                        product,
+                    } => {
+                        storage.insert_instance(uuid, &identifier, product)?;
+                        (
+                            EntityType::Instance,
+                            uuid,
+                            json(storage.get_instance(uuid)?),
+                        )
+                    }
+                };
+                self.audit(storage, "import_catalogue", entity_type, uuid, None, after)?;
+            }
+            Ok(report)
+        })
+    }
+
+    /// The catalogue as CSV in the format `import_catalogue` reads, one row
+    /// per instance. Products without instances and categories without
+    /// products or subcategories get a row of their own.
+    pub fn export_catalogue(&self) -> String {
+        let categories = self.get_categories(None);
+        let products = self.get_products(ProductQueryParams::new());
+        let instances = self.get_instances(None);
+
+        let mut rows = Vec::new();
+        for category in categories.iter() {
+            let path = category_path(&categories, category.uuid);
+            let mut category_products: Vec<&Product> = products
+                .iter()
+                .filter(|p| p.category.uuid == category.uuid)
+                .collect();
+            category_products.sort_by(|a, b| a.name.cmp(&b.name));
+
+            if category_products.is_empty()
+                && !categories
+                    .iter()
+                    .any(|c| c.supercategory == Some(category.uuid))
+            {
+                rows.push(CatalogueRow {
+                    line: 0,
+                    category_path: path.clone(),
+                    product: None,
+                    identifier: None,
+                });
+            }
+            for product in category_products {
+                let mut identifiers: Vec<&str> = instances
+                    .iter()
+                    .filter(|i| i.product.uuid == product.uuid)
+                    .map(|i| i.identifier.as_str())
+                    .collect();
+                identifiers.sort();
+                if identifiers.is_empty() {
+                    rows.push(CatalogueRow {
+                        line: 0,
+                        category_path: path.clone(),
+                        product: Some(product.name.clone()),
+                        identifier: None,
+                    });
+                }
+                for identifier in identifiers {
+                    rows.push(CatalogueRow {
+                        line: 0,
+                        category_path: path.clone(),
+                        product: Some(product.name.clone()),
+                        identifier: Some(identifier.to_string()),
+                    });
+                }
+            }
+        }
+        rows.sort_by(|a, b| a.category_path.cmp(&b.category_path));
+        return catalogue::write(&rows);
+    }
+
+    /// Late fee in cents per day, inherited from the supercategories.
+    pub fn get_late_fee_rate(&self, category_uuid: Uuid) -> i64 {
+        return late_fee_rate(self.storage.as_ref(), category_uuid).unwrap();
+    }
+
+    /// Products of the category, if given, whose attributes pass the
+    /// filters. Products without a value for a filtered attribute don't.
+    pub fn get_products(&self, params: ProductQueryParams) -> Vec<Product> {
+        let storage = self.storage.as_ref();
+        let products = storage.get_products(params.category_uuid).unwrap();
+        if params.attributes.is_empty() {
+            return products;
+        }
+        let categories = storage.get_categories(None).unwrap();
+        let attributes = storage.get_attributes(None).unwrap();
+        let values = storage.get_attribute_values(None).unwrap();
+        return products
+            .into_iter()
+            .filter(|product| {
+                params.attributes.iter().all(|filter| {
+                    attributes
+                        .iter()
+                        .filter(|a| a.name.eq_ignore_ascii_case(&filter.name))
+                        .filter(|a| is_within(&categories, product.category.uuid, a.category))
+                        .any(|a| {
+                            values
+                                .iter()
+                                .find(|v| v.product == product.uuid && v.attribute == a.uuid)
+                                .is_some_and(|v| filter.matches(&a.kind, &v.value))
+                        })
+                })
+            })
+            .collect();
+    }
+
+    pub fn get_product_by_name(&self, name: &str) -> Option<Product> {
+        return self.storage.get_product_by_name(name).unwrap();
+    }
+
+    pub fn get_product(&self, product_uuid: Uuid) -> Option<Product> {
+        return self.storage.get_product(product_uuid).unwrap();
+    }
+
+    /// Category existence and name uniqueness are enforced by the storage.
+    pub fn add_product(&self, name: &str, category_id: Uuid) -> Result<Product, DatabaseError> {
+        let uuid = Uuid::new_v4();
+        self.transaction(|storage| {
+            storage.insert_product(uuid, name, category_id)?;
+            let product = storage.get_product(uuid)?;
+            self.audit(
+                storage,
+                "add_product",
+                EntityType::Product,
+                uuid,
+                None,
+                json(product),
+            )
+        })?;
+
+        match self.get_product(uuid) {
+            Some(product) => Ok(product),
+            None => Err(DatabaseError::NotFound("Product not found.".to_string())),
+        }
+    }
+
+    /// Removes the product together with its instances. Fails with `InUse`
+    /// if any of the instances has been loaned.
+    pub fn remove_product(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let product = storage.get_product(uuid)?;
+            let instances = storage.get_instances(Some(uuid))?;
+            storage.delete_product(uuid)?;
+            for instance in instances {
+                self.audit(
+                    storage,
+                    "remove_product",
+                    EntityType::Instance,
+                    instance.uuid,
+                    json(Some(&instance)),
+                    None,
+                )?;
+            }
+            self.audit(
+                storage,
+                "remove_product",
+                EntityType::Product,
+                uuid,
+                json(product),
+                None,
+            )
+        })
+    }
+
+    pub fn get_instances(&self, product_id: Option<Uuid>) -> Vec<Instance> {
+        return self.storage.get_instances(product_id).unwrap();
+    }
+
+    pub fn get_instance(&self, instance_uuid: Uuid) -> Instance {
+        return self.storage.get_instance(instance_uuid).unwrap().unwrap();
+    }
+
+    /// Product existence and (product, identifier) uniqueness are enforced by
+    /// the storage.
+    pub fn add_instance(
+        &self,
+        identifier: &str,
+        product_uuid: Uuid,
+    ) -> Result<Instance, DatabaseError> {
+        let uuid = Uuid::new_v4();
+        self.transaction(|storage| {
+            storage.insert_instance(uuid, identifier, product_uuid)?;
+            let instance = storage.get_instance(uuid)?;
+            self.audit(
+                storage,
+                "add_instance",
+                EntityType::Instance,
+                uuid,
+                None,
+                json(instance),
+            )
+        })?;
+
+        return Ok(self.get_instance(uuid));
+    }
+
+    /// Dates are returned in Helsinki time.
+    pub fn get_loans(&self, params: LoanQueryParams) -> Vec<Loan> {
+        let storage = self.storage.as_ref();
+        let mut loans = storage.get_loans(&params).unwrap();
+        if loans.is_empty() {
+            return loans;
+        }
+        let products = storage.get_products(None).unwrap();
+        let relations = storage.get_product_relations(None).unwrap();
+        let kits = storage.get_kits().unwrap();
+        let kit_instances = storage.get_loan_kit_instances(None).unwrap();
+        for loan in loans.iter_mut() {
+            let loaned: Vec<Uuid> = loan.instaces.iter().map(|i| i.product.uuid).collect();
+            loan.compatibility = compatibility(&products, &relations, &loaned);
+
+            // Group the instances booked through kits, in booking order
+            for row in kit_instances.iter().filter(|k| k.loan == loan.uuid) {
+                let Some(instance) = loan.instaces.iter().find(|i| i.uuid == row.instance) else {
+                    continue;
+                };
+                match loan.kits.iter_mut().find(|k| k.kit.uuid == row.kit) {
+                    Some(group) => group.instances.push(instance.clone()),
+                    None => {
+                        if let Some(kit) = kits.iter().find(|k| k.uuid == row.kit) {
+                            loan.kits.push(LoanKit {
+                                kit: kit.clone(),
+                                instances: vec![instance.clone()],
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        return loans;
+    }
+
+    pub fn get_loan(&self, loan_uuid: Uuid) -> Option<Loan> {
+        let query_params = LoanQueryParams {
+            loan_uuid: Some(loan_uuid),
+            include_rejected: true,
+            ..Default::default()
+        };
+        let loans = self.get_loans(query_params);
+
+        if !loans.is_empty() {
+            return Some(loans[0].clone());
+        } else {
+            None
+        }
+    }
+
+    /// Books the instances for the time frame. Kits can be given among the
+    /// instances and are booked as the instances they consist of, either
+    /// all of them or none.
+    pub fn add_loan(
+        &self,
+        user_id: Uuid,
+        instaces: Vec<Uuid>,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+    ) -> Result<Loan, DatabaseError> {
+        if instaces.is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Loan needs at least one instance.".to_string(),
+            ));
+        }
+        if date_start >= date_end {
+            return Err(DatabaseError::Invalid(
+                "Loan must end after it starts.".to_string(),
+            ));
+        }
+
+        // Manual acceptance for long loans
+        let mut accepted = true;
+        if date_end - date_start > self.policy.approval_limit {
+            accepted = false;
+        }
+
+        let loan_uuid = Uuid::new_v4();
+
+        // The conflict check and the insert run in one transaction so
+        // concurrent requests can't double-book an instance.
+        self.transaction(|storage| {
+            if self.policy.block_on_balance && balance(storage, user_id)? > 0 {
+                return Err(DatabaseError::Invalid(
+                    "User has an outstanding balance.".to_string(),
+                ));
+            }
+
+            let (instaces, kit_instances) =
+                expand_kits(storage, loan_uuid, &instaces, date_start, date_end)?;
+            check_bookable(storage, &instaces, date_start, date_end)?;
+
+            // The price is stored so later price changes don't alter it
+            let quote = quote(storage, &instaces, date_start, date_end, user_id)?;
+            storage.insert_loan(&NewLoan {
+                uuid: loan_uuid,
+                user: user_id,
+                date_start,
+                date_end,
+                accepted,
+                rejected: false,
+                description: None,
+                instances: instaces,
+                price: quote.total,
+                price_lines: quote.lines,
+            })?;
+            for row in kit_instances.iter() {
+                storage.insert_loan_kit_instance(row)?;
+            }
+
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                ..Default::default()
+            };
+            let loan = storage
+                .get_loans(&query_params)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| DatabaseError::Internal("Loan was not stored.".to_string()))?;
+            self.audit(
+                storage,
+                "add_loan",
+                EntityType::Loan,
+                loan_uuid,
+                None,
+                json(Some(&loan)),
+            )?;
+            let mut status = if accepted {
+                "It has been approved.".to_string()
+            } else {
+                "It is waiting for approval.".to_string()
+            };
+            // Warn about missing requirements, e.g. adapters
+            let loaned: Vec<Uuid> = loan.instaces.iter().map(|i| i.product.uuid).collect();
+            let compatibility = compatibility(
+                &storage.get_products(None)?,
+                &storage.get_product_relations(None)?,
+                &loaned,
+            );
+            for missing in compatibility.missing.iter() {
+                status.push_str(&format!(
+                    " Note that {} requires {}, which is not included.",
+                    missing.product.name, missing.required.name
+                ));
+            }
+            let mut values = loan_values(&loan);
+            values.push(("status", status));
+            enqueue(
+                storage,
+                &self.templates,
+                user_id,
+                NotificationKind::LoanCreated,
+                Some(loan_uuid),
+                &values,
+                Utc::now().with_timezone(&date_start.timezone()),
+            )
+        })?;
+
+        match self.get_loan(loan_uuid) {
+            Some(loan) => {
+                return Ok(loan);
+            }
+            None => {
+                return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+            }
+        }
+    }
+
+    /// Accepts a loan waiting for approval, unless an accepted loan has
+    /// booked one of its instances in the meantime.
+    pub fn approve_loan(&self, loan_uuid: Uuid) -> Result<Loan, DatabaseError> {
+        self.transaction(|storage| {
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                include_rejected: true,
+                ..Default::default()
+            };
+            let Some(loan) = storage.get_loans(&query_params)?.pop() else {
+                return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+            };
+            if loan.accepted {
+                return Err(DatabaseError::Invalid(
+                    "Loan is already approved.".to_string(),
+                ));
+            }
+            if loan.rejected {
+                return Err(DatabaseError::Invalid(
+                    "Rejected loans can't be approved.".to_string(),
+                ));
+            }
+
+            let instances: Vec<Uuid> = loan.instaces.iter().map(|i| i.uuid).collect();
+            check_bookable(storage, &instances, loan.date_start, loan.date_end)?;
+            storage.set_loan_accepted(loan_uuid, true)?;
+            let approved = storage.get_loans(&query_params)?.pop();
+            self.audit(
+                storage,
+                "approve_loan",
+                EntityType::Loan,
+                loan_uuid,
+                json(Some(&loan)),
+                json(approved),
+            )?;
+            enqueue(
+                storage,
+                &self.templates,
+                loan.user.uuid,
+                NotificationKind::LoanApproved,
+                Some(loan_uuid),
+                &loan_values(&loan),
+                Utc::now().with_timezone(&loan.date_start.timezone()),
+            )
+        })?;
+
+        return Ok(self.get_loan(loan_uuid).unwrap());
+    }
+
+    /// Rejects a loan waiting for approval and lets the user know why. The
+    /// loan is kept, but only `get_loan` finds it afterwards.
+    pub fn reject_loan(&self, loan_uuid: Uuid, reason: Option<&str>) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                include_rejected: true,
+                ..Default::default()
+            };
+            let Some(loan) = storage.get_loans(&query_params)?.pop() else {
+                return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+            };
+            if loan.accepted || loan.rejected {
+                return Err(DatabaseError::Invalid(
+                    "Only loans waiting for approval can be rejected.".to_string(),
+                ));
+            }
+
+            let mut values = loan_values(&loan);
+            values.push(("reason", reason.unwrap_or_default().to_string()));
+            enqueue(
+                storage,
+                &self.templates,
+                loan.user.uuid,
+                NotificationKind::LoanRejected,
+                Some(loan_uuid),
+                &values,
+                Utc::now().with_timezone(&loan.date_start.timezone()),
+            )?;
+            storage.set_loan_rejected(loan_uuid, true)?;
+            let rejected = storage.get_loans(&query_params)?.pop();
+            self.audit(
+                storage,
+                "reject_loan",
+                EntityType::Loan,
+                loan_uuid,
+                json(Some(&loan)),
+                json(rejected),
+            )
+        })
+    }
+
+    /// Marks instances of a loan as returned. Either all of them are checked
+    /// in or, if one is not on the loan or is already returned, none.
+    ///
+    /// Instances returned after the loan has ended are charged the daily late
+    /// fee of their category for every calendar day they are late.
+    pub fn check_in(
+        &self,
+        loan_uuid: Uuid,
+        instances: Vec<Uuid>,
+        date: DateTime<Tz>,
+    ) -> Result<(), DatabaseError> {
+        if instances.is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Check-in needs at least one instance.".to_string(),
+            ));
+        }
+
+        self.transaction(|storage| {
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                ..Default::default()
+            };
+            let loan = storage
+                .get_loans(&query_params)?
+                .into_iter()
+                .next()
+                .ok_or_else(|| DatabaseError::NotFound("Loan not found.".to_string()))?;
+
+            for instance_id in instances.iter() {
+                storage.check_in(loan_uuid, *instance_id, date)?;
+                self.audit(
+                    storage,
+                    "check_in",
+                    EntityType::Instance,
+                    *instance_id,
+                    Some(serde_json::json!({ "loan": loan_uuid, "date_returned": null })),
+                    Some(serde_json::json!({ "loan": loan_uuid, "date_returned": date.to_rfc3339() })),
+                )?;
+            }
+
+            let days_late = days_between(loan.date_end, date);
+            if days_late <= 0 {
+                return Ok(());
+            }
+
+            let mut amount = 0;
+            let mut late_instances = Vec::new();
+            for instance in loan.instaces.iter() {
+                if instances.contains(&instance.uuid) {
+                    amount += late_fee_rate(storage, instance.product.category.uuid)? * days_late;
+                    late_instances
+                        .push(format!("{} {}", instance.product.name, instance.identifier));
+                }
+            }
+            if amount == 0 {
+                return Ok(());
+            }
+            let entry = LedgerEntry {
+                uuid: Uuid::new_v4(),
+                user: loan.user.uuid,
+                date,
+                kind: LedgerEntryKind::LateFee,
+                amount,
+                loan: Some(loan_uuid),
+                description: Some(format!(
+                    "{} days late: {}",
+                    days_late,
+                    late_instances.join(", ")
+                )),
+            };
+            storage.insert_ledger_entry(&entry)?;
+            self.audit(
+                storage,
+                "check_in",
+                EntityType::LedgerEntry,
+                entry.uuid,
+                None,
+                json(Some(&entry)),
+            )
+        })
+    }
+
+    /// Charges the user. Amounts are in cents. Late fees and damages must be
+    /// positive, adjustments may also credit the user.
+    pub fn add_charge(
+        &self,
+        user_uuid: Uuid,
+        kind: LedgerEntryKind,
+        amount: i64,
+        loan_uuid: Option<Uuid>,
+        description: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<LedgerEntry, DatabaseError> {
+        let valid = match kind {
+            LedgerEntryKind::LateFee | LedgerEntryKind::Damage => amount > 0,
+            LedgerEntryKind::Adjustment => amount != 0,
+            LedgerEntryKind::Payment => false,
+        };
+        if !valid {
+            return Err(DatabaseError::Invalid(format!(
+                "Invalid {} charge of {}.",
+                kind.as_str(),
+                amount
+            )));
+        }
+
+        let entry = LedgerEntry {
+            uuid: Uuid::new_v4(),
+            user: user_uuid,
+            date,
+            kind,
+            amount,
+            loan: loan_uuid,
+            description: description.map(str::to_string),
+        };
+        self.transaction(|storage| {
+            storage.insert_ledger_entry(&entry)?;
+            self.audit(
+                storage,
+                "add_charge",
+                EntityType::LedgerEntry,
+                entry.uuid,
+                None,
+                json(Some(&entry)),
+            )
+        })?;
+        return Ok(entry);
+    }
+
+    /// Records a payment of `amount` cents from the user.
+    pub fn add_payment(
+        &self,
+        user_uuid: Uuid,
+        amount: i64,
+        description: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<LedgerEntry, DatabaseError> {
+        if amount <= 0 {
+            return Err(DatabaseError::Invalid(
+                "Payment must be positive.".to_string(),
+            ));
+        }
+
+        let entry = LedgerEntry {
+            uuid: Uuid::new_v4(),
+            user: user_uuid,
+            date,
+            kind: LedgerEntryKind::Payment,
+            amount: -amount,
+            loan: None,
+            description: description.map(str::to_string),
+        };
+        self.transaction(|storage| {
+            storage.insert_ledger_entry(&entry)?;
+            self.audit(
+                storage,
+                "add_payment",
+                EntityType::LedgerEntry,
+                entry.uuid,
+                None,
+                json(Some(&entry)),
+            )
+        })?;
+        return Ok(entry);
+    }
+
+    /// Charges and payments of the user, oldest first.
+    pub fn get_ledger(&self, user_uuid: Uuid) -> Vec<LedgerEntry> {
+        let mut entries = self.storage.get_ledger_entries(Some(user_uuid)).unwrap();
+        entries.sort_by_key(|entry| entry.date);
+        return entries;
+    }
+
+    /// What the user owes in cents. Negative if the user has credit.
+    pub fn get_balance(&self, user_uuid: Uuid) -> i64 {
+        return balance(self.storage.as_ref(), user_uuid).unwrap();
+    }
+
+    /// Sets the deposit for instances of the product, overriding the one of
+    /// its category. Amounts are in cents.
+    pub fn set_product_deposit(
+        &self,
+        product_uuid: Uuid,
+        amount: i64,
+    ) -> Result<(), DatabaseError> {
+        if amount < 0 {
+            return Err(DatabaseError::Invalid(
+                "Deposit can't be negative.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_product_deposit(product_uuid)?;
+            storage.set_product_deposit(product_uuid, amount)?;
+            self.audit(
+                storage,
+                "set_product_deposit",
+                EntityType::Product,
+                product_uuid,
+                Some(serde_json::json!({ "deposit": before })),
+                Some(serde_json::json!({ "deposit": amount })),
+            )
+        })
+    }
+
+    /// Sets the deposit for products in the category and its subcategories
+    /// that don't have one of their own.
+    pub fn set_category_deposit(
+        &self,
+        category_uuid: Uuid,
+        amount: i64,
+    ) -> Result<(), DatabaseError> {
+        if amount < 0 {
+            return Err(DatabaseError::Invalid(
+                "Deposit can't be negative.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_category_deposit(category_uuid)?;
+            storage.set_category_deposit(category_uuid, amount)?;
+            self.audit(
+                storage,
+                "set_category_deposit",
+                EntityType::Category,
+                category_uuid,
+                Some(serde_json::json!({ "deposit": before })),
+                Some(serde_json::json!({ "deposit": amount })),
+            )
+        })
+    }
+
+    /// Deposit required for one instance of the product, in cents.
+    pub fn get_deposit_amount(&self, product_uuid: Uuid) -> Option<i64> {
+        let product = self.get_product(product_uuid)?;
+        return Some(deposit_amount(self.storage.as_ref(), &product).unwrap());
+    }
+
+    /// Records the deposit for the instances of the loan when they are handed
+    /// out. Fails with `Invalid` if none of them needs a deposit.
+    pub fn take_deposit(
+        &self,
+        loan_uuid: Uuid,
+        date: DateTime<Tz>,
+    ) -> Result<Deposit, DatabaseError> {
+        self.transaction(|storage| {
+            let query_params = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                ..Default::default()
+            };
+            let Some(loan) = storage.get_loans(&query_params)?.pop() else {
+                return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+            };
+
+            let mut amount = 0;
+            for instance in loan.instaces.iter() {
+                amount += deposit_amount(storage, &instance.product)?;
+            }
+            if amount == 0 {
+                return Err(DatabaseError::Invalid("Loan needs no deposit.".to_string()));
+            }
+
+            let deposit = Deposit {
+                loan: loan_uuid,
+                amount,
+                date_received: date,
+                status: DepositStatus::Held,
+                date_settled: None,
+                reason: None,
+            };
+            storage.insert_deposit(&deposit)?;
+            self.audit(
+                storage,
+                "take_deposit",
+                EntityType::Deposit,
+                loan_uuid,
+                None,
+                json(Some(&deposit)),
+            )?;
+            Ok(deposit)
+        })
+    }
+
+    /// Refunds the deposit, or withholds it if a reason is given. Withheld
+    /// deposits are credited to the user's ledger as a payment. Every
+    /// instance of the loan must have been checked in.
+    pub fn settle_deposit(
+        &self,
+        loan_uuid: Uuid,
+        withhold_reason: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<Deposit, DatabaseError> {
+        self.transaction(|storage| {
+            let Some(mut deposit) = storage.get_deposits(Some(loan_uuid))?.pop() else {
+                return Err(DatabaseError::NotFound("Deposit not found.".to_string()));
+            };
+            let before = json(Some(&deposit));
+            if deposit.status != DepositStatus::Held {
+                return Err(DatabaseError::Invalid(
+                    "Deposit has already been settled.".to_string(),
+                ));
+            }
+            let out = LoanQueryParams {
+                loan_uuid: Some(loan_uuid),
+                returned: Some(false),
+                ..Default::default()
+            };
+            if !storage.get_loans(&out)?.is_empty() {
+                return Err(DatabaseError::Invalid(
+                    "Loan has instances that are not checked in.".to_string(),
+                ));
+            }
+
+            deposit.date_settled = Some(date);
+            match withhold_reason {
+                Some(reason) => {
+                    deposit.status = DepositStatus::Withheld;
+                    deposit.reason = Some(reason.to_string());
+
+                    let query_params = LoanQueryParams {
+                        loan_uuid: Some(loan_uuid),
+                        ..Default::default()
+                    };
+                    let loan = storage
+                        .get_loans(&query_params)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| DatabaseError::NotFound("Loan not found.".to_string()))?;
+                    let entry = LedgerEntry {
+                        uuid: Uuid::new_v4(),
+                        user: loan.user.uuid,
+                        date,
+                        kind: LedgerEntryKind::Payment,
+                        amount: -deposit.amount,
+                        loan: Some(loan_uuid),
+                        description: Some(format!("Withheld deposit: {}", reason)),
+                    };
+                    storage.insert_ledger_entry(&entry)?;
+                    self.audit(
+                        storage,
+                        "settle_deposit",
+                        EntityType::LedgerEntry,
+                        entry.uuid,
+                        None,
+                        json(Some(&entry)),
+                    )?;
+                }
+                None => deposit.status = DepositStatus::Refunded,
+            }
+            storage.update_deposit(&deposit)?;
+            self.audit(
+                storage,
+                "settle_deposit",
+                EntityType::Deposit,
+                loan_uuid,
+                before,
+                json(Some(&deposit)),
+            )?;
+            Ok(deposit)
+        })
+    }
+
+    pub fn get_deposit(&self, loan_uuid: Uuid) -> Option<Deposit> {
+        return self.storage.get_deposits(Some(loan_uuid)).unwrap().pop();
+    }
+
+    /// Deposits that have not been refunded or withheld yet, oldest first.
+    pub fn get_held_deposits(&self) -> Vec<Deposit> {
+        let mut deposits: Vec<Deposit> = self
+            .storage
+            .get_deposits(None)
+            .unwrap()
+            .into_iter()
+            .filter(|deposit| deposit.status == DepositStatus::Held)
+            .collect();
+        deposits.sort_by_key(|deposit| deposit.date_received);
+        return deposits;
+    }
+
+    /// Files a condition report on the instance. If it puts the instance out
+    /// of service, the instance can't be booked until the report is resolved.
+    /// Loans that already have it are left alone.
+    pub fn add_condition_report(
+        &self,
+        report: NewConditionReport,
+    ) -> Result<ConditionReport, DatabaseError> {
+        if report.description.trim().is_empty() || report.reporter.trim().is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Description and reporter are required.".to_string(),
+            ));
+        }
+
+        self.transaction(|storage| {
+            if let Some(loan_uuid) = report.loan {
+                let query_params = LoanQueryParams {
+                    loan_uuid: Some(loan_uuid),
+                    ..Default::default()
+                };
+                let Some(loan) = storage.get_loans(&query_params)?.pop() else {
+                    return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+                };
+                if !loan.instaces.iter().any(|i| i.uuid == report.instance) {
+                    return Err(DatabaseError::Invalid(
+                        "Instance is not on the loan.".to_string(),
+                    ));
+                }
+            }
+
+            let report = ConditionReport {
+                uuid: Uuid::new_v4(),
+                instance: report.instance,
+                loan: report.loan,
+                severity: report.severity,
+                description: report.description.clone(),
+                reporter: report.reporter.clone(),
+                date: report.date,
+                out_of_service: report.out_of_service,
+                date_resolved: None,
+                resolution: None,
+            };
+            storage.insert_condition_report(&report)?;
+            self.audit(
+                storage,
+                "add_condition_report",
+                EntityType::ConditionReport,
+                report.uuid,
+                None,
+                json(Some(&report)),
+            )?;
+            Ok(report)
+        })
+    }
+
+    /// Marks the report resolved, which puts the instance back in service
+    /// unless another open report keeps it out.
+    pub fn resolve_condition_report(
+        &self,
+        report_uuid: Uuid,
+        resolution: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<ConditionReport, DatabaseError> {
+        self.transaction(|storage| {
+            let Some(mut report) = storage
+                .get_condition_reports(None)?
+                .into_iter()
+                .find(|report| report.uuid == report_uuid)
+            else {
+                return Err(DatabaseError::NotFound(
+                    "Condition report not found.".to_string(),
+                ));
+            };
+            if report.resolved() {
+                return Err(DatabaseError::Invalid(
+                    "Condition report is already resolved.".to_string(),
+                ));
+            }
+            let before = json(Some(&report));
+
+            report.date_resolved = Some(date);
+            report.resolution = resolution.map(str::to_string);
+            storage.update_condition_report(&report)?;
+            self.audit(
+                storage,
+                "resolve_condition_report",
+                EntityType::ConditionReport,
+                report_uuid,
+                before,
+                json(Some(&report)),
+            )?;
+            Ok(report)
+        })
+    }
+
+    /// Condition reports of the instance, oldest first.
+    pub fn get_condition_reports(&self, instance_uuid: Uuid) -> Vec<ConditionReport> {
+        return self
+            .storage
+            .get_condition_reports(Some(instance_uuid))
+            .unwrap();
+    }
+
+    /// Reports of every instance that have not been resolved, oldest first.
+    pub fn get_open_condition_reports(&self) -> Vec<ConditionReport> {
+        return self
+            .storage
+            .get_condition_reports(None)
+            .unwrap()
+            .into_iter()
+            .filter(|report| !report.resolved())
+            .collect();
+    }
+
+    /// Whether an unresolved condition report keeps the instance from being
+    /// booked.
+    pub fn is_out_of_service(&self, instance_uuid: Uuid) -> bool {
+        return check_in_service(self.storage.as_ref(), &[instance_uuid]).is_err();
+    }
+
+    /// Takes the instance away for service for the time frame, which keeps
+    /// it from being booked. Fails with `Conflict` if an accepted loan or
+    /// other maintenance of the instance overlaps the time frame.
+    pub fn add_maintenance_window(
+        &self,
+        instance_uuid: Uuid,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        reason: &str,
+    ) -> Result<MaintenanceWindow, DatabaseError> {
+        if date_start >= date_end {
+            return Err(DatabaseError::Invalid(
+                "Maintenance must end after it starts.".to_string(),
+            ));
+        }
+        if reason.trim().is_empty() {
+            return Err(DatabaseError::Invalid("Reason is required.".to_string()));
+        }
+
+        self.transaction(|storage| {
+            let Some(instance) = storage.get_instance(instance_uuid)? else {
+                return Err(DatabaseError::NotFound("Instance not found.".to_string()));
+            };
+            check_maintenance(storage, &[instance_uuid], date_start, date_end)?;
+            check_overlap(storage, &[instance_uuid], date_start, date_end)?;
+
+            let window = MaintenanceWindow {
+                uuid: Uuid::new_v4(),
+                instance,
+                date_start,
+                date_end,
+                reason: reason.to_string(),
+            };
+            storage.insert_maintenance_window(
+                window.uuid,
+                instance_uuid,
+                date_start,
+                date_end,
+                reason,
+            )?;
+            self.audit(
+                storage,
+                "add_maintenance_window",
+                EntityType::MaintenanceWindow,
+                window.uuid,
+                None,
+                json(Some(&window)),
+            )?;
+            Ok(window)
+        })
+    }
+
+    /// Cancels maintenance, which makes the instance bookable again for the
+    /// time frame.
+    pub fn remove_maintenance_window(&self, window_uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let before = storage
+                .get_maintenance_windows(None)?
+                .into_iter()
+                .find(|window| window.uuid == window_uuid);
+            storage.delete_maintenance_window(window_uuid)?;
+            self.audit(
+                storage,
+                "remove_maintenance_window",
+                EntityType::MaintenanceWindow,
+                window_uuid,
+                json(before),
+                None,
+            )
+        })
+    }
+
+    /// Maintenance of the instance, or of every instance, in start order.
+    pub fn get_maintenance_windows(&self, instance_uuid: Option<Uuid>) -> Vec<MaintenanceWindow> {
+        let mut windows = self.storage.get_maintenance_windows(instance_uuid).unwrap();
+        windows.sort_by_key(|window| window.date_start);
+        return windows;
+    }
+
+    /// Available unless the status of the instance has been changed.
+    pub fn get_instance_status(&self, instance_uuid: Uuid) -> InstanceStatus {
+        return instance_status(self.storage.as_ref(), instance_uuid).unwrap();
+    }
+
+    /// Status changes of the instance, oldest first.
+    pub fn get_status_history(&self, instance_uuid: Uuid) -> Vec<StatusChange> {
+        return self
+            .storage
+            .get_status_changes(Some(instance_uuid))
+            .unwrap();
+    }
+
+    /// Instances currently in the status, with the change that put them
+    /// there, oldest change first. Instances that have always been available
+    /// are not listed.
+    pub fn get_instances_by_status(&self, status: InstanceStatus) -> Vec<InstanceWithStatus> {
+        let storage = self.storage.as_ref();
+        let changes = storage.get_status_changes(None).unwrap();
+        let mut instances = Vec::new();
+        for instance in storage.get_instances(None).unwrap() {
+            let latest = changes.iter().rev().find(|c| c.instance == instance.uuid);
+            if let Some(change) = latest.filter(|change| change.status == status) {
+                instances.push(InstanceWithStatus {
+                    instance,
+                    change: change.clone(),
+                });
+            }
+        }
+        instances.sort_by_key(|instance| instance.change.date);
+        return instances;
+    }
+
+    /// Lost and stolen instances, oldest first.
+    pub fn get_missing_instances(&self) -> Vec<InstanceWithStatus> {
+        let mut missing = self.get_instances_by_status(InstanceStatus::Lost);
+        missing.extend(self.get_instances_by_status(InstanceStatus::Stolen));
+        missing.sort_by_key(|instance| instance.change.date);
+        return missing;
+    }
+
+    fn change_status(
+        &self,
+        storage: &dyn Storage,
+        operation: &str,
+        change: StatusChange,
+    ) -> Result<StatusChange, DatabaseError> {
+        let before = instance_status(storage, change.instance)?;
+        if before == change.status {
+            return Err(DatabaseError::Invalid(format!(
+                "Instance is already {}.",
+                before.as_str().replace('_', " ")
+            )));
+        }
+        storage.insert_status_change(&change)?;
+        self.audit(
+            storage,
+            operation,
+            EntityType::Instance,
+            change.instance,
+            Some(serde_json::json!({ "status": before })),
+            json(Some(&change)),
+        )?;
+        Ok(change)
+    }
+
+    /// Records a new status for the instance. Only available instances can
+    /// be booked; loans that already have it are left alone.
+    pub fn set_instance_status(
+        &self,
+        instance_uuid: Uuid,
+        status: InstanceStatus,
+        note: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<StatusChange, DatabaseError> {
+        self.transaction(|storage| {
+            let change = StatusChange {
+                uuid: Uuid::new_v4(),
+                instance: instance_uuid,
+                status,
+                date,
+                loan: None,
+                note: note.map(str::to_string),
+            };
+            self.change_status(storage, "set_instance_status", change)
+        })
+    }
+
+    /// Checks in an instance of the loan that the borrower could not return
+    /// and marks it lost or stolen. No late fee is charged for it.
+    pub fn report_missing(
+        &self,
+        loan_uuid: Uuid,
+        instance_uuid: Uuid,
+        status: InstanceStatus,
+        note: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<StatusChange, DatabaseError> {
+        if !status.is_missing() {
+            return Err(DatabaseError::Invalid(
+                "Missing instances are lost or stolen.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            storage.check_in(loan_uuid, instance_uuid, date)?;
+            self.audit(
+                storage,
+                "report_missing",
+                EntityType::Instance,
+                instance_uuid,
+                Some(serde_json::json!({ "loan": loan_uuid, "date_returned": null })),
+                Some(serde_json::json!({ "loan": loan_uuid, "date_returned": date.to_rfc3339() })),
+            )?;
+            let change = StatusChange {
+                uuid: Uuid::new_v4(),
+                instance: instance_uuid,
+                status,
+                date,
+                loan: Some(loan_uuid),
+                note: note.map(str::to_string),
+            };
+            self.change_status(storage, "report_missing", change)
+        })
+    }
+
+    /// Makes a lost or stolen instance available again.
+    pub fn mark_found(
+        &self,
+        instance_uuid: Uuid,
+        note: Option<&str>,
+        date: DateTime<Tz>,
+    ) -> Result<StatusChange, DatabaseError> {
+        self.transaction(|storage| {
+            if storage.get_instance(instance_uuid)?.is_none() {
+                return Err(DatabaseError::NotFound("Instance not found.".to_string()));
+            }
+            if !instance_status(storage, instance_uuid)?.is_missing() {
+                return Err(DatabaseError::Invalid(
+                    "Instance is not lost or stolen.".to_string(),
+                ));
+            }
+            let change = StatusChange {
+                uuid: Uuid::new_v4(),
+                instance: instance_uuid,
+                status: InstanceStatus::Available,
+                date,
+                loan: None,
+                note: note.map(str::to_string),
+            };
+            self.change_status(storage, "mark_found", change)
+        })
+    }
+
+    pub fn get_asset_info(&self, instance_uuid: Uuid) -> Option<AssetInfo> {
+        return self.storage.get_asset_info(instance_uuid).unwrap();
+    }
+
+    /// Sets the inventory details of the instance, replacing earlier ones.
+    /// Fails with `AlreadyExists` if another instance has the serial number.
+    pub fn set_asset_info(&self, asset: AssetInfo) -> Result<(), DatabaseError> {
+        let prices = [asset.purchase_price, asset.current_value];
+        if prices.iter().flatten().any(|price| *price < 0) {
+            return Err(DatabaseError::Invalid(
+                "Price can't be negative.".to_string(),
+            ));
+        }
+        let asset = AssetInfo {
+            serial_number: asset
+                .serial_number
+                .map(|serial| serial.trim().to_string())
+                .filter(|serial| !serial.is_empty()),
+            ..asset
+        };
+        self.transaction(|storage| {
+            let before = storage.get_asset_info(asset.instance)?;
+            storage.set_asset_info(&asset)?;
+            self.audit(
+                storage,
+                "set_asset_info",
+                EntityType::Instance,
+                asset.instance,
+                json(before),
+                json(Some(&asset)),
+            )
+        })
+    }
+
+    /// Instances whose serial number contains `query`, ignoring case, by
+    /// serial number.
+    pub fn search_serial_numbers(&self, query: &str) -> Vec<InstanceAsset> {
+        let query = query.trim().to_lowercase();
+        let storage = self.storage.as_ref();
+        let mut found = Vec::new();
+        for asset in storage.get_asset_infos().unwrap() {
+            let matches = asset
+                .serial_number
+                .as_ref()
+                .is_some_and(|serial| serial.to_lowercase().contains(&query));
+            if matches {
+                let instance = storage.get_instance(asset.instance).unwrap().unwrap();
+                found.push(InstanceAsset { instance, asset });
+            }
+        }
+        found.sort_by(|a, b| a.asset.serial_number.cmp(&b.asset.serial_number));
+        return found;
+    }
+
+    /// Purchase price and current value of the instances in the category and
+    /// its subcategories, totalled for each category of the subtree.
+    pub fn get_inventory_valuation(&self, category_uuid: Uuid) -> Option<InventoryValuation> {
+        let storage = self.storage.as_ref();
+        let category = storage
+            .get_categories(None)
+            .unwrap()
+            .into_iter()
+            .find(|category| category.uuid == category_uuid)?;
+        let assets = storage.get_asset_infos().unwrap();
+        let counted = |instance: Uuid| {
+            let status = instance_status(storage, instance).unwrap();
+            !status.is_missing() && status != InstanceStatus::Retired
+        };
+        return Some(inventory_valuation(storage, category, &assets, &counted).unwrap());
+    }
+
+    pub fn get_instance_location(&self, instance_uuid: Uuid) -> Option<String> {
+        return self.storage.get_instance_location(instance_uuid).unwrap();
+    }
+
+    /// Sets where the instance is kept, or forgets it when `None`.
+    pub fn set_instance_location(
+        &self,
+        instance_uuid: Uuid,
+        location: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        let location = location.map(str::trim).filter(|l| !l.is_empty());
+        self.transaction(|storage| {
+            let before = storage.get_instance_location(instance_uuid)?;
+            storage.set_instance_location(instance_uuid, location)?;
+            self.audit(
+                storage,
+                "set_instance_location",
+                EntityType::Instance,
+                instance_uuid,
+                Some(serde_json::json!({ "location": before })),
+                Some(serde_json::json!({ "location": location })),
+            )
+        })
+    }
+
+    /// Depreciation of the category, inherited from the closest
+    /// supercategory that has one.
+    pub fn get_depreciation(&self, category_uuid: Uuid) -> Option<Depreciation> {
+        let storage = self.storage.as_ref();
+        return inherited(storage, category_uuid, |c| storage.get_depreciation(c)).unwrap();
+    }
+
+    /// Sets the depreciation of the category and the subcategories without
+    /// one of their own. `None` makes it inherit again.
+    pub fn set_depreciation(
+        &self,
+        category_uuid: Uuid,
+        depreciation: Option<Depreciation>,
+    ) -> Result<(), DatabaseError> {
+        if depreciation.is_some_and(|d| !(1..=100).contains(&d.rate)) {
+            return Err(DatabaseError::Invalid(
+                "Depreciation rate must be between 1 and 100 percent.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_depreciation(category_uuid)?;
+            storage.set_depreciation(category_uuid, depreciation.as_ref())?;
+            self.audit(
+                storage,
+                "set_depreciation",
+                EntityType::Category,
+                category_uuid,
+                Some(serde_json::json!({ "depreciation": before })),
+                Some(serde_json::json!({ "depreciation": depreciation })),
+            )
+        })
+    }
+
+    /// Value of the instance on the date, as it is insured. None if the
+    /// instance doesn't exist or has no price.
+    pub fn get_insured_value(&self, instance_uuid: Uuid, date: DateTime<Tz>) -> Option<i64> {
+        let storage = self.storage.as_ref();
+        let instance = storage.get_instance(instance_uuid).unwrap()?;
+        let asset = storage.get_asset_info(instance_uuid).unwrap()?;
+        if asset.purchase_price.is_none() && asset.current_value.is_none() {
+            return None;
+        }
+        let category = instance.product.category.uuid;
+        return Some(insured_value(storage, &asset, category, date).unwrap());
+    }
+
+    /// Insured value of the instances owned on the date, by category and
+    /// location. Instances bought later, or lost, stolen or retired by then,
+    /// are left out. Instances without a price count with no value.
+    pub fn get_insurance_report(&self, date: DateTime<Tz>) -> InsuranceReport {
+        let storage = self.storage.as_ref();
+        let categories = storage.get_categories(None).unwrap();
+        let assets = storage.get_asset_infos().unwrap();
+        let changes = storage.get_status_changes(None).unwrap();
+
+        let mut rows: Vec<InsuranceRow> = Vec::new();
+        for instance in storage.get_instances(None).unwrap() {
+            let asset = assets
+                .iter()
+                .find(|a| a.instance == instance.uuid)
+                .cloned()
+                .unwrap_or_else(|| AssetInfo::new(instance.uuid));
+            if asset
+                .purchase_date
+                .is_some_and(|purchased| purchased > date)
+            {
+                continue;
+            }
+            let status = changes
+                .iter()
+                .rfind(|c| c.instance == instance.uuid && c.date <= date)
+                .map_or(InstanceStatus::Available, |c| c.status);
+            if status.is_missing() || status == InstanceStatus::Retired {
+                continue;
+            }
+
+            let category = instance.product.category.uuid;
+            let value = insured_value(storage, &asset, category, date).unwrap();
+            let category_path = category_path(&categories, category);
+            let location = storage.get_instance_location(instance.uuid).unwrap();
+            let row = match rows
+                .iter_mut()
+                .find(|row| row.category_path == category_path && row.location == location)
+            {
+                Some(row) => row,
+                None => {
+                    rows.push(InsuranceRow {
+                        category_path,
+                        location,
+                        instances: 0,
+                        purchase_price: 0,
+                        insured_value: 0,
+                    });
+                    rows.last_mut().unwrap()
+                }
             };
+            row.instances += 1;
+            row.purchase_price += asset.purchase_price.unwrap_or(0);
+            row.insured_value += value;
+        }
+        rows.sort_by(|a, b| (&a.category_path, &a.location).cmp(&(&b.category_path, &b.location)));
+        return InsuranceReport { date, rows };
+    }
 
-            let loans = self.get_loans(query_params);
+    /// Kits sorted by name.
+    pub fn get_kits(&self) -> Vec<Kit> {
+        return self.storage.get_kits().unwrap();
+    }
 
-            dbg!(&loans);
+    pub fn get_kit(&self, kit_uuid: Uuid) -> Option<Kit> {
+        return self.get_kits().into_iter().find(|k| k.uuid == kit_uuid);
+    }
 
-            if loans.len() > 0 {
-                let error_message = format!(
-                    "Error: Instance is already loaned in the requested time frame.\n\
-                 Conflicting Loan - User ID: {}, Date Start: {}, Date End: {}",
-                    loans[0].user.uuid, loans[0].date_start, loans[0].date_end
-                );
-                return Err(error_message);
+    /// Defines a kit that `add_loan` books as one unit. Name uniqueness and
+    /// the existence of the items are enforced by the storage.
+    pub fn add_kit(&self, name: &str, items: Vec<KitItem>) -> Result<Kit, DatabaseError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Kit name can't be empty.".to_string(),
+            ));
+        }
+        if items.is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Kit must have at least one item.".to_string(),
+            ));
+        }
+        for (i, item) in items.iter().enumerate() {
+            match item {
+                KitItem::Product { quantity, .. } if *quantity < 1 => {
+                    return Err(DatabaseError::Invalid(
+                        "Quantity must be positive.".to_string(),
+                    ));
+                }
+                KitItem::Instance { .. } if items[..i].contains(item) => {
+                    return Err(DatabaseError::Invalid(
+                        "Instance is in the kit twice.".to_string(),
+                    ));
+                }
+                _ => {}
             }
         }
+        let kit = Kit {
+            uuid: Uuid::new_v4(),
+            name: name.to_string(),
+            items,
+        };
+        self.transaction(|storage| {
+            storage.insert_kit(&kit)?;
+            self.audit(
+                storage,
+                "add_kit",
+                EntityType::Kit,
+                kit.uuid,
+                None,
+                json(Some(&kit)),
+            )
+        })?;
+        return Ok(kit);
+    }
 
-        // Insert the new loan if no conflicts
-        let loan_uuid = Uuid::new_v4();
+    /// Fails with `InUse` once the kit has been booked.
+    pub fn remove_kit(&self, kit_uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let kit = storage.get_kits()?.into_iter().find(|k| k.uuid == kit_uuid);
+            storage.delete_kit(kit_uuid)?;
+            self.audit(
+                storage,
+                "remove_kit",
+                EntityType::Kit,
+                kit_uuid,
+                json(kit),
+                None,
+            )
+        })
+    }
 
-        // Manual acceptance for loans longer than 7 days
-        let mut accepted = true;
-        if date_end - date_start > chrono::Duration::days(7) {
-            accepted = false;
+    /// Relations the product is on either side of.
+    pub fn get_product_relations(&self, product_uuid: Uuid) -> Vec<ProductRelation> {
+        return self
+            .storage
+            .get_product_relations(Some(product_uuid))
+            .unwrap();
+    }
+
+    /// Relates `product` to `related`. Compatibility holds both ways, so it
+    /// can only be added once for a pair.
+    pub fn add_product_relation(
+        &self,
+        product_uuid: Uuid,
+        related_uuid: Uuid,
+        kind: ProductRelationKind,
+    ) -> Result<ProductRelation, DatabaseError> {
+        if product_uuid == related_uuid {
+            return Err(DatabaseError::Invalid(
+                "Products can't be related to themselves.".to_string(),
+            ));
         }
+        let relation = ProductRelation {
+            product: product_uuid,
+            related: related_uuid,
+            kind,
+        };
+        self.transaction(|storage| {
+            let reverse = ProductRelation {
+                product: related_uuid,
+                related: product_uuid,
+                kind,
+            };
+            if kind == ProductRelationKind::CompatibleWith
+                && storage
+                    .get_product_relations(Some(product_uuid))?
+                    .contains(&reverse)
+            {
+                return Err(DatabaseError::AlreadyExists(
+                    "Products are already compatible.".to_string(),
+                ));
+            }
+            storage.insert_product_relation(&relation)?;
+            self.audit(
+                storage,
+                "add_product_relation",
+                EntityType::Product,
+                product_uuid,
+                None,
+                json(Some(&relation)),
+            )
+        })?;
+        return Ok(relation);
+    }
 
-        let add_loan_query = String::from(
-            "INSERT INTO
-                loan (uuid, user, date_start, date_end, accepted, description)
-            VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6)",
-        );
-        self.connection
-            .execute(
-                &add_loan_query,
-                params![
-                    loan_uuid,
-                    user_id,
-                    date_start.to_rfc3339(),
-                    date_end.to_rfc3339(),
-                    accepted,
-                    None::<String>,
-                ],
-            )
-            .map_err(|e| e.to_string())?;
-
-        let add_loan_instance_query = String::from(
-            "INSERT INTO
-                loan_instances (loan, instance)
-            VALUES
-                (?1, ?2)",
+    /// Removes a relation. Compatibility is found whichever way it was
+    /// added.
+    pub fn remove_product_relation(
+        &self,
+        product_uuid: Uuid,
+        related_uuid: Uuid,
+        kind: ProductRelationKind,
+    ) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let relations = storage.get_product_relations(Some(product_uuid))?;
+            let relation = relations
+                .into_iter()
+                .find(|r| {
+                    r.kind == kind
+                        && ((r.product == product_uuid && r.related == related_uuid)
+                            || (kind == ProductRelationKind::CompatibleWith
+                                && r.product == related_uuid
+                                && r.related == product_uuid))
+                })
+                .ok_or_else(|| {
+                    DatabaseError::NotFound("Product relation not found.".to_string())
+                })?;
+            storage.delete_product_relation(&relation)?;
+            self.audit(
+                storage,
+                "remove_product_relation",
+                EntityType::Product,
+                relation.product,
+                json(Some(&relation)),
+                None,
+            )
+        })
+    }
+
+    /// What loaning the instances together is missing and what could be
+    /// added to it, as `add_loan` would see it.
+    pub fn check_compatibility(&self, instance_uuids: &[Uuid]) -> Compatibility {
+        let storage = self.storage.as_ref();
+        let loaned: Vec<Uuid> = storage
+            .get_instances(None)
+            .unwrap()
+            .into_iter()
+            .filter(|i| instance_uuids.contains(&i.uuid))
+            .map(|i| i.product.uuid)
+            .collect();
+        return compatibility(
+            &storage.get_products(None).unwrap(),
+            &storage.get_product_relations(None).unwrap(),
+            &loaned,
         );
+    }
+
+    /// Attributes of products in the category, including those of its
+    /// supercategories. The root category's come first.
+    pub fn get_attributes(&self, category_uuid: Uuid) -> Vec<AttributeDefinition> {
+        return category_attributes(self.storage.as_ref(), category_uuid).unwrap();
+    }
 
-        for instance_id in instaces {
-            let result = self
-                .connection
-                .execute(&add_loan_instance_query, params![loan_uuid, instance_id])
-                .map_err(|e| e.to_string());
-            assert!(result.is_ok());
+    /// Defines an attribute for products of the category and its
+    /// subcategories. The name must not be used by another attribute that
+    /// any of them has. Products that already exist are only checked
+    /// against a required attribute when their attributes are set again.
+    pub fn add_attribute(
+        &self,
+        category_uuid: Uuid,
+        name: &str,
+        kind: AttributeType,
+        required: bool,
+    ) -> Result<AttributeDefinition, DatabaseError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Attribute name can't be empty.".to_string(),
+            ));
         }
+        let kind = match kind {
+            AttributeType::Enum(options) => {
+                let options: Vec<String> = options.iter().map(|o| o.trim().to_string()).collect();
+                if options.is_empty() || options.iter().any(String::is_empty) {
+                    return Err(DatabaseError::Invalid(
+                        "Enum attributes need options that aren't empty.".to_string(),
+                    ));
+                }
+                for (i, option) in options.iter().enumerate() {
+                    if options[..i].iter().any(|o| o.eq_ignore_ascii_case(option)) {
+                        return Err(DatabaseError::Invalid(format!(
+                            "Option \"{}\" is given twice.",
+                            option
+                        )));
+                    }
+                }
+                AttributeType::Enum(options)
+            }
+            kind => kind,
+        };
+        let attribute = AttributeDefinition {
+            uuid: Uuid::new_v4(),
+            category: category_uuid,
+            name: name.to_string(),
+            kind,
+            required,
+        };
+        self.transaction(|storage| {
+            let categories = storage.get_categories(None)?;
+            if !categories.iter().any(|c| c.uuid == category_uuid) {
+                return Err(DatabaseError::NotFound("Category not found.".to_string()));
+            }
+            let clash = storage.get_attributes(None)?.into_iter().any(|a| {
+                a.name.eq_ignore_ascii_case(name)
+                    && (is_within(&categories, category_uuid, a.category)
+                        || is_within(&categories, a.category, category_uuid))
+            });
+            if clash {
+                return Err(DatabaseError::AlreadyExists(format!(
+                    "Attribute \"{}\" already exists.",
+                    name
+                )));
+            }
+            storage.insert_attribute(&attribute)?;
+            self.audit(
+                storage,
+                "add_attribute",
+                EntityType::Attribute,
+                attribute.uuid,
+                None,
+                json(Some(&attribute)),
+            )
+        })?;
+        return Ok(attribute);
+    }
 
-        let new_loan = self.get_loan(loan_uuid);
-        match new_loan {
-            Some(loan) => {
-                return Ok(loan);
+    /// Removes the attribute together with the values products have for it.
+    pub fn remove_attribute(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let attribute = storage
+                .get_attributes(None)?
+                .into_iter()
+                .find(|a| a.uuid == uuid)
+                .ok_or_else(|| DatabaseError::NotFound("Attribute not found.".to_string()))?;
+            storage.delete_attribute(uuid)?;
+            self.audit(
+                storage,
+                "remove_attribute",
+                EntityType::Attribute,
+                uuid,
+                json(Some(&attribute)),
+                None,
+            )
+        })
+    }
+
+    /// Attributes the product has, in the order of `get_attributes`, with
+    /// the values set for them.
+    pub fn get_product_attributes(&self, product_uuid: Uuid) -> Vec<ProductAttribute> {
+        let storage = self.storage.as_ref();
+        let Some(product) = storage.get_product(product_uuid).unwrap() else {
+            return Vec::new();
+        };
+        let values = storage.get_attribute_values(Some(product_uuid)).unwrap();
+        return category_attributes(storage, product.category.uuid)
+            .unwrap()
+            .into_iter()
+            .map(|attribute| ProductAttribute {
+                value: values
+                    .iter()
+                    .find(|v| v.attribute == attribute.uuid)
+                    .map(|v| v.value.clone()),
+                attribute,
+            })
+            .collect();
+    }
+
+    /// Replaces the attribute values of the product with `(name, value)`
+    /// pairs. Fails with `Invalid` if an attribute is unknown, given twice
+    /// or required but missing, or a value isn't of its type.
+    pub fn set_product_attributes(
+        &self,
+        product_uuid: Uuid,
+        values: &[(&str, &str)],
+    ) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let product = storage
+                .get_product(product_uuid)?
+                .ok_or_else(|| DatabaseError::NotFound("Product not found.".to_string()))?;
+            let attributes = category_attributes(storage, product.category.uuid)?;
+
+            let mut new_values: Vec<AttributeValue> = Vec::new();
+            for (name, value) in values.iter() {
+                let attribute = attributes
+                    .iter()
+                    .find(|a| a.name.eq_ignore_ascii_case(name.trim()))
+                    .ok_or_else(|| {
+                        DatabaseError::Invalid(format!(
+                            "{} has no attribute \"{}\".",
+                            product.name, name
+                        ))
+                    })?;
+                if new_values.iter().any(|v| v.attribute == attribute.uuid) {
+                    return Err(DatabaseError::Invalid(format!(
+                        "Attribute \"{}\" is given twice.",
+                        attribute.name
+                    )));
+                }
+                new_values.push(AttributeValue {
+                    product: product_uuid,
+                    attribute: attribute.uuid,
+                    value: attribute.kind.validate(value)?,
+                });
             }
-            None => {
-                return Err("Error: Loan not found.".to_string());
+            for attribute in attributes.iter().filter(|a| a.required) {
+                if !new_values.iter().any(|v| v.attribute == attribute.uuid) {
+                    return Err(DatabaseError::Invalid(format!(
+                        "Attribute \"{}\" is required.",
+                        attribute.name
+                    )));
+                }
+            }
+
+            let before = storage.get_attribute_values(Some(product_uuid))?;
+            storage.set_attribute_values(product_uuid, &new_values)?;
+            self.audit(
+                storage,
+                "set_product_attributes",
+                EntityType::Product,
+                product_uuid,
+                Some(serde_json::json!({ "attributes": before })),
+                Some(serde_json::json!({ "attributes": new_values })),
+            )
+        })
+    }
+
+    pub fn get_maintenance_rule(&self, product_uuid: Uuid) -> Option<MaintenanceRule> {
+        return self.storage.get_maintenance_rule(product_uuid).unwrap();
+    }
+
+    /// Sets the service intervals of a product, replacing earlier ones.
+    pub fn set_maintenance_rule(&self, rule: MaintenanceRule) -> Result<(), DatabaseError> {
+        let limits = [rule.loans, rule.days_out, rule.months];
+        if limits.iter().all(Option::is_none) {
+            return Err(DatabaseError::Invalid(
+                "Maintenance rule needs at least one limit.".to_string(),
+            ));
+        }
+        if limits.iter().flatten().any(|limit| *limit <= 0) {
+            return Err(DatabaseError::Invalid(
+                "Maintenance limits must be positive.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_maintenance_rule(rule.product)?;
+            storage.set_maintenance_rule(&rule)?;
+            self.audit(
+                storage,
+                "set_maintenance_rule",
+                EntityType::MaintenanceRule,
+                rule.product,
+                json(before),
+                json(Some(&rule)),
+            )
+        })
+    }
+
+    pub fn remove_maintenance_rule(&self, product_uuid: Uuid) -> Result<(), DatabaseError> {
+        self.transaction(|storage| {
+            let before = storage.get_maintenance_rule(product_uuid)?;
+            storage.delete_maintenance_rule(product_uuid)?;
+            self.audit(
+                storage,
+                "remove_maintenance_rule",
+                EntityType::MaintenanceRule,
+                product_uuid,
+                json(before),
+                None,
+            )
+        })
+    }
+
+    /// Records a reading of a counter the instance keeps itself, e.g.
+    /// `add_usage_reading(camera, "shutter count", 41250, now)`.
+    pub fn add_usage_reading(
+        &self,
+        instance_uuid: Uuid,
+        counter: &str,
+        value: i64,
+        date: DateTime<Tz>,
+    ) -> Result<UsageReading, DatabaseError> {
+        if counter.trim().is_empty() {
+            return Err(DatabaseError::Invalid(
+                "Counter name is required.".to_string(),
+            ));
+        }
+        if value < 0 {
+            return Err(DatabaseError::Invalid(
+                "Reading can't be negative.".to_string(),
+            ));
+        }
+        self.transaction(|storage| {
+            let reading = UsageReading {
+                uuid: Uuid::new_v4(),
+                instance: instance_uuid,
+                counter: counter.trim().to_string(),
+                value,
+                date,
+            };
+            storage.insert_usage_reading(&reading)?;
+            self.audit(
+                storage,
+                "add_usage_reading",
+                EntityType::UsageReading,
+                reading.uuid,
+                None,
+                json(Some(&reading)),
+            )?;
+            Ok(reading)
+        })
+    }
+
+    /// Every reading of the instance, oldest first.
+    pub fn get_usage_readings(&self, instance_uuid: Uuid) -> Vec<UsageReading> {
+        return self
+            .storage
+            .get_usage_readings(Some(instance_uuid))
+            .unwrap();
+    }
+
+    /// Loan count, days out and latest readings of the instance as of `now`.
+    pub fn get_instance_usage(
+        &self,
+        instance_uuid: Uuid,
+        now: DateTime<Tz>,
+    ) -> Option<InstanceUsage> {
+        let storage = self.storage.as_ref();
+        let instance = storage.get_instance(instance_uuid).unwrap()?;
+        return Some(instance_usage(storage, instance, now).unwrap());
+    }
+
+    /// Instances whose product has a maintenance rule with a limit reached
+    /// since their last maintenance. Months are counted from the first loan
+    /// of instances that have never been maintained. Instances that are not
+    /// available, or have maintenance scheduled or under way, are left out.
+    pub fn get_maintenance_due(&self, now: DateTime<Tz>) -> Vec<MaintenanceDue> {
+        let storage = self.storage.as_ref();
+        let mut due = Vec::new();
+        for product in storage.get_products(None).unwrap() {
+            let Some(rule) = storage.get_maintenance_rule(product.uuid).unwrap() else {
+                continue;
+            };
+            for instance in storage.get_instances(Some(product.uuid)).unwrap() {
+                if instance_status(storage, instance.uuid).unwrap() != InstanceStatus::Available {
+                    continue;
+                }
+                let windows = storage
+                    .get_maintenance_windows(Some(instance.uuid))
+                    .unwrap();
+                if windows.iter().any(|window| window.date_end > now) {
+                    continue;
+                }
+                let first_loan = storage
+                    .get_loans(&LoanQueryParams {
+                        instance_uuid: Some(instance.uuid),
+                        loan_accepted: Some(true),
+                        ..Default::default()
+                    })
+                    .unwrap()
+                    .iter()
+                    .map(|loan| loan.date_start)
+                    .filter(|date_start| *date_start <= now)
+                    .min();
+                let usage = instance_usage(storage, instance, now).unwrap();
+
+                let mut reasons = Vec::new();
+                if rule
+                    .loans
+                    .is_some_and(|loans| usage.loans_since_maintenance >= loans)
+                {
+                    reasons.push(MaintenanceDueReason::Loans);
+                }
+                if rule
+                    .days_out
+                    .is_some_and(|days| usage.days_out_since_maintenance >= days)
+                {
+                    reasons.push(MaintenanceDueReason::DaysOut);
+                }
+                let since = usage.last_maintenance.or(first_loan);
+                if let (Some(months), Some(since)) = (rule.months, since) {
+                    let limit = since.checked_add_months(chrono::Months::new(months as u32));
+                    if limit.is_some_and(|limit| limit <= now) {
+                        reasons.push(MaintenanceDueReason::Months);
+                    }
+                }
+                if !reasons.is_empty() {
+                    due.push(MaintenanceDue {
+                        usage,
+                        rule: rule.clone(),
+                        reasons,
+                    });
+                }
+            }
+        }
+        return due;
+    }
+
+    /// Instances of the product, or of every product, that `add_loan` would
+    /// accept for the time frame: available, in service, not in maintenance
+    /// and not booked by an accepted loan.
+    pub fn get_available_instances(
+        &self,
+        product_uuid: Option<Uuid>,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+    ) -> Vec<Instance> {
+        let storage = self.storage.as_ref();
+        let mut available = Vec::new();
+        for instance in storage.get_instances(product_uuid).unwrap() {
+            let bookable = check_bookable(storage, &[instance.uuid], date_start, date_end);
+            if matches!(bookable, Err(DatabaseError::Conflict(_))) {
+                continue;
+            }
+            bookable.unwrap();
+            available.push(instance);
+        }
+        return available;
+    }
+
+    /// Accepted loans that ended before `now` and still have instances out,
+    /// grouped by user.
+    pub fn get_overdue_loans(&self, now: DateTime<Tz>) -> Vec<UserOverdueLoans> {
+        let query_params = LoanQueryParams {
+            loan_accepted: Some(true),
+            returned: Some(false),
+            due_before: Some(now),
+            ..Default::default()
+        };
+        let mut loans = self.get_loans(query_params);
+        loans.sort_by_key(|loan| loan.date_end);
+
+        let mut overdue: Vec<UserOverdueLoans> = Vec::new();
+        for loan in loans {
+            let overdue_loan = OverdueLoan {
+                days_overdue: days_between(loan.date_end, now),
+                loan,
+            };
+            match overdue
+                .iter_mut()
+                .find(|user| user.user.uuid == overdue_loan.loan.user.uuid)
+            {
+                Some(user) => user.loans.push(overdue_loan),
+                None => overdue.push(UserOverdueLoans {
+                    user: overdue_loan.loan.user.clone(),
+                    loans: vec![overdue_loan],
+                }),
+            }
+        }
+
+        return overdue;
+    }
+
+    /// Loans still out that are due on the day of `now` or the day after.
+    pub fn get_due_loans(&self, now: DateTime<Tz>) -> DueLoans {
+        let now = now.with_timezone(&Helsinki);
+        let today = now.date_naive();
+        let tomorrow = today + chrono::Days::new(1);
+        let due = |start: NaiveDate, end: NaiveDate| {
+            let query_params = LoanQueryParams {
+                loan_accepted: Some(true),
+                returned: Some(false),
+                due_after: Some(start_of_day(now.timezone(), start)),
+                due_before: Some(start_of_day(now.timezone(), end)),
+                ..Default::default()
+            };
+            let mut loans = self.get_loans(query_params);
+            loans.sort_by_key(|loan| loan.date_end);
+            loans
+        };
+
+        return DueLoans {
+            today: due(today, tomorrow),
+            tomorrow: due(tomorrow, tomorrow + chrono::Days::new(1)),
+        };
+    }
+
+    pub fn get_user_email(&self, user_uuid: Uuid) -> Option<String> {
+        return self.storage.get_user_email(user_uuid).unwrap();
+    }
+
+    /// Address notifications to the user are sent to.
+    pub fn set_user_email(&self, user_uuid: Uuid, email: &str) -> Result<(), DatabaseError> {
+        if email.parse::<lettre::Address>().is_err() {
+            return Err(DatabaseError::Invalid(format!(
+                "Invalid email address {}.",
+                email
+            )));
+        }
+        self.transaction(|storage| {
+            let before = storage.get_user_email(user_uuid)?;
+            storage.set_user_email(user_uuid, email)?;
+            self.audit(
+                storage,
+                "set_user_email",
+                EntityType::User,
+                user_uuid,
+                Some(serde_json::json!({ "email": before })),
+                Some(serde_json::json!({ "email": email })),
+            )
+        })
+    }
+
+    /// Notifications to the user, oldest first.
+    pub fn get_notifications(&self, user_uuid: Uuid) -> Vec<Notification> {
+        return self.storage.get_notifications(Some(user_uuid)).unwrap();
+    }
+
+    /// Queues reminders for loans due by the end of the next day, overdue
+    /// loans and memberships about to expire. Each loan or membership is only
+    /// reminded of once, so this can be run as often as needed. Returns the
+    /// number of notifications queued.
+    pub fn queue_reminders(&self, now: DateTime<Tz>) -> Result<usize, DatabaseError> {
+        let now = now.with_timezone(&Helsinki);
+        self.transaction(|storage| {
+            let queued: Vec<(NotificationKind, Option<Uuid>)> = storage
+                .get_notifications(None)?
+                .into_iter()
+                .map(|notification| (notification.kind, notification.reference))
+                .collect();
+            let mut count = 0;
+
+            let tomorrow = now.date_naive() + chrono::Days::new(1);
+            let reminders = [
+                (
+                    NotificationKind::LoanDueSoon,
+                    Some(now),
+                    Some(start_of_day(
+                        now.timezone(),
+                        tomorrow + chrono::Days::new(1),
+                    )),
+                ),
+                (NotificationKind::LoanOverdue, None, Some(now)),
+            ];
+            for (kind, due_after, due_before) in reminders {
+                let query_params = LoanQueryParams {
+                    loan_accepted: Some(true),
+                    returned: Some(false),
+                    due_after,
+                    due_before,
+                    ..Default::default()
+                };
+                let mut loans = storage.get_loans(&query_params)?;
+                loans.sort_by_key(|loan| loan.date_end);
+                for loan in loans {
+                    if queued.contains(&(kind, Some(loan.uuid))) {
+                        continue;
+                    }
+                    let mut values = loan_values(&loan);
+                    let days_overdue = days_between(loan.date_end, now);
+                    values.push(("days", days_overdue.to_string()));
+                    enqueue(
+                        storage,
+                        &self.templates,
+                        loan.user.uuid,
+                        kind,
+                        Some(loan.uuid),
+                        &values,
+                        now,
+                    )?;
+                    count += 1;
+                }
+            }
+
+            let last_day = now.date_naive() + chrono::Days::new(MEMBERSHIP_REMINDER_DAYS);
+            let payments = storage.get_membership_payments(None)?;
+            let membership_types = storage.get_membership_types()?;
+            for payment in payments.iter() {
+                let kind = NotificationKind::MembershipExpiring;
+                if payment.date_end < now.date_naive()
+                    || payment.date_end > last_day
+                    || queued.contains(&(kind, Some(payment.uuid)))
+                {
+                    continue;
+                }
+                // Renewed memberships don't expire
+                if payments
+                    .iter()
+                    .any(|p| p.user == payment.user && p.date_end > payment.date_end)
+                {
+                    continue;
+                }
+                let user = storage.get_user(payment.user)?.unwrap();
+                let membership = membership_types
+                    .iter()
+                    .find(|t| t.uuid == payment.membership_type)
+                    .map(|t| t.name.clone())
+                    .unwrap_or_default();
+                let values = [
+                    ("user", user.name),
+                    ("membership", membership),
+                    ("date", payment.date_end.format("%Y-%m-%d").to_string()),
+                ];
+                enqueue(
+                    storage,
+                    &self.templates,
+                    payment.user,
+                    kind,
+                    Some(payment.uuid),
+                    &values,
+                    now,
+                )?;
+                count += 1;
+            }
+
+            Ok(count)
+        })
+    }
+
+    /// Sends the pending notifications that are due at `now`.
+    ///
+    /// Failed deliveries are retried with an exponentially growing delay and
+    /// given up after a few attempts. Notifications to users without an email
+    /// address fail right away. Only one worker should deliver at a time.
+    pub fn deliver_notifications(
+        &self,
+        transport: &dyn Transport,
+        now: DateTime<Tz>,
+    ) -> Result<DeliveryReport, DatabaseError> {
+        let mut report = DeliveryReport::default();
+        let due = self
+            .storage
+            .get_notifications(None)?
+            .into_iter()
+            .filter(|n| n.status == NotificationStatus::Pending && n.next_attempt <= now);
+
+        for mut notification in due {
+            match self.storage.get_user_email(notification.user)? {
+                None => {
+                    notification.status = NotificationStatus::Failed;
+                    notification.last_error = Some("User has no email address.".to_string());
+                    report.failed += 1;
+                }
+                Some(email) => {
+                    notification.attempts += 1;
+                    match transport.send(&email, &notification.subject, &notification.body) {
+                        Ok(()) => {
+                            notification.status = NotificationStatus::Sent;
+                            notification.date_sent = Some(now);
+                            notification.last_error = None;
+                            report.sent += 1;
+                        }
+                        Err(error) => {
+                            notification.last_error = Some(error);
+                            if notification.attempts >= MAX_DELIVERY_ATTEMPTS {
+                                notification.status = NotificationStatus::Failed;
+                                report.failed += 1;
+                            } else {
+                                let delay = RETRY_DELAY_MINUTES << (notification.attempts - 1);
+                                notification.next_attempt = now + chrono::Duration::minutes(delay);
+                                report.retried += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            self.storage.update_notification(&notification)?;
+        }
+
+        return Ok(report);
+    }
+
+    /// Everything in the database, read in one transaction.
+    pub fn export_snapshot(&self) -> Snapshot {
+        self.transaction(|storage| {
+            let mut users = Vec::new();
+            for user in storage.get_users()? {
+                users.push(SnapshotUser {
+                    email: storage.get_user_email(user.uuid)?,
+                    uuid: user.uuid,
+                    name: user.name,
+                });
+            }
+
+            // Supercategories first so that restoring can insert in order
+            let mut categories = Vec::new();
+            let mut level = storage
+                .get_categories(None)?
+                .into_iter()
+                .filter(|category| category.supercategory.is_none())
+                .collect::<Vec<_>>();
+            while !level.is_empty() {
+                let mut next = Vec::new();
+                for category in level {
+                    next.extend(storage.get_categories(Some(category.uuid))?);
+                    categories.push(SnapshotCategory {
+                        late_fee_rate: storage.get_late_fee_rate(category.uuid)?,
+                        deposit: storage.get_category_deposit(category.uuid)?,
+                        depreciation: storage.get_depreciation(category.uuid)?,
+                        uuid: category.uuid,
+                        name: category.name,
+                        supercategory: category.supercategory,
+                    });
+                }
+                level = next;
+            }
+
+            let mut products = Vec::new();
+            let mut product_prices = Vec::new();
+            let mut maintenance_rules = Vec::new();
+            for product in storage.get_products(None)? {
+                if let Some(price) = storage.get_product_price(product.uuid)? {
+                    product_prices.push(price);
+                }
+                if let Some(rule) = storage.get_maintenance_rule(product.uuid)? {
+                    maintenance_rules.push(rule);
+                }
+                products.push(SnapshotProduct {
+                    deposit: storage.get_product_deposit(product.uuid)?,
+                    uuid: product.uuid,
+                    name: product.name,
+                    category: product.category.uuid,
+                });
+            }
+
+            let mut instances = Vec::new();
+            for instance in storage.get_instances(None)? {
+                instances.push(SnapshotInstance {
+                    location: storage.get_instance_location(instance.uuid)?,
+                    uuid: instance.uuid,
+                    identifier: instance.identifier,
+                    product: instance.product.uuid,
+                });
             }
+
+            let check_ins = storage.get_check_ins()?;
+            let mut loans = Vec::new();
+            let query_params = LoanQueryParams {
+                include_rejected: true,
+                ..Default::default()
+            };
+            for loan in storage.get_loans(&query_params)? {
+                let instances = loan
+                    .instaces
+                    .iter()
+                    .map(|instance| SnapshotLoanInstance {
+                        instance: instance.uuid,
+                        date_returned: check_ins
+                            .iter()
+                            .find(|c| c.loan == loan.uuid && c.instance == instance.uuid)
+                            .map(|c| c.date),
+                    })
+                    .collect();
+                loans.push(SnapshotLoan {
+                    price_lines: storage.get_loan_price_lines(loan.uuid)?,
+                    uuid: loan.uuid,
+                    user: loan.user.uuid,
+                    date_start: loan.date_start,
+                    date_end: loan.date_end,
+                    accepted: loan.accepted,
+                    rejected: loan.rejected,
+                    description: loan.description,
+                    price: loan.price,
+                    instances,
+                });
+            }
+
+            let invoices = storage
+                .get_invoices(None)?
+                .into_iter()
+                .map(|invoice| {
+                    Ok(SnapshotInvoice {
+                        lines: storage.get_invoice_lines(invoice.number)?,
+                        invoice,
+                    })
+                })
+                .collect::<Result<Vec<_>, DatabaseError>>()?;
+
+            let maintenance_windows = storage
+                .get_maintenance_windows(None)?
+                .into_iter()
+                .map(|window| SnapshotMaintenanceWindow {
+                    uuid: window.uuid,
+                    instance: window.instance.uuid,
+                    date_start: window.date_start,
+                    date_end: window.date_end,
+                    reason: window.reason,
+                })
+                .collect();
+
+            Ok(Snapshot {
+                version: SNAPSHOT_VERSION,
+                users,
+                categories,
+                products,
+                product_prices,
+                instances,
+                membership_types: storage.get_membership_types()?,
+                membership_payments: storage.get_membership_payments(None)?,
+                loans,
+                ledger: storage.get_ledger_entries(None)?,
+                invoices,
+                deposits: storage.get_deposits(None)?,
+                notifications: storage.get_notifications(None)?,
+                condition_reports: storage.get_condition_reports(None)?,
+                maintenance_windows,
+                maintenance_rules,
+                usage_readings: storage.get_usage_readings(None)?,
+                status_changes: storage.get_status_changes(None)?,
+                assets: storage.get_asset_infos()?,
+                attributes: storage.get_attributes(None)?,
+                attribute_values: storage.get_attribute_values(None)?,
+                product_relations: storage.get_product_relations(None)?,
+                kits: storage.get_kits()?,
+                loan_kit_instances: storage.get_loan_kit_instances(None)?,
+                audit_log: storage.get_audit_entries(&AuditQueryParams::new())?,
+            })
+        })
+        .unwrap()
+    }
+
+    /// Loads a snapshot into an empty database, keeping the UUIDs. Either
+    /// everything is restored or nothing. The audit log of the snapshot is
+    /// restored as it is, without entries for the restore itself.
+    pub fn restore_snapshot(&self, snapshot: &Snapshot) -> Result<(), DatabaseError> {
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(DatabaseError::Invalid(format!(
+                "Unsupported snapshot version {}, expected {}.",
+                snapshot.version, SNAPSHOT_VERSION
+            )));
         }
+
+        self.transaction(|storage| {
+            if !storage.get_users()?.is_empty() || !storage.get_categories(None)?.is_empty() {
+                return Err(DatabaseError::Invalid(
+                    "Snapshots can only be restored into an empty database.".to_string(),
+                ));
+            }
+
+            for user in snapshot.users.iter() {
+                storage.insert_user(user.uuid, &user.name)?;
+                if let Some(email) = &user.email {
+                    storage.set_user_email(user.uuid, email)?;
+                }
+            }
+            for category in snapshot.categories.iter() {
+                storage.insert_category(category.uuid, &category.name, category.supercategory)?;
+                if let Some(rate) = category.late_fee_rate {
+                    storage.set_late_fee_rate(category.uuid, rate)?;
+                }
+                if let Some(amount) = category.deposit {
+                    storage.set_category_deposit(category.uuid, amount)?;
+                }
+                if let Some(depreciation) = &category.depreciation {
+                    storage.set_depreciation(category.uuid, Some(depreciation))?;
+                }
+            }
+            for attribute in snapshot.attributes.iter() {
+                storage.insert_attribute(attribute)?;
+            }
+            for product in snapshot.products.iter() {
+                storage.insert_product(product.uuid, &product.name, product.category)?;
+                if let Some(amount) = product.deposit {
+                    storage.set_product_deposit(product.uuid, amount)?;
+                }
+                let values: Vec<AttributeValue> = snapshot
+                    .attribute_values
+                    .iter()
+                    .filter(|v| v.product == product.uuid)
+                    .cloned()
+                    .collect();
+                if !values.is_empty() {
+                    storage.set_attribute_values(product.uuid, &values)?;
+                }
+            }
+            for relation in snapshot.product_relations.iter() {
+                storage.insert_product_relation(relation)?;
+            }
+            for price in snapshot.product_prices.iter() {
+                storage.set_product_price(price)?;
+            }
+            for instance in snapshot.instances.iter() {
+                storage.insert_instance(instance.uuid, &instance.identifier, instance.product)?;
+                if let Some(location) = &instance.location {
+                    storage.set_instance_location(instance.uuid, Some(location))?;
+                }
+            }
+            for kit in snapshot.kits.iter() {
+                storage.insert_kit(kit)?;
+            }
+            for membership_type in snapshot.membership_types.iter() {
+                storage.insert_membership_type(membership_type)?;
+            }
+            for payment in snapshot.membership_payments.iter() {
+                storage.insert_membership_payment(payment)?;
+            }
+            for loan in snapshot.loans.iter() {
+                storage.insert_loan(&NewLoan {
+                    uuid: loan.uuid,
+                    user: loan.user,
+                    date_start: loan.date_start,
+                    date_end: loan.date_end,
+                    accepted: loan.accepted,
+                    rejected: loan.rejected,
+                    description: loan.description.clone(),
+                    instances: loan.instances.iter().map(|i| i.instance).collect(),
+                    price: loan.price,
+                    price_lines: loan.price_lines.clone(),
+                })?;
+                for instance in loan.instances.iter() {
+                    if let Some(date) = instance.date_returned {
+                        storage.check_in(loan.uuid, instance.instance, date)?;
+                    }
+                }
+            }
+            for row in snapshot.loan_kit_instances.iter() {
+                storage.insert_loan_kit_instance(row)?;
+            }
+            for entry in snapshot.ledger.iter() {
+                storage.insert_ledger_entry(entry)?;
+            }
+            for invoice in snapshot.invoices.iter() {
+                storage.insert_invoice(&invoice.invoice, &invoice.lines)?;
+            }
+            for deposit in snapshot.deposits.iter() {
+                storage.insert_deposit(deposit)?;
+            }
+            for report in snapshot.condition_reports.iter() {
+                storage.insert_condition_report(report)?;
+            }
+            for window in snapshot.maintenance_windows.iter() {
+                storage.insert_maintenance_window(
+                    window.uuid,
+                    window.instance,
+                    window.date_start,
+                    window.date_end,
+                    &window.reason,
+                )?;
+            }
+            for rule in snapshot.maintenance_rules.iter() {
+                storage.set_maintenance_rule(rule)?;
+            }
+            for reading in snapshot.usage_readings.iter() {
+                storage.insert_usage_reading(reading)?;
+            }
+            for change in snapshot.status_changes.iter() {
+                storage.insert_status_change(change)?;
+            }
+            for asset in snapshot.assets.iter() {
+                storage.set_asset_info(asset)?;
+            }
+            for notification in snapshot.notifications.iter() {
+                storage.insert_notification(notification)?;
+            }
+            // Entries are numbered anew, in the same order
+            for entry in snapshot.audit_log.iter() {
+                storage.insert_audit_entry(&NewAuditEntry {
+                    date: entry.date,
+                    actor: entry.actor.clone(),
+                    operation: entry.operation.clone(),
+                    entity_type: entry.entity_type,
+                    entity: entry.entity,
+                    before: entry.before.clone(),
+                    after: entry.after.clone(),
+                })?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes a consistent copy of the database to the file while it stays
+    /// in use. Only SQLite databases can be backed up this way.
+    pub fn backup_to(&self, path: &str) -> Result<(), DatabaseError> {
+        return self.storage.backup_to(Path::new(path));
+    }
+
+    /// Backs up into a new file in the directory, named after `now`, and
+    /// deletes all but the newest `keep` backups there, never the new one.
+    /// Returns the path of the new backup.
+    pub fn backup_rotating(
+        &self,
+        directory: &str,
+        keep: usize,
+        now: DateTime<Tz>,
+    ) -> Result<String, DatabaseError> {
+        let directory = Path::new(directory);
+        fs::create_dir_all(directory).map_err(|e| DatabaseError::Internal(e.to_string()))?;
+        let path = backup::new_backup_path(directory, now);
+        self.storage.backup_to(&path)?;
+        backup::remove_old_backups(directory, keep.max(1))?;
+        return Ok(path.to_string_lossy().into_owned());
+    }
+
+    /// Replaces the SQLite database file with a backup, after checking that
+    /// the backup is intact and not of a newer schema version than this
+    /// program supports. Backups of older versions are migrated when opened.
+    /// The database must not be open while it is restored.
+    pub fn restore_backup(backup: &str, file_name: &str) -> Result<(), DatabaseError> {
+        return SqliteStorage::restore(Path::new(backup), Path::new(file_name));
     }
 }
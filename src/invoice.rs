@@ -0,0 +1,159 @@
+use chrono::DateTime;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::database::{Invoice, Loan};
+
+/// Title printed on the document. Receipts are the same document handed out
+/// once the loan has been paid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Invoice,
+    Receipt,
+}
+
+impl DocumentKind {
+    fn title(&self) -> &'static str {
+        match self {
+            DocumentKind::Invoice => "Invoice",
+            DocumentKind::Receipt => "Receipt",
+        }
+    }
+}
+
+/// Row of an invoice. Amounts are in cents, discounts are negative.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvoiceLine {
+    pub description: String,
+    pub amount: i64,
+}
+
+/// Everything printed on an invoice or receipt.
+#[derive(Debug, Clone)]
+pub struct InvoiceDocument {
+    pub invoice: Invoice,
+    pub loan: Loan,
+    pub lines: Vec<InvoiceLine>,
+}
+
+/// Cents as euros with two decimals, e.g. `-12.50`.
+pub fn format_money(cents: i64) -> String {
+    let sign = if cents < 0 { "-" } else { "" };
+    format!("{}{}.{:02}", sign, cents.abs() / 100, cents.abs() % 100)
+}
+
+fn format_date(date: &DateTime<Tz>) -> String {
+    date.format("%Y-%m-%d %H:%M").to_string()
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl InvoiceDocument {
+    /// Instances of the loan, one per line.
+    fn items(&self) -> Vec<String> {
+        self.loan
+            .instaces
+            .iter()
+            .map(|instance| format!("{} {}", instance.product.name, instance.identifier))
+            .collect()
+    }
+
+    pub fn to_text(&self, kind: DocumentKind) -> String {
+        let width = self
+            .lines
+            .iter()
+            .map(|line| line.description.chars().count())
+            .chain(std::iter::once("Total".len()))
+            .max()
+            .unwrap();
+
+        let mut text = String::new();
+        text.push_str(&format!("{} {}\n", kind.title(), self.invoice.number));
+        text.push_str(&format!("Date: {}\n", self.invoice.date.format("%Y-%m-%d")));
+        text.push_str(&format!(
+            "Borrower: {} ({})\n",
+            self.loan.user.name, self.loan.user.uuid
+        ));
+        text.push_str(&format!(
+            "Loan: {} - {}\n",
+            format_date(&self.loan.date_start),
+            format_date(&self.loan.date_end)
+        ));
+        text.push_str("Items:\n");
+        for item in self.items() {
+            text.push_str(&format!("  {}\n", item));
+        }
+        text.push('\n');
+        for line in self.lines.iter() {
+            text.push_str(&format!(
+                "{:<width$}  {:>10}\n",
+                line.description,
+                format_money(line.amount),
+                width = width
+            ));
+        }
+        text.push_str(&format!(
+            "{:<width$}  {:>10}\n",
+            "Total",
+            format_money(self.invoice.total),
+            width = width
+        ));
+        text
+    }
+
+    pub fn to_html(&self, kind: DocumentKind) -> String {
+        let title = format!("{} {}", kind.title(), self.invoice.number);
+
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str(&format!("<title>{}</title>\n", title));
+        html.push_str("</head>\n<body>\n");
+        html.push_str(&format!("<h1>{}</h1>\n", title));
+        html.push_str("<dl>\n");
+        html.push_str(&format!(
+            "<dt>Date</dt><dd>{}</dd>\n",
+            self.invoice.date.format("%Y-%m-%d")
+        ));
+        html.push_str(&format!(
+            "<dt>Borrower</dt><dd>{} ({})</dd>\n",
+            escape_html(&self.loan.user.name),
+            self.loan.user.uuid
+        ));
+        html.push_str(&format!(
+            "<dt>Loan</dt><dd>{} - {}</dd>\n",
+            format_date(&self.loan.date_start),
+            format_date(&self.loan.date_end)
+        ));
+        html.push_str("</dl>\n<ul>\n");
+        for item in self.items() {
+            html.push_str(&format!("<li>{}</li>\n", escape_html(&item)));
+        }
+        html.push_str("</ul>\n<table>\n");
+        for line in self.lines.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td></tr>\n",
+                escape_html(&line.description),
+                format_money(line.amount)
+            ));
+        }
+        html.push_str(&format!(
+            "<tr><th>Total</th><th>{}</th></tr>\n",
+            format_money(self.invoice.total)
+        ));
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
@@ -1,7 +1,17 @@
-use database::LoanQueryParams;
+use database::{LoanQueryParams, ProductQueryParams};
 
+pub mod attribute;
+pub mod audit;
+pub mod backup;
+pub mod calendar;
+pub mod catalogue;
 pub mod database;
+pub mod invoice;
+pub mod notification;
+pub mod snapshot;
+pub mod storage;
 pub mod test_database;
+pub mod valuation;
 
 fn add_test_data(db: &database::Database) {
     let catalogue = db.add_category("Catalogue", None).unwrap();
@@ -41,16 +51,49 @@ fn add_test_data(db: &database::Database) {
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
-    if args.len() < 2 {
-        println!("Usage: {} <database>", args[0]);
+    if args.len() < 2 || args.len() == 3 || args.len() > 4 {
+        println!(
+            "Usage: {} <database> [backup <file> | restore <file>]",
+            args[0]
+        );
         std::process::exit(1);
     }
-    let db = database::Database::new(&args[1]);
+
+    if args.len() == 4 {
+        let result = match args[2].as_str() {
+            // Opening a missing file would create an empty database
+            "backup" if !std::path::Path::new(&args[1]).is_file() => Err(
+                database::DatabaseError::NotFound(format!("Database {} not found.", args[1])),
+            ),
+            "backup" => database::Database::open(&args[1]).and_then(|db| db.backup_to(&args[3])),
+            "restore" => database::Database::restore_backup(&args[3], &args[1]),
+            command => {
+                println!("Unknown command {}", command);
+                std::process::exit(1);
+            }
+        };
+        match result {
+            Ok(()) => println!("Done"),
+            Err(e) => {
+                println!("Failed: {:?}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let db = match database::Database::open(&args[1]) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Failed: {:?}", e);
+            std::process::exit(1);
+        }
+    };
 
     add_test_data(&db);
 
     let user_count = &db.get_users().len();
-    let product_count = &db.get_products(None).len();
+    let product_count = &db.get_products(ProductQueryParams::new()).len();
     let category_count = &db.get_categories(None).len();
     let loan_count = &db.get_loans(LoanQueryParams::new()).len();
 
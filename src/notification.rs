@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Event a notification is sent for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    LoanCreated,
+    LoanApproved,
+    LoanRejected,
+    LoanDueSoon,
+    LoanOverdue,
+    MembershipExpiring,
+}
+
+impl NotificationKind {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::LoanCreated => "loan_created",
+            NotificationKind::LoanApproved => "loan_approved",
+            NotificationKind::LoanRejected => "loan_rejected",
+            NotificationKind::LoanDueSoon => "loan_due_soon",
+            NotificationKind::LoanOverdue => "loan_overdue",
+            NotificationKind::MembershipExpiring => "membership_expiring",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "loan_created" => Some(NotificationKind::LoanCreated),
+            "loan_approved" => Some(NotificationKind::LoanApproved),
+            "loan_rejected" => Some(NotificationKind::LoanRejected),
+            "loan_due_soon" => Some(NotificationKind::LoanDueSoon),
+            "loan_overdue" => Some(NotificationKind::LoanOverdue),
+            "membership_expiring" => Some(NotificationKind::MembershipExpiring),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationStatus {
+    /// Waiting for its first or next delivery attempt.
+    Pending,
+    Sent,
+    /// Gave up after too many attempts, or the user has no address.
+    Failed,
+}
+
+impl NotificationStatus {
+    /// Name stored in the database.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationStatus::Pending => "pending",
+            NotificationStatus::Sent => "sent",
+            NotificationStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        match status {
+            "pending" => Some(NotificationStatus::Pending),
+            "sent" => Some(NotificationStatus::Sent),
+            "failed" => Some(NotificationStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Message in the outbox. The text is rendered when the event happens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub uuid: Uuid,
+    pub user: Uuid,
+    pub kind: NotificationKind,
+    /// Loan or membership payment the notification is about.
+    pub reference: Option<Uuid>,
+    pub subject: String,
+    pub body: String,
+    #[serde(with = "crate::snapshot::date")]
+    pub created: DateTime<Tz>,
+    pub status: NotificationStatus,
+    pub attempts: i64,
+    #[serde(with = "crate::snapshot::date")]
+    pub next_attempt: DateTime<Tz>,
+    pub last_error: Option<String>,
+    #[serde(with = "crate::snapshot::optional_date")]
+    pub date_sent: Option<DateTime<Tz>>,
+}
+
+/// Outcome of a delivery run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub sent: usize,
+    /// Failed this time, to be tried again later.
+    pub retried: usize,
+    /// Given up on.
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Template {
+    pub subject: String,
+    pub body: String,
+}
+
+/// Message templates per event. `{name}` placeholders are replaced with the
+/// values of the event:
+///
+/// - loans: `{user}`, `{items}`, `{start}`, `{end}`
+/// - created: `{status}`, rejected: `{reason}`, overdue: `{days}`
+/// - membership expiring: `{user}`, `{membership}`, `{date}`
+#[derive(Debug, Clone)]
+pub struct Templates {
+    templates: HashMap<NotificationKind, Template>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        let mut templates = Self {
+            templates: HashMap::new(),
+        };
+        templates.set(
+            NotificationKind::LoanCreated,
+            "Loan request received",
+            "Hi {user},\n\nwe have received your loan of {items} from {start} to {end}. {status}\n",
+        );
+        templates.set(
+            NotificationKind::LoanApproved,
+            "Loan approved",
+            "Hi {user},\n\nyour loan of {items} from {start} to {end} has been approved.\n",
+        );
+        templates.set(
+            NotificationKind::LoanRejected,
+            "Loan rejected",
+            "Hi {user},\n\nyour loan of {items} from {start} to {end} has been rejected. {reason}\n",
+        );
+        templates.set(
+            NotificationKind::LoanDueSoon,
+            "Loan due soon",
+            "Hi {user},\n\nplease return {items} by {end}.\n",
+        );
+        templates.set(
+            NotificationKind::LoanOverdue,
+            "Loan overdue",
+            "Hi {user},\n\n{items} should have been returned by {end}, {days} days ago. \
+             Please return them as soon as possible.\n",
+        );
+        templates.set(
+            NotificationKind::MembershipExpiring,
+            "Membership expiring",
+            "Hi {user},\n\nyour {membership} membership expires on {date}.\n",
+        );
+        templates
+    }
+}
+
+impl Templates {
+    pub fn set(&mut self, kind: NotificationKind, subject: &str, body: &str) {
+        self.templates.insert(
+            kind,
+            Template {
+                subject: subject.to_string(),
+                body: body.to_string(),
+            },
+        );
+    }
+
+    /// Subject and body for the event.
+    pub fn render(&self, kind: NotificationKind, values: &[(&str, String)]) -> (String, String) {
+        let template = &self.templates[&kind];
+        let fill = |text: &str| {
+            let mut text = text.to_string();
+            for (name, value) in values {
+                text = text.replace(&format!("{{{}}}", name), value);
+            }
+            text
+        };
+        (fill(&template.subject), fill(&template.body))
+    }
+}
+
+/// Delivers notifications to an address.
+pub trait Transport {
+    /// Returns a description of the failure if the message was not accepted.
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+/// Sends notifications as plain text email.
+pub struct SmtpTransport {
+    transport: lettre::SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpTransport {
+    /// Relay that requires TLS and authentication, e.g. `smtp.example.com`.
+    pub fn relay(host: &str, user: &str, password: &str, from: &str) -> Result<Self, String> {
+        let transport = lettre::SmtpTransport::relay(host)
+            .map_err(|e| e.to_string())?
+            .credentials(Credentials::new(user.to_string(), password.to_string()))
+            .build();
+        Self::with_transport(transport, from)
+    }
+
+    /// Unencrypted connection without authentication, for a local mail server.
+    pub fn unencrypted(host: &str, port: u16, from: &str) -> Result<Self, String> {
+        let transport = lettre::SmtpTransport::builder_dangerous(host)
+            .port(port)
+            .build();
+        Self::with_transport(transport, from)
+    }
+
+    fn with_transport(transport: lettre::SmtpTransport, from: &str) -> Result<Self, String> {
+        let from = from.parse::<Mailbox>().map_err(|e| e.to_string())?;
+        Ok(Self { transport, from })
+    }
+}
+
+impl Transport for SmtpTransport {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        use lettre::Transport;
+
+        let to = to.parse::<Mailbox>().map_err(|e| e.to_string())?;
+        let message = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|e| e.to_string())?;
+        self.transport.send(&message).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
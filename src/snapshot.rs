@@ -0,0 +1,224 @@
+use chrono::DateTime;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::attribute::{AttributeDefinition, AttributeValue};
+use crate::audit::AuditEntry;
+use crate::database::{
+    AssetInfo, ConditionReport, DatabaseError, Deposit, Depreciation, Invoice, Kit, LedgerEntry,
+    LoanKitInstance, MaintenanceRule, MembershipPayment, MembershipType, ProductPrice,
+    ProductRelation, QuoteLine, StatusChange, UsageReading,
+};
+use crate::invoice::InvoiceLine;
+use crate::notification::Notification;
+
+/// Format version of the snapshots written. Restoring only accepts this
+/// version.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Dates as RFC 3339 strings, read back in Helsinki time like the storages
+/// do.
+pub(crate) mod date {
+    use chrono::DateTime;
+    use chrono_tz::Europe::Helsinki;
+    use chrono_tz::Tz;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(date: &DateTime<Tz>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&date.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<Tz>, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&date)
+            .map(|date| date.with_timezone(&Helsinki))
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub(crate) mod optional_date {
+    use chrono::DateTime;
+    use chrono_tz::Tz;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Date(#[serde(with = "super::date")] DateTime<Tz>);
+
+    pub fn serialize<S: Serializer>(
+        date: &Option<DateTime<Tz>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        date.map(Date).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<Tz>>, D::Error> {
+        Ok(Option::<Date>::deserialize(deserializer)?.map(|date| date.0))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotUser {
+    pub uuid: Uuid,
+    pub name: String,
+    pub email: Option<String>,
+}
+
+/// Category with the settings of the category itself, not the inherited
+/// ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotCategory {
+    pub uuid: Uuid,
+    pub name: String,
+    pub supercategory: Option<Uuid>,
+    pub late_fee_rate: Option<i64>,
+    pub deposit: Option<i64>,
+    /// None in snapshots made before depreciation existed.
+    #[serde(default)]
+    pub depreciation: Option<Depreciation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotProduct {
+    pub uuid: Uuid,
+    pub name: String,
+    pub category: Uuid,
+    pub deposit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInstance {
+    pub uuid: Uuid,
+    pub identifier: String,
+    pub product: Uuid,
+    /// None in snapshots made before locations existed.
+    #[serde(default)]
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotLoanInstance {
+    pub instance: Uuid,
+    #[serde(with = "optional_date")]
+    pub date_returned: Option<DateTime<Tz>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotLoan {
+    pub uuid: Uuid,
+    pub user: Uuid,
+    #[serde(with = "date")]
+    pub date_start: DateTime<Tz>,
+    #[serde(with = "date")]
+    pub date_end: DateTime<Tz>,
+    pub accepted: bool,
+    #[serde(default)]
+    pub rejected: bool,
+    pub description: Option<String>,
+    pub price: i64,
+    pub instances: Vec<SnapshotLoanInstance>,
+    pub price_lines: Vec<QuoteLine>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotMaintenanceWindow {
+    pub uuid: Uuid,
+    pub instance: Uuid,
+    #[serde(with = "date")]
+    pub date_start: DateTime<Tz>,
+    #[serde(with = "date")]
+    pub date_end: DateTime<Tz>,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInvoice {
+    #[serde(flatten)]
+    pub invoice: Invoice,
+    /// Empty in snapshots made before invoice lines were stored.
+    #[serde(default)]
+    pub lines: Vec<InvoiceLine>,
+}
+
+/// Everything in a database, with references between rows as UUIDs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub users: Vec<SnapshotUser>,
+    /// Supercategories come before their subcategories.
+    pub categories: Vec<SnapshotCategory>,
+    pub products: Vec<SnapshotProduct>,
+    pub product_prices: Vec<ProductPrice>,
+    pub instances: Vec<SnapshotInstance>,
+    pub membership_types: Vec<MembershipType>,
+    pub membership_payments: Vec<MembershipPayment>,
+    pub loans: Vec<SnapshotLoan>,
+    pub ledger: Vec<LedgerEntry>,
+    pub invoices: Vec<SnapshotInvoice>,
+    pub deposits: Vec<Deposit>,
+    pub notifications: Vec<Notification>,
+    /// Empty in snapshots made before condition reports existed.
+    #[serde(default)]
+    pub condition_reports: Vec<ConditionReport>,
+    /// Empty in snapshots made before maintenance windows existed.
+    #[serde(default)]
+    pub maintenance_windows: Vec<SnapshotMaintenanceWindow>,
+    /// Empty in snapshots made before usage tracking existed.
+    #[serde(default)]
+    pub maintenance_rules: Vec<MaintenanceRule>,
+    /// Empty in snapshots made before usage tracking existed.
+    #[serde(default)]
+    pub usage_readings: Vec<UsageReading>,
+    /// Empty in snapshots made before instance statuses existed.
+    #[serde(default)]
+    pub status_changes: Vec<StatusChange>,
+    /// Empty in snapshots made before asset details existed.
+    #[serde(default)]
+    pub assets: Vec<AssetInfo>,
+    /// Empty in snapshots made before attributes existed.
+    #[serde(default)]
+    pub attributes: Vec<AttributeDefinition>,
+    /// Empty in snapshots made before attributes existed.
+    #[serde(default)]
+    pub attribute_values: Vec<AttributeValue>,
+    /// Empty in snapshots made before product relations existed.
+    #[serde(default)]
+    pub product_relations: Vec<ProductRelation>,
+    /// Empty in snapshots made before kits existed.
+    #[serde(default)]
+    pub kits: Vec<Kit>,
+    /// Empty in snapshots made before kits existed.
+    #[serde(default)]
+    pub loan_kit_instances: Vec<LoanKitInstance>,
+    /// Empty in snapshots made before the audit log existed.
+    #[serde(default)]
+    pub audit_log: Vec<AuditEntry>,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Fails with `Invalid` if the document is malformed or of another
+    /// version.
+    pub fn from_json(json: &str) -> Result<Self, DatabaseError> {
+        let invalid = |e: serde_json::Error| DatabaseError::Invalid(e.to_string());
+
+        // Checked first, other versions may not parse
+        let document: serde_json::Value = serde_json::from_str(json).map_err(invalid)?;
+        let version = document.get("version").and_then(|version| version.as_u64());
+        if version != Some(SNAPSHOT_VERSION as u64) {
+            return Err(DatabaseError::Invalid(format!(
+                "Unsupported snapshot version {}, expected {}.",
+                version.map_or("none".to_string(), |version| version.to_string()),
+                SNAPSHOT_VERSION
+            )));
+        }
+        serde_json::from_value(document).map_err(invalid)
+    }
+}
@@ -0,0 +1,305 @@
+use std::path::Path;
+
+use uuid::Uuid;
+
+use crate::attribute::{AttributeDefinition, AttributeValue};
+use crate::audit::{AuditEntry, AuditQueryParams, EntityType};
+use crate::database::{
+    AssetInfo, Category, ConditionReport, DatabaseError, Deposit, Depreciation, Instance, Invoice,
+    Kit, LedgerEntry, Loan, LoanKitInstance, LoanQueryParams, MaintenanceRule, MaintenanceWindow,
+    MembershipPayment, MembershipType, Product, ProductPrice, ProductRelation, QuoteLine,
+    StatusChange, UsageReading, User,
+};
+use crate::invoice::InvoiceLine;
+use crate::notification::Notification;
+use chrono::DateTime;
+use chrono_tz::Tz;
+
+pub mod memory;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use self::postgres::PostgresStorage;
+pub use memory::MemoryStorage;
+pub use sqlite::SqliteStorage;
+
+/// Loan row to be stored together with its instances.
+#[derive(Debug, Clone)]
+pub struct NewLoan {
+    pub uuid: Uuid,
+    pub user: Uuid,
+    pub date_start: DateTime<Tz>,
+    pub date_end: DateTime<Tz>,
+    pub accepted: bool,
+    pub rejected: bool,
+    pub description: Option<String>,
+    pub instances: Vec<Uuid>,
+    /// Total of the price lines less discounts.
+    pub price: i64,
+    pub price_lines: Vec<QuoteLine>,
+}
+
+/// Instance of a loan that has been returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckIn {
+    pub loan: Uuid,
+    pub instance: Uuid,
+    pub date: DateTime<Tz>,
+}
+
+/// Audit log entry to be appended. The storage numbers it.
+#[derive(Debug, Clone)]
+pub struct NewAuditEntry {
+    pub date: DateTime<Tz>,
+    pub actor: String,
+    pub operation: String,
+    pub entity_type: EntityType,
+    pub entity: Uuid,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+/// Persistence operations behind `Database`.
+///
+/// Implementations only store and fetch rows. They enforce referential
+/// integrity and uniqueness the way the SQL schema does: inserts referencing
+/// a missing row fail with `NotFound`, duplicates with `AlreadyExists` and
+/// deleting a referenced row with `InUse`. Business rules such as loan
+/// overlap and approval live in `Database`.
+pub trait Storage {
+    /// Runs `f` in a transaction. Everything done through the storage handed
+    /// to `f` is committed together, or rolled back if `f` returns an error.
+    /// Transactions are serializable, so what `f` reads stays valid until it
+    /// returns. A storage may roll back and run `f` again when it conflicts
+    /// with a concurrent transaction, so `f` should only change the storage.
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), DatabaseError>,
+    ) -> Result<(), DatabaseError>;
+
+    /// Writes a consistent copy of the whole database to the file while it
+    /// stays in use. Fails with `Invalid` if the backend has no file to copy.
+    fn backup_to(&self, path: &Path) -> Result<(), DatabaseError>;
+
+    fn get_users(&self) -> Result<Vec<User>, DatabaseError>;
+    fn get_user(&self, uuid: Uuid) -> Result<Option<User>, DatabaseError>;
+    fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DatabaseError>;
+    fn insert_user(&self, uuid: Uuid, name: &str) -> Result<(), DatabaseError>;
+    /// Fails with `InUse` while the user has loans, ledger entries or
+    /// membership payments.
+    fn delete_user(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+    fn get_user_email(&self, user: Uuid) -> Result<Option<String>, DatabaseError>;
+    fn set_user_email(&self, user: Uuid, email: &str) -> Result<(), DatabaseError>;
+
+    /// All categories, or only the direct subcategories of `supercategory`.
+    fn get_categories(&self, supercategory: Option<Uuid>) -> Result<Vec<Category>, DatabaseError>;
+    fn get_category(&self, name: &str) -> Result<Option<Category>, DatabaseError>;
+    fn insert_category(
+        &self,
+        uuid: Uuid,
+        name: &str,
+        supercategory: Option<Uuid>,
+    ) -> Result<(), DatabaseError>;
+    /// Fails with `InUse` while the category has subcategories or products.
+    fn delete_category(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+    /// Daily late fee of the category itself, without inheritance.
+    fn get_late_fee_rate(&self, category: Uuid) -> Result<Option<i64>, DatabaseError>;
+    fn set_late_fee_rate(&self, category: Uuid, daily_rate: i64) -> Result<(), DatabaseError>;
+
+    fn get_products(&self, category: Option<Uuid>) -> Result<Vec<Product>, DatabaseError>;
+    fn get_product(&self, uuid: Uuid) -> Result<Option<Product>, DatabaseError>;
+    fn get_product_by_name(&self, name: &str) -> Result<Option<Product>, DatabaseError>;
+    fn insert_product(&self, uuid: Uuid, name: &str, category: Uuid) -> Result<(), DatabaseError>;
+    /// Deletes the instances of the product as well. Fails with `InUse` if
+    /// any of them has been loaned.
+    fn delete_product(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+
+    /// Attributes defined on the category itself, or on every category, in
+    /// the order they were added.
+    fn get_attributes(
+        &self,
+        category: Option<Uuid>,
+    ) -> Result<Vec<AttributeDefinition>, DatabaseError>;
+    fn insert_attribute(&self, attribute: &AttributeDefinition) -> Result<(), DatabaseError>;
+    /// Deletes the attribute with the values products have for it.
+    fn delete_attribute(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+    /// Attribute values of the product, or of every product.
+    fn get_attribute_values(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<AttributeValue>, DatabaseError>;
+    /// Replaces every attribute value of the product.
+    fn set_attribute_values(
+        &self,
+        product: Uuid,
+        values: &[AttributeValue],
+    ) -> Result<(), DatabaseError>;
+
+    /// Relations the product is on either side of, or all of them, in the
+    /// order they were added.
+    fn get_product_relations(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<ProductRelation>, DatabaseError>;
+    fn insert_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError>;
+    fn delete_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError>;
+
+    /// Every kit with its items in order, sorted by name.
+    fn get_kits(&self) -> Result<Vec<Kit>, DatabaseError>;
+    fn insert_kit(&self, kit: &Kit) -> Result<(), DatabaseError>;
+    /// Fails with `InUse` while a loan has instances booked through it.
+    fn delete_kit(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+
+    fn get_product_price(&self, product: Uuid) -> Result<Option<ProductPrice>, DatabaseError>;
+    fn set_product_price(&self, price: &ProductPrice) -> Result<(), DatabaseError>;
+
+    /// Deposit of the product itself, without its category.
+    fn get_product_deposit(&self, product: Uuid) -> Result<Option<i64>, DatabaseError>;
+    fn set_product_deposit(&self, product: Uuid, amount: i64) -> Result<(), DatabaseError>;
+    /// Deposit of the category itself, without inheritance.
+    fn get_category_deposit(&self, category: Uuid) -> Result<Option<i64>, DatabaseError>;
+    fn set_category_deposit(&self, category: Uuid, amount: i64) -> Result<(), DatabaseError>;
+
+    fn get_instances(&self, product: Option<Uuid>) -> Result<Vec<Instance>, DatabaseError>;
+    fn get_instance(&self, uuid: Uuid) -> Result<Option<Instance>, DatabaseError>;
+    fn insert_instance(
+        &self,
+        uuid: Uuid,
+        identifier: &str,
+        product: Uuid,
+    ) -> Result<(), DatabaseError>;
+
+    /// Loans matching every given filter. Filters apply to the loaned
+    /// instances, so a loan only lists the instances that matched.
+    fn get_loans(&self, params: &LoanQueryParams) -> Result<Vec<Loan>, DatabaseError>;
+    /// Stores the loan together with its instances and price lines.
+    fn insert_loan(&self, loan: &NewLoan) -> Result<(), DatabaseError>;
+    fn set_loan_accepted(&self, loan: Uuid, accepted: bool) -> Result<(), DatabaseError>;
+    fn set_loan_rejected(&self, loan: Uuid, rejected: bool) -> Result<(), DatabaseError>;
+    fn get_loan_price_lines(&self, loan: Uuid) -> Result<Vec<QuoteLine>, DatabaseError>;
+    /// Instances of the loan, or of every loan, that were booked as part of
+    /// a kit.
+    fn get_loan_kit_instances(
+        &self,
+        loan: Option<Uuid>,
+    ) -> Result<Vec<LoanKitInstance>, DatabaseError>;
+    fn insert_loan_kit_instance(&self, row: &LoanKitInstance) -> Result<(), DatabaseError>;
+    /// Records the instance of the loan as returned at `date`. Fails with
+    /// `NotFound` unless the instance is on the loan and still out.
+    fn check_in(&self, loan: Uuid, instance: Uuid, date: DateTime<Tz>)
+        -> Result<(), DatabaseError>;
+    /// Returned instances of every loan.
+    fn get_check_ins(&self) -> Result<Vec<CheckIn>, DatabaseError>;
+
+    fn get_membership_types(&self) -> Result<Vec<MembershipType>, DatabaseError>;
+    fn insert_membership_type(&self, membership_type: &MembershipType)
+        -> Result<(), DatabaseError>;
+    /// Membership payments of the user, or of everyone.
+    fn get_membership_payments(
+        &self,
+        user: Option<Uuid>,
+    ) -> Result<Vec<MembershipPayment>, DatabaseError>;
+    fn insert_membership_payment(&self, payment: &MembershipPayment) -> Result<(), DatabaseError>;
+
+    /// Ledger entries of the user, or of everyone.
+    fn get_ledger_entries(&self, user: Option<Uuid>) -> Result<Vec<LedgerEntry>, DatabaseError>;
+    fn insert_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), DatabaseError>;
+
+    /// Invoices of the loan, or all of them.
+    fn get_invoices(&self, loan: Option<Uuid>) -> Result<Vec<Invoice>, DatabaseError>;
+    /// Stores the invoice with its lines, which are never changed afterwards.
+    /// Fails with `AlreadyExists` if the number is taken.
+    fn insert_invoice(&self, invoice: &Invoice, lines: &[InvoiceLine])
+        -> Result<(), DatabaseError>;
+    /// Lines of the invoice in print order.
+    fn get_invoice_lines(&self, invoice: i64) -> Result<Vec<InvoiceLine>, DatabaseError>;
+
+    /// Deposit of the loan, or all deposits.
+    fn get_deposits(&self, loan: Option<Uuid>) -> Result<Vec<Deposit>, DatabaseError>;
+    /// Fails with `AlreadyExists` if the loan already has a deposit.
+    fn insert_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError>;
+    /// Stores the status, settlement date and reason of the deposit.
+    fn update_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError>;
+
+    /// Condition reports of the instance, or of every instance, oldest first.
+    fn get_condition_reports(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<ConditionReport>, DatabaseError>;
+    fn insert_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError>;
+    /// Stores the resolution date and text of the report.
+    fn update_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError>;
+
+    /// Maintenance windows of the instance, or of every instance.
+    fn get_maintenance_windows(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<MaintenanceWindow>, DatabaseError>;
+    fn insert_maintenance_window(
+        &self,
+        uuid: Uuid,
+        instance: Uuid,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        reason: &str,
+    ) -> Result<(), DatabaseError>;
+    fn delete_maintenance_window(&self, uuid: Uuid) -> Result<(), DatabaseError>;
+
+    /// Status history of the instance, or of every instance, oldest first.
+    fn get_status_changes(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<StatusChange>, DatabaseError>;
+    fn insert_status_change(&self, change: &StatusChange) -> Result<(), DatabaseError>;
+
+    fn get_asset_info(&self, instance: Uuid) -> Result<Option<AssetInfo>, DatabaseError>;
+    /// Details of every instance that has them.
+    fn get_asset_infos(&self) -> Result<Vec<AssetInfo>, DatabaseError>;
+    /// Fails with `AlreadyExists` if another instance has the serial number.
+    fn set_asset_info(&self, asset: &AssetInfo) -> Result<(), DatabaseError>;
+
+    fn get_instance_location(&self, instance: Uuid) -> Result<Option<String>, DatabaseError>;
+    /// Forgets the location when `None`.
+    fn set_instance_location(
+        &self,
+        instance: Uuid,
+        location: Option<&str>,
+    ) -> Result<(), DatabaseError>;
+
+    /// Depreciation set on the category itself, not an inherited one.
+    fn get_depreciation(&self, category: Uuid) -> Result<Option<Depreciation>, DatabaseError>;
+    /// Removes it when `None`.
+    fn set_depreciation(
+        &self,
+        category: Uuid,
+        depreciation: Option<&Depreciation>,
+    ) -> Result<(), DatabaseError>;
+
+    fn get_maintenance_rule(&self, product: Uuid)
+        -> Result<Option<MaintenanceRule>, DatabaseError>;
+    fn set_maintenance_rule(&self, rule: &MaintenanceRule) -> Result<(), DatabaseError>;
+    fn delete_maintenance_rule(&self, product: Uuid) -> Result<(), DatabaseError>;
+
+    /// Usage readings of the instance, or of every instance, oldest first.
+    fn get_usage_readings(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<UsageReading>, DatabaseError>;
+    fn insert_usage_reading(&self, reading: &UsageReading) -> Result<(), DatabaseError>;
+
+    /// Notifications to the user, or to everyone, oldest first.
+    fn get_notifications(&self, user: Option<Uuid>) -> Result<Vec<Notification>, DatabaseError>;
+    fn insert_notification(&self, notification: &Notification) -> Result<(), DatabaseError>;
+    /// Stores the delivery state: status, attempts, next attempt, last error
+    /// and sending date.
+    fn update_notification(&self, notification: &Notification) -> Result<(), DatabaseError>;
+
+    /// Audit log entries matching every given filter, oldest first.
+    fn get_audit_entries(
+        &self,
+        params: &AuditQueryParams,
+    ) -> Result<Vec<AuditEntry>, DatabaseError>;
+    fn insert_audit_entry(&self, entry: &NewAuditEntry) -> Result<(), DatabaseError>;
+}
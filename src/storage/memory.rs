@@ -0,0 +1,1646 @@
+use std::ops::{Deref, DerefMut};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::DateTime;
+use chrono_tz::Tz;
+use uuid::Uuid;
+
+use super::{CheckIn, NewAuditEntry, NewLoan, Storage};
+use crate::attribute::{AttributeDefinition, AttributeValue};
+use crate::audit::{AuditEntry, AuditQueryParams};
+use crate::database::{
+    AssetInfo, Category, Compatibility, ConditionReport, DatabaseError, Deposit, Depreciation,
+    Instance, Invoice, Kit, KitItem, LedgerEntry, Loan, LoanKitInstance, LoanQueryParams,
+    MaintenanceRule, MaintenanceWindow, MembershipPayment, MembershipType, Product, ProductPrice,
+    ProductRelation, QuoteLine, StatusChange, UsageReading, User,
+};
+use crate::invoice::InvoiceLine;
+use crate::notification::Notification;
+
+#[derive(Debug, Clone)]
+struct ProductRow {
+    uuid: Uuid,
+    name: String,
+    category: Uuid,
+}
+
+#[derive(Debug, Clone)]
+struct InstanceRow {
+    uuid: Uuid,
+    identifier: String,
+    product: Uuid,
+}
+
+#[derive(Debug, Clone)]
+struct LoanRow {
+    uuid: Uuid,
+    user: Uuid,
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+    accepted: bool,
+    rejected: bool,
+    description: Option<String>,
+    price: i64,
+}
+
+#[derive(Debug, Clone)]
+struct MaintenanceWindowRow {
+    uuid: Uuid,
+    instance: Uuid,
+    date_start: DateTime<Tz>,
+    date_end: DateTime<Tz>,
+    reason: String,
+}
+
+#[derive(Debug, Clone)]
+struct LoanInstanceRow {
+    loan: Uuid,
+    instance: Uuid,
+    date_returned: Option<DateTime<Tz>>,
+}
+
+/// Rows of a table. Changes are recorded until they are committed, so a
+/// failed write or transaction can undo them without a copy of the other
+/// tables. Appends only remember the old length. Any other change copies
+/// the rows of this table once, on the first change after a commit.
+#[derive(Debug)]
+struct Table<T> {
+    rows: Vec<T>,
+    undo: Undo<T>,
+}
+
+#[derive(Debug)]
+enum Undo<T> {
+    Unchanged,
+    /// Rows were only appended to the first `usize` rows
+    Appended(usize),
+    /// The rows before they were changed
+    Replaced(Vec<T>),
+}
+
+impl<T> Default for Table<T> {
+    fn default() -> Self {
+        Self {
+            rows: Vec::new(),
+            undo: Undo::Unchanged,
+        }
+    }
+}
+
+impl<T: Clone> Table<T> {
+    fn push(&mut self, row: T) {
+        if let Undo::Unchanged = self.undo {
+            self.undo = Undo::Appended(self.rows.len());
+        }
+        self.rows.push(row);
+    }
+
+    fn commit(&mut self) {
+        self.undo = Undo::Unchanged;
+    }
+
+    fn rollback(&mut self) {
+        match std::mem::replace(&mut self.undo, Undo::Unchanged) {
+            Undo::Unchanged => {}
+            Undo::Appended(len) => self.rows.truncate(len),
+            Undo::Replaced(rows) => self.rows = rows,
+        }
+    }
+}
+
+impl<T> Deref for Table<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.rows
+    }
+}
+
+impl<T: Clone> DerefMut for Table<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        match self.undo {
+            Undo::Replaced(_) => {}
+            Undo::Appended(len) => self.undo = Undo::Replaced(self.rows[..len].to_vec()),
+            Undo::Unchanged => self.undo = Undo::Replaced(self.rows.clone()),
+        }
+        &mut self.rows
+    }
+}
+
+/// Declares `Tables` along with committing and rolling back all of them.
+macro_rules! tables {
+    ($($(#[$doc:meta])* $name:ident: $row:ty,)*) => {
+        /// Tables of the schema, kept in insertion order like SQLite returns them.
+        #[derive(Debug, Default)]
+        struct Tables {
+            $($(#[$doc])* $name: Table<$row>,)*
+        }
+
+        impl Tables {
+            fn commit(&mut self) {
+                $(self.$name.commit();)*
+            }
+
+            fn rollback(&mut self) {
+                $(self.$name.rollback();)*
+            }
+        }
+    };
+}
+
+tables! {
+    users: User,
+    categories: Category,
+    products: ProductRow,
+    instances: InstanceRow,
+    loans: LoanRow,
+    loan_instances: LoanInstanceRow,
+    /// (loan, line)
+    loan_prices: (Uuid, QuoteLine),
+    product_prices: ProductPrice,
+    attributes: AttributeDefinition,
+    attribute_values: AttributeValue,
+    product_relations: ProductRelation,
+    kits: Kit,
+    loan_kit_instances: LoanKitInstance,
+    membership_types: MembershipType,
+    membership_payments: MembershipPayment,
+    /// (category, daily rate)
+    late_fee_rates: (Uuid, i64),
+    ledger: LedgerEntry,
+    invoices: Invoice,
+    /// (invoice, line)
+    invoice_lines: (i64, InvoiceLine),
+    /// (product, amount)
+    product_deposits: (Uuid, i64),
+    /// (category, amount)
+    category_deposits: (Uuid, i64),
+    deposits: Deposit,
+    condition_reports: ConditionReport,
+    maintenance_windows: MaintenanceWindowRow,
+    maintenance_rules: MaintenanceRule,
+    usage_readings: UsageReading,
+    status_changes: StatusChange,
+    assets: AssetInfo,
+    /// (instance, location)
+    instance_locations: (Uuid, String),
+    /// (category, depreciation)
+    depreciations: (Uuid, Depreciation),
+    /// (user, email)
+    user_emails: (Uuid, String),
+    notifications: Notification,
+    audit_log: AuditEntry,
+}
+
+impl Tables {
+    fn category(&self, uuid: Uuid) -> Option<&Category> {
+        self.categories.iter().find(|c| c.uuid == uuid)
+    }
+
+    fn product(&self, row: &ProductRow) -> Product {
+        Product {
+            uuid: row.uuid,
+            name: row.name.clone(),
+            category: self.category(row.category).unwrap().clone(),
+        }
+    }
+
+    fn instance(&self, row: &InstanceRow) -> Instance {
+        let product = self
+            .products
+            .iter()
+            .find(|p| p.uuid == row.product)
+            .unwrap();
+        Instance {
+            uuid: row.uuid,
+            identifier: row.identifier.clone(),
+            product: self.product(product),
+        }
+    }
+}
+
+fn not_found(what: &str) -> DatabaseError {
+    DatabaseError::NotFound(format!("{} not found.", what))
+}
+
+fn already_exists(what: &str) -> DatabaseError {
+    DatabaseError::AlreadyExists(format!("{} already exists.", what))
+}
+
+fn in_use(what: &str) -> DatabaseError {
+    DatabaseError::InUse(format!("{} is still referenced.", what))
+}
+
+/// Storage that keeps everything in memory, for tests and embedding.
+///
+/// Constraints of the SQL schema are checked by hand so it behaves like
+/// `SqliteStorage`.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    tables: Mutex<Tables>,
+    /// Set on the storage a transaction runs on. Its writes are committed or
+    /// rolled back together when the transaction ends.
+    in_transaction: bool,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Tables) -> T) -> Result<T, DatabaseError> {
+        let tables = self.tables.lock().unwrap();
+        Ok(f(&tables))
+    }
+
+    /// Runs `f` on the tables. Outside a transaction a failed write is
+    /// undone right away, like a single statement in SQL.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&mut Tables) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        let mut tables = self.tables.lock().unwrap();
+        let result = f(&mut tables);
+        if !self.in_transaction {
+            match result {
+                Ok(_) => tables.commit(),
+                Err(_) => tables.rollback(),
+            }
+        }
+        result
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        if self.in_transaction {
+            return f(self);
+        }
+
+        // Holding the lock serializes transactions. The tables are lent to
+        // the storage of the transaction and undone if `f` fails. A panic in
+        // `f` is caught so that the tables are put back before it goes on.
+        let mut tables = self.tables.lock().unwrap();
+        let storage = MemoryStorage {
+            tables: Mutex::new(std::mem::take(&mut *tables)),
+            in_transaction: true,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&storage)));
+        *tables = storage
+            .tables
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(Ok(())) => {
+                tables.commit();
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                tables.rollback();
+                Err(e)
+            }
+            Err(payload) => {
+                tables.rollback();
+                // Unlocked first, so that the panic doesn't poison the lock
+                drop(tables);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    fn backup_to(&self, _path: &Path) -> Result<(), DatabaseError> {
+        Err(DatabaseError::Invalid(
+            "In-memory storage has no file to back up, export a snapshot instead.".to_string(),
+        ))
+    }
+
+    fn get_users(&self) -> Result<Vec<User>, DatabaseError> {
+        self.read(|t| t.users.clone())
+    }
+
+    fn get_user(&self, uuid: Uuid) -> Result<Option<User>, DatabaseError> {
+        self.read(|t| t.users.iter().find(|u| u.uuid == uuid).cloned())
+    }
+
+    fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DatabaseError> {
+        self.read(|t| t.users.iter().find(|u| u.name == name).cloned())
+    }
+
+    fn insert_user(&self, uuid: Uuid, name: &str) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.users.iter().any(|u| u.uuid == uuid) {
+                return Err(already_exists("User"));
+            }
+            t.users.push(User {
+                uuid,
+                name: name.to_string(),
+            });
+            Ok(())
+        })
+    }
+
+    fn delete_user(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == uuid) {
+                return Err(not_found("User"));
+            }
+            if t.loans.iter().any(|l| l.user == uuid)
+                || t.ledger.iter().any(|e| e.user == uuid)
+                || t.membership_payments.iter().any(|p| p.user == uuid)
+            {
+                return Err(in_use("User"));
+            }
+            t.users.retain(|u| u.uuid != uuid);
+            t.user_emails.retain(|(user, _)| *user != uuid);
+            t.notifications.retain(|n| n.user != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_user_email(&self, user: Uuid) -> Result<Option<String>, DatabaseError> {
+        self.read(|t| {
+            t.user_emails
+                .iter()
+                .find(|(u, _)| *u == user)
+                .map(|(_, email)| email.clone())
+        })
+    }
+
+    fn set_user_email(&self, user: Uuid, email: &str) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == user) {
+                return Err(not_found("User"));
+            }
+            t.user_emails.retain(|(u, _)| *u != user);
+            t.user_emails.push((user, email.to_string()));
+            Ok(())
+        })
+    }
+
+    fn get_categories(&self, supercategory: Option<Uuid>) -> Result<Vec<Category>, DatabaseError> {
+        self.read(|t| {
+            t.categories
+                .iter()
+                .filter(|c| supercategory.is_none() || c.supercategory == supercategory)
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn get_category(&self, name: &str) -> Result<Option<Category>, DatabaseError> {
+        self.read(|t| t.categories.iter().find(|c| c.name == name).cloned())
+    }
+
+    fn insert_category(
+        &self,
+        uuid: Uuid,
+        name: &str,
+        supercategory: Option<Uuid>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if let Some(supercategory) = supercategory {
+                if t.category(supercategory).is_none() {
+                    return Err(not_found("Supercategory"));
+                }
+            }
+            if t.categories
+                .iter()
+                .any(|c| c.uuid == uuid || c.name == name)
+            {
+                return Err(already_exists("Category"));
+            }
+            t.categories.push(Category {
+                uuid,
+                name: name.to_string(),
+                supercategory,
+            });
+            Ok(())
+        })
+    }
+
+    fn delete_category(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(uuid).is_none() {
+                return Err(not_found("Category"));
+            }
+            if t.categories.iter().any(|c| c.supercategory == Some(uuid))
+                || t.products.iter().any(|p| p.category == uuid)
+            {
+                return Err(in_use("Category"));
+            }
+            t.categories.retain(|c| c.uuid != uuid);
+            t.late_fee_rates.retain(|(category, _)| *category != uuid);
+            t.category_deposits
+                .retain(|(category, _)| *category != uuid);
+            t.depreciations.retain(|(category, _)| *category != uuid);
+            let attributes: Vec<Uuid> = t
+                .attributes
+                .iter()
+                .filter(|a| a.category == uuid)
+                .map(|a| a.uuid)
+                .collect();
+            t.attribute_values
+                .retain(|v| !attributes.contains(&v.attribute));
+            t.attributes.retain(|a| a.category != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_late_fee_rate(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        self.read(|t| {
+            t.late_fee_rates
+                .iter()
+                .find(|(c, _)| *c == category)
+                .map(|(_, rate)| *rate)
+        })
+    }
+
+    fn set_late_fee_rate(&self, category: Uuid, daily_rate: i64) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(category).is_none() {
+                return Err(not_found("Category"));
+            }
+            if daily_rate < 0 {
+                return Err(DatabaseError::Invalid(
+                    "Late fee can't be negative.".to_string(),
+                ));
+            }
+            t.late_fee_rates.retain(|(c, _)| *c != category);
+            t.late_fee_rates.push((category, daily_rate));
+            Ok(())
+        })
+    }
+
+    fn get_products(&self, category: Option<Uuid>) -> Result<Vec<Product>, DatabaseError> {
+        self.read(|t| {
+            t.products
+                .iter()
+                .filter(|p| category.is_none() || Some(p.category) == category)
+                .map(|p| t.product(p))
+                .collect()
+        })
+    }
+
+    fn get_product(&self, uuid: Uuid) -> Result<Option<Product>, DatabaseError> {
+        self.read(|t| {
+            t.products
+                .iter()
+                .find(|p| p.uuid == uuid)
+                .map(|p| t.product(p))
+        })
+    }
+
+    fn get_product_by_name(&self, name: &str) -> Result<Option<Product>, DatabaseError> {
+        self.read(|t| {
+            t.products
+                .iter()
+                .find(|p| p.name == name)
+                .map(|p| t.product(p))
+        })
+    }
+
+    fn insert_product(&self, uuid: Uuid, name: &str, category: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(category).is_none() {
+                return Err(not_found("Category"));
+            }
+            if t.products.iter().any(|p| p.uuid == uuid || p.name == name) {
+                return Err(already_exists("Product"));
+            }
+            t.products.push(ProductRow {
+                uuid,
+                name: name.to_string(),
+                category,
+            });
+            Ok(())
+        })
+    }
+
+    fn delete_product(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == uuid) {
+                return Err(not_found("Product"));
+            }
+            let loaned = t.instances.iter().any(|i| {
+                i.product == uuid && t.loan_instances.iter().any(|li| li.instance == i.uuid)
+            });
+            if loaned {
+                return Err(in_use("Product"));
+            }
+            let instances: Vec<Uuid> = t
+                .instances
+                .iter()
+                .filter(|i| i.product == uuid)
+                .map(|i| i.uuid)
+                .collect();
+            let in_kit = t
+                .kits
+                .iter()
+                .flat_map(|k| k.items.iter())
+                .any(|item| match item {
+                    KitItem::Product { product, .. } => *product == uuid,
+                    KitItem::Instance { instance } => instances.contains(instance),
+                });
+            if in_kit {
+                return Err(in_use("Product"));
+            }
+            t.condition_reports
+                .retain(|r| !instances.contains(&r.instance));
+            t.maintenance_windows
+                .retain(|w| !instances.contains(&w.instance));
+            t.usage_readings
+                .retain(|r| !instances.contains(&r.instance));
+            t.status_changes
+                .retain(|c| !instances.contains(&c.instance));
+            t.assets.retain(|a| !instances.contains(&a.instance));
+            t.instance_locations
+                .retain(|(instance, _)| !instances.contains(instance));
+            t.instances.retain(|i| i.product != uuid);
+            t.products.retain(|p| p.uuid != uuid);
+            t.product_prices.retain(|p| p.product != uuid);
+            t.maintenance_rules.retain(|r| r.product != uuid);
+            t.attribute_values.retain(|v| v.product != uuid);
+            t.product_relations
+                .retain(|r| r.product != uuid && r.related != uuid);
+            t.product_deposits.retain(|(product, _)| *product != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_attributes(
+        &self,
+        category: Option<Uuid>,
+    ) -> Result<Vec<AttributeDefinition>, DatabaseError> {
+        self.read(|t| {
+            t.attributes
+                .iter()
+                .filter(|a| category.is_none_or(|category| a.category == category))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_attribute(&self, attribute: &AttributeDefinition) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(attribute.category).is_none() {
+                return Err(not_found("Category"));
+            }
+            if t.attributes.iter().any(|a| {
+                a.uuid == attribute.uuid
+                    || (a.category == attribute.category && a.name == attribute.name)
+            }) {
+                return Err(already_exists("Attribute"));
+            }
+            t.attributes.push(attribute.clone());
+            Ok(())
+        })
+    }
+
+    fn delete_attribute(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.attributes.iter().any(|a| a.uuid == uuid) {
+                return Err(not_found("Attribute"));
+            }
+            t.attributes.retain(|a| a.uuid != uuid);
+            t.attribute_values.retain(|v| v.attribute != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_attribute_values(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<AttributeValue>, DatabaseError> {
+        self.read(|t| {
+            t.attribute_values
+                .iter()
+                .filter(|v| product.is_none_or(|product| v.product == product))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn set_attribute_values(
+        &self,
+        product: Uuid,
+        values: &[AttributeValue],
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == product) {
+                return Err(not_found("Product"));
+            }
+            for value in values.iter() {
+                if !t.attributes.iter().any(|a| a.uuid == value.attribute) {
+                    return Err(not_found("Attribute"));
+                }
+            }
+            t.attribute_values.retain(|v| v.product != product);
+            t.attribute_values
+                .extend(values.iter().map(|value| AttributeValue {
+                    product,
+                    ..value.clone()
+                }));
+            Ok(())
+        })
+    }
+
+    fn get_product_relations(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<ProductRelation>, DatabaseError> {
+        self.read(|t| {
+            t.product_relations
+                .iter()
+                .filter(|r| product.is_none_or(|p| r.product == p || r.related == p))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            for product in [relation.product, relation.related] {
+                if !t.products.iter().any(|p| p.uuid == product) {
+                    return Err(not_found("Product"));
+                }
+            }
+            if relation.product == relation.related {
+                return Err(DatabaseError::Invalid(
+                    "Products can't be related to themselves.".to_string(),
+                ));
+            }
+            if t.product_relations.contains(relation) {
+                return Err(already_exists("Product relation"));
+            }
+            t.product_relations.push(relation.clone());
+            Ok(())
+        })
+    }
+
+    fn delete_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.product_relations.contains(relation) {
+                return Err(not_found("Product relation"));
+            }
+            t.product_relations.retain(|r| r != relation);
+            Ok(())
+        })
+    }
+
+    fn get_kits(&self) -> Result<Vec<Kit>, DatabaseError> {
+        self.read(|t| {
+            let mut kits = t.kits.clone();
+            kits.sort_by(|a, b| a.name.cmp(&b.name));
+            kits
+        })
+    }
+
+    fn insert_kit(&self, kit: &Kit) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            for item in kit.items.iter() {
+                match item {
+                    KitItem::Product { product, quantity } => {
+                        if !t.products.iter().any(|p| p.uuid == *product) {
+                            return Err(not_found("Product"));
+                        }
+                        if *quantity < 1 {
+                            return Err(DatabaseError::Invalid(
+                                "Quantity must be positive.".to_string(),
+                            ));
+                        }
+                    }
+                    KitItem::Instance { instance } => {
+                        if !t.instances.iter().any(|i| i.uuid == *instance) {
+                            return Err(not_found("Instance"));
+                        }
+                    }
+                }
+            }
+            if t.kits
+                .iter()
+                .any(|k| k.uuid == kit.uuid || k.name == kit.name)
+            {
+                return Err(already_exists("Kit"));
+            }
+            t.kits.push(kit.clone());
+            Ok(())
+        })
+    }
+
+    fn delete_kit(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.kits.iter().any(|k| k.uuid == uuid) {
+                return Err(not_found("Kit"));
+            }
+            if t.loan_kit_instances.iter().any(|k| k.kit == uuid) {
+                return Err(in_use("Kit"));
+            }
+            t.kits.retain(|k| k.uuid != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_product_price(&self, product: Uuid) -> Result<Option<ProductPrice>, DatabaseError> {
+        self.read(|t| {
+            t.product_prices
+                .iter()
+                .find(|p| p.product == product)
+                .cloned()
+        })
+    }
+
+    fn set_product_price(&self, price: &ProductPrice) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == price.product) {
+                return Err(not_found("Product"));
+            }
+            let prices = [Some(price.per_day), price.per_weekend, price.per_week];
+            if prices.iter().flatten().any(|price| *price < 0) {
+                return Err(DatabaseError::Invalid(
+                    "Price can't be negative.".to_string(),
+                ));
+            }
+            t.product_prices.retain(|p| p.product != price.product);
+            t.product_prices.push(price.clone());
+            Ok(())
+        })
+    }
+
+    fn get_product_deposit(&self, product: Uuid) -> Result<Option<i64>, DatabaseError> {
+        self.read(|t| {
+            t.product_deposits
+                .iter()
+                .find(|(p, _)| *p == product)
+                .map(|(_, amount)| *amount)
+        })
+    }
+
+    fn set_product_deposit(&self, product: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == product) {
+                return Err(not_found("Product"));
+            }
+            if amount < 0 {
+                return Err(DatabaseError::Invalid(
+                    "Deposit can't be negative.".to_string(),
+                ));
+            }
+            t.product_deposits.retain(|(p, _)| *p != product);
+            t.product_deposits.push((product, amount));
+            Ok(())
+        })
+    }
+
+    fn get_category_deposit(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        self.read(|t| {
+            t.category_deposits
+                .iter()
+                .find(|(c, _)| *c == category)
+                .map(|(_, amount)| *amount)
+        })
+    }
+
+    fn set_category_deposit(&self, category: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(category).is_none() {
+                return Err(not_found("Category"));
+            }
+            if amount < 0 {
+                return Err(DatabaseError::Invalid(
+                    "Deposit can't be negative.".to_string(),
+                ));
+            }
+            t.category_deposits.retain(|(c, _)| *c != category);
+            t.category_deposits.push((category, amount));
+            Ok(())
+        })
+    }
+
+    fn get_instances(&self, product: Option<Uuid>) -> Result<Vec<Instance>, DatabaseError> {
+        self.read(|t| {
+            t.instances
+                .iter()
+                .filter(|i| product.is_none() || Some(i.product) == product)
+                .map(|i| t.instance(i))
+                .collect()
+        })
+    }
+
+    fn get_instance(&self, uuid: Uuid) -> Result<Option<Instance>, DatabaseError> {
+        self.read(|t| {
+            t.instances
+                .iter()
+                .find(|i| i.uuid == uuid)
+                .map(|i| t.instance(i))
+        })
+    }
+
+    fn insert_instance(
+        &self,
+        uuid: Uuid,
+        identifier: &str,
+        product: Uuid,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == product) {
+                return Err(not_found("Product"));
+            }
+            if t.instances
+                .iter()
+                .any(|i| i.uuid == uuid || (i.product == product && i.identifier == identifier))
+            {
+                return Err(already_exists("Instance"));
+            }
+            t.instances.push(InstanceRow {
+                uuid,
+                identifier: identifier.to_string(),
+                product,
+            });
+            Ok(())
+        })
+    }
+
+    fn get_loans(&self, params: &LoanQueryParams) -> Result<Vec<Loan>, DatabaseError> {
+        self.read(|t| {
+            let mut loans: Vec<Loan> = Vec::new();
+            for loan_instance in t.loan_instances.iter() {
+                let loan = t
+                    .loans
+                    .iter()
+                    .find(|l| l.uuid == loan_instance.loan)
+                    .unwrap();
+                let instance_row = t
+                    .instances
+                    .iter()
+                    .find(|i| i.uuid == loan_instance.instance)
+                    .unwrap();
+                let instance = t.instance(instance_row);
+
+                let matches = (params.include_rejected || !loan.rejected)
+                    && params.loan_uuid.is_none_or(|id| id == loan.uuid)
+                    && params.instance_uuid.is_none_or(|id| id == instance.uuid)
+                    && params.loan_accepted.is_none_or(|a| a == loan.accepted)
+                    && params.user_uuid.is_none_or(|id| id == loan.user)
+                    && params
+                        .product_uuid
+                        .is_none_or(|id| id == instance.product.uuid)
+                    && params
+                        .category_uuid
+                        .is_none_or(|id| id == instance.product.category.uuid)
+                    && params.date_start.is_none_or(|start| loan.date_end >= start)
+                    && params.date_end.is_none_or(|end| loan.date_start <= end)
+                    && params.due_after.is_none_or(|after| loan.date_end >= after)
+                    && params
+                        .due_before
+                        .is_none_or(|before| loan.date_end < before)
+                    && params
+                        .returned
+                        .is_none_or(|r| r == loan_instance.date_returned.is_some());
+                if !matches {
+                    continue;
+                }
+
+                // Combine rows of the same loan
+                match loans.iter_mut().find(|l| l.uuid == loan.uuid) {
+                    Some(existing) => existing.instaces.push(instance),
+                    None => loans.push(Loan {
+                        uuid: loan.uuid,
+                        user: t
+                            .users
+                            .iter()
+                            .find(|u| u.uuid == loan.user)
+                            .unwrap()
+                            .clone(),
+                        date_start: loan.date_start,
+                        date_end: loan.date_end,
+                        accepted: loan.accepted,
+                        rejected: loan.rejected,
+                        description: loan.description.clone(),
+                        instaces: vec![instance],
+                        price: loan.price,
+                        compatibility: Compatibility::default(),
+                        kits: Vec::new(),
+                    }),
+                }
+            }
+            loans
+        })
+    }
+
+    fn insert_loan(&self, loan: &NewLoan) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == loan.user) {
+                return Err(not_found("User"));
+            }
+            if t.loans.iter().any(|l| l.uuid == loan.uuid) {
+                return Err(already_exists("Loan"));
+            }
+            if loan.date_start >= loan.date_end {
+                return Err(DatabaseError::Invalid(
+                    "Loan must end after it starts.".to_string(),
+                ));
+            }
+            for (i, instance) in loan.instances.iter().enumerate() {
+                if !t.instances.iter().any(|row| row.uuid == *instance) {
+                    return Err(not_found("Instance"));
+                }
+                if loan.instances[..i].contains(instance) {
+                    return Err(already_exists("Loan instance"));
+                }
+            }
+
+            t.loans.push(LoanRow {
+                uuid: loan.uuid,
+                user: loan.user,
+                date_start: loan.date_start,
+                date_end: loan.date_end,
+                accepted: loan.accepted,
+                rejected: loan.rejected,
+                description: loan.description.clone(),
+                price: loan.price,
+            });
+            for instance in loan.instances.iter() {
+                t.loan_instances.push(LoanInstanceRow {
+                    loan: loan.uuid,
+                    instance: *instance,
+                    date_returned: None,
+                });
+            }
+            for line in loan.price_lines.iter() {
+                if !loan.instances.contains(&line.instance) {
+                    return Err(not_found("Loan instance"));
+                }
+                if t.loan_prices
+                    .iter()
+                    .any(|(l, p)| *l == loan.uuid && p.instance == line.instance)
+                {
+                    return Err(already_exists("Loan price"));
+                }
+                t.loan_prices.push((loan.uuid, line.clone()));
+            }
+            Ok(())
+        })
+    }
+
+    fn set_loan_accepted(&self, loan: Uuid, accepted: bool) -> Result<(), DatabaseError> {
+        self.write(|t| match t.loans.iter_mut().find(|l| l.uuid == loan) {
+            Some(row) => {
+                row.accepted = accepted;
+                Ok(())
+            }
+            None => Err(not_found("Loan")),
+        })
+    }
+
+    fn set_loan_rejected(&self, loan: Uuid, rejected: bool) -> Result<(), DatabaseError> {
+        self.write(|t| match t.loans.iter_mut().find(|l| l.uuid == loan) {
+            Some(row) => {
+                row.rejected = rejected;
+                Ok(())
+            }
+            None => Err(not_found("Loan")),
+        })
+    }
+
+    fn get_loan_price_lines(&self, loan: Uuid) -> Result<Vec<QuoteLine>, DatabaseError> {
+        self.read(|t| {
+            t.loan_prices
+                .iter()
+                .filter(|(l, _)| *l == loan)
+                .map(|(_, line)| line.clone())
+                .collect()
+        })
+    }
+
+    fn get_loan_kit_instances(
+        &self,
+        loan: Option<Uuid>,
+    ) -> Result<Vec<LoanKitInstance>, DatabaseError> {
+        self.read(|t| {
+            t.loan_kit_instances
+                .iter()
+                .filter(|k| loan.is_none_or(|loan| k.loan == loan))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_loan_kit_instance(&self, row: &LoanKitInstance) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t
+                .loan_instances
+                .iter()
+                .any(|li| li.loan == row.loan && li.instance == row.instance)
+            {
+                return Err(not_found("Loan instance"));
+            }
+            if !t.kits.iter().any(|k| k.uuid == row.kit) {
+                return Err(not_found("Kit"));
+            }
+            if t.loan_kit_instances
+                .iter()
+                .any(|k| k.loan == row.loan && k.instance == row.instance)
+            {
+                return Err(already_exists("Loan instance"));
+            }
+            t.loan_kit_instances.push(row.clone());
+            Ok(())
+        })
+    }
+
+    fn check_in(
+        &self,
+        loan: Uuid,
+        instance: Uuid,
+        date: DateTime<Tz>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            let row = t.loan_instances.iter_mut().find(|li| {
+                li.loan == loan && li.instance == instance && li.date_returned.is_none()
+            });
+            match row {
+                Some(row) => {
+                    row.date_returned = Some(date);
+                    Ok(())
+                }
+                None => Err(not_found("Loaned instance")),
+            }
+        })
+    }
+
+    fn get_check_ins(&self) -> Result<Vec<CheckIn>, DatabaseError> {
+        self.read(|t| {
+            t.loan_instances
+                .iter()
+                .filter_map(|li| {
+                    li.date_returned.map(|date| CheckIn {
+                        loan: li.loan,
+                        instance: li.instance,
+                        date,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    fn get_membership_types(&self) -> Result<Vec<MembershipType>, DatabaseError> {
+        self.read(|t| t.membership_types.clone())
+    }
+
+    fn insert_membership_type(
+        &self,
+        membership_type: &MembershipType,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.membership_types
+                .iter()
+                .any(|m| m.uuid == membership_type.uuid)
+            {
+                return Err(already_exists("Membership type"));
+            }
+            if !(0..=100).contains(&membership_type.rental_discount) {
+                return Err(DatabaseError::Invalid(
+                    "Discount must be between 0 and 100 percent.".to_string(),
+                ));
+            }
+            t.membership_types.push(membership_type.clone());
+            Ok(())
+        })
+    }
+
+    fn get_membership_payments(
+        &self,
+        user: Option<Uuid>,
+    ) -> Result<Vec<MembershipPayment>, DatabaseError> {
+        self.read(|t| {
+            t.membership_payments
+                .iter()
+                .filter(|p| user.is_none_or(|user| p.user == user))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_membership_payment(&self, payment: &MembershipPayment) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == payment.user) {
+                return Err(not_found("User"));
+            }
+            if !t
+                .membership_types
+                .iter()
+                .any(|m| m.uuid == payment.membership_type)
+            {
+                return Err(not_found("Membership type"));
+            }
+            if t.membership_payments.iter().any(|p| p.uuid == payment.uuid) {
+                return Err(already_exists("Membership payment"));
+            }
+            t.membership_payments.push(payment.clone());
+            Ok(())
+        })
+    }
+
+    fn get_ledger_entries(&self, user: Option<Uuid>) -> Result<Vec<LedgerEntry>, DatabaseError> {
+        self.read(|t| {
+            t.ledger
+                .iter()
+                .filter(|e| user.is_none_or(|user| e.user == user))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == entry.user) {
+                return Err(not_found("User"));
+            }
+            if let Some(loan) = entry.loan {
+                if !t.loans.iter().any(|l| l.uuid == loan) {
+                    return Err(not_found("Loan"));
+                }
+            }
+            if t.ledger.iter().any(|e| e.uuid == entry.uuid) {
+                return Err(already_exists("Ledger entry"));
+            }
+            t.ledger.push(entry.clone());
+            Ok(())
+        })
+    }
+
+    fn get_invoices(&self, loan: Option<Uuid>) -> Result<Vec<Invoice>, DatabaseError> {
+        self.read(|t| {
+            t.invoices
+                .iter()
+                .filter(|i| loan.is_none_or(|loan| i.loan == loan))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_invoice(
+        &self,
+        invoice: &Invoice,
+        lines: &[InvoiceLine],
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.loans.iter().any(|l| l.uuid == invoice.loan) {
+                return Err(not_found("Loan"));
+            }
+            if t.invoices.iter().any(|i| i.number == invoice.number) {
+                return Err(already_exists("Invoice"));
+            }
+            t.invoices.push(invoice.clone());
+            for line in lines.iter() {
+                t.invoice_lines.push((invoice.number, line.clone()));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_invoice_lines(&self, invoice: i64) -> Result<Vec<InvoiceLine>, DatabaseError> {
+        self.read(|t| {
+            t.invoice_lines
+                .iter()
+                .filter(|(number, _)| *number == invoice)
+                .map(|(_, line)| line.clone())
+                .collect()
+        })
+    }
+
+    fn get_deposits(&self, loan: Option<Uuid>) -> Result<Vec<Deposit>, DatabaseError> {
+        self.read(|t| {
+            t.deposits
+                .iter()
+                .filter(|d| loan.is_none_or(|loan| d.loan == loan))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.loans.iter().any(|l| l.uuid == deposit.loan) {
+                return Err(not_found("Loan"));
+            }
+            if t.deposits.iter().any(|d| d.loan == deposit.loan) {
+                return Err(already_exists("Deposit"));
+            }
+            if deposit.amount <= 0 {
+                return Err(DatabaseError::Invalid(
+                    "Deposit must be positive.".to_string(),
+                ));
+            }
+            t.deposits.push(deposit.clone());
+            Ok(())
+        })
+    }
+
+    fn update_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        self.write(
+            |t| match t.deposits.iter_mut().find(|d| d.loan == deposit.loan) {
+                Some(row) => {
+                    row.status = deposit.status;
+                    row.date_settled = deposit.date_settled;
+                    row.reason = deposit.reason.clone();
+                    Ok(())
+                }
+                None => Err(not_found("Deposit")),
+            },
+        )
+    }
+
+    fn get_condition_reports(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<ConditionReport>, DatabaseError> {
+        self.read(|t| {
+            let mut reports: Vec<ConditionReport> = t
+                .condition_reports
+                .iter()
+                .filter(|r| instance.is_none_or(|instance| r.instance == instance))
+                .cloned()
+                .collect();
+            reports.sort_by_key(|r| r.date);
+            reports
+        })
+    }
+
+    fn insert_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == report.instance) {
+                return Err(not_found("Instance"));
+            }
+            if let Some(loan) = report.loan {
+                if !t.loans.iter().any(|l| l.uuid == loan) {
+                    return Err(not_found("Loan"));
+                }
+            }
+            if t.condition_reports.iter().any(|r| r.uuid == report.uuid) {
+                return Err(already_exists("Condition report"));
+            }
+            t.condition_reports.push(report.clone());
+            Ok(())
+        })
+    }
+
+    fn update_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            match t
+                .condition_reports
+                .iter_mut()
+                .find(|r| r.uuid == report.uuid)
+            {
+                Some(row) => {
+                    row.date_resolved = report.date_resolved;
+                    row.resolution = report.resolution.clone();
+                    Ok(())
+                }
+                None => Err(not_found("Condition report")),
+            }
+        })
+    }
+
+    fn get_maintenance_windows(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<MaintenanceWindow>, DatabaseError> {
+        self.read(|t| {
+            t.maintenance_windows
+                .iter()
+                .filter(|w| instance.is_none_or(|instance| w.instance == instance))
+                .map(|w| MaintenanceWindow {
+                    uuid: w.uuid,
+                    instance: t
+                        .instance(t.instances.iter().find(|i| i.uuid == w.instance).unwrap()),
+                    date_start: w.date_start,
+                    date_end: w.date_end,
+                    reason: w.reason.clone(),
+                })
+                .collect()
+        })
+    }
+
+    fn insert_maintenance_window(
+        &self,
+        uuid: Uuid,
+        instance: Uuid,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        reason: &str,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == instance) {
+                return Err(not_found("Instance"));
+            }
+            if t.maintenance_windows.iter().any(|w| w.uuid == uuid) {
+                return Err(already_exists("Maintenance window"));
+            }
+            if date_start >= date_end {
+                return Err(DatabaseError::Invalid(
+                    "Maintenance must end after it starts.".to_string(),
+                ));
+            }
+            t.maintenance_windows.push(MaintenanceWindowRow {
+                uuid,
+                instance,
+                date_start,
+                date_end,
+                reason: reason.to_string(),
+            });
+            Ok(())
+        })
+    }
+
+    fn delete_maintenance_window(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.maintenance_windows.iter().any(|w| w.uuid == uuid) {
+                return Err(not_found("Maintenance window"));
+            }
+            t.maintenance_windows.retain(|w| w.uuid != uuid);
+            Ok(())
+        })
+    }
+
+    fn get_status_changes(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<StatusChange>, DatabaseError> {
+        self.read(|t| {
+            let mut changes: Vec<StatusChange> = t
+                .status_changes
+                .iter()
+                .filter(|c| instance.is_none_or(|instance| c.instance == instance))
+                .cloned()
+                .collect();
+            changes.sort_by_key(|c| c.date);
+            changes
+        })
+    }
+
+    fn insert_status_change(&self, change: &StatusChange) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == change.instance) {
+                return Err(not_found("Instance"));
+            }
+            if let Some(loan) = change.loan {
+                if !t.loans.iter().any(|l| l.uuid == loan) {
+                    return Err(not_found("Loan"));
+                }
+            }
+            if t.status_changes.iter().any(|c| c.uuid == change.uuid) {
+                return Err(already_exists("Status change"));
+            }
+            t.status_changes.push(change.clone());
+            Ok(())
+        })
+    }
+
+    fn get_asset_info(&self, instance: Uuid) -> Result<Option<AssetInfo>, DatabaseError> {
+        self.read(|t| t.assets.iter().find(|a| a.instance == instance).cloned())
+    }
+
+    fn get_asset_infos(&self) -> Result<Vec<AssetInfo>, DatabaseError> {
+        self.read(|t| t.assets.clone())
+    }
+
+    fn set_asset_info(&self, asset: &AssetInfo) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == asset.instance) {
+                return Err(not_found("Instance"));
+            }
+            let duplicate = asset.serial_number.is_some()
+                && t.assets.iter().any(|a| {
+                    a.instance != asset.instance && a.serial_number == asset.serial_number
+                });
+            if duplicate {
+                return Err(already_exists("Serial number"));
+            }
+            let prices = [asset.purchase_price, asset.current_value];
+            if prices.iter().flatten().any(|price| *price < 0) {
+                return Err(DatabaseError::Invalid(
+                    "Price can't be negative.".to_string(),
+                ));
+            }
+            t.assets.retain(|a| a.instance != asset.instance);
+            t.assets.push(asset.clone());
+            Ok(())
+        })
+    }
+
+    fn get_instance_location(&self, instance: Uuid) -> Result<Option<String>, DatabaseError> {
+        self.read(|t| {
+            t.instance_locations
+                .iter()
+                .find(|(i, _)| *i == instance)
+                .map(|(_, location)| location.clone())
+        })
+    }
+
+    fn set_instance_location(
+        &self,
+        instance: Uuid,
+        location: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == instance) {
+                return Err(not_found("Instance"));
+            }
+            t.instance_locations.retain(|(i, _)| *i != instance);
+            if let Some(location) = location {
+                t.instance_locations.push((instance, location.to_string()));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_depreciation(&self, category: Uuid) -> Result<Option<Depreciation>, DatabaseError> {
+        self.read(|t| {
+            t.depreciations
+                .iter()
+                .find(|(c, _)| *c == category)
+                .map(|(_, depreciation)| *depreciation)
+        })
+    }
+
+    fn set_depreciation(
+        &self,
+        category: Uuid,
+        depreciation: Option<&Depreciation>,
+    ) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if t.category(category).is_none() {
+                return Err(not_found("Category"));
+            }
+            if depreciation.is_some_and(|d| !(1..=100).contains(&d.rate)) {
+                return Err(DatabaseError::Invalid(
+                    "Depreciation rate must be between 1 and 100 percent.".to_string(),
+                ));
+            }
+            t.depreciations.retain(|(c, _)| *c != category);
+            if let Some(depreciation) = depreciation {
+                t.depreciations.push((category, *depreciation));
+            }
+            Ok(())
+        })
+    }
+
+    fn get_maintenance_rule(
+        &self,
+        product: Uuid,
+    ) -> Result<Option<MaintenanceRule>, DatabaseError> {
+        self.read(|t| {
+            t.maintenance_rules
+                .iter()
+                .find(|r| r.product == product)
+                .cloned()
+        })
+    }
+
+    fn set_maintenance_rule(&self, rule: &MaintenanceRule) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.products.iter().any(|p| p.uuid == rule.product) {
+                return Err(not_found("Product"));
+            }
+            let limits = [rule.loans, rule.days_out, rule.months];
+            if limits.iter().all(Option::is_none) || limits.iter().flatten().any(|l| *l <= 0) {
+                return Err(DatabaseError::Invalid(
+                    "Maintenance rule needs a positive limit.".to_string(),
+                ));
+            }
+            t.maintenance_rules.retain(|r| r.product != rule.product);
+            t.maintenance_rules.push(rule.clone());
+            Ok(())
+        })
+    }
+
+    fn delete_maintenance_rule(&self, product: Uuid) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.maintenance_rules.iter().any(|r| r.product == product) {
+                return Err(not_found("Maintenance rule"));
+            }
+            t.maintenance_rules.retain(|r| r.product != product);
+            Ok(())
+        })
+    }
+
+    fn get_usage_readings(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<UsageReading>, DatabaseError> {
+        self.read(|t| {
+            let mut readings: Vec<UsageReading> = t
+                .usage_readings
+                .iter()
+                .filter(|r| instance.is_none_or(|instance| r.instance == instance))
+                .cloned()
+                .collect();
+            readings.sort_by_key(|r| r.date);
+            readings
+        })
+    }
+
+    fn insert_usage_reading(&self, reading: &UsageReading) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.instances.iter().any(|i| i.uuid == reading.instance) {
+                return Err(not_found("Instance"));
+            }
+            if t.usage_readings.iter().any(|r| r.uuid == reading.uuid) {
+                return Err(already_exists("Usage reading"));
+            }
+            if reading.value < 0 {
+                return Err(DatabaseError::Invalid(
+                    "Reading can't be negative.".to_string(),
+                ));
+            }
+            t.usage_readings.push(reading.clone());
+            Ok(())
+        })
+    }
+
+    fn get_notifications(&self, user: Option<Uuid>) -> Result<Vec<Notification>, DatabaseError> {
+        self.read(|t| {
+            let mut notifications: Vec<Notification> = t
+                .notifications
+                .iter()
+                .filter(|n| user.is_none_or(|user| n.user == user))
+                .cloned()
+                .collect();
+            notifications.sort_by_key(|n| n.created);
+            notifications
+        })
+    }
+
+    fn insert_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            if !t.users.iter().any(|u| u.uuid == notification.user) {
+                return Err(not_found("User"));
+            }
+            if t.notifications.iter().any(|n| n.uuid == notification.uuid) {
+                return Err(already_exists("Notification"));
+            }
+            t.notifications.push(notification.clone());
+            Ok(())
+        })
+    }
+
+    fn update_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            match t
+                .notifications
+                .iter_mut()
+                .find(|n| n.uuid == notification.uuid)
+            {
+                Some(row) => {
+                    row.status = notification.status;
+                    row.attempts = notification.attempts;
+                    row.next_attempt = notification.next_attempt;
+                    row.last_error = notification.last_error.clone();
+                    row.date_sent = notification.date_sent;
+                    Ok(())
+                }
+                None => Err(not_found("Notification")),
+            }
+        })
+    }
+
+    fn get_audit_entries(
+        &self,
+        params: &AuditQueryParams,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        self.read(|t| {
+            t.audit_log
+                .iter()
+                .filter(|e| params.entity_type.is_none_or(|t| e.entity_type == t))
+                .filter(|e| params.entity.is_none_or(|uuid| e.entity == uuid))
+                .filter(|e| params.actor.as_ref().is_none_or(|a| &e.actor == a))
+                .filter(|e| params.date_start.is_none_or(|date| e.date >= date))
+                .filter(|e| params.date_end.is_none_or(|date| e.date < date))
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn insert_audit_entry(&self, entry: &NewAuditEntry) -> Result<(), DatabaseError> {
+        self.write(|t| {
+            let id = t.audit_log.last().map_or(1, |e| e.id + 1);
+            t.audit_log.push(AuditEntry {
+                id,
+                date: entry.date,
+                actor: entry.actor.clone(),
+                operation: entry.operation.clone(),
+                entity_type: entry.entity_type,
+                entity: entry.entity,
+                before: entry.before.clone(),
+                after: entry.after.clone(),
+            });
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,2382 @@
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use chrono_tz::Europe::Helsinki;
+use chrono_tz::Tz;
+use postgres::error::SqlState;
+use postgres::types::ToSql;
+use postgres::{Client, NoTls, Row};
+use r2d2::PooledConnection;
+use r2d2_postgres::PostgresConnectionManager;
+use rand::Rng;
+use uuid::Uuid;
+
+use super::{CheckIn, NewAuditEntry, NewLoan, Storage};
+use crate::attribute::{AttributeDefinition, AttributeType, AttributeValue};
+use crate::audit::{AuditEntry, AuditQueryParams, EntityType};
+use crate::database::{
+    AssetInfo, Category, Compatibility, ConditionReport, DatabaseError, Deposit, DepositStatus,
+    Depreciation, DepreciationMethod, Instance, InstanceStatus, Invoice, Kit, KitItem, LedgerEntry,
+    LedgerEntryKind, Loan, LoanKitInstance, LoanQueryParams, MaintenanceRule, MaintenanceWindow,
+    MembershipPayment, MembershipType, Product, ProductPrice, ProductRelation, ProductRelationKind,
+    QuoteLine, Severity, StatusChange, UsageReading, User,
+};
+use crate::invoice::InvoiceLine;
+use crate::notification::{Notification, NotificationKind, NotificationStatus};
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+/// Transactions that fail because of a concurrent one are run this many
+/// times before the conflict is reported.
+const TRANSACTION_ATTEMPTS: u32 = 10;
+/// Longest random pause before the second attempt, doubled for every
+/// attempt after it, so that conflicting transactions drift apart.
+const RETRY_PAUSE: Duration = Duration::from_millis(5);
+
+impl From<postgres::Error> for DatabaseError {
+    /// Foreign key failures are reported as `NotFound`, which is right for
+    /// inserts. Deletes go through `map_delete_error` to get `InUse` instead.
+    fn from(error: postgres::Error) -> Self {
+        let message = error.to_string();
+        match error.code() {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION => {
+                DatabaseError::AlreadyExists(message)
+            }
+            Some(code) if *code == SqlState::FOREIGN_KEY_VIOLATION => {
+                DatabaseError::NotFound(message)
+            }
+            // Overlapping accepted loans, or a transaction that ran into a
+            // concurrent one
+            Some(code)
+                if *code == SqlState::EXCLUSION_VIOLATION
+                    || *code == SqlState::T_R_SERIALIZATION_FAILURE
+                    || *code == SqlState::T_R_DEADLOCK_DETECTED =>
+            {
+                DatabaseError::Conflict(message)
+            }
+            Some(code)
+                if *code == SqlState::CHECK_VIOLATION || *code == SqlState::NOT_NULL_VIOLATION =>
+            {
+                DatabaseError::Invalid(message)
+            }
+            _ => DatabaseError::Internal(message),
+        }
+    }
+}
+
+/// Foreign key failures on delete mean the row is still referenced.
+fn map_delete_error(error: postgres::Error) -> DatabaseError {
+    match DatabaseError::from(error) {
+        DatabaseError::NotFound(message) => DatabaseError::InUse(message),
+        e => e,
+    }
+}
+
+fn user_from_row(row: &Row, start: usize) -> Result<User, postgres::Error> {
+    Ok(User {
+        uuid: row.try_get(start)?,
+        name: row.try_get(start + 1)?,
+    })
+}
+
+fn category_from_row(row: &Row, start: usize) -> Result<Category, postgres::Error> {
+    Ok(Category {
+        uuid: row.try_get(start)?,
+        name: row.try_get(start + 1)?,
+        supercategory: row.try_get(start + 2)?,
+    })
+}
+
+fn product_from_row(row: &Row, start: usize) -> Result<Product, postgres::Error> {
+    Ok(Product {
+        uuid: row.try_get(start)?,
+        name: row.try_get(start + 1)?,
+        category: category_from_row(row, start + 2)?,
+    })
+}
+
+fn instance_from_row(row: &Row, start: usize) -> Result<Instance, postgres::Error> {
+    Ok(Instance {
+        uuid: row.try_get(start)?,
+        identifier: row.try_get(start + 1)?,
+        product: product_from_row(row, start + 2)?,
+    })
+}
+
+/// Connection of an open transaction, rolled back on drop unless committed.
+struct TransactionConnection {
+    connection: PooledConnection<Manager>,
+    open: bool,
+}
+
+impl Drop for TransactionConnection {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = self.connection.batch_execute("ROLLBACK");
+        }
+    }
+}
+
+fn ledger_entry_from_row(row: &Row, start: usize) -> Result<LedgerEntry, DatabaseError> {
+    let kind = row.try_get::<_, String>(start + 3)?;
+    let kind = LedgerEntryKind::parse(&kind)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown ledger entry kind {}", kind)))?;
+    Ok(LedgerEntry {
+        uuid: row.try_get(start)?,
+        user: row.try_get(start + 1)?,
+        date: row
+            .try_get::<_, DateTime<Utc>>(start + 2)?
+            .with_timezone(&Helsinki),
+        kind,
+        amount: row.try_get(start + 4)?,
+        loan: row.try_get(start + 5)?,
+        description: row.try_get(start + 6)?,
+    })
+}
+
+fn deposit_from_row(row: &Row, start: usize) -> Result<Deposit, DatabaseError> {
+    let status = row.try_get::<_, String>(start + 3)?;
+    let status = DepositStatus::parse(&status)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown deposit status {}", status)))?;
+    Ok(Deposit {
+        loan: row.try_get(start)?,
+        amount: row.try_get(start + 1)?,
+        date_received: row
+            .try_get::<_, DateTime<Utc>>(start + 2)?
+            .with_timezone(&Helsinki),
+        status,
+        date_settled: row
+            .try_get::<_, Option<DateTime<Utc>>>(start + 4)?
+            .map(|date| date.with_timezone(&Helsinki)),
+        reason: row.try_get(start + 5)?,
+    })
+}
+
+fn condition_report_from_row(row: &Row, start: usize) -> Result<ConditionReport, DatabaseError> {
+    let severity = row.try_get::<_, String>(start + 3)?;
+    let severity = Severity::parse(&severity)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown severity {}", severity)))?;
+    Ok(ConditionReport {
+        uuid: row.try_get(start)?,
+        instance: row.try_get(start + 1)?,
+        loan: row.try_get(start + 2)?,
+        severity,
+        description: row.try_get(start + 4)?,
+        reporter: row.try_get(start + 5)?,
+        date: row
+            .try_get::<_, DateTime<Utc>>(start + 6)?
+            .with_timezone(&Helsinki),
+        out_of_service: row.try_get(start + 7)?,
+        date_resolved: row
+            .try_get::<_, Option<DateTime<Utc>>>(start + 8)?
+            .map(|date| date.with_timezone(&Helsinki)),
+        resolution: row.try_get(start + 9)?,
+    })
+}
+
+fn maintenance_window_from_row(
+    row: &Row,
+    start: usize,
+) -> Result<MaintenanceWindow, postgres::Error> {
+    Ok(MaintenanceWindow {
+        uuid: row.try_get(start)?,
+        date_start: row
+            .try_get::<_, DateTime<Utc>>(start + 1)?
+            .with_timezone(&Helsinki),
+        date_end: row
+            .try_get::<_, DateTime<Utc>>(start + 2)?
+            .with_timezone(&Helsinki),
+        reason: row.try_get(start + 3)?,
+        instance: instance_from_row(row, start + 4)?,
+    })
+}
+
+fn status_change_from_row(row: &Row, start: usize) -> Result<StatusChange, DatabaseError> {
+    let status = row.try_get::<_, String>(start + 2)?;
+    let status = InstanceStatus::parse(&status)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown instance status {}", status)))?;
+    Ok(StatusChange {
+        uuid: row.try_get(start)?,
+        instance: row.try_get(start + 1)?,
+        status,
+        date: row
+            .try_get::<_, DateTime<Utc>>(start + 3)?
+            .with_timezone(&Helsinki),
+        loan: row.try_get(start + 4)?,
+        note: row.try_get(start + 5)?,
+    })
+}
+
+fn asset_info_from_row(row: &Row, start: usize) -> Result<AssetInfo, postgres::Error> {
+    let date = |index: usize| {
+        row.try_get::<_, Option<DateTime<Utc>>>(index)
+            .map(|date| date.map(|date| date.with_timezone(&Helsinki)))
+    };
+    Ok(AssetInfo {
+        instance: row.try_get(start)?,
+        serial_number: row.try_get(start + 1)?,
+        purchase_date: date(start + 2)?,
+        purchase_price: row.try_get(start + 3)?,
+        current_value: row.try_get(start + 4)?,
+        supplier: row.try_get(start + 5)?,
+        warranty_end: date(start + 6)?,
+    })
+}
+
+/// Enum options are stored as a JSON array.
+fn attribute_from_row(row: &Row, start: usize) -> Result<AttributeDefinition, DatabaseError> {
+    let kind = row.try_get::<_, String>(start + 3)?;
+    let options = row.try_get::<_, String>(start + 4)?;
+    let options =
+        serde_json::from_str(&options).map_err(|e| DatabaseError::Internal(e.to_string()))?;
+    let kind = AttributeType::parse(&kind, options)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown attribute type {}", kind)))?;
+    Ok(AttributeDefinition {
+        uuid: row.try_get(start)?,
+        category: row.try_get(start + 1)?,
+        name: row.try_get(start + 2)?,
+        kind,
+        required: row.try_get(start + 5)?,
+    })
+}
+
+fn usage_reading_from_row(row: &Row, start: usize) -> Result<UsageReading, postgres::Error> {
+    Ok(UsageReading {
+        uuid: row.try_get(start)?,
+        instance: row.try_get(start + 1)?,
+        counter: row.try_get(start + 2)?,
+        value: row.try_get(start + 3)?,
+        date: row
+            .try_get::<_, DateTime<Utc>>(start + 4)?
+            .with_timezone(&Helsinki),
+    })
+}
+
+fn notification_from_row(row: &Row, start: usize) -> Result<Notification, DatabaseError> {
+    let kind = row.try_get::<_, String>(start + 2)?;
+    let kind = NotificationKind::parse(&kind)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown notification kind {}", kind)))?;
+    let status = row.try_get::<_, String>(start + 7)?;
+    let status = NotificationStatus::parse(&status).ok_or_else(|| {
+        DatabaseError::Internal(format!("Unknown notification status {}", status))
+    })?;
+    Ok(Notification {
+        uuid: row.try_get(start)?,
+        user: row.try_get(start + 1)?,
+        kind,
+        reference: row.try_get(start + 3)?,
+        subject: row.try_get(start + 4)?,
+        body: row.try_get(start + 5)?,
+        created: row
+            .try_get::<_, DateTime<Utc>>(start + 6)?
+            .with_timezone(&Helsinki),
+        status,
+        attempts: row.try_get(start + 8)?,
+        next_attempt: row
+            .try_get::<_, DateTime<Utc>>(start + 9)?
+            .with_timezone(&Helsinki),
+        last_error: row.try_get(start + 10)?,
+        date_sent: row
+            .try_get::<_, Option<DateTime<Utc>>>(start + 11)?
+            .map(|date| date.with_timezone(&Helsinki)),
+    })
+}
+
+/// before and after are read as text, the postgres crate has no JSON types
+/// without another feature.
+fn json_from_row(row: &Row, index: usize) -> Result<Option<serde_json::Value>, DatabaseError> {
+    match row.try_get::<_, Option<String>>(index)? {
+        Some(json) => serde_json::from_str(&json)
+            .map(Some)
+            .map_err(|e| DatabaseError::Internal(e.to_string())),
+        None => Ok(None),
+    }
+}
+
+fn audit_entry_from_row(row: &Row, start: usize) -> Result<AuditEntry, DatabaseError> {
+    let entity_type = row.try_get::<_, String>(start + 4)?;
+    let entity_type = EntityType::parse(&entity_type)
+        .ok_or_else(|| DatabaseError::Internal(format!("Unknown entity type {}", entity_type)))?;
+    Ok(AuditEntry {
+        id: row.try_get(start)?,
+        date: row
+            .try_get::<_, DateTime<Utc>>(start + 1)?
+            .with_timezone(&Helsinki),
+        actor: row.try_get(start + 2)?,
+        operation: row.try_get(start + 3)?,
+        entity_type,
+        entity: row.try_get(start + 5)?,
+        before: json_from_row(row, start + 6)?,
+        after: json_from_row(row, start + 7)?,
+    })
+}
+
+/// Connection checked out from the pool, or the one of the open transaction.
+enum Checkout<'a> {
+    Pooled(Box<PooledConnection<Manager>>),
+    Locked(MutexGuard<'a, TransactionConnection>),
+}
+
+impl Deref for Checkout<'_> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        match self {
+            Checkout::Pooled(connection) => connection,
+            Checkout::Locked(transaction) => &transaction.connection,
+        }
+    }
+}
+
+impl DerefMut for Checkout<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        match self {
+            Checkout::Pooled(connection) => connection,
+            Checkout::Locked(transaction) => &mut transaction.connection,
+        }
+    }
+}
+
+/// PostgreSQL storage over a connection pool, for central multi-site
+/// installations.
+///
+/// Uses `schema_postgres.sql`, which keeps accepted loans of an instance
+/// from overlapping with an exclusion constraint.
+pub struct PostgresStorage {
+    pool: r2d2::Pool<Manager>,
+    /// Connection of the open transaction, if this storage is bound to one
+    transaction: Option<Mutex<TransactionConnection>>,
+}
+
+impl PostgresStorage {
+    /// Connects with a libpq style connection string, such as
+    /// `host=localhost user=loaner dbname=loaner`, and creates the schema if
+    /// it doesn't exist yet.
+    pub fn new(params: &str) -> Self {
+        let manager = PostgresConnectionManager::new(params.parse().unwrap(), NoTls);
+        let pool = r2d2::Pool::builder()
+            .min_idle(Some(1))
+            .build(manager)
+            .unwrap();
+
+        let storage = Self {
+            pool,
+            transaction: None,
+        };
+        storage.initialize_database();
+        storage
+    }
+
+    fn initialize_database(&self) {
+        let schema = include_str!("../../schema_postgres.sql");
+        self.connection().unwrap().batch_execute(schema).unwrap();
+    }
+
+    /// Checks out a connection from the pool. It is returned when dropped.
+    /// Inside a transaction this is the connection of the transaction.
+    pub fn connection(&self) -> Result<impl DerefMut<Target = Client> + '_, DatabaseError> {
+        match &self.transaction {
+            Some(transaction) => Ok(Checkout::Locked(transaction.lock().unwrap())),
+            None => Ok(Checkout::Pooled(Box::new(self.pool.get()?))),
+        }
+    }
+
+    /// Runs `f` with a storage bound to an open transaction, committing when
+    /// `f` succeeds. Nested calls join the transaction that is already open.
+    ///
+    /// Transactions are serializable, so whatever `f` reads can't change
+    /// before it commits. Transactions on unrelated rows run concurrently.
+    /// When one fails because of a concurrent transaction, `f` is run again.
+    fn in_transaction<T>(
+        &self,
+        mut f: impl FnMut(&PostgresStorage) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        if self.transaction.is_some() {
+            return f(self);
+        }
+
+        let mut attempt = 1;
+        loop {
+            match self.try_transaction(&mut f) {
+                Err((DatabaseError::Conflict(_), true)) if attempt < TRANSACTION_ATTEMPTS => {
+                    let pause = RETRY_PAUSE * (1 << (attempt - 1));
+                    std::thread::sleep(pause.mul_f64(rand::thread_rng().gen()));
+                    attempt += 1;
+                }
+                Err((e, _)) => return Err(e),
+                Ok(result) => return Ok(result),
+            }
+        }
+    }
+
+    /// Runs `f` in one transaction. Errors come with whether the database
+    /// aborted the transaction, as it does for a failed statement. Returning
+    /// early drops the storage, which rolls the transaction back.
+    fn try_transaction<T>(
+        &self,
+        f: &mut impl FnMut(&PostgresStorage) -> Result<T, DatabaseError>,
+    ) -> Result<T, (DatabaseError, bool)> {
+        let from_database = |e: postgres::Error| (DatabaseError::from(e), true);
+
+        let mut connection = self.pool.get().map_err(|e| (e.into(), false))?;
+        connection
+            .batch_execute("BEGIN ISOLATION LEVEL SERIALIZABLE")
+            .map_err(from_database)?;
+        let storage = PostgresStorage {
+            pool: self.pool.clone(),
+            transaction: Some(Mutex::new(TransactionConnection {
+                connection,
+                open: true,
+            })),
+        };
+
+        let transaction = storage.transaction.as_ref().unwrap();
+        match f(&storage) {
+            Ok(result) => {
+                let mut transaction = transaction.lock().unwrap();
+                transaction
+                    .connection
+                    .batch_execute("COMMIT")
+                    .map_err(from_database)?;
+                transaction.open = false;
+                Ok(result)
+            }
+            Err(e) => {
+                // Statements can't run in an aborted transaction, errors of
+                // `f` itself leave it usable
+                let mut transaction = transaction.lock().unwrap();
+                let aborted = transaction.connection.batch_execute("SELECT 1").is_err();
+                Err((e, aborted))
+            }
+        }
+    }
+}
+
+impl Storage for PostgresStorage {
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        self.in_transaction(|storage| f(storage))
+    }
+
+    fn backup_to(&self, _path: &Path) -> Result<(), DatabaseError> {
+        Err(DatabaseError::Invalid(
+            "PostgreSQL databases are backed up with pg_dump.".to_string(),
+        ))
+    }
+
+    fn get_users(&self) -> Result<Vec<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                \"user\".uuid,
+                \"user\".name
+            FROM \"user\"",
+        );
+        let rows = self.connection()?.query(&query, &[])?;
+        let users = rows
+            .iter()
+            .map(|row| user_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    fn get_user(&self, uuid: Uuid) -> Result<Option<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                \"user\".uuid,
+                \"user\".name
+            FROM \"user\"
+            WHERE \"user\".uuid = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&uuid])?;
+        Ok(row.map(|row| user_from_row(&row, 0)).transpose()?)
+    }
+
+    fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                \"user\".uuid,
+                \"user\".name
+            FROM \"user\"
+            WHERE \"user\".name = $1
+            LIMIT 1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&name])?;
+        Ok(row.map(|row| user_from_row(&row, 0)).transpose()?)
+    }
+
+    fn insert_user(&self, uuid: Uuid, name: &str) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                \"user\" (uuid, name)
+            VALUES
+                ($1, $2)",
+        );
+        self.connection()?.execute(&query, &[&uuid, &name])?;
+        Ok(())
+    }
+
+    fn delete_user(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM \"user\"
+            WHERE \"user\".uuid = $1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, &[&uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("User not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_user_email(&self, user: Uuid) -> Result<Option<String>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                user_email.email
+            FROM user_email
+            WHERE user_email.\"user\" = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&user])?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    fn set_user_email(&self, user: Uuid, email: &str) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                user_email (\"user\", email)
+            VALUES
+                ($1, $2)
+            ON CONFLICT (\"user\") DO UPDATE SET email = excluded.email",
+        );
+        self.connection()?.execute(&query, &[&user, &email])?;
+        Ok(())
+    }
+
+    fn get_categories(&self, supercategory: Option<Uuid>) -> Result<Vec<Category>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM category",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = supercategory {
+            query.push_str(" WHERE category.supercategory = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let categories = rows
+            .iter()
+            .map(|row| category_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(categories)
+    }
+
+    fn get_category(&self, name: &str) -> Result<Option<Category>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM category
+            WHERE category.name = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&name])?;
+        Ok(row.map(|row| category_from_row(&row, 0)).transpose()?)
+    }
+
+    fn insert_category(
+        &self,
+        uuid: Uuid,
+        name: &str,
+        supercategory: Option<Uuid>,
+    ) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO category (uuid, name, supercategory) VALUES ($1, $2, $3)",
+            &[&uuid, &name, &supercategory],
+        )?;
+        Ok(())
+    }
+
+    fn delete_category(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM category
+            WHERE category.uuid = $1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, &[&uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Category not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_late_fee_rate(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        // Money is numeric in the schema and bigint cents in Rust
+        let query = String::from(
+            "SELECT
+                late_fee_rate.daily_rate::bigint
+            FROM late_fee_rate
+            WHERE late_fee_rate.category = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&category])?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    fn set_late_fee_rate(&self, category: Uuid, daily_rate: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                late_fee_rate (category, daily_rate)
+            VALUES
+                ($1, $2::bigint)
+            ON CONFLICT (category) DO UPDATE SET daily_rate = excluded.daily_rate",
+        );
+        self.connection()?
+            .execute(&query, &[&category, &daily_rate])?;
+        Ok(())
+    }
+
+    fn get_products(&self, category: Option<Uuid>) -> Result<Vec<Product>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = category {
+            query.push_str(" WHERE product.category = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let products = rows
+            .iter()
+            .map(|row| product_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(products)
+    }
+
+    fn get_product(&self, uuid: Uuid) -> Result<Option<Product>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid
+            WHERE product.uuid = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&uuid])?;
+        Ok(row.map(|row| product_from_row(&row, 0)).transpose()?)
+    }
+
+    fn get_product_by_name(&self, name: &str) -> Result<Option<Product>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid
+            WHERE product.name = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&name])?;
+        Ok(row.map(|row| product_from_row(&row, 0)).transpose()?)
+    }
+
+    fn insert_product(&self, uuid: Uuid, name: &str, category: Uuid) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO product (uuid, name, category) VALUES ($1, $2, $3)",
+            &[&uuid, &name, &category],
+        )?;
+        Ok(())
+    }
+
+    fn delete_product(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM product
+            WHERE product.uuid = $1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, &[&uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Product not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_attributes(
+        &self,
+        category: Option<Uuid>,
+    ) -> Result<Vec<AttributeDefinition>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                attribute.uuid,
+                attribute.category,
+                attribute.name,
+                attribute.kind,
+                attribute.options,
+                attribute.required
+            FROM attribute",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = category {
+            query.push_str(" WHERE attribute.category = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY attribute.ctid");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter().map(|row| attribute_from_row(row, 0)).collect()
+    }
+
+    fn insert_attribute(&self, attribute: &AttributeDefinition) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                attribute (uuid, category, name, kind, options, required)
+            VALUES
+                ($1, $2, $3, $4, $5, $6)",
+        );
+        let options = serde_json::to_string(attribute.kind.options()).unwrap();
+        self.connection()?.execute(
+            &query,
+            &[
+                &attribute.uuid,
+                &attribute.category,
+                &attribute.name,
+                &attribute.kind.as_str(),
+                &options,
+                &attribute.required,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_attribute(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM attribute
+            WHERE attribute.uuid = $1",
+        );
+        let removed = self.connection()?.execute(&query, &[&uuid])?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Attribute not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_attribute_values(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<AttributeValue>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product_attribute.product,
+                product_attribute.attribute,
+                product_attribute.value
+            FROM product_attribute",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE product_attribute.product = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let values = rows
+            .iter()
+            .map(|row| {
+                Ok::<_, postgres::Error>(AttributeValue {
+                    product: row.try_get(0)?,
+                    attribute: row.try_get(1)?,
+                    value: row.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(values)
+    }
+
+    fn set_attribute_values(
+        &self,
+        product: Uuid,
+        values: &[AttributeValue],
+    ) -> Result<(), DatabaseError> {
+        let mut connection = self.connection()?;
+        connection.execute(
+            "DELETE FROM product_attribute
+            WHERE product_attribute.product = $1",
+            &[&product],
+        )?;
+        for value in values.iter() {
+            connection.execute(
+                "INSERT INTO
+                    product_attribute (product, attribute, value)
+                VALUES
+                    ($1, $2, $3)",
+                &[&product, &value.attribute, &value.value],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_product_relations(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<ProductRelation>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product_relation.product,
+                product_relation.related,
+                product_relation.kind
+            FROM product_relation",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE product_relation.product = $1 OR product_relation.related = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY product_relation.ctid");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| {
+                let kind = row.try_get::<_, String>(2)?;
+                let kind = ProductRelationKind::parse(&kind).ok_or_else(|| {
+                    DatabaseError::Internal(format!("Unknown product relation {}", kind))
+                })?;
+                Ok(ProductRelation {
+                    product: row.try_get(0)?,
+                    related: row.try_get(1)?,
+                    kind,
+                })
+            })
+            .collect()
+    }
+
+    fn insert_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_relation (product, related, kind)
+            VALUES
+                ($1, $2, $3)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &relation.product,
+                &relation.related,
+                &relation.kind.as_str(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM product_relation
+            WHERE product_relation.product = $1
+                AND product_relation.related = $2
+                AND product_relation.kind = $3",
+        );
+        let removed = self.connection()?.execute(
+            &query,
+            &[
+                &relation.product,
+                &relation.related,
+                &relation.kind.as_str(),
+            ],
+        )?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Product relation not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_kits(&self) -> Result<Vec<Kit>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                kit.uuid,
+                kit.name,
+                kit_item.product,
+                kit_item.instance,
+                kit_item.quantity
+            FROM kit
+                LEFT JOIN kit_item ON kit_item.kit = kit.uuid
+            ORDER BY kit.name, kit_item.position",
+        );
+        let rows = self.connection()?.query(&query, &[])?;
+
+        // Combine rows of the same kit
+        let mut kits: Vec<Kit> = Vec::new();
+        for row in rows.iter() {
+            let uuid: Uuid = row.try_get(0)?;
+            if kits.last().is_none_or(|kit| kit.uuid != uuid) {
+                kits.push(Kit {
+                    uuid,
+                    name: row.try_get(1)?,
+                    items: Vec::new(),
+                });
+            }
+            let item = match (row.try_get(2)?, row.try_get(3)?) {
+                (Some(product), _) => KitItem::Product {
+                    product,
+                    quantity: row.try_get(4)?,
+                },
+                (None, Some(instance)) => KitItem::Instance { instance },
+                (None, None) => continue,
+            };
+            kits.last_mut().unwrap().items.push(item);
+        }
+        Ok(kits)
+    }
+
+    fn insert_kit(&self, kit: &Kit) -> Result<(), DatabaseError> {
+        let mut connection = self.connection()?;
+        connection.execute(
+            "INSERT INTO
+                kit (uuid, name)
+            VALUES
+                ($1, $2)",
+            &[&kit.uuid, &kit.name],
+        )?;
+        for (position, item) in kit.items.iter().enumerate() {
+            let (product, instance, quantity) = match item {
+                KitItem::Product { product, quantity } => (Some(product), None, *quantity),
+                KitItem::Instance { instance } => (None, Some(instance), 1),
+            };
+            connection.execute(
+                "INSERT INTO
+                    kit_item (kit, position, product, instance, quantity)
+                VALUES
+                    ($1, $2, $3, $4, $5)",
+                &[
+                    &kit.uuid,
+                    &(position as i64),
+                    &product,
+                    &instance,
+                    &quantity,
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn delete_kit(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM kit
+            WHERE kit.uuid = $1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, &[&uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Kit not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_product_price(&self, product: Uuid) -> Result<Option<ProductPrice>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product_price.product,
+                product_price.per_day::bigint,
+                product_price.per_weekend::bigint,
+                product_price.per_week::bigint
+            FROM product_price
+            WHERE product_price.product = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&product])?;
+        let price = row
+            .map(|row| {
+                Ok::<_, postgres::Error>(ProductPrice {
+                    product: row.try_get(0)?,
+                    per_day: row.try_get(1)?,
+                    per_weekend: row.try_get(2)?,
+                    per_week: row.try_get(3)?,
+                })
+            })
+            .transpose()?;
+        Ok(price)
+    }
+
+    fn set_product_price(&self, price: &ProductPrice) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_price (product, per_day, per_weekend, per_week)
+            VALUES
+                ($1, $2::bigint, $3::bigint, $4::bigint)
+            ON CONFLICT (product) DO UPDATE SET
+                per_day = excluded.per_day,
+                per_weekend = excluded.per_weekend,
+                per_week = excluded.per_week",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &price.product,
+                &price.per_day,
+                &price.per_weekend,
+                &price.per_week,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_product_deposit(&self, product: Uuid) -> Result<Option<i64>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product_deposit.amount::bigint
+            FROM product_deposit
+            WHERE product_deposit.product = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&product])?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    fn set_product_deposit(&self, product: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_deposit (product, amount)
+            VALUES
+                ($1, $2::bigint)
+            ON CONFLICT (product) DO UPDATE SET amount = excluded.amount",
+        );
+        self.connection()?.execute(&query, &[&product, &amount])?;
+        Ok(())
+    }
+
+    fn get_category_deposit(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                category_deposit.amount::bigint
+            FROM category_deposit
+            WHERE category_deposit.category = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&category])?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    fn set_category_deposit(&self, category: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                category_deposit (category, amount)
+            VALUES
+                ($1, $2::bigint)
+            ON CONFLICT (category) DO UPDATE SET amount = excluded.amount",
+        );
+        self.connection()?.execute(&query, &[&category, &amount])?;
+        Ok(())
+    }
+
+    fn get_instances(&self, product: Option<Uuid>) -> Result<Vec<Instance>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM instance
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE instance.product = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let instances = rows
+            .iter()
+            .map(|row| instance_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(instances)
+    }
+
+    fn get_instance(&self, uuid: Uuid) -> Result<Option<Instance>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM instance
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid
+            WHERE instance.uuid = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&uuid])?;
+        Ok(row.map(|row| instance_from_row(&row, 0)).transpose()?)
+    }
+
+    fn insert_instance(
+        &self,
+        uuid: Uuid,
+        identifier: &str,
+        product: Uuid,
+    ) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO instance (uuid, identifier, product) VALUES ($1, $2, $3)",
+            &[&uuid, &identifier, &product],
+        )?;
+        Ok(())
+    }
+
+    /// Get loans from loan_view
+    /// Transform dates to Helsinki timezone
+    fn get_loans(&self, params: &LoanQueryParams) -> Result<Vec<Loan>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_uuid,
+                loan_date_start,
+                loan_date_end,
+                loan_accepted,
+                loan_description,
+                user_uuid,
+                user_name,
+                instance_uuid,
+                instance_identifier,
+                product_uuid,
+                product_name,
+                category_uuid,
+                category_name,
+                category_supercategory,
+                loan_price::bigint,
+                loan_rejected
+            FROM loan_view
+            WHERE 1=1",
+        );
+
+        let date_start = params.date_start.map(|date| date.with_timezone(&Utc));
+        let date_end = params.date_end.map(|date| date.with_timezone(&Utc));
+        let due_after = params.due_after.map(|date| date.with_timezone(&Utc));
+        let due_before = params.due_before.map(|date| date.with_timezone(&Utc));
+
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let mut filter = |query: &mut String, condition: &str, value| {
+            query_params.push(value);
+            query.push_str(&format!(" AND {} ${}", condition, query_params.len()));
+        };
+
+        if !params.include_rejected {
+            query.push_str(" AND NOT loan_rejected");
+        }
+        if let Some(ref id) = params.loan_uuid {
+            filter(&mut query, "loan_uuid =", id);
+        }
+        if let Some(ref id) = params.instance_uuid {
+            filter(&mut query, "instance_uuid =", id);
+        }
+        if let Some(ref accepted) = params.loan_accepted {
+            filter(&mut query, "loan_accepted =", accepted);
+        }
+        if let Some(ref id) = params.user_uuid {
+            filter(&mut query, "user_uuid =", id);
+        }
+        if let Some(ref id) = params.product_uuid {
+            filter(&mut query, "product_uuid =", id);
+        }
+        if let Some(ref id) = params.category_uuid {
+            filter(&mut query, "category_uuid =", id);
+        }
+        if let Some(ref start) = date_start {
+            filter(&mut query, "loan_date_end >=", start);
+        }
+        if let Some(ref end) = date_end {
+            filter(&mut query, "loan_date_start <=", end);
+        }
+        if let Some(ref after) = due_after {
+            filter(&mut query, "loan_date_end >=", after);
+        }
+        if let Some(ref before) = due_before {
+            filter(&mut query, "loan_date_end <", before);
+        }
+        if let Some(returned) = params.returned {
+            if returned {
+                query.push_str(" AND instance_date_returned IS NOT NULL");
+            } else {
+                query.push_str(" AND instance_date_returned IS NULL");
+            }
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+
+        // Combine rows of the same loan
+        let mut loans: Vec<Loan> = Vec::new();
+        for row in rows.iter() {
+            let uuid: Uuid = row.try_get(0)?;
+            let instance = instance_from_row(row, 7)?;
+            if let Some(loan) = loans.iter_mut().find(|loan| loan.uuid == uuid) {
+                loan.instaces.push(instance);
+                continue;
+            }
+            loans.push(Loan {
+                uuid,
+                user: user_from_row(row, 5)?,
+                date_start: row.try_get::<_, DateTime<Utc>>(1)?.with_timezone(&Helsinki),
+                date_end: row.try_get::<_, DateTime<Utc>>(2)?.with_timezone(&Helsinki),
+                accepted: row.try_get(3)?,
+                rejected: row.try_get(15)?,
+                description: row.try_get(4)?,
+                instaces: vec![instance],
+                price: row.try_get(14)?,
+                compatibility: Compatibility::default(),
+                kits: Vec::new(),
+            });
+        }
+
+        Ok(loans)
+    }
+
+    fn insert_loan(&self, loan: &NewLoan) -> Result<(), DatabaseError> {
+        let add_loan_query = String::from(
+            "INSERT INTO
+                loan (uuid, \"user\", date_start, date_end, accepted, rejected, description, price)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7, $8::bigint)",
+        );
+        let add_loan_instance_query = String::from(
+            "INSERT INTO
+                loan_instances (loan, instance, accepted, period)
+            SELECT uuid, $2, accepted, tstzrange(date_start, date_end, '[]')
+            FROM loan
+            WHERE uuid = $1",
+        );
+        let add_loan_price_query = String::from(
+            "INSERT INTO
+                loan_price (loan, instance, description, price, discount)
+            VALUES
+                ($1, $2, $3, $4::bigint, $5::bigint)",
+        );
+        let date_start = loan.date_start.with_timezone(&Utc);
+        let date_end = loan.date_end.with_timezone(&Utc);
+
+        // The loan and its instances are inserted together
+        self.in_transaction(|storage| {
+            let mut connection = storage.connection()?;
+            connection.execute(
+                &add_loan_query,
+                &[
+                    &loan.uuid,
+                    &loan.user,
+                    &date_start,
+                    &date_end,
+                    &loan.accepted,
+                    &loan.rejected,
+                    &loan.description,
+                    &loan.price,
+                ],
+            )?;
+            for instance in loan.instances.iter() {
+                connection.execute(&add_loan_instance_query, &[&loan.uuid, instance])?;
+            }
+            for line in loan.price_lines.iter() {
+                connection.execute(
+                    &add_loan_price_query,
+                    &[
+                        &loan.uuid,
+                        &line.instance,
+                        &line.description,
+                        &line.price,
+                        &line.discount,
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_loan_accepted(&self, loan: Uuid, accepted: bool) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan
+            SET accepted = $2
+            WHERE loan.uuid = $1",
+        );
+        let updated = self.connection()?.execute(&query, &[&loan, &accepted])?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn set_loan_rejected(&self, loan: Uuid, rejected: bool) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan
+            SET rejected = $2
+            WHERE loan.uuid = $1",
+        );
+        let updated = self.connection()?.execute(&query, &[&loan, &rejected])?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_loan_price_lines(&self, loan: Uuid) -> Result<Vec<QuoteLine>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                loan_price.instance,
+                loan_price.description,
+                loan_price.price::bigint,
+                loan_price.discount::bigint
+            FROM loan_price
+            WHERE loan_price.loan = $1",
+        );
+        let rows = self.connection()?.query(&query, &[&loan])?;
+        let lines = rows
+            .iter()
+            .map(|row| {
+                Ok(QuoteLine {
+                    instance: row.try_get(0)?,
+                    description: row.try_get(1)?,
+                    price: row.try_get(2)?,
+                    discount: row.try_get(3)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(lines)
+    }
+
+    fn get_loan_kit_instances(
+        &self,
+        loan: Option<Uuid>,
+    ) -> Result<Vec<LoanKitInstance>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_kit.loan,
+                loan_kit.instance,
+                loan_kit.kit
+            FROM loan_kit",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE loan_kit.loan = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY loan_kit.ctid");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let rows = rows
+            .iter()
+            .map(|row| {
+                Ok(LoanKitInstance {
+                    loan: row.try_get(0)?,
+                    instance: row.try_get(1)?,
+                    kit: row.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(rows)
+    }
+
+    fn insert_loan_kit_instance(&self, row: &LoanKitInstance) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                loan_kit (loan, instance, kit)
+            VALUES
+                ($1, $2, $3)",
+        );
+        self.connection()?
+            .execute(&query, &[&row.loan, &row.instance, &row.kit])?;
+        Ok(())
+    }
+
+    fn check_in(
+        &self,
+        loan: Uuid,
+        instance: Uuid,
+        date: DateTime<Tz>,
+    ) -> Result<(), DatabaseError> {
+        let updated = self.connection()?.execute(
+            "UPDATE loan_instances SET date_returned = $3
+            WHERE loan = $1 AND instance = $2 AND date_returned IS NULL",
+            &[&loan, &instance, &date.with_timezone(&Utc)],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Loaned instance not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_check_ins(&self) -> Result<Vec<CheckIn>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                loan_instances.loan,
+                loan_instances.instance,
+                loan_instances.date_returned
+            FROM loan_instances
+            WHERE loan_instances.date_returned IS NOT NULL",
+        );
+        let rows = self.connection()?.query(&query, &[])?;
+        let check_ins = rows
+            .iter()
+            .map(|row| {
+                Ok(CheckIn {
+                    loan: row.try_get(0)?,
+                    instance: row.try_get(1)?,
+                    date: row.try_get::<_, DateTime<Utc>>(2)?.with_timezone(&Helsinki),
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(check_ins)
+    }
+
+    fn get_membership_types(&self) -> Result<Vec<MembershipType>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                membership_type.uuid,
+                membership_type.type,
+                membership_type.rental_discount::bigint
+            FROM membership_type",
+        );
+        let rows = self.connection()?.query(&query, &[])?;
+        let types = rows
+            .iter()
+            .map(|row| {
+                Ok(MembershipType {
+                    uuid: row.try_get(0)?,
+                    name: row.try_get(1)?,
+                    rental_discount: row.try_get(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(types)
+    }
+
+    fn insert_membership_type(
+        &self,
+        membership_type: &MembershipType,
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                membership_type (uuid, type, rental_discount)
+            VALUES
+                ($1, $2, $3::bigint)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &membership_type.uuid,
+                &membership_type.name,
+                &membership_type.rental_discount,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_membership_payments(
+        &self,
+        user: Option<Uuid>,
+    ) -> Result<Vec<MembershipPayment>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                membership_payments.uuid,
+                membership_payments.\"user\",
+                membership_payments.membership_type,
+                membership_payments.price::bigint,
+                membership_payments.date_start,
+                membership_payments.date_end
+            FROM membership_payments",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE membership_payments.\"user\" = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let payments = rows
+            .iter()
+            .map(|row| {
+                Ok(MembershipPayment {
+                    uuid: row.try_get(0)?,
+                    user: row.try_get(1)?,
+                    membership_type: row.try_get(2)?,
+                    price: row.try_get(3)?,
+                    date_start: row.try_get(4)?,
+                    date_end: row.try_get(5)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(payments)
+    }
+
+    fn insert_membership_payment(&self, payment: &MembershipPayment) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                membership_payments (uuid, \"user\", membership_type, price, date_start, date_end)
+            VALUES
+                ($1, $2, $3, $4::bigint, $5, $6)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &payment.uuid,
+                &payment.user,
+                &payment.membership_type,
+                &payment.price,
+                &payment.date_start,
+                &payment.date_end,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_ledger_entries(&self, user: Option<Uuid>) -> Result<Vec<LedgerEntry>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                ledger.uuid,
+                ledger.\"user\",
+                ledger.date,
+                ledger.kind,
+                ledger.amount::bigint,
+                ledger.loan,
+                ledger.description
+            FROM ledger",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE ledger.\"user\" = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| ledger_entry_from_row(row, 0))
+            .collect()
+    }
+
+    fn insert_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                ledger (uuid, \"user\", date, kind, amount, loan, description)
+            VALUES
+                ($1, $2, $3, $4, $5::bigint, $6, $7)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &entry.uuid,
+                &entry.user,
+                &entry.date.with_timezone(&Utc),
+                &entry.kind.as_str(),
+                &entry.amount,
+                &entry.loan,
+                &entry.description,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_invoices(&self, loan: Option<Uuid>) -> Result<Vec<Invoice>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                invoice.number,
+                invoice.loan,
+                invoice.date,
+                invoice.total::bigint
+            FROM invoice",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE invoice.loan = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let invoices = rows
+            .iter()
+            .map(|row| {
+                Ok(Invoice {
+                    number: row.try_get(0)?,
+                    loan: row.try_get(1)?,
+                    date: row.try_get::<_, DateTime<Utc>>(2)?.with_timezone(&Helsinki),
+                    total: row.try_get(3)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(invoices)
+    }
+
+    fn insert_invoice(
+        &self,
+        invoice: &Invoice,
+        lines: &[InvoiceLine],
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                invoice (number, loan, date, total)
+            VALUES
+                ($1, $2, $3, $4::bigint)",
+        );
+        let line_query = String::from(
+            "INSERT INTO
+                invoice_line (invoice, position, description, amount)
+            VALUES
+                ($1, $2, $3, $4::bigint)",
+        );
+
+        // The invoice and its lines are inserted together
+        self.in_transaction(|storage| {
+            let mut connection = storage.connection()?;
+            connection.execute(
+                &query,
+                &[
+                    &invoice.number,
+                    &invoice.loan,
+                    &invoice.date.with_timezone(&Utc),
+                    &invoice.total,
+                ],
+            )?;
+            for (position, line) in lines.iter().enumerate() {
+                connection.execute(
+                    &line_query,
+                    &[
+                        &invoice.number,
+                        &(position as i32),
+                        &line.description,
+                        &line.amount,
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_invoice_lines(&self, invoice: i64) -> Result<Vec<InvoiceLine>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                invoice_line.description,
+                invoice_line.amount::bigint
+            FROM invoice_line
+            WHERE invoice_line.invoice = $1
+            ORDER BY invoice_line.position",
+        );
+        let rows = self.connection()?.query(&query, &[&invoice])?;
+        let lines = rows
+            .iter()
+            .map(|row| {
+                Ok(InvoiceLine {
+                    description: row.try_get(0)?,
+                    amount: row.try_get(1)?,
+                })
+            })
+            .collect::<Result<Vec<_>, postgres::Error>>()?;
+        Ok(lines)
+    }
+
+    fn get_deposits(&self, loan: Option<Uuid>) -> Result<Vec<Deposit>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_deposit.loan,
+                loan_deposit.amount::bigint,
+                loan_deposit.date_received,
+                loan_deposit.status,
+                loan_deposit.date_settled,
+                loan_deposit.reason
+            FROM loan_deposit",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE loan_deposit.loan = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter().map(|row| deposit_from_row(row, 0)).collect()
+    }
+
+    fn insert_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                loan_deposit (loan, amount, date_received, status, date_settled, reason)
+            VALUES
+                ($1, $2::bigint, $3, $4, $5, $6)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &deposit.loan,
+                &deposit.amount,
+                &deposit.date_received.with_timezone(&Utc),
+                &deposit.status.as_str(),
+                &deposit.date_settled.map(|date| date.with_timezone(&Utc)),
+                &deposit.reason,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan_deposit
+            SET status = $2, date_settled = $3, reason = $4
+            WHERE loan_deposit.loan = $1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            &[
+                &deposit.loan,
+                &deposit.status.as_str(),
+                &deposit.date_settled.map(|date| date.with_timezone(&Utc)),
+                &deposit.reason,
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Deposit not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_condition_reports(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<ConditionReport>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                condition_report.uuid,
+                condition_report.instance,
+                condition_report.loan,
+                condition_report.severity,
+                condition_report.description,
+                condition_report.reporter,
+                condition_report.date,
+                condition_report.out_of_service,
+                condition_report.date_resolved,
+                condition_report.resolution
+            FROM condition_report",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE condition_report.instance = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY condition_report.date");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| condition_report_from_row(row, 0))
+            .collect()
+    }
+
+    fn insert_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                condition_report (uuid, instance, loan, severity, description, reporter, date,
+                    out_of_service, date_resolved, resolution)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &report.uuid,
+                &report.instance,
+                &report.loan,
+                &report.severity.as_str(),
+                &report.description,
+                &report.reporter,
+                &report.date.with_timezone(&Utc),
+                &report.out_of_service,
+                &report.date_resolved.map(|date| date.with_timezone(&Utc)),
+                &report.resolution,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE condition_report
+            SET date_resolved = $2, resolution = $3
+            WHERE condition_report.uuid = $1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            &[
+                &report.uuid,
+                &report.date_resolved.map(|date| date.with_timezone(&Utc)),
+                &report.resolution,
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Condition report not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_maintenance_windows(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<MaintenanceWindow>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                maintenance_window.uuid,
+                maintenance_window.date_start,
+                maintenance_window.date_end,
+                maintenance_window.reason,
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM maintenance_window
+                INNER JOIN instance ON maintenance_window.instance = instance.uuid
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE maintenance_window.instance = $1");
+            query_params.push(id);
+        }
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let windows = rows
+            .iter()
+            .map(|row| maintenance_window_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(windows)
+    }
+
+    fn insert_maintenance_window(
+        &self,
+        uuid: Uuid,
+        instance: Uuid,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        reason: &str,
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                maintenance_window (uuid, instance, date_start, date_end, reason)
+            VALUES
+                ($1, $2, $3, $4, $5)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &uuid,
+                &instance,
+                &date_start.with_timezone(&Utc),
+                &date_end.with_timezone(&Utc),
+                &reason,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_maintenance_window(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM maintenance_window
+            WHERE maintenance_window.uuid = $1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, &[&uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Maintenance window not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_status_changes(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<StatusChange>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                instance_status.uuid,
+                instance_status.instance,
+                instance_status.status,
+                instance_status.date,
+                instance_status.loan,
+                instance_status.note
+            FROM instance_status",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE instance_status.instance = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY instance_status.date");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| status_change_from_row(row, 0))
+            .collect()
+    }
+
+    fn insert_status_change(&self, change: &StatusChange) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                instance_status (uuid, instance, status, date, loan, note)
+            VALUES
+                ($1, $2, $3, $4, $5, $6)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &change.uuid,
+                &change.instance,
+                &change.status.as_str(),
+                &change.date.with_timezone(&Utc),
+                &change.loan,
+                &change.note,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_asset_info(&self, instance: Uuid) -> Result<Option<AssetInfo>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_asset.instance,
+                instance_asset.serial_number,
+                instance_asset.purchase_date,
+                instance_asset.purchase_price::bigint,
+                instance_asset.current_value::bigint,
+                instance_asset.supplier,
+                instance_asset.warranty_end
+            FROM instance_asset
+            WHERE instance_asset.instance = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&instance])?;
+        let asset = row.map(|row| asset_info_from_row(&row, 0)).transpose()?;
+        Ok(asset)
+    }
+
+    fn get_asset_infos(&self) -> Result<Vec<AssetInfo>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_asset.instance,
+                instance_asset.serial_number,
+                instance_asset.purchase_date,
+                instance_asset.purchase_price::bigint,
+                instance_asset.current_value::bigint,
+                instance_asset.supplier,
+                instance_asset.warranty_end
+            FROM instance_asset",
+        );
+        let rows = self.connection()?.query(&query, &[])?;
+        let assets = rows
+            .iter()
+            .map(|row| asset_info_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(assets)
+    }
+
+    fn set_asset_info(&self, asset: &AssetInfo) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                instance_asset (instance, serial_number, purchase_date, purchase_price,
+                    current_value, supplier, warranty_end)
+            VALUES
+                ($1, $2, $3, $4::bigint, $5::bigint, $6, $7)
+            ON CONFLICT (instance) DO UPDATE SET
+                serial_number = excluded.serial_number,
+                purchase_date = excluded.purchase_date,
+                purchase_price = excluded.purchase_price,
+                current_value = excluded.current_value,
+                supplier = excluded.supplier,
+                warranty_end = excluded.warranty_end",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &asset.instance,
+                &asset.serial_number,
+                &asset.purchase_date.map(|date| date.with_timezone(&Utc)),
+                &asset.purchase_price,
+                &asset.current_value,
+                &asset.supplier,
+                &asset.warranty_end.map(|date| date.with_timezone(&Utc)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_instance_location(&self, instance: Uuid) -> Result<Option<String>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_location.location
+            FROM instance_location
+            WHERE instance_location.instance = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&instance])?;
+        Ok(row.map(|row| row.try_get(0)).transpose()?)
+    }
+
+    fn set_instance_location(
+        &self,
+        instance: Uuid,
+        location: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        let mut connection = self.connection()?;
+        match location {
+            Some(location) => connection.execute(
+                "INSERT INTO
+                    instance_location (instance, location)
+                VALUES
+                    ($1, $2)
+                ON CONFLICT (instance) DO UPDATE SET location = excluded.location",
+                &[&instance, &location],
+            )?,
+            None => connection.execute(
+                "DELETE FROM instance_location
+                WHERE instance_location.instance = $1",
+                &[&instance],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn get_depreciation(&self, category: Uuid) -> Result<Option<Depreciation>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                depreciation.method,
+                depreciation.rate
+            FROM depreciation
+            WHERE depreciation.category = $1",
+        );
+        let Some(row) = self.connection()?.query_opt(&query, &[&category])? else {
+            return Ok(None);
+        };
+        let method = row.try_get::<_, String>(0)?;
+        let method = DepreciationMethod::parse(&method).ok_or_else(|| {
+            DatabaseError::Internal(format!("Unknown depreciation method {}", method))
+        })?;
+        Ok(Some(Depreciation {
+            method,
+            rate: row.try_get(1)?,
+        }))
+    }
+
+    fn set_depreciation(
+        &self,
+        category: Uuid,
+        depreciation: Option<&Depreciation>,
+    ) -> Result<(), DatabaseError> {
+        let mut connection = self.connection()?;
+        match depreciation {
+            Some(depreciation) => connection.execute(
+                "INSERT INTO
+                    depreciation (category, method, rate)
+                VALUES
+                    ($1, $2, $3)
+                ON CONFLICT (category) DO UPDATE SET
+                    method = excluded.method,
+                    rate = excluded.rate",
+                &[&category, &depreciation.method.as_str(), &depreciation.rate],
+            )?,
+            None => connection.execute(
+                "DELETE FROM depreciation
+                WHERE depreciation.category = $1",
+                &[&category],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn get_maintenance_rule(
+        &self,
+        product: Uuid,
+    ) -> Result<Option<MaintenanceRule>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                maintenance_rule.product,
+                maintenance_rule.loans,
+                maintenance_rule.days_out,
+                maintenance_rule.months
+            FROM maintenance_rule
+            WHERE maintenance_rule.product = $1",
+        );
+        let row = self.connection()?.query_opt(&query, &[&product])?;
+        let rule = row
+            .map(|row| {
+                Ok::<_, postgres::Error>(MaintenanceRule {
+                    product: row.try_get(0)?,
+                    loans: row.try_get(1)?,
+                    days_out: row.try_get(2)?,
+                    months: row.try_get(3)?,
+                })
+            })
+            .transpose()?;
+        Ok(rule)
+    }
+
+    fn set_maintenance_rule(&self, rule: &MaintenanceRule) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                maintenance_rule (product, loans, days_out, months)
+            VALUES
+                ($1, $2, $3, $4)
+            ON CONFLICT (product) DO UPDATE SET
+                loans = excluded.loans,
+                days_out = excluded.days_out,
+                months = excluded.months",
+        );
+        self.connection()?.execute(
+            &query,
+            &[&rule.product, &rule.loans, &rule.days_out, &rule.months],
+        )?;
+        Ok(())
+    }
+
+    fn delete_maintenance_rule(&self, product: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM maintenance_rule
+            WHERE maintenance_rule.product = $1",
+        );
+        let removed = self.connection()?.execute(&query, &[&product])?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Maintenance rule not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_usage_readings(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<UsageReading>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                usage_reading.uuid,
+                usage_reading.instance,
+                usage_reading.counter,
+                usage_reading.value,
+                usage_reading.date
+            FROM usage_reading",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE usage_reading.instance = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY usage_reading.date");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        let readings = rows
+            .iter()
+            .map(|row| usage_reading_from_row(row, 0))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(readings)
+    }
+
+    fn insert_usage_reading(&self, reading: &UsageReading) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                usage_reading (uuid, instance, counter, value, date)
+            VALUES
+                ($1, $2, $3, $4, $5)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &reading.uuid,
+                &reading.instance,
+                &reading.counter,
+                &reading.value,
+                &reading.date.with_timezone(&Utc),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_notifications(&self, user: Option<Uuid>) -> Result<Vec<Notification>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                notification.uuid,
+                notification.\"user\",
+                notification.kind,
+                notification.reference,
+                notification.subject,
+                notification.body,
+                notification.created,
+                notification.status,
+                notification.attempts::bigint,
+                notification.next_attempt,
+                notification.last_error,
+                notification.date_sent
+            FROM notification",
+        );
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE notification.\"user\" = $1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY notification.created");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| notification_from_row(row, 0))
+            .collect()
+    }
+
+    fn insert_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                notification (uuid, \"user\", kind, reference, subject, body, created, status,
+                    attempts, next_attempt, last_error, date_sent)
+            VALUES
+                ($1, $2, $3, $4, $5, $6, $7, $8, $9::bigint, $10, $11, $12)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &notification.uuid,
+                &notification.user,
+                &notification.kind.as_str(),
+                &notification.reference,
+                &notification.subject,
+                &notification.body,
+                &notification.created.with_timezone(&Utc),
+                &notification.status.as_str(),
+                &notification.attempts,
+                &notification.next_attempt.with_timezone(&Utc),
+                &notification.last_error,
+                &notification.date_sent.map(|date| date.with_timezone(&Utc)),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE notification
+            SET status = $2, attempts = $3::bigint, next_attempt = $4, last_error = $5,
+                date_sent = $6
+            WHERE notification.uuid = $1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            &[
+                &notification.uuid,
+                &notification.status.as_str(),
+                &notification.attempts,
+                &notification.next_attempt.with_timezone(&Utc),
+                &notification.last_error,
+                &notification.date_sent.map(|date| date.with_timezone(&Utc)),
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Notification not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_audit_entries(
+        &self,
+        params: &AuditQueryParams,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                audit_log.id,
+                audit_log.date,
+                audit_log.actor,
+                audit_log.operation,
+                audit_log.entity_type,
+                audit_log.entity,
+                audit_log.before::text,
+                audit_log.after::text
+            FROM audit_log
+            WHERE 1=1",
+        );
+
+        let entity_type = params.entity_type.map(|t| t.as_str());
+        let date_start = params.date_start.map(|date| date.with_timezone(&Utc));
+        let date_end = params.date_end.map(|date| date.with_timezone(&Utc));
+
+        let mut query_params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+        let mut filter = |query: &mut String, condition: &str, value| {
+            query_params.push(value);
+            query.push_str(&format!(" AND {} ${}", condition, query_params.len()));
+        };
+
+        if let Some(ref entity_type) = entity_type {
+            filter(&mut query, "audit_log.entity_type =", entity_type);
+        }
+        if let Some(ref id) = params.entity {
+            filter(&mut query, "audit_log.entity =", id);
+        }
+        if let Some(ref actor) = params.actor {
+            filter(&mut query, "audit_log.actor =", actor);
+        }
+        if let Some(ref start) = date_start {
+            filter(&mut query, "audit_log.date >=", start);
+        }
+        if let Some(ref end) = date_end {
+            filter(&mut query, "audit_log.date <", end);
+        }
+        query.push_str(" ORDER BY audit_log.id");
+
+        let rows = self.connection()?.query(&query, &query_params)?;
+        rows.iter()
+            .map(|row| audit_entry_from_row(row, 0))
+            .collect()
+    }
+
+    fn insert_audit_entry(&self, entry: &NewAuditEntry) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                audit_log (date, actor, operation, entity_type, entity, before, after)
+            VALUES
+                ($1, $2, $3, $4, $5, $6::text::jsonb, $7::text::jsonb)",
+        );
+        self.connection()?.execute(
+            &query,
+            &[
+                &entry.date.with_timezone(&Utc),
+                &entry.actor,
+                &entry.operation,
+                &entry.entity_type.as_str(),
+                &entry.entity,
+                &entry.before.as_ref().map(|json| json.to_string()),
+                &entry.after.as_ref().map(|json| json.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,2756 @@
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use chrono_tz::Europe::Helsinki;
+use chrono_tz::Tz;
+use r2d2::PooledConnection;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::backup::Backup;
+use rusqlite::params;
+use rusqlite::params_from_iter;
+use rusqlite::Connection;
+use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use rusqlite::Row;
+use rusqlite::{Transaction, TransactionBehavior};
+use uuid::Uuid;
+
+use super::{CheckIn, NewAuditEntry, NewLoan, Storage};
+use crate::attribute::{AttributeDefinition, AttributeType, AttributeValue};
+use crate::audit::{AuditEntry, AuditQueryParams, EntityType};
+use crate::database::{
+    AssetInfo, Category, Compatibility, ConditionReport, DatabaseError, Deposit, DepositStatus,
+    Depreciation, DepreciationMethod, Instance, InstanceStatus, Invoice, Kit, KitItem, LedgerEntry,
+    LedgerEntryKind, Loan, LoanKitInstance, LoanQueryParams, MaintenanceRule, MaintenanceWindow,
+    MembershipPayment, MembershipType, Product, ProductPrice, ProductRelation, ProductRelationKind,
+    QuoteLine, Severity, StatusChange, UsageReading, User,
+};
+use crate::invoice::InvoiceLine;
+use crate::notification::{Notification, NotificationKind, NotificationStatus};
+
+/// How long a connection waits for another writer before giving up.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Version of `schema.sql`, kept in the `user_version` of database files.
+/// Increase it whenever the schema changes, so that older files are migrated
+/// and files and backups of a newer version are refused. Changes to existing
+/// tables also need an entry in `MIGRATIONS`.
+pub const SCHEMA_VERSION: i64 = 14;
+
+const SCHEMA: &str = include_str!("../../schema.sql");
+
+/// Tables whose definition changed in each schema version. Upgrading a file
+/// rebuilds the tables of every version above its own from `schema.sql`.
+/// Added tables, indexes and triggers need no entry, running `schema.sql`
+/// afterwards creates them.
+const MIGRATIONS: &[(i64, &[&str])] = &[
+    // Files of the original schema have no version. Its tables lacked the
+    // prices, discounts and return dates, and the delete rules of the
+    // foreign keys.
+    (
+        1,
+        &[
+            "category",
+            "product",
+            "instance",
+            "loan",
+            "loan_instances",
+            "membership_type",
+            "membership_payments",
+        ],
+    ),
+    // The order of the loan and maintenance dates was checked on their text,
+    // which is wrong for dates in different offsets
+    (12, &["loan", "maintenance_window"]),
+    // Rejected loans were deleted instead of being kept
+    (14, &["loan"]),
+];
+
+/// Pages copied per step of an online backup. Other connections can write
+/// between the steps.
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 256;
+const BACKUP_PAUSE: Duration = Duration::from_millis(10);
+
+impl From<rusqlite::Error> for DatabaseError {
+    /// Foreign key failures are reported as `NotFound`, which is right for
+    /// inserts. Deletes go through `map_delete_error` to get `InUse` instead.
+    fn from(error: rusqlite::Error) -> Self {
+        use rusqlite::ffi;
+
+        let message = error.to_string();
+        match error.sqlite_error() {
+            Some(e) => match e.extended_code {
+                ffi::SQLITE_CONSTRAINT_UNIQUE | ffi::SQLITE_CONSTRAINT_PRIMARYKEY => {
+                    DatabaseError::AlreadyExists(message)
+                }
+                ffi::SQLITE_CONSTRAINT_FOREIGNKEY => DatabaseError::NotFound(message),
+                // SQLite runs ON DELETE RESTRICT as a trigger program raising
+                // this message. Our own triggers, which keep the audit log
+                // append-only, raise other messages.
+                ffi::SQLITE_CONSTRAINT_TRIGGER
+                    if message.contains("FOREIGN KEY constraint failed") =>
+                {
+                    DatabaseError::InUse(message)
+                }
+                ffi::SQLITE_CONSTRAINT_TRIGGER
+                | ffi::SQLITE_CONSTRAINT_CHECK
+                | ffi::SQLITE_CONSTRAINT_NOTNULL => DatabaseError::Invalid(message),
+                _ => DatabaseError::Internal(message),
+            },
+            None => match error {
+                rusqlite::Error::QueryReturnedNoRows => DatabaseError::NotFound(message),
+                _ => DatabaseError::Internal(message),
+            },
+        }
+    }
+}
+
+impl From<r2d2::Error> for DatabaseError {
+    fn from(error: r2d2::Error) -> Self {
+        DatabaseError::Internal(error.to_string())
+    }
+}
+
+fn io_error(error: io::Error) -> DatabaseError {
+    DatabaseError::Internal(error.to_string())
+}
+
+/// File next to `path` that is written first and then renamed over it, so
+/// that `path` is never left half written.
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+fn schema_version(connection: &Connection) -> Result<i64, DatabaseError> {
+    let version = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(version)
+}
+
+/// Files written by a newer version of the program can't be read safely.
+fn check_not_newer(version: i64) -> Result<(), DatabaseError> {
+    if version > SCHEMA_VERSION {
+        return Err(DatabaseError::Invalid(format!(
+            "Database has schema version {}, newer than the supported {}.",
+            version, SCHEMA_VERSION
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the migrations in a single transaction, so a failed upgrade leaves
+/// the file as it was. Foreign keys must be off on the connection.
+fn upgrade(connection: &Connection) -> Result<(), DatabaseError> {
+    let transaction = Transaction::new_unchecked(connection, TransactionBehavior::Immediate)?;
+    // Another connection may have upgraded the file while this one waited
+    let version = schema_version(&transaction)?;
+    check_not_newer(version)?;
+    if version == SCHEMA_VERSION {
+        return Ok(());
+    }
+
+    let objects: i64 =
+        transaction.query_row("SELECT count(*) FROM sqlite_master", [], |row| row.get(0))?;
+    if objects > 0 {
+        // The view refers to the rebuilt tables. It has no data of its own
+        // and `schema.sql` creates it again.
+        transaction.execute_batch("DROP VIEW IF EXISTS loan_view")?;
+        for (_, tables) in MIGRATIONS.iter().filter(|(to, _)| *to > version) {
+            for table in tables.iter() {
+                rebuild_table(&transaction, table)?;
+            }
+        }
+    }
+    transaction.execute_batch(SCHEMA)?;
+
+    let broken = transaction
+        .query_row("PRAGMA foreign_key_check", [], |row| {
+            row.get::<usize, String>(0)
+        })
+        .optional()?;
+    if let Some(table) = broken {
+        return Err(DatabaseError::Invalid(format!(
+            "Database can't be migrated, table {} refers to missing rows.",
+            table
+        )));
+    }
+    transaction.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Replaces the table with one of its definition in `schema.sql`, copying the
+/// columns the two have in common. Columns only in the new definition get
+/// their defaults. The indexes and triggers of the table are dropped with it.
+fn rebuild_table(connection: &Connection, table: &str) -> Result<(), DatabaseError> {
+    let columns = table_columns(connection, table)?;
+    if columns.is_empty() {
+        // Created by `schema.sql` like any new table
+        return Ok(());
+    }
+
+    let prefix = format!("CREATE TABLE IF NOT EXISTS {} (", table);
+    let start = SCHEMA
+        .find(&prefix)
+        .ok_or_else(|| DatabaseError::Internal(format!("Table {} is not in the schema.", table)))?;
+    let end = start + SCHEMA[start..].find(");").unwrap() + 2;
+    let rebuilt = format!("{}_new", table);
+    let definition =
+        SCHEMA[start..end].replacen(&prefix, &format!("CREATE TABLE {} (", rebuilt), 1);
+    connection.execute_batch(&definition)?;
+
+    let new_columns = table_columns(connection, &rebuilt)?;
+    let common = columns
+        .into_iter()
+        .filter(|column| new_columns.contains(column))
+        .collect::<Vec<_>>()
+        .join(", ");
+    connection.execute_batch(&format!(
+        "INSERT INTO {rebuilt} ({common}) SELECT {common} FROM {table} ORDER BY rowid;
+        DROP TABLE {table};
+        ALTER TABLE {rebuilt} RENAME TO {table};"
+    ))?;
+    Ok(())
+}
+
+fn table_columns(connection: &Connection, table: &str) -> Result<Vec<String>, DatabaseError> {
+    let mut statement = connection.prepare("SELECT name FROM pragma_table_info(?1)")?;
+    let columns = statement
+        .query_map([table], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(columns)
+}
+
+/// Foreign key failures on delete mean the row is still referenced.
+fn map_delete_error(error: rusqlite::Error) -> DatabaseError {
+    match DatabaseError::from(error) {
+        DatabaseError::NotFound(message) => DatabaseError::InUse(message),
+        e => e,
+    }
+}
+
+fn user_from_row(row: &Row, start: usize) -> rusqlite::Result<User> {
+    Ok(User {
+        uuid: row.get(start)?,
+        name: row.get(start + 1)?,
+    })
+}
+
+fn category_from_row(row: &Row, start: usize) -> rusqlite::Result<Category> {
+    Ok(Category {
+        uuid: row.get(start)?,
+        name: row.get(start + 1)?,
+        supercategory: row.get(start + 2)?,
+    })
+}
+
+fn product_from_row(row: &Row, start: usize) -> rusqlite::Result<Product> {
+    Ok(Product {
+        uuid: row.get(start)?,
+        name: row.get(start + 1)?,
+        category: category_from_row(row, start + 2)?,
+    })
+}
+
+fn instance_from_row(row: &Row, start: usize) -> rusqlite::Result<Instance> {
+    Ok(Instance {
+        uuid: row.get(start)?,
+        identifier: row.get(start + 1)?,
+        product: product_from_row(row, start + 2)?,
+    })
+}
+
+/// Dates are stored as RFC 3339 strings and read back in Helsinki time.
+fn date_from_row(row: &Row, index: usize) -> rusqlite::Result<DateTime<Tz>> {
+    let date = row.get::<usize, String>(index)?;
+    DateTime::parse_from_rfc3339(&date)
+        .map(|date| date.with_timezone(&Helsinki))
+        .map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        })
+}
+
+/// Days are stored as `YYYY-MM-DD` strings.
+fn naive_date_from_row(row: &Row, index: usize) -> rusqlite::Result<NaiveDate> {
+    let date = row.get::<usize, String>(index)?;
+    date.parse::<NaiveDate>().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn ledger_entry_from_row(row: &Row, start: usize) -> rusqlite::Result<LedgerEntry> {
+    let kind = row.get::<usize, String>(start + 3)?;
+    let kind = LedgerEntryKind::parse(&kind).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 3,
+            rusqlite::types::Type::Text,
+            format!("Unknown ledger entry kind {}", kind).into(),
+        )
+    })?;
+    Ok(LedgerEntry {
+        uuid: row.get(start)?,
+        user: row.get(start + 1)?,
+        date: date_from_row(row, start + 2)?,
+        kind,
+        amount: row.get(start + 4)?,
+        loan: row.get(start + 5)?,
+        description: row.get(start + 6)?,
+    })
+}
+
+fn deposit_from_row(row: &Row, start: usize) -> rusqlite::Result<Deposit> {
+    let status = row.get::<usize, String>(start + 3)?;
+    let status = DepositStatus::parse(&status).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 3,
+            rusqlite::types::Type::Text,
+            format!("Unknown deposit status {}", status).into(),
+        )
+    })?;
+    let date_settled = match row.get::<usize, Option<String>>(start + 4)? {
+        Some(_) => Some(date_from_row(row, start + 4)?),
+        None => None,
+    };
+    Ok(Deposit {
+        loan: row.get(start)?,
+        amount: row.get(start + 1)?,
+        date_received: date_from_row(row, start + 2)?,
+        status,
+        date_settled,
+        reason: row.get(start + 5)?,
+    })
+}
+
+fn condition_report_from_row(row: &Row, start: usize) -> rusqlite::Result<ConditionReport> {
+    let severity = row.get::<usize, String>(start + 3)?;
+    let severity = Severity::parse(&severity).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 3,
+            rusqlite::types::Type::Text,
+            format!("Unknown severity {}", severity).into(),
+        )
+    })?;
+    let date_resolved = match row.get::<usize, Option<String>>(start + 8)? {
+        Some(_) => Some(date_from_row(row, start + 8)?),
+        None => None,
+    };
+    Ok(ConditionReport {
+        uuid: row.get(start)?,
+        instance: row.get(start + 1)?,
+        loan: row.get(start + 2)?,
+        severity,
+        description: row.get(start + 4)?,
+        reporter: row.get(start + 5)?,
+        date: date_from_row(row, start + 6)?,
+        out_of_service: row.get(start + 7)?,
+        date_resolved,
+        resolution: row.get(start + 9)?,
+    })
+}
+
+fn maintenance_window_from_row(row: &Row, start: usize) -> rusqlite::Result<MaintenanceWindow> {
+    Ok(MaintenanceWindow {
+        uuid: row.get(start)?,
+        date_start: date_from_row(row, start + 1)?,
+        date_end: date_from_row(row, start + 2)?,
+        reason: row.get(start + 3)?,
+        instance: instance_from_row(row, start + 4)?,
+    })
+}
+
+fn status_change_from_row(row: &Row, start: usize) -> rusqlite::Result<StatusChange> {
+    let status = row.get::<usize, String>(start + 2)?;
+    let status = InstanceStatus::parse(&status).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 2,
+            rusqlite::types::Type::Text,
+            format!("Unknown instance status {}", status).into(),
+        )
+    })?;
+    Ok(StatusChange {
+        uuid: row.get(start)?,
+        instance: row.get(start + 1)?,
+        status,
+        date: date_from_row(row, start + 3)?,
+        loan: row.get(start + 4)?,
+        note: row.get(start + 5)?,
+    })
+}
+
+fn optional_date_from_row(row: &Row, index: usize) -> rusqlite::Result<Option<DateTime<Tz>>> {
+    match row.get::<usize, Option<String>>(index)? {
+        Some(_) => Ok(Some(date_from_row(row, index)?)),
+        None => Ok(None),
+    }
+}
+
+fn asset_info_from_row(row: &Row, start: usize) -> rusqlite::Result<AssetInfo> {
+    Ok(AssetInfo {
+        instance: row.get(start)?,
+        serial_number: row.get(start + 1)?,
+        purchase_date: optional_date_from_row(row, start + 2)?,
+        purchase_price: row.get(start + 3)?,
+        current_value: row.get(start + 4)?,
+        supplier: row.get(start + 5)?,
+        warranty_end: optional_date_from_row(row, start + 6)?,
+    })
+}
+
+/// Enum options are stored as a JSON array.
+fn attribute_from_row(row: &Row, start: usize) -> rusqlite::Result<AttributeDefinition> {
+    let conversion_failure = |index: usize, message: String| {
+        rusqlite::Error::FromSqlConversionFailure(
+            index,
+            rusqlite::types::Type::Text,
+            message.into(),
+        )
+    };
+    let kind = row.get::<usize, String>(start + 3)?;
+    let options = row.get::<usize, String>(start + 4)?;
+    let options =
+        serde_json::from_str(&options).map_err(|e| conversion_failure(start + 4, e.to_string()))?;
+    let kind = AttributeType::parse(&kind, options)
+        .ok_or_else(|| conversion_failure(start + 3, format!("Unknown attribute type {}", kind)))?;
+    Ok(AttributeDefinition {
+        uuid: row.get(start)?,
+        category: row.get(start + 1)?,
+        name: row.get(start + 2)?,
+        kind,
+        required: row.get(start + 5)?,
+    })
+}
+
+fn usage_reading_from_row(row: &Row, start: usize) -> rusqlite::Result<UsageReading> {
+    Ok(UsageReading {
+        uuid: row.get(start)?,
+        instance: row.get(start + 1)?,
+        counter: row.get(start + 2)?,
+        value: row.get(start + 3)?,
+        date: date_from_row(row, start + 4)?,
+    })
+}
+
+fn notification_from_row(row: &Row, start: usize) -> rusqlite::Result<Notification> {
+    let kind = row.get::<usize, String>(start + 2)?;
+    let kind = NotificationKind::parse(&kind).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 2,
+            rusqlite::types::Type::Text,
+            format!("Unknown notification kind {}", kind).into(),
+        )
+    })?;
+    let status = row.get::<usize, String>(start + 7)?;
+    let status = NotificationStatus::parse(&status).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 7,
+            rusqlite::types::Type::Text,
+            format!("Unknown notification status {}", status).into(),
+        )
+    })?;
+    let date_sent = match row.get::<usize, Option<String>>(start + 11)? {
+        Some(_) => Some(date_from_row(row, start + 11)?),
+        None => None,
+    };
+    Ok(Notification {
+        uuid: row.get(start)?,
+        user: row.get(start + 1)?,
+        kind,
+        reference: row.get(start + 3)?,
+        subject: row.get(start + 4)?,
+        body: row.get(start + 5)?,
+        created: date_from_row(row, start + 6)?,
+        status,
+        attempts: row.get(start + 8)?,
+        next_attempt: date_from_row(row, start + 9)?,
+        last_error: row.get(start + 10)?,
+        date_sent,
+    })
+}
+
+/// Audit log dates are stored in UTC with a fixed number of digits, so that
+/// they compare correctly as text.
+fn audit_date(date: &DateTime<Tz>) -> String {
+    date.with_timezone(&Utc)
+        .to_rfc3339_opts(SecondsFormat::Micros, true)
+}
+
+fn json_from_row(row: &Row, index: usize) -> rusqlite::Result<Option<serde_json::Value>> {
+    match row.get::<usize, Option<String>>(index)? {
+        Some(json) => serde_json::from_str(&json).map(Some).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(
+                index,
+                rusqlite::types::Type::Text,
+                Box::new(e),
+            )
+        }),
+        None => Ok(None),
+    }
+}
+
+fn audit_entry_from_row(row: &Row, start: usize) -> rusqlite::Result<AuditEntry> {
+    let entity_type = row.get::<usize, String>(start + 4)?;
+    let entity_type = EntityType::parse(&entity_type).ok_or_else(|| {
+        rusqlite::Error::FromSqlConversionFailure(
+            start + 4,
+            rusqlite::types::Type::Text,
+            format!("Unknown entity type {}", entity_type).into(),
+        )
+    })?;
+    Ok(AuditEntry {
+        id: row.get(start)?,
+        date: date_from_row(row, start + 1)?,
+        actor: row.get(start + 2)?,
+        operation: row.get(start + 3)?,
+        entity_type,
+        entity: row.get(start + 5)?,
+        before: json_from_row(row, start + 6)?,
+        after: json_from_row(row, start + 7)?,
+    })
+}
+
+/// Connection checked out from the pool, or the one of the open transaction.
+enum Checkout<'a> {
+    Pooled(PooledConnection<SqliteConnectionManager>),
+    Locked(MutexGuard<'a, PooledConnection<SqliteConnectionManager>>),
+}
+
+impl Deref for Checkout<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        match self {
+            Checkout::Pooled(connection) => connection,
+            Checkout::Locked(connection) => connection,
+        }
+    }
+}
+
+/// SQLite storage over a connection pool.
+///
+/// Every operation checks out its own connection, so readers run concurrently
+/// while writers are serialized by SQLite. File databases run in WAL mode.
+pub struct SqliteStorage {
+    pool: r2d2::Pool<SqliteConnectionManager>,
+    /// Connection of the open transaction, if this storage is bound to one
+    transaction: Option<Mutex<PooledConnection<SqliteConnectionManager>>>,
+}
+
+impl SqliteStorage {
+    /// Opens the database file like `open`, panicking if it can't be used.
+    pub fn new(file_name: &str) -> Self {
+        Self::open(file_name).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Opens the database file, creating the schema if the file is new and
+    /// migrating it if it is of an older schema version. Files of a newer
+    /// version are refused. An empty file name gives a private in-memory
+    /// database.
+    pub fn open(file_name: &str) -> Result<Self, DatabaseError> {
+        let init = |connection: &mut Connection| {
+            // Foreign keys are off by default and have to be enabled per connection
+            connection.pragma_update(None, "foreign_keys", true)?;
+            connection.busy_timeout(BUSY_TIMEOUT)
+        };
+
+        let pool = if file_name.is_empty() {
+            // Every connection to an in-memory database sees a database of its
+            // own, so the pool holds exactly one connection that is never
+            // recycled. A shared-cache URI would let the data vanish whenever
+            // the pool happens to close all of its connections.
+            let manager = SqliteConnectionManager::memory().with_init(init);
+            r2d2::Pool::builder()
+                .max_size(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .build(manager)?
+        } else {
+            let manager = SqliteConnectionManager::file(file_name).with_init(init);
+            r2d2::Pool::builder().build(manager)?
+        };
+
+        let storage = Self {
+            pool,
+            transaction: None,
+        };
+        if !file_name.is_empty() {
+            // WAL lets readers proceed while a write is in progress. The
+            // journal mode is stored in the file, so setting it once is enough.
+            storage
+                .connection()?
+                .pragma_update_and_check(None, "journal_mode", "WAL", |row| {
+                    row.get::<usize, String>(0)
+                })?;
+        }
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    /// Brings the schema of the database to `SCHEMA_VERSION`. A new database
+    /// gets `schema.sql` as is. An older one gets the tables of `MIGRATIONS`
+    /// rebuilt and then `schema.sql` run to add whatever is missing.
+    fn migrate(&self) -> Result<(), DatabaseError> {
+        let connection = self.connection()?;
+        let version = schema_version(&connection)?;
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        check_not_newer(version)?;
+
+        // Rebuilding a table drops it, which must not cascade to the rows
+        // referring to it. The pragma can't be changed inside a transaction.
+        connection.pragma_update(None, "foreign_keys", false)?;
+        let result = upgrade(&connection);
+        connection.pragma_update(None, "foreign_keys", true)?;
+        result
+    }
+
+    /// Copies the database to `path` with SQLite's online backup API. Other
+    /// connections keep reading and writing while the copy is made, and the
+    /// copy is a consistent view of the database. An existing file at
+    /// `path` is replaced once the copy is complete.
+    pub fn backup_to(&self, path: &Path) -> Result<(), DatabaseError> {
+        let temporary = temporary_path(path);
+        let _ = fs::remove_file(&temporary);
+        {
+            let source = self.connection()?;
+            let mut destination = Connection::open(&temporary)?;
+            let backup = Backup::new(&source, &mut destination)?;
+            backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_PAUSE, None)?;
+        }
+        fs::rename(&temporary, path).map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Checks that the file is an intact database of the current or an older
+    /// schema version. Fails with `Invalid` if it isn't.
+    pub fn validate_backup(path: &Path) -> Result<(), DatabaseError> {
+        let invalid = |e: rusqlite::Error| {
+            DatabaseError::Invalid(format!("{} is not a valid backup: {}", path.display(), e))
+        };
+
+        if !path.is_file() {
+            return Err(DatabaseError::NotFound(format!(
+                "Backup {} not found.",
+                path.display()
+            )));
+        }
+        let connection =
+            Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(invalid)?;
+        let check = connection
+            .query_row("PRAGMA integrity_check", [], |row| {
+                row.get::<usize, String>(0)
+            })
+            .map_err(invalid)?;
+        if check != "ok" {
+            return Err(DatabaseError::Invalid(format!(
+                "{} is damaged: {}",
+                path.display(),
+                check
+            )));
+        }
+        let version = connection
+            .query_row("PRAGMA user_version", [], |row| row.get::<usize, i64>(0))
+            .map_err(invalid)?;
+        // Older backups are migrated when the restored file is opened
+        if version > SCHEMA_VERSION {
+            return Err(DatabaseError::Invalid(format!(
+                "Backup {} has schema version {}, newer than the supported {}.",
+                path.display(),
+                version,
+                SCHEMA_VERSION
+            )));
+        }
+        Ok(())
+    }
+
+    /// Replaces the database file at `target` with the backup after
+    /// validating it. The backup is copied next to the target and renamed
+    /// over it, so the target is either the old or the new database. Nothing
+    /// may have the target open while it is restored.
+    pub fn restore(backup: &Path, target: &Path) -> Result<(), DatabaseError> {
+        Self::validate_backup(backup)?;
+
+        let temporary = temporary_path(target);
+        fs::copy(backup, &temporary).map_err(io_error)?;
+        // The WAL of the old database would otherwise be replayed onto the
+        // restored one
+        for suffix in ["-wal", "-shm"] {
+            let mut path = target.as_os_str().to_owned();
+            path.push(suffix);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    let _ = fs::remove_file(&temporary);
+                    return Err(io_error(e));
+                }
+                _ => {}
+            }
+        }
+        fs::rename(&temporary, target).map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Checks out a connection from the pool. It is returned when dropped.
+    /// Inside a transaction this is the connection of the transaction.
+    pub fn connection(&self) -> Result<impl Deref<Target = Connection> + '_, DatabaseError> {
+        match &self.transaction {
+            Some(connection) => Ok(Checkout::Locked(connection.lock().unwrap())),
+            None => Ok(Checkout::Pooled(self.pool.get()?)),
+        }
+    }
+
+    /// Runs `f` with a storage bound to an open transaction, committing when
+    /// `f` succeeds. Nested calls join the transaction that is already open.
+    fn in_transaction<T>(
+        &self,
+        f: impl FnOnce(&SqliteStorage) -> Result<T, DatabaseError>,
+    ) -> Result<T, DatabaseError> {
+        if self.transaction.is_some() {
+            return f(self);
+        }
+
+        // Immediate transactions take the write lock up front, so whatever
+        // `f` reads can't change before it commits. Returning early drops
+        // the storage, which rolls the transaction back.
+        let storage = SqliteStorage {
+            pool: self.pool.clone(),
+            transaction: Some(Mutex::new(self.pool.get()?)),
+        };
+        storage.connection()?.execute_batch("BEGIN IMMEDIATE")?;
+        let result = f(&storage)?;
+        storage.connection()?.execute_batch("COMMIT")?;
+        Ok(result)
+    }
+}
+
+impl Drop for SqliteStorage {
+    /// Rolls back a transaction that was not committed, so the connection
+    /// goes back to the pool clean.
+    fn drop(&mut self) {
+        if let Some(connection) = self.transaction.as_mut() {
+            let connection = connection.get_mut().unwrap_or_else(|e| e.into_inner());
+            if !connection.is_autocommit() {
+                let _ = connection.execute_batch("ROLLBACK");
+            }
+        }
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn transaction(
+        &self,
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), DatabaseError>,
+    ) -> Result<(), DatabaseError> {
+        self.in_transaction(|storage| f(storage))
+    }
+
+    fn backup_to(&self, path: &Path) -> Result<(), DatabaseError> {
+        SqliteStorage::backup_to(self, path)
+    }
+
+    fn get_users(&self) -> Result<Vec<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                user.uuid,
+                user.name
+            FROM user",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let users = statement
+            .query_map([], |row| user_from_row(row, 0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    fn get_user(&self, uuid: Uuid) -> Result<Option<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                user.uuid,
+                user.name
+            FROM user
+            WHERE user.uuid = ?1",
+        );
+        let connection = self.connection()?;
+        let user = connection
+            .query_row(&query, params![uuid], |row| user_from_row(row, 0))
+            .optional()?;
+        Ok(user)
+    }
+
+    fn get_user_by_name(&self, name: &str) -> Result<Option<User>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                user.uuid,
+                user.name
+            FROM user
+            WHERE user.name = ?1",
+        );
+        let connection = self.connection()?;
+        let user = connection
+            .query_row(&query, params![name], |row| user_from_row(row, 0))
+            .optional()?;
+        Ok(user)
+    }
+
+    fn insert_user(&self, uuid: Uuid, name: &str) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                user (uuid, name)
+            VALUES
+                (?1, ?2)",
+        );
+        self.connection()?.execute(&query, params![uuid, name])?;
+        Ok(())
+    }
+
+    fn delete_user(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM user
+            WHERE user.uuid = ?1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, params![uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("User not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_user_email(&self, user: Uuid) -> Result<Option<String>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                user_email.email
+            FROM user_email
+            WHERE user_email.user = ?1",
+        );
+        let email = self
+            .connection()?
+            .query_row(&query, params![user], |row| row.get(0))
+            .optional()?;
+        Ok(email)
+    }
+
+    fn set_user_email(&self, user: Uuid, email: &str) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                user_email (user, email)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT (user) DO UPDATE SET email = excluded.email",
+        );
+        self.connection()?.execute(&query, params![user, email])?;
+        Ok(())
+    }
+
+    fn get_categories(&self, supercategory: Option<Uuid>) -> Result<Vec<Category>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM category",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = supercategory {
+            query.push_str(" WHERE category.supercategory = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let categories = statement
+            .query_map(params_from_iter(query_params), |row| {
+                category_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(categories)
+    }
+
+    fn get_category(&self, name: &str) -> Result<Option<Category>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM category
+            WHERE category.name = ?1",
+        );
+        let connection = self.connection()?;
+        let category = connection
+            .query_row(&query, params![name], |row| category_from_row(row, 0))
+            .optional()?;
+        Ok(category)
+    }
+
+    fn insert_category(
+        &self,
+        uuid: Uuid,
+        name: &str,
+        supercategory: Option<Uuid>,
+    ) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO category (uuid, name, supercategory) VALUES (?1, ?2, ?3)",
+            params![uuid, name, supercategory],
+        )?;
+        Ok(())
+    }
+
+    fn delete_category(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM category
+            WHERE category.uuid = ?1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, params![uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Category not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_late_fee_rate(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                late_fee_rate.daily_rate
+            FROM late_fee_rate
+            WHERE late_fee_rate.category = ?1",
+        );
+        let connection = self.connection()?;
+        let rate = connection
+            .query_row(&query, params![category], |row| row.get(0))
+            .optional()?;
+        Ok(rate)
+    }
+
+    fn set_late_fee_rate(&self, category: Uuid, daily_rate: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                late_fee_rate (category, daily_rate)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT (category) DO UPDATE SET daily_rate = excluded.daily_rate",
+        );
+        self.connection()?
+            .execute(&query, params![category, daily_rate])?;
+        Ok(())
+    }
+
+    fn get_products(&self, category: Option<Uuid>) -> Result<Vec<Product>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = category {
+            query.push_str(" WHERE product.category = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let products = statement
+            .query_map(params_from_iter(query_params), |row| {
+                product_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(products)
+    }
+
+    fn get_product(&self, uuid: Uuid) -> Result<Option<Product>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid
+            WHERE product.uuid = ?1",
+        );
+        let connection = self.connection()?;
+        let product = connection
+            .query_row(&query, params![uuid], |row| product_from_row(row, 0))
+            .optional()?;
+        Ok(product)
+    }
+
+    fn get_product_by_name(&self, name: &str) -> Result<Option<Product>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM product
+                INNER JOIN category ON product.category = category.uuid
+            WHERE product.name = ?1",
+        );
+        let connection = self.connection()?;
+        let product = connection
+            .query_row(&query, params![name], |row| product_from_row(row, 0))
+            .optional()?;
+        Ok(product)
+    }
+
+    fn insert_product(&self, uuid: Uuid, name: &str, category: Uuid) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO product (uuid, name, category) VALUES (?1, ?2, ?3)",
+            params![uuid, name, category],
+        )?;
+        Ok(())
+    }
+
+    fn delete_product(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM product
+            WHERE product.uuid = ?1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, params![uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Product not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_attributes(
+        &self,
+        category: Option<Uuid>,
+    ) -> Result<Vec<AttributeDefinition>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                attribute.uuid,
+                attribute.category,
+                attribute.name,
+                attribute.kind,
+                attribute.options,
+                attribute.required
+            FROM attribute",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = category {
+            query.push_str(" WHERE attribute.category = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY attribute.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let attributes = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                attribute_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(attributes)
+    }
+
+    fn insert_attribute(&self, attribute: &AttributeDefinition) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                attribute (uuid, category, name, kind, options, required)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)",
+        );
+        let options = serde_json::to_string(attribute.kind.options()).unwrap();
+        self.connection()?.execute(
+            &query,
+            params![
+                attribute.uuid,
+                attribute.category,
+                attribute.name,
+                attribute.kind.as_str(),
+                options,
+                attribute.required,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_attribute(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM attribute
+            WHERE attribute.uuid = ?1",
+        );
+        let removed = self.connection()?.execute(&query, params![uuid])?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Attribute not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_attribute_values(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<AttributeValue>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product_attribute.product,
+                product_attribute.attribute,
+                product_attribute.value
+            FROM product_attribute",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE product_attribute.product = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let values = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                Ok(AttributeValue {
+                    product: row.get(0)?,
+                    attribute: row.get(1)?,
+                    value: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(values)
+    }
+
+    fn set_attribute_values(
+        &self,
+        product: Uuid,
+        values: &[AttributeValue],
+    ) -> Result<(), DatabaseError> {
+        let connection = self.connection()?;
+        connection.execute(
+            "DELETE FROM product_attribute
+            WHERE product_attribute.product = ?1",
+            params![product],
+        )?;
+        for value in values.iter() {
+            connection.execute(
+                "INSERT INTO
+                    product_attribute (product, attribute, value)
+                VALUES
+                    (?1, ?2, ?3)",
+                params![product, value.attribute, value.value],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn get_product_relations(
+        &self,
+        product: Option<Uuid>,
+    ) -> Result<Vec<ProductRelation>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                product_relation.product,
+                product_relation.related,
+                product_relation.kind
+            FROM product_relation",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE product_relation.product = ?1 OR product_relation.related = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY product_relation.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let relations = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                let kind = row.get::<usize, String>(2)?;
+                let kind = ProductRelationKind::parse(&kind).ok_or_else(|| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        2,
+                        rusqlite::types::Type::Text,
+                        format!("Unknown product relation {}", kind).into(),
+                    )
+                })?;
+                Ok(ProductRelation {
+                    product: row.get(0)?,
+                    related: row.get(1)?,
+                    kind,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(relations)
+    }
+
+    fn insert_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_relation (product, related, kind)
+            VALUES
+                (?1, ?2, ?3)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![relation.product, relation.related, relation.kind.as_str()],
+        )?;
+        Ok(())
+    }
+
+    fn delete_product_relation(&self, relation: &ProductRelation) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM product_relation
+            WHERE product_relation.product = ?1
+                AND product_relation.related = ?2
+                AND product_relation.kind = ?3",
+        );
+        let removed = self.connection()?.execute(
+            &query,
+            params![relation.product, relation.related, relation.kind.as_str()],
+        )?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Product relation not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_kits(&self) -> Result<Vec<Kit>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                kit.uuid,
+                kit.name,
+                kit_item.product,
+                kit_item.instance,
+                kit_item.quantity
+            FROM kit
+                LEFT JOIN kit_item ON kit_item.kit = kit.uuid
+            ORDER BY kit.name, kit_item.position",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let mut rows = statement.query([])?;
+
+        // Combine rows of the same kit
+        let mut kits: Vec<Kit> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let uuid: Uuid = row.get(0)?;
+            if kits.last().is_none_or(|kit| kit.uuid != uuid) {
+                kits.push(Kit {
+                    uuid,
+                    name: row.get(1)?,
+                    items: Vec::new(),
+                });
+            }
+            let item = match (row.get(2)?, row.get(3)?) {
+                (Some(product), _) => KitItem::Product {
+                    product,
+                    quantity: row.get(4)?,
+                },
+                (None, Some(instance)) => KitItem::Instance { instance },
+                (None, None) => continue,
+            };
+            kits.last_mut().unwrap().items.push(item);
+        }
+        Ok(kits)
+    }
+
+    fn insert_kit(&self, kit: &Kit) -> Result<(), DatabaseError> {
+        let connection = self.connection()?;
+        connection.execute(
+            "INSERT INTO
+                kit (uuid, name)
+            VALUES
+                (?1, ?2)",
+            params![kit.uuid, kit.name],
+        )?;
+        for (position, item) in kit.items.iter().enumerate() {
+            let (product, instance, quantity) = match item {
+                KitItem::Product { product, quantity } => (Some(product), None, *quantity),
+                KitItem::Instance { instance } => (None, Some(instance), 1),
+            };
+            connection.execute(
+                "INSERT INTO
+                    kit_item (kit, position, product, instance, quantity)
+                VALUES
+                    (?1, ?2, ?3, ?4, ?5)",
+                params![kit.uuid, position as i64, product, instance, quantity],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn delete_kit(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM kit
+            WHERE kit.uuid = ?1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, params![uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound("Kit not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_product_price(&self, product: Uuid) -> Result<Option<ProductPrice>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product_price.product,
+                product_price.per_day,
+                product_price.per_weekend,
+                product_price.per_week
+            FROM product_price
+            WHERE product_price.product = ?1",
+        );
+        let connection = self.connection()?;
+        let price = connection
+            .query_row(&query, params![product], |row| {
+                Ok(ProductPrice {
+                    product: row.get(0)?,
+                    per_day: row.get(1)?,
+                    per_weekend: row.get(2)?,
+                    per_week: row.get(3)?,
+                })
+            })
+            .optional()?;
+        Ok(price)
+    }
+
+    fn set_product_price(&self, price: &ProductPrice) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_price (product, per_day, per_weekend, per_week)
+            VALUES
+                (?1, ?2, ?3, ?4)
+            ON CONFLICT (product) DO UPDATE SET
+                per_day = excluded.per_day,
+                per_weekend = excluded.per_weekend,
+                per_week = excluded.per_week",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                price.product,
+                price.per_day,
+                price.per_weekend,
+                price.per_week
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_product_deposit(&self, product: Uuid) -> Result<Option<i64>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                product_deposit.amount
+            FROM product_deposit
+            WHERE product_deposit.product = ?1",
+        );
+        let connection = self.connection()?;
+        let amount = connection
+            .query_row(&query, params![product], |row| row.get(0))
+            .optional()?;
+        Ok(amount)
+    }
+
+    fn set_product_deposit(&self, product: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                product_deposit (product, amount)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT (product) DO UPDATE SET amount = excluded.amount",
+        );
+        self.connection()?
+            .execute(&query, params![product, amount])?;
+        Ok(())
+    }
+
+    fn get_category_deposit(&self, category: Uuid) -> Result<Option<i64>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                category_deposit.amount
+            FROM category_deposit
+            WHERE category_deposit.category = ?1",
+        );
+        let connection = self.connection()?;
+        let amount = connection
+            .query_row(&query, params![category], |row| row.get(0))
+            .optional()?;
+        Ok(amount)
+    }
+
+    fn set_category_deposit(&self, category: Uuid, amount: i64) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                category_deposit (category, amount)
+            VALUES
+                (?1, ?2)
+            ON CONFLICT (category) DO UPDATE SET amount = excluded.amount",
+        );
+        self.connection()?
+            .execute(&query, params![category, amount])?;
+        Ok(())
+    }
+
+    fn get_instances(&self, product: Option<Uuid>) -> Result<Vec<Instance>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM instance
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = product {
+            query.push_str(" WHERE instance.product = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let instances = statement
+            .query_map(params_from_iter(query_params), |row| {
+                instance_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(instances)
+    }
+
+    fn get_instance(&self, uuid: Uuid) -> Result<Option<Instance>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM instance
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid
+            WHERE instance.uuid = ?1",
+        );
+        let connection = self.connection()?;
+        let instance = connection
+            .query_row(&query, params![uuid], |row| instance_from_row(row, 0))
+            .optional()?;
+        Ok(instance)
+    }
+
+    fn insert_instance(
+        &self,
+        uuid: Uuid,
+        identifier: &str,
+        product: Uuid,
+    ) -> Result<(), DatabaseError> {
+        self.connection()?.execute(
+            "INSERT INTO instance (uuid, identifier, product) VALUES (?1, ?2, ?3)",
+            params![uuid, identifier, product],
+        )?;
+        Ok(())
+    }
+
+    /// Get loans from loan_view
+    /// Transform dates to Helsinki timezone
+    fn get_loans(&self, params: &LoanQueryParams) -> Result<Vec<Loan>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_uuid,
+                loan_date_start,
+                loan_date_end,
+                loan_accepted,
+                loan_description,
+                user_uuid,
+                user_name,
+                instance_uuid,
+                instance_identifier,
+                product_uuid,
+                product_name,
+                category_uuid,
+                category_name,
+                category_supercategory,
+                loan_price,
+                loan_rejected
+            FROM loan_view
+            WHERE 1=1",
+        );
+
+        let mut query_params: Vec<&(dyn rusqlite::ToSql + Sync)> = Vec::new();
+        let mut date_strings: Vec<String> = Vec::new();
+
+        if !params.include_rejected {
+            query.push_str(" AND NOT loan_rejected");
+        }
+        if let Some(ref id) = params.loan_uuid {
+            query.push_str(" AND loan_uuid = ?");
+            query_params.push(id);
+        }
+        if let Some(ref id) = params.instance_uuid {
+            query.push_str(" AND instance_uuid = ?");
+            query_params.push(id);
+        }
+        if let Some(ref accepted) = params.loan_accepted {
+            query.push_str(" AND loan_accepted = ?");
+            query_params.push(accepted);
+        }
+        if let Some(ref id) = params.user_uuid {
+            query.push_str(" AND user_uuid = ?");
+            query_params.push(id);
+        }
+        if let Some(ref id) = params.product_uuid {
+            query.push_str(" AND product_uuid = ?");
+            query_params.push(id);
+        }
+        if let Some(ref id) = params.category_uuid {
+            query.push_str(" AND category_uuid = ?");
+            query_params.push(id);
+        }
+        // The dates are stored with the offset they were given in, so they
+        // are compared as instants rather than as text
+        if let Some(ref start) = params.date_start {
+            date_strings.push(start.to_rfc3339());
+            query.push_str(" AND julianday(loan_date_end) >= julianday(?)");
+        }
+        if let Some(ref end) = params.date_end {
+            date_strings.push(end.to_rfc3339());
+            query.push_str(" AND julianday(loan_date_start) <= julianday(?)");
+        }
+        if let Some(ref after) = params.due_after {
+            date_strings.push(after.to_rfc3339());
+            query.push_str(" AND julianday(loan_date_end) >= julianday(?)");
+        }
+        if let Some(ref before) = params.due_before {
+            date_strings.push(before.to_rfc3339());
+            query.push_str(" AND julianday(loan_date_end) < julianday(?)");
+        }
+        if let Some(returned) = params.returned {
+            if returned {
+                query.push_str(" AND instance_date_returned IS NOT NULL");
+            } else {
+                query.push_str(" AND instance_date_returned IS NULL");
+            }
+        }
+
+        for date in date_strings.iter() {
+            query_params.push(date);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement.query_map(params_from_iter(query_params.iter()), |row| {
+            Ok(Loan {
+                uuid: row.get(0)?,
+                user: user_from_row(row, 5)?,
+                date_start: date_from_row(row, 1)?,
+                date_end: date_from_row(row, 2)?,
+                accepted: row.get(3)?,
+                rejected: row.get(15)?,
+                description: row.get(4)?,
+                instaces: vec![instance_from_row(row, 7)?],
+                price: row.get(14)?,
+                compatibility: Compatibility::default(),
+                kits: Vec::new(),
+            })
+        })?;
+
+        // Combine rows of the same loan
+        let mut loans: Vec<Loan> = Vec::new();
+        'rows: for row in rows {
+            let row = row?;
+            for loan in loans.iter_mut() {
+                if loan.uuid == row.uuid {
+                    loan.instaces.extend(row.instaces);
+                    continue 'rows;
+                }
+            }
+            loans.push(row);
+        }
+
+        Ok(loans)
+    }
+
+    fn insert_loan(&self, loan: &NewLoan) -> Result<(), DatabaseError> {
+        let add_loan_query = String::from(
+            "INSERT INTO
+                loan (uuid, user, date_start, date_end, accepted, rejected, description, price)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        );
+        let add_loan_instance_query = String::from(
+            "INSERT INTO
+                loan_instances (loan, instance)
+            VALUES
+                (?1, ?2)",
+        );
+        let add_loan_price_query = String::from(
+            "INSERT INTO
+                loan_price (loan, instance, description, price, discount)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)",
+        );
+
+        // The loan and its instances are inserted together
+        self.in_transaction(|storage| {
+            let connection = storage.connection()?;
+            connection.execute(
+                &add_loan_query,
+                params![
+                    loan.uuid,
+                    loan.user,
+                    loan.date_start.to_rfc3339(),
+                    loan.date_end.to_rfc3339(),
+                    loan.accepted,
+                    loan.rejected,
+                    loan.description,
+                    loan.price,
+                ],
+            )?;
+            for instance in loan.instances.iter() {
+                connection.execute(&add_loan_instance_query, params![loan.uuid, instance])?;
+            }
+            for line in loan.price_lines.iter() {
+                connection.execute(
+                    &add_loan_price_query,
+                    params![
+                        loan.uuid,
+                        line.instance,
+                        line.description,
+                        line.price,
+                        line.discount,
+                    ],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn set_loan_accepted(&self, loan: Uuid, accepted: bool) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan
+            SET accepted = ?2
+            WHERE loan.uuid = ?1",
+        );
+        let updated = self
+            .connection()?
+            .execute(&query, params![loan, accepted])?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn set_loan_rejected(&self, loan: Uuid, rejected: bool) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan
+            SET rejected = ?2
+            WHERE loan.uuid = ?1",
+        );
+        let updated = self
+            .connection()?
+            .execute(&query, params![loan, rejected])?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Loan not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_loan_price_lines(&self, loan: Uuid) -> Result<Vec<QuoteLine>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                loan_price.instance,
+                loan_price.description,
+                loan_price.price,
+                loan_price.discount
+            FROM loan_price
+            WHERE loan_price.loan = ?1",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let lines = statement
+            .query_map(params![loan], |row| {
+                Ok(QuoteLine {
+                    instance: row.get(0)?,
+                    description: row.get(1)?,
+                    price: row.get(2)?,
+                    discount: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(lines)
+    }
+
+    fn get_loan_kit_instances(
+        &self,
+        loan: Option<Uuid>,
+    ) -> Result<Vec<LoanKitInstance>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_kit.loan,
+                loan_kit.instance,
+                loan_kit.kit
+            FROM loan_kit",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE loan_kit.loan = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY loan_kit.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let rows = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                Ok(LoanKitInstance {
+                    loan: row.get(0)?,
+                    instance: row.get(1)?,
+                    kit: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(rows)
+    }
+
+    fn insert_loan_kit_instance(&self, row: &LoanKitInstance) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                loan_kit (loan, instance, kit)
+            VALUES
+                (?1, ?2, ?3)",
+        );
+        self.connection()?
+            .execute(&query, params![row.loan, row.instance, row.kit])?;
+        Ok(())
+    }
+
+    fn check_in(
+        &self,
+        loan: Uuid,
+        instance: Uuid,
+        date: DateTime<Tz>,
+    ) -> Result<(), DatabaseError> {
+        let updated = self.connection()?.execute(
+            "UPDATE loan_instances SET date_returned = ?3
+            WHERE loan = ?1 AND instance = ?2 AND date_returned IS NULL",
+            params![loan, instance, date.to_rfc3339()],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Loaned instance not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_check_ins(&self) -> Result<Vec<CheckIn>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                loan_instances.loan,
+                loan_instances.instance,
+                loan_instances.date_returned
+            FROM loan_instances
+            WHERE loan_instances.date_returned IS NOT NULL",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let check_ins = statement
+            .query_map([], |row| {
+                Ok(CheckIn {
+                    loan: row.get(0)?,
+                    instance: row.get(1)?,
+                    date: date_from_row(row, 2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(check_ins)
+    }
+
+    fn get_membership_types(&self) -> Result<Vec<MembershipType>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                membership_type.uuid,
+                membership_type.type,
+                membership_type.rental_discount
+            FROM membership_type",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let types = statement
+            .query_map([], |row| {
+                Ok(MembershipType {
+                    uuid: row.get(0)?,
+                    name: row.get(1)?,
+                    rental_discount: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(types)
+    }
+
+    fn insert_membership_type(
+        &self,
+        membership_type: &MembershipType,
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                membership_type (uuid, type, rental_discount)
+            VALUES
+                (?1, ?2, ?3)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                membership_type.uuid,
+                membership_type.name,
+                membership_type.rental_discount
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_membership_payments(
+        &self,
+        user: Option<Uuid>,
+    ) -> Result<Vec<MembershipPayment>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                membership_payments.uuid,
+                membership_payments.user,
+                membership_payments.membership_type,
+                membership_payments.price,
+                membership_payments.date_start,
+                membership_payments.date_end
+            FROM membership_payments",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE membership_payments.user = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let payments = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                Ok(MembershipPayment {
+                    uuid: row.get(0)?,
+                    user: row.get(1)?,
+                    membership_type: row.get(2)?,
+                    price: row.get(3)?,
+                    date_start: naive_date_from_row(row, 4)?,
+                    date_end: naive_date_from_row(row, 5)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(payments)
+    }
+
+    fn insert_membership_payment(&self, payment: &MembershipPayment) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                membership_payments (uuid, user, membership_type, price, date_start, date_end)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                payment.uuid,
+                payment.user,
+                payment.membership_type,
+                payment.price,
+                payment.date_start.to_string(),
+                payment.date_end.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_ledger_entries(&self, user: Option<Uuid>) -> Result<Vec<LedgerEntry>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                ledger.uuid,
+                ledger.user,
+                ledger.date,
+                ledger.kind,
+                ledger.amount,
+                ledger.loan,
+                ledger.description
+            FROM ledger",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE ledger.user = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let entries = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                ledger_entry_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    fn insert_ledger_entry(&self, entry: &LedgerEntry) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                ledger (uuid, user, date, kind, amount, loan, description)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                entry.uuid,
+                entry.user,
+                entry.date.to_rfc3339(),
+                entry.kind.as_str(),
+                entry.amount,
+                entry.loan,
+                entry.description,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_invoices(&self, loan: Option<Uuid>) -> Result<Vec<Invoice>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                invoice.number,
+                invoice.loan,
+                invoice.date,
+                invoice.total
+            FROM invoice",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE invoice.loan = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let invoices = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                Ok(Invoice {
+                    number: row.get(0)?,
+                    loan: row.get(1)?,
+                    date: date_from_row(row, 2)?,
+                    total: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(invoices)
+    }
+
+    fn insert_invoice(
+        &self,
+        invoice: &Invoice,
+        lines: &[InvoiceLine],
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                invoice (number, loan, date, total)
+            VALUES
+                (?1, ?2, ?3, ?4)",
+        );
+        let line_query = String::from(
+            "INSERT INTO
+                invoice_line (invoice, position, description, amount)
+            VALUES
+                (?1, ?2, ?3, ?4)",
+        );
+
+        // The invoice and its lines are inserted together
+        self.in_transaction(|storage| {
+            let connection = storage.connection()?;
+            connection.execute(
+                &query,
+                params![
+                    invoice.number,
+                    invoice.loan,
+                    invoice.date.to_rfc3339(),
+                    invoice.total
+                ],
+            )?;
+            for (position, line) in lines.iter().enumerate() {
+                connection.execute(
+                    &line_query,
+                    params![invoice.number, position, line.description, line.amount],
+                )?;
+            }
+            Ok(())
+        })
+    }
+
+    fn get_invoice_lines(&self, invoice: i64) -> Result<Vec<InvoiceLine>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                invoice_line.description,
+                invoice_line.amount
+            FROM invoice_line
+            WHERE invoice_line.invoice = ?1
+            ORDER BY invoice_line.position",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let lines = statement
+            .query_map(params![invoice], |row| {
+                Ok(InvoiceLine {
+                    description: row.get(0)?,
+                    amount: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(lines)
+    }
+
+    fn get_deposits(&self, loan: Option<Uuid>) -> Result<Vec<Deposit>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                loan_deposit.loan,
+                loan_deposit.amount,
+                loan_deposit.date_received,
+                loan_deposit.status,
+                loan_deposit.date_settled,
+                loan_deposit.reason
+            FROM loan_deposit",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = loan {
+            query.push_str(" WHERE loan_deposit.loan = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let deposits = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                deposit_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(deposits)
+    }
+
+    fn insert_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                loan_deposit (loan, amount, date_received, status, date_settled, reason)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                deposit.loan,
+                deposit.amount,
+                deposit.date_received.to_rfc3339(),
+                deposit.status.as_str(),
+                deposit.date_settled.map(|date| date.to_rfc3339()),
+                deposit.reason,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_deposit(&self, deposit: &Deposit) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE loan_deposit
+            SET status = ?2, date_settled = ?3, reason = ?4
+            WHERE loan_deposit.loan = ?1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            params![
+                deposit.loan,
+                deposit.status.as_str(),
+                deposit.date_settled.map(|date| date.to_rfc3339()),
+                deposit.reason,
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound("Deposit not found.".to_string()));
+        }
+        Ok(())
+    }
+
+    fn get_condition_reports(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<ConditionReport>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                condition_report.uuid,
+                condition_report.instance,
+                condition_report.loan,
+                condition_report.severity,
+                condition_report.description,
+                condition_report.reporter,
+                condition_report.date,
+                condition_report.out_of_service,
+                condition_report.date_resolved,
+                condition_report.resolution
+            FROM condition_report",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE condition_report.instance = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY julianday(condition_report.date), condition_report.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let reports = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                condition_report_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(reports)
+    }
+
+    fn insert_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                condition_report (uuid, instance, loan, severity, description, reporter, date,
+                    out_of_service, date_resolved, resolution)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                report.uuid,
+                report.instance,
+                report.loan,
+                report.severity.as_str(),
+                report.description,
+                report.reporter,
+                report.date.to_rfc3339(),
+                report.out_of_service,
+                report.date_resolved.map(|date| date.to_rfc3339()),
+                report.resolution,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_condition_report(&self, report: &ConditionReport) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE condition_report
+            SET date_resolved = ?2, resolution = ?3
+            WHERE condition_report.uuid = ?1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            params![
+                report.uuid,
+                report.date_resolved.map(|date| date.to_rfc3339()),
+                report.resolution,
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Condition report not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_maintenance_windows(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<MaintenanceWindow>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                maintenance_window.uuid,
+                maintenance_window.date_start,
+                maintenance_window.date_end,
+                maintenance_window.reason,
+                instance.uuid,
+                instance.identifier,
+                product.uuid,
+                product.name,
+                category.uuid,
+                category.name,
+                category.supercategory
+            FROM maintenance_window
+                INNER JOIN instance ON maintenance_window.instance = instance.uuid
+                INNER JOIN product ON instance.product = product.uuid
+                INNER JOIN category ON product.category = category.uuid",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE maintenance_window.instance = ?1");
+            query_params.push(id);
+        }
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let windows = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                maintenance_window_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(windows)
+    }
+
+    fn insert_maintenance_window(
+        &self,
+        uuid: Uuid,
+        instance: Uuid,
+        date_start: DateTime<Tz>,
+        date_end: DateTime<Tz>,
+        reason: &str,
+    ) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                maintenance_window (uuid, instance, date_start, date_end, reason)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                uuid,
+                instance,
+                date_start.to_rfc3339(),
+                date_end.to_rfc3339(),
+                reason,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_maintenance_window(&self, uuid: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM maintenance_window
+            WHERE maintenance_window.uuid = ?1",
+        );
+        let removed = self
+            .connection()?
+            .execute(&query, params![uuid])
+            .map_err(map_delete_error)?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Maintenance window not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_status_changes(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<StatusChange>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                instance_status.uuid,
+                instance_status.instance,
+                instance_status.status,
+                instance_status.date,
+                instance_status.loan,
+                instance_status.note
+            FROM instance_status",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE instance_status.instance = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY julianday(instance_status.date), instance_status.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let changes = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                status_change_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(changes)
+    }
+
+    fn insert_status_change(&self, change: &StatusChange) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                instance_status (uuid, instance, status, date, loan, note)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                change.uuid,
+                change.instance,
+                change.status.as_str(),
+                change.date.to_rfc3339(),
+                change.loan,
+                change.note,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_asset_info(&self, instance: Uuid) -> Result<Option<AssetInfo>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_asset.instance,
+                instance_asset.serial_number,
+                instance_asset.purchase_date,
+                instance_asset.purchase_price,
+                instance_asset.current_value,
+                instance_asset.supplier,
+                instance_asset.warranty_end
+            FROM instance_asset
+            WHERE instance_asset.instance = ?1",
+        );
+        let connection = self.connection()?;
+        let asset = connection
+            .query_row(&query, params![instance], |row| asset_info_from_row(row, 0))
+            .optional()?;
+        Ok(asset)
+    }
+
+    fn get_asset_infos(&self) -> Result<Vec<AssetInfo>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_asset.instance,
+                instance_asset.serial_number,
+                instance_asset.purchase_date,
+                instance_asset.purchase_price,
+                instance_asset.current_value,
+                instance_asset.supplier,
+                instance_asset.warranty_end
+            FROM instance_asset",
+        );
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let assets = statement
+            .query_map([], |row| asset_info_from_row(row, 0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(assets)
+    }
+
+    fn set_asset_info(&self, asset: &AssetInfo) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                instance_asset (instance, serial_number, purchase_date, purchase_price,
+                    current_value, supplier, warranty_end)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT (instance) DO UPDATE SET
+                serial_number = excluded.serial_number,
+                purchase_date = excluded.purchase_date,
+                purchase_price = excluded.purchase_price,
+                current_value = excluded.current_value,
+                supplier = excluded.supplier,
+                warranty_end = excluded.warranty_end",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                asset.instance,
+                asset.serial_number,
+                asset.purchase_date.map(|date| date.to_rfc3339()),
+                asset.purchase_price,
+                asset.current_value,
+                asset.supplier,
+                asset.warranty_end.map(|date| date.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_instance_location(&self, instance: Uuid) -> Result<Option<String>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                instance_location.location
+            FROM instance_location
+            WHERE instance_location.instance = ?1",
+        );
+        let connection = self.connection()?;
+        let location = connection
+            .query_row(&query, params![instance], |row| row.get(0))
+            .optional()?;
+        Ok(location)
+    }
+
+    fn set_instance_location(
+        &self,
+        instance: Uuid,
+        location: Option<&str>,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.connection()?;
+        match location {
+            Some(location) => connection.execute(
+                "INSERT INTO
+                    instance_location (instance, location)
+                VALUES
+                    (?1, ?2)
+                ON CONFLICT (instance) DO UPDATE SET location = excluded.location",
+                params![instance, location],
+            )?,
+            None => connection.execute(
+                "DELETE FROM instance_location
+                WHERE instance_location.instance = ?1",
+                params![instance],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn get_depreciation(&self, category: Uuid) -> Result<Option<Depreciation>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                depreciation.method,
+                depreciation.rate
+            FROM depreciation
+            WHERE depreciation.category = ?1",
+        );
+        let connection = self.connection()?;
+        let depreciation = connection
+            .query_row(&query, params![category], |row| {
+                let method = row.get::<usize, String>(0)?;
+                let method = DepreciationMethod::parse(&method).ok_or_else(|| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        0,
+                        rusqlite::types::Type::Text,
+                        format!("Unknown depreciation method {}", method).into(),
+                    )
+                })?;
+                Ok(Depreciation {
+                    method,
+                    rate: row.get(1)?,
+                })
+            })
+            .optional()?;
+        Ok(depreciation)
+    }
+
+    fn set_depreciation(
+        &self,
+        category: Uuid,
+        depreciation: Option<&Depreciation>,
+    ) -> Result<(), DatabaseError> {
+        let connection = self.connection()?;
+        match depreciation {
+            Some(depreciation) => connection.execute(
+                "INSERT INTO
+                    depreciation (category, method, rate)
+                VALUES
+                    (?1, ?2, ?3)
+                ON CONFLICT (category) DO UPDATE SET
+                    method = excluded.method,
+                    rate = excluded.rate",
+                params![category, depreciation.method.as_str(), depreciation.rate],
+            )?,
+            None => connection.execute(
+                "DELETE FROM depreciation
+                WHERE depreciation.category = ?1",
+                params![category],
+            )?,
+        };
+        Ok(())
+    }
+
+    fn get_maintenance_rule(
+        &self,
+        product: Uuid,
+    ) -> Result<Option<MaintenanceRule>, DatabaseError> {
+        let query = String::from(
+            "SELECT
+                maintenance_rule.product,
+                maintenance_rule.loans,
+                maintenance_rule.days_out,
+                maintenance_rule.months
+            FROM maintenance_rule
+            WHERE maintenance_rule.product = ?1",
+        );
+        let connection = self.connection()?;
+        let rule = connection
+            .query_row(&query, params![product], |row| {
+                Ok(MaintenanceRule {
+                    product: row.get(0)?,
+                    loans: row.get(1)?,
+                    days_out: row.get(2)?,
+                    months: row.get(3)?,
+                })
+            })
+            .optional()?;
+        Ok(rule)
+    }
+
+    fn set_maintenance_rule(&self, rule: &MaintenanceRule) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                maintenance_rule (product, loans, days_out, months)
+            VALUES
+                (?1, ?2, ?3, ?4)
+            ON CONFLICT (product) DO UPDATE SET
+                loans = excluded.loans,
+                days_out = excluded.days_out,
+                months = excluded.months",
+        );
+        self.connection()?.execute(
+            &query,
+            params![rule.product, rule.loans, rule.days_out, rule.months],
+        )?;
+        Ok(())
+    }
+
+    fn delete_maintenance_rule(&self, product: Uuid) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "DELETE FROM maintenance_rule
+            WHERE maintenance_rule.product = ?1",
+        );
+        let removed = self.connection()?.execute(&query, params![product])?;
+        if removed == 0 {
+            return Err(DatabaseError::NotFound(
+                "Maintenance rule not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_usage_readings(
+        &self,
+        instance: Option<Uuid>,
+    ) -> Result<Vec<UsageReading>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                usage_reading.uuid,
+                usage_reading.instance,
+                usage_reading.counter,
+                usage_reading.value,
+                usage_reading.date
+            FROM usage_reading",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = instance {
+            query.push_str(" WHERE usage_reading.instance = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY julianday(usage_reading.date), usage_reading.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let readings = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                usage_reading_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(readings)
+    }
+
+    fn insert_usage_reading(&self, reading: &UsageReading) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                usage_reading (uuid, instance, counter, value, date)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                reading.uuid,
+                reading.instance,
+                reading.counter,
+                reading.value,
+                reading.date.to_rfc3339(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn get_notifications(&self, user: Option<Uuid>) -> Result<Vec<Notification>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                notification.uuid,
+                notification.user,
+                notification.kind,
+                notification.reference,
+                notification.subject,
+                notification.body,
+                notification.created,
+                notification.status,
+                notification.attempts,
+                notification.next_attempt,
+                notification.last_error,
+                notification.date_sent
+            FROM notification",
+        );
+        let mut query_params = Vec::new();
+        if let Some(ref id) = user {
+            query.push_str(" WHERE notification.user = ?1");
+            query_params.push(id);
+        }
+        query.push_str(" ORDER BY julianday(notification.created), notification.rowid");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let notifications = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                notification_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(notifications)
+    }
+
+    fn insert_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                notification (uuid, user, kind, reference, subject, body, created, status,
+                    attempts, next_attempt, last_error, date_sent)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                notification.uuid,
+                notification.user,
+                notification.kind.as_str(),
+                notification.reference,
+                notification.subject,
+                notification.body,
+                notification.created.to_rfc3339(),
+                notification.status.as_str(),
+                notification.attempts,
+                notification.next_attempt.to_rfc3339(),
+                notification.last_error,
+                notification.date_sent.map(|date| date.to_rfc3339()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn update_notification(&self, notification: &Notification) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "UPDATE notification
+            SET status = ?2, attempts = ?3, next_attempt = ?4, last_error = ?5, date_sent = ?6
+            WHERE notification.uuid = ?1",
+        );
+        let updated = self.connection()?.execute(
+            &query,
+            params![
+                notification.uuid,
+                notification.status.as_str(),
+                notification.attempts,
+                notification.next_attempt.to_rfc3339(),
+                notification.last_error,
+                notification.date_sent.map(|date| date.to_rfc3339()),
+            ],
+        )?;
+        if updated == 0 {
+            return Err(DatabaseError::NotFound(
+                "Notification not found.".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_audit_entries(
+        &self,
+        params: &AuditQueryParams,
+    ) -> Result<Vec<AuditEntry>, DatabaseError> {
+        let mut query = String::from(
+            "SELECT
+                audit_log.id,
+                audit_log.date,
+                audit_log.actor,
+                audit_log.operation,
+                audit_log.entity_type,
+                audit_log.entity,
+                audit_log.before,
+                audit_log.after
+            FROM audit_log
+            WHERE 1=1",
+        );
+
+        let entity_type = params.entity_type.map(|t| t.as_str());
+        let date_start = params.date_start.as_ref().map(audit_date);
+        let date_end = params.date_end.as_ref().map(audit_date);
+        let mut query_params: Vec<&(dyn rusqlite::ToSql + Sync)> = Vec::new();
+
+        if let Some(ref entity_type) = entity_type {
+            query.push_str(" AND audit_log.entity_type = ?");
+            query_params.push(entity_type);
+        }
+        if let Some(ref id) = params.entity {
+            query.push_str(" AND audit_log.entity = ?");
+            query_params.push(id);
+        }
+        if let Some(ref actor) = params.actor {
+            query.push_str(" AND audit_log.actor = ?");
+            query_params.push(actor);
+        }
+        if let Some(ref start) = date_start {
+            query.push_str(" AND audit_log.date >= ?");
+            query_params.push(start);
+        }
+        if let Some(ref end) = date_end {
+            query.push_str(" AND audit_log.date < ?");
+            query_params.push(end);
+        }
+        query.push_str(" ORDER BY audit_log.id");
+
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(&query)?;
+        let entries = statement
+            .query_map(params_from_iter(query_params.iter()), |row| {
+                audit_entry_from_row(row, 0)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(entries)
+    }
+
+    fn insert_audit_entry(&self, entry: &NewAuditEntry) -> Result<(), DatabaseError> {
+        let query = String::from(
+            "INSERT INTO
+                audit_log (date, actor, operation, entity_type, entity, before, after)
+            VALUES
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        );
+        self.connection()?.execute(
+            &query,
+            params![
+                audit_date(&entry.date),
+                entry.actor,
+                entry.operation,
+                entry.entity_type.as_str(),
+                entry.entity,
+                entry.before.as_ref().map(|json| json.to_string()),
+                entry.after.as_ref().map(|json| json.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+}
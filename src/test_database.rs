@@ -1,10 +1,94 @@
-#[allow(dead_code)]
-pub fn initialize_test_database(db_name: Option<&str>) -> crate::database::Database {
-    use crate::database::Database;
+// The test bodies below are only called from the generated #[test] functions
+#![allow(dead_code)]
+
+use crate::database::Database;
+
+/// Runs each test against every storage backend, on the same test data.
+/// The PostgreSQL tests are ignored unless asked for, see docs/Loaner.md:
+/// `LOANER_TEST_POSTGRES=... cargo test --features postgres -- --ignored`
+macro_rules! storage_tests {
+    ($($test:ident),* $(,)?) => {
+        mod sqlite {
+            $(
+                #[test]
+                fn $test() {
+                    super::$test(super::initialize_test_database(None));
+                }
+            )*
+        }
+
+        mod memory {
+            $(
+                #[test]
+                fn $test() {
+                    let db = super::Database::with_storage(crate::storage::MemoryStorage::new());
+                    super::$test(super::fill_test_database(db));
+                }
+            )*
+        }
 
+        #[cfg(feature = "postgres")]
+        mod postgres {
+            $(
+                #[test]
+                #[ignore = "needs a PostgreSQL server in LOANER_TEST_POSTGRES"]
+                fn $test() {
+                    let db = super::postgres_test_database();
+                    super::$test(super::fill_test_database(db));
+                }
+            )*
+        }
+    };
+}
+
+storage_tests!(
+    test_initialization,
+    test_users,
+    test_categories,
+    test_duplicate_categories,
+    test_add_loan,
+    test_add_overlapping_loan,
+    test_loan_filters,
+    test_loans,
+    test_add_loan_rollback,
+    test_referential_integrity,
+    test_schema_constraints,
+    test_shared_database,
+    test_check_in,
+    test_overdue_loans,
+    test_loan_dates_across_time_zones,
+    test_ledger,
+    test_late_fees,
+    test_balance_policy,
+    test_quotes,
+    test_invoices,
+    test_deposits,
+    test_loan_approval,
+    test_notification_delivery,
+    test_reminders,
+    test_calendars,
+    test_catalogue_csv,
+    test_snapshot,
+    test_audit_log,
+    test_condition_reports,
+    test_maintenance_windows,
+    test_maintenance_due,
+    test_instance_status,
+    test_asset_info,
+    test_insurance_report,
+    test_product_attributes,
+    test_product_relations,
+    test_kits,
+);
+
+#[allow(dead_code)]
+pub fn initialize_test_database(db_name: Option<&str>) -> Database {
     let db_name = db_name.unwrap_or("");
-    let db = Database::new(&db_name);
+    fill_test_database(Database::new(db_name))
+}
 
+/// Adds the test users and catalogue to an empty database.
+pub fn fill_test_database(db: Database) -> Database {
     let user_names = vec!["Alice", "Bob", "Charlie"];
     for user_name in &user_names {
         let _ = db.add_user(user_name);
@@ -34,15 +118,57 @@ pub fn initialize_test_database(db_name: Option<&str>) -> crate::database::Datab
     db
 }
 
-#[test]
-fn test_initialization() {
-    let _db = initialize_test_database(None);
+/// Connection string for a fresh schema on the PostgreSQL server given in
+/// `LOANER_TEST_POSTGRES`, e.g. `host=localhost user=postgres`. The
+/// PostgreSQL tests are ignored by default, run them with `--ignored`
+/// or `--include-ignored`.
+#[cfg(feature = "postgres")]
+pub fn postgres_test_params() -> String {
+    use std::sync::Mutex;
+
+    // CREATE EXTENSION is not safe to run concurrently
+    static SETUP: Mutex<()> = Mutex::new(());
+
+    let params = std::env::var("LOANER_TEST_POSTGRES")
+        .expect("LOANER_TEST_POSTGRES must name the PostgreSQL server to test against");
+
+    let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+    {
+        let _setup = SETUP.lock().unwrap();
+        let mut client = ::postgres::Client::connect(&params, ::postgres::NoTls).unwrap();
+        client
+            .batch_execute(&format!(
+                "CREATE EXTENSION IF NOT EXISTS btree_gist;
+                CREATE SCHEMA {};",
+                schema
+            ))
+            .unwrap();
+    }
+
+    format!("{} options='-c search_path={},public'", params, schema)
 }
 
-#[test]
-fn test_users() {
-    let db = initialize_test_database(None);
+#[cfg(feature = "postgres")]
+pub fn postgres_test_database() -> Database {
+    Database::with_storage(crate::storage::PostgresStorage::new(&postgres_test_params()))
+}
+
+/// Unique database file name in the system temp directory.
+pub fn temporary_database_path() -> String {
+    let path = std::env::temp_dir().join(format!("loaner-{}.db", uuid::Uuid::new_v4()));
+    path.to_str().unwrap().to_string()
+}
+
+/// Removes a database file along with its WAL and shared memory files.
+pub fn remove_database_files(path: &str) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path, suffix));
+    }
+}
+
+fn test_initialization(_db: Database) {}
 
+fn test_users(db: Database) {
     let user_count = db.get_users().len();
 
     assert_eq!(user_count, 3);
@@ -68,10 +194,7 @@ fn test_users() {
     assert!(users[5].name == "Frank");
 }
 
-#[test]
-fn test_categories() {
-    let db = initialize_test_database(None);
-
+fn test_categories(db: Database) {
     let mut categories = db.get_categories(None);
     for category in &categories {
         println!("Category: {}", category.name);
@@ -87,7 +210,7 @@ fn test_categories() {
 
     for category_name in &new_category_names {
         let result = db.add_category(category_name, Some(catalogue_uuid));
-        assert!(!result.is_err());
+        assert!(result.is_ok());
     }
 
     categories = db.get_categories(None);
@@ -110,10 +233,7 @@ fn test_categories() {
     assert!(result.is_ok());
 }
 
-#[test]
-fn test_duplicate_categories() {
-    let db = initialize_test_database(None);
-
+fn test_duplicate_categories(db: Database) {
     let new_category_names = vec!["Cameras", "Lenses"];
 
     let catalogue_uuid = db.get_category("Catalogue").unwrap().uuid;
@@ -132,10 +252,7 @@ fn test_duplicate_categories() {
     assert!(db.add_category("RF-S", Some(lenses_uuid)).is_err());
 }
 
-#[test]
-fn test_add_loan() {
-    let db = initialize_test_database(None);
-
+fn test_add_loan(db: Database) {
     let user = &db.get_users()[0];
     let product = &db.get_product_by_name("Canon R6").unwrap();
     let instance = &db.get_instances(Some(product.uuid))[0];
@@ -149,12 +266,19 @@ fn test_add_loan() {
         now + chrono::Duration::days(7),
     );
     assert!(loan.is_ok());
-}
 
-#[test]
-fn test_add_overlapping_loan() {
-    let db = initialize_test_database(None);
+    let empty = db.add_loan(user.uuid, vec![], now, now + chrono::Duration::days(7));
+    assert!(matches!(
+        empty,
+        Err(crate::database::DatabaseError::Invalid(_))
+    ));
+    assert_eq!(
+        db.get_loans(crate::database::LoanQueryParams::new()).len(),
+        1
+    );
+}
 
+fn test_add_overlapping_loan(db: Database) {
     let user = &db.get_users()[0];
     let product = &db.get_product_by_name("Canon R6").unwrap();
     let instance = &db.get_instances(Some(product.uuid))[0];
@@ -178,10 +302,7 @@ fn test_add_overlapping_loan() {
     assert!(overlapping_loan.is_err());
 }
 
-#[test]
-fn test_loan_filters() {
-    let db = initialize_test_database(None);
-
+fn test_loan_filters(db: Database) {
     let user_1 = &db.get_users()[0];
     let user_2 = &db.get_users()[1];
     let product_1 = &db.get_product_by_name("Canon R6").unwrap();
@@ -238,10 +359,8 @@ fn test_loan_filters() {
     assert!(product_2_loans[0].instaces[0].product.uuid == product_2.uuid);
 }
 
-#[test]
-fn test_loans() {
+fn test_loans(db: Database) {
     use chrono_tz::Europe::Helsinki;
-    let db = initialize_test_database(None);
 
     let loan_count = db.get_loans(crate::database::LoanQueryParams::new()).len();
     assert_eq!(loan_count, 0);
@@ -287,3 +406,3207 @@ fn test_loans() {
 
     assert!(db.get_loans(crate::database::LoanQueryParams::new()).len() == 1);
 }
+
+fn test_add_loan_rollback(db: Database) {
+    let user = &db.get_users()[0];
+    let product = &db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(product.uuid))[0];
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    // The same instance twice violates the loan_instances primary key
+    // half way through the inserts.
+    let loan = db.add_loan(
+        user.uuid,
+        vec![instance.uuid, instance.uuid],
+        now,
+        now + chrono::Duration::days(7),
+    );
+    assert!(loan.is_err());
+
+    assert!(db
+        .get_loans(crate::database::LoanQueryParams::new())
+        .is_empty());
+
+    let loan = db.add_loan(
+        user.uuid,
+        vec![instance.uuid],
+        now,
+        now + chrono::Duration::days(7),
+    );
+    assert!(loan.is_ok());
+}
+
+fn test_check_in(db: Database) {
+    use crate::database::{DatabaseError, LoanQueryParams};
+
+    let user = &db.get_users()[0];
+    let product = &db.get_product_by_name("Hasselblad 500c").unwrap();
+    let instances = db.get_instances(Some(product.uuid));
+    let other = &db.get_instances(None)[0];
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![instances[0].uuid, instances[1].uuid],
+            now,
+            now + chrono::Duration::days(7),
+        )
+        .unwrap();
+
+    assert!(db.check_in(loan.uuid, vec![instances[0].uuid], now).is_ok());
+
+    let out = db.get_loans(LoanQueryParams {
+        returned: Some(false),
+        ..Default::default()
+    });
+    assert!(out.len() == 1);
+    assert!(out[0].instaces.len() == 1);
+    assert!(out[0].instaces[0].uuid == instances[1].uuid);
+
+    // Already returned
+    let result = db.check_in(loan.uuid, vec![instances[0].uuid], now);
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+
+    // Not on the loan, the other instance stays out as well
+    let result = db.check_in(loan.uuid, vec![instances[1].uuid, other.uuid], now);
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+
+    // Unknown loans and empty check-ins are refused
+    let result = db.check_in(uuid::Uuid::new_v4(), vec![instances[1].uuid], now);
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+    let result = db.check_in(loan.uuid, vec![], now);
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+    let returned = db.get_loans(LoanQueryParams {
+        returned: Some(true),
+        ..Default::default()
+    });
+    assert!(returned.len() == 1);
+    assert!(returned[0].instaces.len() == 1);
+    assert!(returned[0].instaces[0].uuid == instances[0].uuid);
+}
+
+fn test_overdue_loans(db: Database) {
+    use chrono::TimeZone;
+
+    let alice = &db.get_user_by_name("Alice").unwrap();
+    let bob = &db.get_user_by_name("Bob").unwrap();
+    let charlie = &db.get_user_by_name("Charlie").unwrap();
+    let r6 = db.get_instances(Some(db.get_product_by_name("Canon R6").unwrap().uuid));
+    let hassel = db.get_instances(Some(
+        db.get_product_by_name("Hasselblad 500c").unwrap().uuid,
+    ));
+    let zoom = db.get_instances(Some(
+        db.get_product_by_name("Canon 24-70mm f/2.8").unwrap().uuid,
+    ));
+    let tele = db.get_instances(Some(
+        db.get_product_by_name("Canon 70-200mm f/2.8").unwrap().uuid,
+    ));
+
+    let now = chrono_tz::Europe::Helsinki
+        .with_ymd_and_hms(2024, 3, 15, 10, 0, 0)
+        .unwrap();
+    let days = chrono::Duration::days;
+    let hours = chrono::Duration::hours;
+
+    // Due three days ago
+    let late = db
+        .add_loan(alice.uuid, vec![r6[0].uuid], now - days(10), now - days(3))
+        .unwrap();
+    // Due earlier today, one of the instances is back
+    let partly_returned = db
+        .add_loan(
+            alice.uuid,
+            vec![hassel[0].uuid, hassel[1].uuid],
+            now - days(5),
+            now - hours(2),
+        )
+        .unwrap();
+    db.check_in(partly_returned.uuid, vec![hassel[0].uuid], now - hours(3))
+        .unwrap();
+    // Returned in time
+    let returned = db
+        .add_loan(bob.uuid, vec![zoom[0].uuid], now - days(7), now - days(1))
+        .unwrap();
+    db.check_in(returned.uuid, vec![zoom[0].uuid], now - days(1))
+        .unwrap();
+    // Never accepted
+    let pending = db
+        .add_loan(bob.uuid, vec![tele[0].uuid], now - days(12), now - days(1))
+        .unwrap();
+    assert!(!pending.accepted);
+    // Due later today and tomorrow
+    let due_today = db
+        .add_loan(bob.uuid, vec![zoom[1].uuid], now - days(2), now + hours(8))
+        .unwrap();
+    let due_tomorrow = db
+        .add_loan(charlie.uuid, vec![r6[1].uuid], now - days(1), now + days(1))
+        .unwrap();
+
+    let overdue = db.get_overdue_loans(now);
+    assert!(overdue.len() == 1);
+    assert!(overdue[0].user.uuid == alice.uuid);
+    assert!(overdue[0].loans.len() == 2);
+    assert!(overdue[0].loans[0].loan.uuid == late.uuid);
+    assert!(overdue[0].loans[0].days_overdue == 3);
+    assert!(overdue[0].loans[1].loan.uuid == partly_returned.uuid);
+    assert!(overdue[0].loans[1].days_overdue == 0);
+    assert!(overdue[0].loans[1].loan.instaces.len() == 1);
+    assert!(overdue[0].loans[1].loan.instaces[0].uuid == hassel[1].uuid);
+
+    let due = db.get_due_loans(now);
+    assert!(due.today.len() == 2);
+    assert!(due.today[0].uuid == partly_returned.uuid);
+    assert!(due.today[1].uuid == due_today.uuid);
+    assert!(due.tomorrow.len() == 1);
+    assert!(due.tomorrow[0].uuid == due_tomorrow.uuid);
+
+    // Checking in the rest clears the user
+    db.check_in(late.uuid, vec![r6[0].uuid], now).unwrap();
+    db.check_in(partly_returned.uuid, vec![hassel[1].uuid], now)
+        .unwrap();
+    assert!(db.get_overdue_loans(now).is_empty());
+}
+
+fn test_loan_dates_across_time_zones(db: Database) {
+    use chrono::TimeZone;
+
+    let alice = &db.get_user_by_name("Alice").unwrap();
+    let r6 = db.get_instances(Some(db.get_product_by_name("Canon R6").unwrap().uuid));
+
+    // Due at 10:00+03:00, which is 07:00 UTC
+    let loan = db
+        .add_loan(
+            alice.uuid,
+            vec![r6[0].uuid],
+            chrono_tz::Europe::Helsinki
+                .with_ymd_and_hms(2024, 6, 10, 12, 0, 0)
+                .unwrap(),
+            chrono_tz::Europe::Helsinki
+                .with_ymd_and_hms(2024, 6, 12, 10, 0, 0)
+                .unwrap(),
+        )
+        .unwrap();
+
+    // Dates in UTC are compared as instants, not as text
+    let now = chrono_tz::UTC
+        .with_ymd_and_hms(2024, 6, 12, 8, 30, 0)
+        .unwrap();
+    let overdue = db.get_overdue_loans(now);
+    assert_eq!(overdue.len(), 1);
+    assert_eq!(overdue[0].loans[0].loan.uuid, loan.uuid);
+    assert_eq!(overdue[0].loans[0].days_overdue, 0);
+
+    // Days overdue change at midnight in Helsinki, not in the offset of `now`
+    let after_midnight = [
+        chrono_tz::UTC
+            .with_ymd_and_hms(2024, 6, 12, 22, 30, 0)
+            .unwrap(),
+        chrono_tz::America::New_York
+            .with_ymd_and_hms(2024, 6, 12, 20, 0, 0)
+            .unwrap(),
+    ];
+    for now in after_midnight {
+        let overdue = db.get_overdue_loans(now);
+        assert_eq!(overdue[0].loans[0].days_overdue, 1);
+    }
+    let before_midnight = chrono_tz::Asia::Tokyo
+        .with_ymd_and_hms(2024, 6, 13, 5, 0, 0)
+        .unwrap();
+    let overdue = db.get_overdue_loans(before_midnight);
+    assert_eq!(overdue[0].loans[0].days_overdue, 0);
+    let due_later = db.get_loans(crate::database::LoanQueryParams {
+        due_after: Some(
+            chrono_tz::UTC
+                .with_ymd_and_hms(2024, 6, 12, 7, 30, 0)
+                .unwrap(),
+        ),
+        ..Default::default()
+    });
+    assert!(due_later.is_empty());
+
+    // The instance is free again from 07:00 UTC
+    db.add_loan(
+        alice.uuid,
+        vec![r6[0].uuid],
+        chrono_tz::UTC
+            .with_ymd_and_hms(2024, 6, 12, 8, 0, 0)
+            .unwrap(),
+        chrono_tz::UTC
+            .with_ymd_and_hms(2024, 6, 13, 8, 0, 0)
+            .unwrap(),
+    )
+    .unwrap();
+    assert!(db
+        .add_loan(
+            alice.uuid,
+            vec![r6[0].uuid],
+            chrono_tz::UTC
+                .with_ymd_and_hms(2024, 6, 12, 6, 0, 0)
+                .unwrap(),
+            chrono_tz::UTC
+                .with_ymd_and_hms(2024, 6, 12, 6, 30, 0)
+                .unwrap(),
+        )
+        .is_err());
+}
+
+fn test_ledger(db: Database) {
+    use crate::database::{DatabaseError, LedgerEntryKind};
+
+    let alice = &db.get_user_by_name("Alice").unwrap();
+    let bob = &db.get_user_by_name("Bob").unwrap();
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+
+    assert!(db.get_balance(alice.uuid) == 0);
+
+    let damage = db.add_charge(
+        alice.uuid,
+        LedgerEntryKind::Damage,
+        2500,
+        None,
+        Some("Scratched lens"),
+        now - days(2),
+    );
+    assert!(damage.is_ok());
+    let adjustment = db.add_charge(
+        alice.uuid,
+        LedgerEntryKind::Adjustment,
+        -500,
+        None,
+        Some("Goodwill"),
+        now - days(1),
+    );
+    assert!(adjustment.is_ok());
+    assert!(db.get_balance(alice.uuid) == 2000);
+
+    let payment = db.add_payment(alice.uuid, 1500, None, now).unwrap();
+    assert!(payment.amount == -1500);
+    assert!(db.get_balance(alice.uuid) == 500);
+    assert!(db.get_balance(bob.uuid) == 0);
+
+    let ledger = db.get_ledger(alice.uuid);
+    assert!(ledger.len() == 3);
+    assert!(ledger[0].kind == LedgerEntryKind::Damage);
+    assert!(ledger[0].description.as_deref() == Some("Scratched lens"));
+    assert!(ledger[2].kind == LedgerEntryKind::Payment);
+
+    // Charges must be positive, payments go through add_payment
+    let result = db.add_charge(alice.uuid, LedgerEntryKind::Damage, -1, None, None, now);
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+    let result = db.add_charge(alice.uuid, LedgerEntryKind::Payment, 100, None, None, now);
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+    let result = db.add_payment(alice.uuid, 0, None, now);
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+    let result = db.add_payment(uuid::Uuid::new_v4(), 100, None, now);
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+
+    // Users with ledger entries can't be removed
+    assert!(matches!(
+        db.remove_user(alice.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+}
+
+fn test_late_fees(db: Database) {
+    use crate::database::LedgerEntryKind;
+
+    let user = &db.get_users()[0];
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let cameras = db.get_category("Cameras").unwrap();
+    let lenses = db.get_category("Lenses").unwrap();
+    let r6 = &db.get_instances(Some(db.get_product_by_name("Canon R6").unwrap().uuid))[0];
+    let zoom = &db.get_instances(Some(
+        db.get_product_by_name("Canon 24-70mm f/2.8").unwrap().uuid,
+    ))[0];
+
+    // Lenses inherit the rate of the catalogue
+    assert!(db.set_late_fee_rate(catalogue.uuid, 200).is_ok());
+    assert!(db.set_late_fee_rate(cameras.uuid, 500).is_ok());
+    assert!(db.set_late_fee_rate(cameras.uuid, -1).is_err());
+    assert!(db.get_late_fee_rate(cameras.uuid) == 500);
+    assert!(db.get_late_fee_rate(lenses.uuid) == 200);
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![r6.uuid, zoom.uuid],
+            now - days(7),
+            now - days(3),
+        )
+        .unwrap();
+
+    db.check_in(loan.uuid, vec![r6.uuid, zoom.uuid], now)
+        .unwrap();
+
+    let ledger = db.get_ledger(user.uuid);
+    assert!(ledger.len() == 1);
+    assert!(ledger[0].kind == LedgerEntryKind::LateFee);
+    assert!(ledger[0].loan == Some(loan.uuid));
+    assert!(ledger[0].amount == 3 * (500 + 200));
+    assert!(db.get_balance(user.uuid) == 2100);
+
+    // Returned in time
+    let loan = db
+        .add_loan(user.uuid, vec![r6.uuid], now - days(2), now + days(1))
+        .unwrap();
+    db.check_in(loan.uuid, vec![r6.uuid], now).unwrap();
+    assert!(db.get_ledger(user.uuid).len() == 1);
+
+    // Returned after midnight in Helsinki, but before it in UTC
+    let helsinki = |day, hour| {
+        use chrono::TimeZone;
+        chrono_tz::Europe::Helsinki
+            .with_ymd_and_hms(2024, 6, day, hour, 0, 0)
+            .unwrap()
+    };
+    let loan = db
+        .add_loan(user.uuid, vec![r6.uuid], helsinki(10, 12), helsinki(12, 10))
+        .unwrap();
+    let returned = helsinki(13, 1).with_timezone(&chrono_tz::UTC);
+    db.check_in(loan.uuid, vec![r6.uuid], returned).unwrap();
+    let ledger = db.get_ledger(user.uuid);
+    assert!(ledger.len() == 2);
+    assert!(ledger[0].loan == Some(loan.uuid));
+    assert!(ledger[0].amount == 500);
+
+    // Categories with a rate can still be removed
+    let empty = db.add_category("Empty", Some(catalogue.uuid)).unwrap();
+    db.set_late_fee_rate(empty.uuid, 100).unwrap();
+    assert!(db.remove_category(empty.uuid).is_ok());
+}
+
+fn test_balance_policy(db: Database) {
+    use crate::database::{BorrowingPolicy, DatabaseError, LedgerEntryKind};
+
+    let db = db.with_policy(BorrowingPolicy {
+        block_on_balance: true,
+        ..Default::default()
+    });
+    let user = &db.get_users()[0];
+    let instance = &db.get_instances(None)[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+
+    db.add_charge(user.uuid, LedgerEntryKind::Damage, 1000, None, None, now)
+        .unwrap();
+    let result = db.add_loan(user.uuid, vec![instance.uuid], now, now + days(1));
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+
+    db.add_payment(user.uuid, 1000, None, now).unwrap();
+    let result = db.add_loan(user.uuid, vec![instance.uuid], now, now + days(1));
+    assert!(result.is_ok());
+
+    // Loans up to the approval limit are accepted right away
+    let db = db.with_policy(BorrowingPolicy {
+        approval_limit: days(2),
+        ..Default::default()
+    });
+    let loan = db
+        .add_loan(user.uuid, vec![instance.uuid], now + days(5), now + days(8))
+        .unwrap();
+    assert!(!loan.accepted);
+}
+
+fn test_quotes(db: Database) {
+    use crate::database::{DatabaseError, NaiveDate, ProductPrice};
+    use chrono::TimeZone;
+
+    let alice = &db.get_user_by_name("Alice").unwrap();
+    let bob = &db.get_user_by_name("Bob").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let r6_instance = &db.get_instances(Some(r6.uuid))[0];
+    let hassel = &db.get_instances(Some(
+        db.get_product_by_name("Hasselblad 500c").unwrap().uuid,
+    ))[0];
+
+    let price = ProductPrice {
+        product: r6.uuid,
+        per_day: 1000,
+        per_weekend: Some(1500),
+        per_week: Some(5000),
+    };
+    assert!(db.set_product_price(price.clone()).is_ok());
+    let result = db.set_product_price(ProductPrice {
+        per_day: -1,
+        ..price.clone()
+    });
+    assert!(matches!(result, Err(DatabaseError::Invalid(_))));
+
+    // Friday 15 March 2024
+    let friday = chrono_tz::Europe::Helsinki
+        .with_ymd_and_hms(2024, 3, 15, 10, 0, 0)
+        .unwrap();
+    let tuesday = friday + chrono::Duration::days(4);
+    let days = chrono::Duration::days;
+    let single = |start, end| {
+        let quote = db
+            .quote_loan(vec![r6_instance.uuid], start, end, bob.uuid)
+            .unwrap();
+        (quote.total, quote.lines[0].description.clone())
+    };
+
+    assert!(single(friday, friday + days(3)) == (1500, "Weekend".to_string()));
+    assert!(single(tuesday, tuesday + chrono::Duration::hours(5)) == (1000, "1 day".to_string()));
+    assert!(single(tuesday, tuesday + days(2)) == (2000, "2 days".to_string()));
+    assert!(single(tuesday, tuesday + days(6)) == (5000, "1 week".to_string()));
+    assert!(single(tuesday, tuesday + days(9)) == (7000, "1 week, 2 days".to_string()));
+    // Saturday to Monday is a weekend, to Tuesday it is not
+    let saturday = friday + days(1);
+    assert!(single(saturday, saturday + days(2)) == (1500, "Weekend".to_string()));
+    assert!(single(saturday, saturday + days(3)) == (3000, "3 days".to_string()));
+
+    // Members get a discount, products without a price are free
+    let member = db.add_membership_type("Member", 20).unwrap();
+    assert!(db.add_membership_type("Invalid", 101).is_err());
+    let year_start = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+    let year_end = NaiveDate::from_ymd_opt(2024, 12, 31).unwrap();
+    assert!(db
+        .add_membership_payment(alice.uuid, member.uuid, 3000, year_end, year_start)
+        .is_err());
+    db.add_membership_payment(alice.uuid, member.uuid, 3000, year_start, year_end)
+        .unwrap();
+    assert!(db.get_membership_payments(alice.uuid).len() == 1);
+
+    let instances = vec![r6_instance.uuid, hassel.uuid];
+    let quote = db
+        .quote_loan(instances.clone(), tuesday, tuesday + days(2), alice.uuid)
+        .unwrap();
+    assert!(quote.lines.len() == 2);
+    assert!(quote.lines[0].price == 2000);
+    assert!(quote.lines[0].discount == 400);
+    assert!(quote.lines[1].price == 0);
+    assert!(quote.total == 1600);
+
+    let result = db.quote_loan(
+        instances.clone(),
+        tuesday,
+        tuesday + days(2),
+        uuid::Uuid::new_v4(),
+    );
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+
+    // The loan keeps the price it was made with
+    let loan = db
+        .add_loan(alice.uuid, instances, tuesday, tuesday + days(2))
+        .unwrap();
+    assert!(loan.price == 1600);
+    db.set_product_price(ProductPrice {
+        per_day: 9999,
+        ..price
+    })
+    .unwrap();
+    let stored = db.get_loan_quote(loan.uuid).unwrap();
+    assert!(stored.total == 1600);
+    assert!(stored.lines.len() == 2);
+    assert!(stored.lines.contains(&quote.lines[0]));
+    assert!(db.get_loan(loan.uuid).unwrap().price == 1600);
+}
+
+fn test_invoices(db: Database) {
+    use crate::database::{DatabaseError, LedgerEntryKind, ProductPrice};
+    use crate::invoice::{format_money, DocumentKind};
+
+    let user = &db.get_user_by_name("Alice").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(r6.uuid))[0];
+    db.set_product_price(ProductPrice {
+        product: r6.uuid,
+        per_day: 1250,
+        per_weekend: None,
+        per_week: None,
+    })
+    .unwrap();
+    db.set_late_fee_rate(r6.category.uuid, 300).unwrap();
+    let member = db.add_membership_type("Member", 10).unwrap();
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+    db.add_membership_payment(
+        user.uuid,
+        member.uuid,
+        2000,
+        (now - days(30)).date_naive(),
+        (now + days(30)).date_naive(),
+    )
+    .unwrap();
+
+    let loan = db
+        .add_loan(user.uuid, vec![instance.uuid], now - days(6), now - days(4))
+        .unwrap();
+    db.check_in(loan.uuid, vec![instance.uuid], now - days(2))
+        .unwrap();
+    db.add_charge(
+        user.uuid,
+        LedgerEntryKind::Damage,
+        1500,
+        Some(loan.uuid),
+        Some("Dented <hood>"),
+        now - days(1),
+    )
+    .unwrap();
+
+    let invoice = db.create_invoice(loan.uuid, now - days(2)).unwrap();
+    assert!(invoice.number == 1);
+    // Rental less discount and the late fee, not yet the damage
+    assert!(invoice.total == 2500 - 250 + 600);
+
+    let invoice = db.create_invoice(loan.uuid, now).unwrap();
+    assert!(invoice.number == 2);
+    assert!(invoice.total == 2500 - 250 + 600 + 1500);
+    assert!(db.get_invoices(loan.uuid).len() == 2);
+
+    let result = db.create_invoice(uuid::Uuid::new_v4(), now);
+    assert!(matches!(result, Err(DatabaseError::NotFound(_))));
+
+    let document = db.get_invoice_document(2).unwrap();
+    assert!(document.lines.len() == 4);
+    assert!(document.lines[0].description == "Canon R6 #1, 2 days");
+    assert!(document.lines[1].amount == -250);
+    assert!(document.lines[2].amount == 600);
+    assert!(document.lines[3].description == "Damage: Dented <hood>");
+    assert!(db.get_invoice_document(3).is_none());
+
+    let text = document.to_text(DocumentKind::Invoice);
+    assert!(text.starts_with("Invoice 2\n"));
+    assert!(text.contains("Borrower: Alice"));
+    assert!(text.contains("Canon R6 #1"));
+    assert!(text.contains("43.50"));
+
+    let html = document.to_html(DocumentKind::Receipt);
+    assert!(html.contains("<h1>Receipt 2</h1>"));
+    assert!(html.contains("Dented &lt;hood&gt;"));
+    assert!(html.contains("<th>43.50</th>"));
+
+    assert!(format_money(-1250) == "-12.50");
+    assert!(format_money(5) == "0.05");
+
+    // Issued invoices don't change when charges are backdated later
+    let first = db.get_invoice_document(1).unwrap();
+    db.add_charge(
+        user.uuid,
+        LedgerEntryKind::Adjustment,
+        -300,
+        Some(loan.uuid),
+        Some("Goodwill"),
+        now - days(3),
+    )
+    .unwrap();
+    let unchanged = db.get_invoice_document(1).unwrap();
+    assert!(unchanged.lines == first.lines);
+    assert!(unchanged.lines.iter().map(|line| line.amount).sum::<i64>() == 2500 - 250 + 600);
+    assert!(unchanged.to_text(DocumentKind::Invoice) == first.to_text(DocumentKind::Invoice));
+    assert!(db.get_invoice_document(2).unwrap().lines == document.lines);
+}
+
+fn test_deposits(db: Database) {
+    use crate::database::{DatabaseError, DepositStatus};
+
+    let user = &db.get_users()[0];
+    let cameras = db.get_category("Cameras").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let hassel = db.get_product_by_name("Hasselblad 500c").unwrap();
+    let zoom = db.get_product_by_name("Canon 24-70mm f/2.8").unwrap();
+    let r6_instance = &db.get_instances(Some(r6.uuid))[0];
+    let hassel_instance = &db.get_instances(Some(hassel.uuid))[0];
+    let zoom_instance = &db.get_instances(Some(zoom.uuid))[0];
+
+    assert!(db.set_category_deposit(cameras.uuid, 10000).is_ok());
+    assert!(db.set_product_deposit(hassel.uuid, 30000).is_ok());
+    assert!(db.set_product_deposit(r6.uuid, -1).is_err());
+    assert!(db.get_deposit_amount(hassel.uuid) == Some(30000));
+    assert!(db.get_deposit_amount(r6.uuid) == Some(10000));
+    assert!(db.get_deposit_amount(zoom.uuid) == Some(0));
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![hassel_instance.uuid, zoom_instance.uuid],
+            now,
+            now + days(2),
+        )
+        .unwrap();
+    let deposit = db.take_deposit(loan.uuid, now).unwrap();
+    assert!(deposit.amount == 30000);
+    assert!(deposit.status == DepositStatus::Held);
+    assert!(matches!(
+        db.take_deposit(loan.uuid, now),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+
+    let free_loan = db
+        .add_loan(
+            user.uuid,
+            vec![zoom_instance.uuid],
+            now + days(3),
+            now + days(4),
+        )
+        .unwrap();
+    assert!(matches!(
+        db.take_deposit(free_loan.uuid, now),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.settle_deposit(free_loan.uuid, None, now),
+        Err(DatabaseError::NotFound(_))
+    ));
+
+    let other_loan = db
+        .add_loan(user.uuid, vec![r6_instance.uuid], now, now + days(1))
+        .unwrap();
+    db.take_deposit(other_loan.uuid, now).unwrap();
+    let held = db.get_held_deposits();
+    assert!(held.len() == 2);
+    assert!(held.iter().map(|d| d.amount).sum::<i64>() == 40000);
+
+    // Everything has to be back first
+    db.check_in(loan.uuid, vec![hassel_instance.uuid], now + days(1))
+        .unwrap();
+    assert!(matches!(
+        db.settle_deposit(loan.uuid, Some("Broken mirror"), now + days(1)),
+        Err(DatabaseError::Invalid(_))
+    ));
+    db.check_in(loan.uuid, vec![zoom_instance.uuid], now + days(1))
+        .unwrap();
+    let deposit = db
+        .settle_deposit(loan.uuid, Some("Broken mirror"), now + days(1))
+        .unwrap();
+    assert!(deposit.status == DepositStatus::Withheld);
+    assert!(db.get_balance(user.uuid) == -30000);
+    assert!(matches!(
+        db.settle_deposit(loan.uuid, None, now + days(1)),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    db.check_in(other_loan.uuid, vec![r6_instance.uuid], now + days(1))
+        .unwrap();
+    db.settle_deposit(other_loan.uuid, None, now + days(1))
+        .unwrap();
+    let deposit = db.get_deposit(other_loan.uuid).unwrap();
+    assert!(deposit.status == DepositStatus::Refunded);
+    assert!(deposit.date_settled.is_some());
+    assert!(deposit.reason.is_none());
+    assert!(db.get_held_deposits().is_empty());
+}
+
+fn test_loan_approval(db: Database) {
+    use crate::database::DatabaseError;
+    use crate::notification::NotificationKind;
+
+    let users = db.get_users();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(r6.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+
+    // Long loans wait for approval
+    let first = db
+        .add_loan(users[0].uuid, vec![instance.uuid], now, now + days(10))
+        .unwrap();
+    let second = db
+        .add_loan(users[1].uuid, vec![instance.uuid], now, now + days(10))
+        .unwrap();
+    assert!(!first.accepted && !second.accepted);
+
+    let approved = db.approve_loan(first.uuid).unwrap();
+    assert!(approved.accepted);
+    assert!(matches!(
+        db.approve_loan(first.uuid),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.approve_loan(second.uuid),
+        Err(DatabaseError::Conflict(_))
+    ));
+    assert!(matches!(
+        db.approve_loan(uuid::Uuid::new_v4()),
+        Err(DatabaseError::NotFound(_))
+    ));
+
+    assert!(matches!(
+        db.reject_loan(first.uuid, None),
+        Err(DatabaseError::Invalid(_))
+    ));
+    // Rejected loans are kept for what refers to them, like charges
+    db.add_charge(
+        users[1].uuid,
+        crate::database::LedgerEntryKind::Damage,
+        500,
+        Some(second.uuid),
+        None,
+        now,
+    )
+    .unwrap();
+    db.reject_loan(second.uuid, Some("Already booked."))
+        .unwrap();
+    let rejected_loan = db.get_loan(second.uuid).unwrap();
+    assert!(rejected_loan.rejected && !rejected_loan.accepted);
+    let user_loans = db.get_loans(crate::database::LoanQueryParams {
+        user_uuid: Some(users[1].uuid),
+        ..Default::default()
+    });
+    assert!(user_loans.is_empty());
+    assert!(matches!(
+        db.reject_loan(second.uuid, None),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.approve_loan(second.uuid),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let ics = db.get_loan_calendar(second.uuid).unwrap().to_ics();
+    assert!(ics.contains("\r\nSTATUS:CANCELLED\r\n"));
+
+    let kinds = |user| {
+        db.get_notifications(user)
+            .iter()
+            .map(|n| n.kind)
+            .collect::<Vec<_>>()
+    };
+    assert!(
+        kinds(users[0].uuid)
+            == [
+                NotificationKind::LoanCreated,
+                NotificationKind::LoanApproved
+            ]
+    );
+    assert!(
+        kinds(users[1].uuid)
+            == [
+                NotificationKind::LoanCreated,
+                NotificationKind::LoanRejected
+            ]
+    );
+    let rejected = &db.get_notifications(users[1].uuid)[1];
+    assert!(rejected.reference == Some(second.uuid));
+    assert!(rejected.body.contains("Canon R6 #1"));
+    assert!(rejected.body.contains("Already booked."));
+    let created = &db.get_notifications(users[1].uuid)[0];
+    assert!(created.body.contains("waiting for approval"));
+
+    // The notification still refers to the loan and can be sent
+    assert!(db.get_loan(rejected.reference.unwrap()).is_some());
+    db.set_user_email(users[1].uuid, "bob@example.com").unwrap();
+    let transport = TestTransport::default();
+    db.deliver_notifications(&transport, now + days(1)).unwrap();
+    let sent = transport.sent.lock().unwrap();
+    assert!(sent.contains(&("bob@example.com".to_string(), rejected.subject.clone())));
+}
+
+/// Transport that records messages, or fails while `failing` is set.
+#[derive(Default)]
+struct TestTransport {
+    sent: std::sync::Mutex<Vec<(String, String)>>,
+    failing: std::sync::atomic::AtomicBool,
+}
+
+impl crate::notification::Transport for TestTransport {
+    fn send(&self, to: &str, subject: &str, _body: &str) -> Result<(), String> {
+        if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err("Connection refused".to_string());
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .push((to.to_string(), subject.to_string()));
+        Ok(())
+    }
+}
+
+fn test_notification_delivery(db: Database) {
+    use crate::database::DatabaseError;
+    use crate::notification::{DeliveryReport, NotificationStatus};
+    use chrono::SubsecRound;
+    use std::sync::atomic::Ordering;
+
+    let users = db.get_users();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instances = db.get_instances(Some(r6.uuid));
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+    let minutes = chrono::Duration::minutes;
+
+    assert!(matches!(
+        db.set_user_email(users[0].uuid, "alice"),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.set_user_email(uuid::Uuid::new_v4(), "nobody@example.com"),
+        Err(DatabaseError::NotFound(_))
+    ));
+    db.set_user_email(users[0].uuid, "old@example.com").unwrap();
+    db.set_user_email(users[0].uuid, "alice@example.com")
+        .unwrap();
+    assert!(db.get_user_email(users[0].uuid) == Some("alice@example.com".to_string()));
+    assert!(db.get_user_email(users[1].uuid).is_none());
+
+    db.add_loan(users[0].uuid, vec![instances[0].uuid], now, now + days(1))
+        .unwrap();
+    db.add_loan(users[1].uuid, vec![instances[1].uuid], now, now + days(1))
+        .unwrap();
+
+    // Bob has no address, Alice's server is down. PostgreSQL keeps
+    // microseconds.
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let transport = TestTransport::default();
+    transport.failing.store(true, Ordering::SeqCst);
+    let report = db.deliver_notifications(&transport, now).unwrap();
+    assert!(
+        report
+            == DeliveryReport {
+                sent: 0,
+                retried: 1,
+                failed: 1
+            }
+    );
+    assert!(db.get_notifications(users[1].uuid)[0].status == NotificationStatus::Failed);
+    let notification = &db.get_notifications(users[0].uuid)[0];
+    assert!(notification.status == NotificationStatus::Pending);
+    assert!(notification.attempts == 1);
+    assert!(notification.next_attempt == now + minutes(5));
+    assert!(notification.last_error.as_deref() == Some("Connection refused"));
+
+    // Not due before the delay has passed
+    let report = db
+        .deliver_notifications(&transport, now + minutes(4))
+        .unwrap();
+    assert!(report == DeliveryReport::default());
+
+    transport.failing.store(false, Ordering::SeqCst);
+    let report = db
+        .deliver_notifications(&transport, now + minutes(5))
+        .unwrap();
+    assert!(report.sent == 1);
+    assert!(
+        *transport.sent.lock().unwrap()
+            == [(
+                "alice@example.com".to_string(),
+                "Loan request received".to_string()
+            )]
+    );
+    let notification = &db.get_notifications(users[0].uuid)[0];
+    assert!(notification.status == NotificationStatus::Sent);
+    assert!(notification.attempts == 2);
+    assert!(notification.date_sent == Some(now + minutes(5)));
+    assert!(notification.last_error.is_none());
+
+    // Delivery is given up after five attempts
+    db.add_loan(
+        users[0].uuid,
+        vec![instances[0].uuid],
+        now + days(2),
+        now + days(3),
+    )
+    .unwrap();
+    transport.failing.store(true, Ordering::SeqCst);
+    let mut time = now + minutes(10);
+    for _ in 0..4 {
+        let report = db.deliver_notifications(&transport, time).unwrap();
+        assert!(report.retried == 1);
+        time = db.get_notifications(users[0].uuid)[1].next_attempt;
+    }
+    let report = db.deliver_notifications(&transport, time).unwrap();
+    assert!(report.failed == 1);
+    let notification = &db.get_notifications(users[0].uuid)[1];
+    assert!(notification.status == NotificationStatus::Failed);
+    assert!(notification.attempts == 5);
+    assert!(
+        db.deliver_notifications(&transport, time + days(1))
+            .unwrap()
+            == Default::default()
+    );
+}
+
+fn test_reminders(db: Database) {
+    use crate::notification::{NotificationKind, Templates};
+    use chrono::TimeZone;
+
+    let db = db.with_templates({
+        let mut templates = Templates::default();
+        templates.set(
+            NotificationKind::LoanOverdue,
+            "Palauta {items}",
+            "{user}: {days} päivää myöhässä",
+        );
+        templates
+    });
+
+    let users = db.get_users();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let hassel = db.get_product_by_name("Hasselblad 500c").unwrap();
+    let r6_instance = &db.get_instances(Some(r6.uuid))[0];
+    let hassel_instance = &db.get_instances(Some(hassel.uuid))[0];
+    let now = chrono_tz::Europe::Helsinki
+        .with_ymd_and_hms(2024, 6, 10, 12, 0, 0)
+        .unwrap();
+    let days = chrono::Duration::days;
+
+    let overdue = db
+        .add_loan(
+            users[0].uuid,
+            vec![r6_instance.uuid],
+            now - days(5),
+            now - days(2),
+        )
+        .unwrap();
+    let due = db
+        .add_loan(
+            users[1].uuid,
+            vec![hassel_instance.uuid],
+            now - days(1),
+            now + days(1),
+        )
+        .unwrap();
+    // Neither due soon nor overdue
+    db.add_loan(
+        users[2].uuid,
+        vec![hassel_instance.uuid],
+        now + days(3),
+        now + days(5),
+    )
+    .unwrap();
+
+    let membership = db.add_membership_type("Student", 0).unwrap();
+    let expiring = db
+        .add_membership_payment(
+            users[0].uuid,
+            membership.uuid,
+            1000,
+            now.date_naive() - days(360),
+            now.date_naive() + days(5),
+        )
+        .unwrap();
+    // Renewed
+    for date_end in [now.date_naive() + days(3), now.date_naive() + days(365)] {
+        db.add_membership_payment(
+            users[1].uuid,
+            membership.uuid,
+            1000,
+            date_end - days(365),
+            date_end,
+        )
+        .unwrap();
+    }
+
+    assert!(db.queue_reminders(now).unwrap() == 3);
+    assert!(db.queue_reminders(now).unwrap() == 0);
+
+    let reminders = |user| {
+        db.get_notifications(user)
+            .into_iter()
+            .filter(|n| n.kind != NotificationKind::LoanCreated)
+            .collect::<Vec<_>>()
+    };
+    let alice = reminders(users[0].uuid);
+    assert!(alice.len() == 2);
+    let overdue_reminder = alice
+        .iter()
+        .find(|n| n.kind == NotificationKind::LoanOverdue)
+        .unwrap();
+    assert!(overdue_reminder.reference == Some(overdue.uuid));
+    assert!(overdue_reminder.subject == "Palauta Canon R6 #1");
+    assert!(overdue_reminder.body == "Alice: 2 päivää myöhässä");
+    let membership_reminder = alice
+        .iter()
+        .find(|n| n.kind == NotificationKind::MembershipExpiring)
+        .unwrap();
+    assert!(membership_reminder.reference == Some(expiring.uuid));
+    assert!(membership_reminder
+        .body
+        .contains("Student membership expires on 2024-06-15"));
+
+    let bob = reminders(users[1].uuid);
+    assert!(bob.len() == 1);
+    assert!(bob[0].kind == NotificationKind::LoanDueSoon);
+    assert!(bob[0].reference == Some(due.uuid));
+    assert!(bob[0].created == now);
+    assert!(reminders(users[2].uuid).is_empty());
+
+    // Returned loans are not reminded of
+    db.check_in(due.uuid, vec![hassel_instance.uuid], now)
+        .unwrap();
+    assert!(db.queue_reminders(now + days(3)).unwrap() == 0);
+
+    // Days overdue change at midnight in Helsinki, not in the offset of `now`
+    let late = db
+        .add_loan(
+            users[2].uuid,
+            vec![r6_instance.uuid],
+            now + days(3),
+            chrono_tz::Europe::Helsinki
+                .with_ymd_and_hms(2024, 6, 14, 10, 0, 0)
+                .unwrap(),
+        )
+        .unwrap();
+    let tokyo = chrono_tz::Asia::Tokyo
+        .with_ymd_and_hms(2024, 6, 15, 5, 0, 0)
+        .unwrap();
+    db.queue_reminders(tokyo).unwrap();
+    let late_reminder = reminders(users[2].uuid)
+        .into_iter()
+        .find(|n| n.reference == Some(late.uuid))
+        .unwrap();
+    assert!(late_reminder.body.ends_with(": 0 päivää myöhässä"));
+}
+
+fn test_calendars(db: Database) {
+    use chrono::TimeZone;
+
+    let users = db.get_users();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let hassel = db.get_product_by_name("Hasselblad 500c").unwrap();
+    let r6_instances = db.get_instances(Some(r6.uuid));
+    let hassel_instance = &db.get_instances(Some(hassel.uuid))[0];
+    let start = chrono_tz::Europe::Helsinki
+        .with_ymd_and_hms(2024, 6, 10, 12, 0, 0)
+        .unwrap();
+    let days = chrono::Duration::days;
+
+    let loan = db
+        .add_loan(
+            users[0].uuid,
+            vec![r6_instances[0].uuid, hassel_instance.uuid],
+            start,
+            start + days(2),
+        )
+        .unwrap();
+    let long_name = "Bartholomew Christopher Montgomery-Wolfeschlegelsteinhausen, Jr.";
+    let long_user = db.add_user(long_name).unwrap();
+    let pending = db
+        .add_loan(
+            long_user.uuid,
+            vec![r6_instances[1].uuid],
+            start,
+            start + days(14),
+        )
+        .unwrap();
+
+    let ics = db.get_loan_calendar(loan.uuid).unwrap().to_ics();
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+    assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+    assert!(ics.contains(&format!("\r\nUID:{}@loaner\r\n", loan.uuid)));
+    assert!(ics.contains("\r\nDTSTART:20240610T090000Z\r\n"));
+    assert!(ics.contains("\r\nDTEND:20240612T090000Z\r\n"));
+    let summary = ics
+        .split("\r\n")
+        .find(|line| line.starts_with("SUMMARY:"))
+        .unwrap();
+    assert!(summary.contains("Canon R6 #1") && summary.contains("Hasselblad 500c #1"));
+    assert!(summary.contains("\\, "));
+    assert!(ics.contains("\r\nSTATUS:CONFIRMED\r\n"));
+
+    // Same UID in every feed
+    let user_ics = db.get_user_calendar(users[0].uuid).unwrap().to_ics();
+    assert!(user_ics.matches("BEGIN:VEVENT").count() == 1);
+    assert!(user_ics.contains(&format!("\r\nUID:{}@loaner\r\n", loan.uuid)));
+
+    let product = db.get_product_calendar(r6.uuid).unwrap();
+    assert!(product.name == "Canon R6 bookings");
+    assert!(product.loans.len() == 2);
+    let product_ics = product.to_ics();
+    assert!(product_ics.contains("X-WR-CALNAME:Canon R6 bookings\r\n"));
+    assert!(product_ics.contains("\r\nSUMMARY:Booked\r\n"));
+    assert!(product_ics.contains("\r\nDESCRIPTION:Canon R6 #1\\nLoan approved\r\n"));
+    assert!(!product_ics.contains(&users[0].name));
+    assert!(!product_ics.replace("\r\n ", "").contains("Montgomery"));
+    assert!(product_ics.contains(&format!("\r\nUID:{}@loaner\r\n", pending.uuid)));
+    assert!(product_ics.contains("\r\nSTATUS:TENTATIVE\r\n"));
+
+    let instance = db.get_instance_calendar(r6_instances[1].uuid).unwrap();
+    assert!(instance.name == "Canon R6 #2 bookings");
+    assert!(instance.loans.len() == 1);
+    assert!(instance.loans[0].uuid == pending.uuid);
+    let instance_ics = instance.to_ics();
+    assert!(instance_ics.contains("\r\nSUMMARY:Booked\r\n"));
+    assert!(!instance_ics.replace("\r\n ", "").contains("Montgomery"));
+
+    // Long lines are folded
+    let ics = db.get_user_calendar(long_user.uuid).unwrap().to_ics();
+    assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+    assert!(ics.replace("\r\n ", "").contains(&format!(
+        "DESCRIPTION:Borrower: {}\\nLoan waiting for approval",
+        long_name.replace(',', "\\,")
+    )));
+
+    let missing = uuid::Uuid::new_v4();
+    assert!(db.get_loan_calendar(missing).is_none());
+    assert!(db.get_user_calendar(missing).is_none());
+    assert!(db.get_instance_calendar(missing).is_none());
+    assert!(db.get_product_calendar(missing).is_none());
+    assert!(db
+        .get_product_calendar(db.get_product_by_name("Canon 24-70mm f/2.8").unwrap().uuid)
+        .unwrap()
+        .loans
+        .is_empty());
+}
+
+fn test_catalogue_csv(db: Database) {
+    use crate::database::DatabaseError;
+
+    let exported = "category,product,identifier\n\
+        Catalogue/Cameras,Canon R6,#1\n\
+        Catalogue/Cameras,Canon R6,#2\n\
+        Catalogue/Cameras,Hasselblad 500c,#1\n\
+        Catalogue/Cameras,Hasselblad 500c,#2\n\
+        Catalogue/Lenses,Canon 24-70mm f/2.8,#1\n\
+        Catalogue/Lenses,Canon 24-70mm f/2.8,#2\n\
+        Catalogue/Lenses,Canon 70-200mm f/2.8,#1\n\
+        Catalogue/Lenses,Canon 70-200mm f/2.8,#2\n";
+    assert!(db.export_catalogue() == exported);
+
+    let csv = "Category,Product,Identifier\n\
+        Catalogue/Cameras/Digital,Sony A7,#1\n\
+        Catalogue/Cameras/Digital,Sony A7,#2\n\
+        Catalogue/Cameras/Digital,Sony A7,#2\n\
+        Catalogue / Cameras , Canon R6 , #1\n\
+        Catalogue/Lenses,\"Nikon 50mm f/1.8, AF-S\",\n\
+        Catalogue/Tripods,,\n";
+    let report = db.import_catalogue(csv, true).unwrap();
+    assert!(report.categories == ["Catalogue/Cameras/Digital", "Catalogue/Tripods"]);
+    assert!(report.products == ["Sony A7", "Nikon 50mm f/1.8, AF-S"]);
+    assert!(report.instances == ["Sony A7 #1", "Sony A7 #2"]);
+    assert!(report.duplicates == 2);
+    assert!(report.errors.is_empty());
+    assert!(report
+        .to_text()
+        .contains("New categories: 2\n  Catalogue/Cameras/Digital\n"));
+
+    // A dry run changes nothing
+    assert!(db.get_category("Digital").is_none());
+    assert!(db.export_catalogue() == exported);
+
+    assert!(db.import_catalogue(csv, false).unwrap() == report);
+    let digital = db.get_category("Digital").unwrap();
+    assert!(digital.supercategory == Some(db.get_category("Cameras").unwrap().uuid));
+    let sony = db.get_product_by_name("Sony A7").unwrap();
+    assert!(sony.category.uuid == digital.uuid);
+    assert!(db.get_instances(Some(sony.uuid)).len() == 2);
+    let exported = db.export_catalogue();
+    assert!(exported.contains("\nCatalogue/Cameras/Digital,Sony A7,#2\n"));
+    assert!(exported.contains("\nCatalogue/Lenses,\"Nikon 50mm f/1.8, AF-S\",\n"));
+    assert!(exported.ends_with("\nCatalogue/Tripods,,\n"));
+
+    // Importing the same file again adds nothing
+    let report = db.import_catalogue(csv, false).unwrap();
+    assert!(report.categories.is_empty() && report.products.is_empty());
+    assert!(report.instances.is_empty());
+    assert!(report.duplicates == 6);
+
+    // Rows clashing with the catalogue stop the whole import
+    let csv = "category,product,identifier\n\
+        Catalogue/Drones,DJI Mavic 3,#1\n\
+        Other/Cameras,Leica M6,#1\n\
+        Catalogue/Lenses,Canon R6,#3\n\
+        Catalogue/Lenses/Cameras,,\n\
+        ,Leica M6,#1\n\
+        Catalogue//Drones,,\n";
+    let report = db.import_catalogue(csv, true).unwrap();
+    let lines: Vec<u64> = report.errors.iter().map(|error| error.line).collect();
+    assert!(lines == [3, 4, 5, 6, 7]);
+    assert!(report.errors[0].message == "Category path must start with Catalogue.");
+    assert!(matches!(
+        db.import_catalogue(csv, false),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(db.get_category("Drones").is_none());
+    assert!(db.get_product_by_name("DJI Mavic 3").is_none());
+
+    assert!(matches!(
+        db.import_catalogue("name,identifier\nCanon R6,#1\n", true),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    // The export can be imported into an empty database
+    let empty = Database::with_storage(crate::storage::MemoryStorage::new());
+    let report = empty.import_catalogue(&exported, false).unwrap();
+    assert!(report.categories[0] == "Catalogue");
+    assert!(empty.export_catalogue() == exported);
+
+    // Separators in category names are escaped
+    let tripods = db.get_category("Tripods").unwrap();
+    let name = "Heads/Plates \\ Clamps";
+    let heads = db.add_category(name, Some(tripods.uuid)).unwrap();
+    db.add_product("Manfrotto 410", heads.uuid).unwrap();
+    let exported = db.export_catalogue();
+    assert!(exported.contains("\nCatalogue/Tripods/Heads\\/Plates \\\\ Clamps,Manfrotto 410,\n"));
+    let empty = Database::with_storage(crate::storage::MemoryStorage::new());
+    let report = empty.import_catalogue(&exported, false).unwrap();
+    assert!(report
+        .categories
+        .contains(&"Catalogue/Tripods/Heads\\/Plates \\\\ Clamps".to_string()));
+    let heads = empty.get_category(name).unwrap();
+    let manfrotto = empty.get_product_by_name("Manfrotto 410").unwrap();
+    assert!(manfrotto.category.uuid == heads.uuid);
+    assert!(empty.export_catalogue() == exported);
+}
+
+fn test_snapshot(db: Database) {
+    use crate::database::{DatabaseError, ProductPrice};
+    use crate::snapshot::Snapshot;
+    use crate::storage::MemoryStorage;
+
+    let user = &db.get_users()[0];
+    let cameras = db.get_category("Cameras").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let r6_instances = db.get_instances(Some(r6.uuid));
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+
+    db.set_user_email(user.uuid, "alice@example.com").unwrap();
+    db.set_late_fee_rate(cameras.uuid, 500).unwrap();
+    db.set_category_deposit(cameras.uuid, 10000).unwrap();
+    db.set_product_deposit(r6.uuid, 20000).unwrap();
+    db.set_product_price(ProductPrice {
+        product: r6.uuid,
+        per_day: 1000,
+        per_weekend: None,
+        per_week: Some(5000),
+    })
+    .unwrap();
+    let membership = db.add_membership_type("Student", 20).unwrap();
+    db.add_membership_payment(
+        user.uuid,
+        membership.uuid,
+        1500,
+        now.date_naive(),
+        now.date_naive() + days(365),
+    )
+    .unwrap();
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![r6_instances[0].uuid, r6_instances[1].uuid],
+            now - days(5),
+            now - days(3),
+        )
+        .unwrap();
+    db.take_deposit(loan.uuid, now - days(5)).unwrap();
+    db.check_in(loan.uuid, vec![r6_instances[0].uuid], now)
+        .unwrap();
+    let invoice = db.create_invoice(loan.uuid, now).unwrap();
+    // Waiting for approval
+    let pending = db
+        .add_loan(
+            user.uuid,
+            vec![r6_instances[0].uuid],
+            now + days(1),
+            now + days(20),
+        )
+        .unwrap();
+
+    db.add_condition_report(crate::database::NewConditionReport {
+        instance: r6_instances[0].uuid,
+        loan: Some(loan.uuid),
+        severity: crate::database::Severity::Minor,
+        description: "Scratched grip".to_string(),
+        reporter: "Erin".to_string(),
+        date: now,
+        out_of_service: false,
+    })
+    .unwrap();
+
+    let json = db.export_snapshot().to_json();
+    let snapshot = Snapshot::from_json(&json).unwrap();
+    assert!(snapshot.categories[0].name == "Catalogue");
+    assert!(snapshot.loans.len() == 2);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&snapshot).unwrap();
+    assert!(restored.get_user(user.uuid).unwrap().name == "Alice");
+    assert!(restored.get_user_email(user.uuid) == Some("alice@example.com".to_string()));
+    assert!(restored
+        .get_category("Lenses")
+        .unwrap()
+        .supercategory
+        .is_some());
+    assert!(restored.get_late_fee_rate(cameras.uuid) == 500);
+    assert!(restored.get_deposit_amount(r6.uuid) == Some(20000));
+    assert!(restored.get_product_price(r6.uuid).unwrap().per_week == Some(5000));
+    assert!(restored.get_instances(Some(r6.uuid)).len() == 2);
+    assert!(restored.get_membership_payments(user.uuid).len() == 1);
+    assert!(restored.get_loan(loan.uuid).unwrap().price == loan.price);
+    assert!(!restored.get_loan(pending.uuid).unwrap().accepted);
+    assert!(restored.get_loan_quote(loan.uuid) == db.get_loan_quote(loan.uuid));
+    assert!(restored.get_balance(user.uuid) == db.get_balance(user.uuid));
+    assert!(restored.get_balance(user.uuid) > 0);
+    assert!(restored.get_deposit(loan.uuid).unwrap().amount == 40000);
+    assert!(restored.get_invoices(loan.uuid) == db.get_invoices(loan.uuid));
+    assert!(restored.get_invoices(loan.uuid)[0].number == invoice.number);
+    assert!(restored.get_notifications(user.uuid).len() == 2);
+    assert!(
+        restored.get_condition_reports(r6_instances[0].uuid)
+            == db.get_condition_reports(r6_instances[0].uuid)
+    );
+    let everything = crate::audit::AuditQueryParams::new;
+    assert!(restored.get_audit_log(everything()).len() == db.get_audit_log(everything()).len());
+    let out = crate::database::LoanQueryParams {
+        loan_uuid: Some(loan.uuid),
+        returned: Some(false),
+        ..Default::default()
+    };
+    let out = restored.get_loans(out);
+    assert!(out[0].instaces.len() == 1 && out[0].instaces[0].uuid == r6_instances[1].uuid);
+
+    // Restoring the restored copy gives the same document
+    let json = restored.export_snapshot().to_json();
+    let copy = Database::with_storage(MemoryStorage::new());
+    copy.restore_snapshot(&Snapshot::from_json(&json).unwrap())
+        .unwrap();
+    assert!(copy.export_snapshot().to_json() == json);
+
+    // Only into an empty database
+    assert!(matches!(
+        restored.restore_snapshot(&snapshot),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    // Nothing is restored if any row fails
+    let mut broken = snapshot.clone();
+    broken.loans[0].instances[0].instance = uuid::Uuid::new_v4();
+    let empty = Database::with_storage(MemoryStorage::new());
+    assert!(matches!(
+        empty.restore_snapshot(&broken),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(empty.get_users().is_empty());
+
+    let newer = json.replacen("\"version\": 1", "\"version\": 2", 1);
+    assert!(matches!(
+        Snapshot::from_json(&newer),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        Snapshot::from_json("{\"version\": 1}"),
+        Err(DatabaseError::Invalid(_))
+    ));
+}
+
+fn test_audit_log(db: Database) {
+    use crate::audit::{AuditQueryParams, EntityType, SYSTEM_ACTOR};
+
+    let user = &db.get_users()[0];
+    let hasselblad = db.get_product_by_name("Hasselblad 500c").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(r6.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let start = now - chrono::Duration::seconds(1);
+
+    // Filling the catalogue was recorded as the system
+    let history = db.get_audit_log(AuditQueryParams {
+        entity: Some(hasselblad.uuid),
+        ..Default::default()
+    });
+    assert!(history.len() == 1);
+    assert!(history[0].operation == "add_product" && history[0].actor == SYSTEM_ACTOR);
+    assert!(history[0].before.is_none());
+    assert!(history[0].after.as_ref().unwrap()["name"] == "Hasselblad 500c");
+
+    let staff = db.acting_as("Erin");
+    staff.remove_product(hasselblad.uuid).unwrap();
+    let loan = staff
+        .add_loan(
+            user.uuid,
+            vec![instance.uuid],
+            now + chrono::Duration::days(1),
+            now + chrono::Duration::days(20),
+        )
+        .unwrap();
+    staff.approve_loan(loan.uuid).unwrap();
+    staff.set_late_fee_rate(r6.category.uuid, 300).unwrap();
+    // Failed changes leave no trace
+    assert!(staff.remove_category(r6.category.uuid).is_err());
+
+    let history = db.get_audit_log(AuditQueryParams {
+        entity: Some(hasselblad.uuid),
+        ..Default::default()
+    });
+    assert!(history.len() == 2);
+    assert!(history[1].operation == "remove_product" && history[1].actor == "Erin");
+    assert!(history[1].before.as_ref().unwrap()["name"] == "Hasselblad 500c");
+    assert!(history[1].after.is_none());
+    assert!(history[0].id < history[1].id);
+
+    // Removing the product removed its instances too
+    let removed = db.get_audit_log(AuditQueryParams {
+        entity_type: Some(EntityType::Instance),
+        actor: Some("Erin".to_string()),
+        ..Default::default()
+    });
+    assert!(removed.len() == 2);
+    assert!(removed.iter().all(|entry| entry.after.is_none()));
+
+    let loan_history = db.get_audit_log(AuditQueryParams {
+        entity_type: Some(EntityType::Loan),
+        entity: Some(loan.uuid),
+        ..Default::default()
+    });
+    let operations: Vec<&str> = loan_history
+        .iter()
+        .map(|entry| entry.operation.as_str())
+        .collect();
+    assert!(operations == ["add_loan", "approve_loan"]);
+    assert!(loan_history[1].before.as_ref().unwrap()["accepted"] == false);
+    assert!(loan_history[1].after.as_ref().unwrap()["accepted"] == true);
+
+    let rate = db.get_audit_log(AuditQueryParams {
+        entity: Some(r6.category.uuid),
+        ..Default::default()
+    });
+    let rate = rate.last().unwrap();
+    assert!(rate.before.as_ref().unwrap()["late_fee_rate"].is_null());
+    assert!(rate.after.as_ref().unwrap()["late_fee_rate"] == 300);
+
+    // Everything by Erin, by time
+    let by_erin = AuditQueryParams {
+        actor: Some("Erin".to_string()),
+        ..Default::default()
+    };
+    assert!(db.get_audit_log(by_erin.clone()).len() == 6);
+    let since = AuditQueryParams {
+        date_start: Some(start),
+        ..by_erin.clone()
+    };
+    assert!(db.get_audit_log(since).len() == 6);
+    let before = AuditQueryParams {
+        date_end: Some(start),
+        ..by_erin
+    };
+    assert!(db.get_audit_log(before).is_empty());
+    assert!(db
+        .get_audit_log(AuditQueryParams {
+            actor: Some("Mallory".to_string()),
+            ..Default::default()
+        })
+        .is_empty());
+}
+
+fn test_condition_reports(db: Database) {
+    use crate::database::{DatabaseError, NewConditionReport, Severity};
+    use chrono::SubsecRound;
+
+    let user = &db.get_users()[0];
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instances = db.get_instances(Some(r6.uuid));
+    let (broken, other) = (&instances[0], &instances[1]);
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let days = chrono::Duration::days;
+    let report = |instance: uuid::Uuid, loan, out_of_service| NewConditionReport {
+        instance,
+        loan,
+        severity: Severity::Major,
+        description: "Cracked LCD".to_string(),
+        reporter: "Erin".to_string(),
+        date: now,
+        out_of_service,
+    };
+
+    let loan = db
+        .add_loan(user.uuid, vec![broken.uuid], now - days(3), now - days(1))
+        .unwrap();
+    db.check_in(loan.uuid, vec![broken.uuid], now).unwrap();
+    let filed = db
+        .add_condition_report(report(broken.uuid, Some(loan.uuid), true))
+        .unwrap();
+    assert!(filed.loan == Some(loan.uuid) && !filed.resolved());
+    assert!(db.get_condition_reports(broken.uuid) == vec![filed.clone()]);
+    assert!(db.is_out_of_service(broken.uuid));
+
+    // Out of service instances can't be booked
+    assert!(matches!(
+        db.add_loan(user.uuid, vec![broken.uuid], now + days(1), now + days(2)),
+        Err(DatabaseError::Conflict(_))
+    ));
+    let available = db.get_available_instances(Some(r6.uuid), now + days(1), now + days(2));
+    assert!(available.len() == 1 && available[0].uuid == other.uuid);
+
+    // Reports that don't take the instance out of service don't block it
+    db.add_condition_report(NewConditionReport {
+        severity: Severity::Minor,
+        description: "Scratched grip".to_string(),
+        ..report(other.uuid, None, false)
+    })
+    .unwrap();
+    assert!(!db.is_out_of_service(other.uuid));
+    assert!(db.get_open_condition_reports().len() == 2);
+
+    // Pending loans can't be approved once an instance goes out of service
+    let pending = db
+        .add_loan(user.uuid, vec![other.uuid], now + days(5), now + days(20))
+        .unwrap();
+    let out = db
+        .add_condition_report(report(other.uuid, None, true))
+        .unwrap();
+    assert!(matches!(
+        db.approve_loan(pending.uuid),
+        Err(DatabaseError::Conflict(_))
+    ));
+    db.resolve_condition_report(out.uuid, Some("Replaced"), now)
+        .unwrap();
+    db.approve_loan(pending.uuid).unwrap();
+
+    // The instance must be on the loan, and reports need a description
+    assert!(matches!(
+        db.add_condition_report(report(other.uuid, Some(loan.uuid), true)),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.add_condition_report(NewConditionReport {
+            description: " ".to_string(),
+            ..report(other.uuid, None, false)
+        }),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    let resolved = db
+        .resolve_condition_report(filed.uuid, Some("Screen replaced"), now + days(1))
+        .unwrap();
+    assert!(resolved.resolved() && resolved.resolution.as_deref() == Some("Screen replaced"));
+    assert!(db.get_condition_reports(broken.uuid)[0].resolved());
+    assert!(matches!(
+        db.resolve_condition_report(filed.uuid, None, now + days(1)),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(!db.is_out_of_service(broken.uuid));
+    assert!(db.get_open_condition_reports().len() == 1);
+    db.add_loan(user.uuid, vec![broken.uuid], now + days(1), now + days(2))
+        .unwrap();
+}
+
+fn test_maintenance_windows(db: Database) {
+    use crate::database::DatabaseError;
+    use crate::storage::MemoryStorage;
+    use chrono::SubsecRound;
+
+    let user = &db.get_users()[0];
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instances = db.get_instances(Some(r6.uuid));
+    let (serviced, other) = (&instances[0], &instances[1]);
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let days = chrono::Duration::days;
+
+    // Maintenance can be scheduled in advance
+    let window = db
+        .add_maintenance_window(
+            serviced.uuid,
+            now + days(10),
+            now + days(12),
+            "Sensor cleaning",
+        )
+        .unwrap();
+    assert!(window.instance.uuid == serviced.uuid);
+    let windows = db.get_maintenance_windows(Some(serviced.uuid));
+    assert!(windows.len() == 1 && windows[0].reason == "Sensor cleaning");
+    assert!(db.get_maintenance_windows(Some(other.uuid)).is_empty());
+
+    // Bookings overlapping it conflict, others don't
+    assert!(matches!(
+        db.add_loan(
+            user.uuid,
+            vec![serviced.uuid],
+            now + days(11),
+            now + days(14)
+        ),
+        Err(DatabaseError::Conflict(_))
+    ));
+    let available = db.get_available_instances(Some(r6.uuid), now + days(9), now + days(11));
+    assert!(available.len() == 1 && available[0].uuid == other.uuid);
+    db.add_loan(user.uuid, vec![serviced.uuid], now + days(1), now + days(3))
+        .unwrap();
+
+    // Maintenance can't collide with accepted loans or other maintenance
+    assert!(matches!(
+        db.add_maintenance_window(serviced.uuid, now + days(2), now + days(4), "Firmware"),
+        Err(DatabaseError::Conflict(_))
+    ));
+    assert!(matches!(
+        db.add_maintenance_window(serviced.uuid, now + days(11), now + days(13), "Firmware"),
+        Err(DatabaseError::Conflict(_))
+    ));
+    assert!(matches!(
+        db.add_maintenance_window(serviced.uuid, now + days(5), now + days(4), "Firmware"),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.add_maintenance_window(serviced.uuid, now + days(5), now + days(6), " "),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.add_maintenance_window(
+            uuid::Uuid::new_v4(),
+            now + days(5),
+            now + days(6),
+            "Firmware"
+        ),
+        Err(DatabaseError::NotFound(_))
+    ));
+
+    // Calendars of the instance and product show it, the user's doesn't
+    let calendar = db.get_instance_calendar(serviced.uuid).unwrap();
+    assert!(calendar.loans.len() == 1 && calendar.maintenance.len() == 1);
+    assert!(calendar.to_ics().contains("SUMMARY:Maintenance: Canon R6"));
+    let calendar = db.get_product_calendar(r6.uuid).unwrap();
+    assert!(calendar.maintenance.len() == 1);
+    assert!(db
+        .get_user_calendar(user.uuid)
+        .unwrap()
+        .maintenance
+        .is_empty());
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    let windows = restored.get_maintenance_windows(None);
+    assert!(windows.len() == 1 && windows[0].uuid == window.uuid);
+    assert!(windows[0].date_start == window.date_start);
+
+    // Removing it frees the instance
+    db.remove_maintenance_window(window.uuid).unwrap();
+    assert!(matches!(
+        db.remove_maintenance_window(window.uuid),
+        Err(DatabaseError::NotFound(_))
+    ));
+    db.add_loan(
+        user.uuid,
+        vec![serviced.uuid],
+        now + days(11),
+        now + days(14),
+    )
+    .unwrap();
+}
+
+fn test_maintenance_due(db: Database) {
+    use crate::database::{DatabaseError, MaintenanceDueReason, MaintenanceRule};
+    use crate::storage::MemoryStorage;
+    use chrono::SubsecRound;
+
+    let user = &db.get_users()[0];
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instances = db.get_instances(Some(r6.uuid));
+    let (busy, old) = (&instances[0], &instances[1]);
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let days = chrono::Duration::days;
+
+    // Usage comes from accepted loans that have started
+    let loan = db
+        .add_loan(user.uuid, vec![busy.uuid], now - days(40), now - days(38))
+        .unwrap();
+    db.check_in(loan.uuid, vec![busy.uuid], now - days(37))
+        .unwrap();
+    // Still out, counted until now
+    db.add_loan(user.uuid, vec![busy.uuid], now - days(4), now - days(1))
+        .unwrap();
+    db.add_loan(user.uuid, vec![busy.uuid], now + days(4), now + days(5))
+        .unwrap();
+    let loan = db
+        .add_loan(user.uuid, vec![old.uuid], now - days(400), now - days(398))
+        .unwrap();
+    db.check_in(loan.uuid, vec![old.uuid], now - days(398))
+        .unwrap();
+
+    db.add_usage_reading(busy.uuid, "shutter count", 1000, now - days(40))
+        .unwrap();
+    db.add_usage_reading(busy.uuid, "shutter count", 1500, now)
+        .unwrap();
+    assert!(matches!(
+        db.add_usage_reading(busy.uuid, "shutter count", -1, now),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let usage = db.get_instance_usage(busy.uuid, now).unwrap();
+    assert!(usage.loans == 2 && usage.days_out == 7);
+    assert!(usage.readings.len() == 1 && usage.readings[0].value == 1500);
+    assert!(usage.last_maintenance.is_none() && usage.loans_since_maintenance == 2);
+
+    // Rules need a positive limit
+    let rule = MaintenanceRule {
+        product: r6.uuid,
+        loans: Some(2),
+        days_out: None,
+        months: Some(12),
+    };
+    assert!(matches!(
+        db.set_maintenance_rule(MaintenanceRule {
+            loans: None,
+            months: None,
+            ..rule.clone()
+        }),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.set_maintenance_rule(MaintenanceRule {
+            loans: Some(0),
+            ..rule.clone()
+        }),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(db.get_maintenance_due(now).is_empty());
+    db.set_maintenance_rule(rule.clone()).unwrap();
+    assert!(db.get_maintenance_rule(r6.uuid) == Some(rule.clone()));
+
+    let due = db.get_maintenance_due(now);
+    assert!(due.len() == 2);
+    let reasons = |uuid: uuid::Uuid| {
+        due.iter()
+            .find(|due| due.usage.instance.uuid == uuid)
+            .map(|due| due.reasons.clone())
+    };
+    assert!(reasons(busy.uuid) == Some(vec![MaintenanceDueReason::Loans]));
+    assert!(reasons(old.uuid) == Some(vec![MaintenanceDueReason::Months]));
+
+    // Counting starts over after maintenance, and scheduled maintenance
+    // takes the instance off the list
+    db.add_maintenance_window(busy.uuid, now - days(20), now - days(19), "Service")
+        .unwrap();
+    let usage = db.get_instance_usage(busy.uuid, now).unwrap();
+    assert!(usage.last_maintenance.is_some() && usage.loans_since_maintenance == 1);
+    assert!(usage.loans == 2);
+    db.add_maintenance_window(old.uuid, now + days(10), now + days(11), "Service")
+        .unwrap();
+    assert!(db.get_maintenance_due(now).is_empty());
+
+    // Days out change at midnight in Helsinki, not in the offset of the dates
+    let helsinki = |day, hour, minute| {
+        use chrono::TimeZone;
+        chrono_tz::Europe::Helsinki
+            .with_ymd_and_hms(2020, 1, day, hour, minute, 0)
+            .unwrap()
+    };
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![old.uuid],
+            helsinki(1, 0, 30),
+            helsinki(3, 12, 0),
+        )
+        .unwrap();
+    let returned = helsinki(3, 1, 30).with_timezone(&chrono_tz::UTC);
+    db.check_in(loan.uuid, vec![old.uuid], returned).unwrap();
+    let usage = db.get_instance_usage(old.uuid, now).unwrap();
+    assert!(usage.loans == 2 && usage.days_out == 4);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_maintenance_rule(r6.uuid) == Some(rule));
+    assert!(restored.get_usage_readings(busy.uuid) == db.get_usage_readings(busy.uuid));
+
+    db.remove_maintenance_rule(r6.uuid).unwrap();
+    assert!(db.get_maintenance_rule(r6.uuid).is_none());
+    assert!(matches!(
+        db.remove_maintenance_rule(r6.uuid),
+        Err(DatabaseError::NotFound(_))
+    ));
+}
+
+fn test_instance_status(db: Database) {
+    use crate::database::{DatabaseError, InstanceStatus};
+    use crate::storage::MemoryStorage;
+    use chrono::SubsecRound;
+
+    let user = &db.get_users()[0];
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instances = db.get_instances(Some(r6.uuid));
+    let (missing, other) = (&instances[0], &instances[1]);
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let days = chrono::Duration::days;
+
+    assert!(db.get_instance_status(missing.uuid) == InstanceStatus::Available);
+    assert!(db.get_status_history(missing.uuid).is_empty());
+
+    // Flagged at check-in, which closes it on the loan without a late fee
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![missing.uuid, other.uuid],
+            now - days(5),
+            now - days(2),
+        )
+        .unwrap();
+    assert!(matches!(
+        db.report_missing(loan.uuid, missing.uuid, InstanceStatus::Retired, None, now),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let change = db
+        .report_missing(
+            loan.uuid,
+            missing.uuid,
+            InstanceStatus::Stolen,
+            Some("Car break-in"),
+            now,
+        )
+        .unwrap();
+    assert!(change.loan == Some(loan.uuid) && change.status == InstanceStatus::Stolen);
+    assert!(db.get_instance_status(missing.uuid) == InstanceStatus::Stolen);
+    assert!(db.get_ledger(user.uuid).is_empty());
+    assert!(matches!(
+        db.report_missing(loan.uuid, missing.uuid, InstanceStatus::Lost, None, now),
+        Err(DatabaseError::NotFound(_))
+    ));
+    db.check_in(loan.uuid, vec![other.uuid], now).unwrap();
+
+    // Missing and retired instances can't be booked
+    assert!(matches!(
+        db.add_loan(user.uuid, vec![missing.uuid], now + days(1), now + days(2)),
+        Err(DatabaseError::Conflict(_))
+    ));
+    db.set_instance_status(other.uuid, InstanceStatus::Retired, None, now)
+        .unwrap();
+    assert!(db
+        .get_available_instances(Some(r6.uuid), now + days(1), now + days(2))
+        .is_empty());
+    assert!(matches!(
+        db.set_instance_status(other.uuid, InstanceStatus::Retired, None, now),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    let reported = db.get_missing_instances();
+    assert!(reported.len() == 1 && reported[0].instance.uuid == missing.uuid);
+    assert!(reported[0].change.note.as_deref() == Some("Car break-in"));
+    let retired = db.get_instances_by_status(InstanceStatus::Retired);
+    assert!(retired.len() == 1 && retired[0].instance.uuid == other.uuid);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_status_history(missing.uuid) == db.get_status_history(missing.uuid));
+
+    // Found items go back into service
+    assert!(matches!(
+        db.mark_found(other.uuid, None, now),
+        Err(DatabaseError::Invalid(_))
+    ));
+    db.mark_found(missing.uuid, Some("Recovered by police"), now + days(1))
+        .unwrap();
+    assert!(db.get_instance_status(missing.uuid) == InstanceStatus::Available);
+    assert!(db.get_status_history(missing.uuid).len() == 2);
+    assert!(db.get_missing_instances().is_empty());
+    db.add_loan(user.uuid, vec![missing.uuid], now + days(1), now + days(2))
+        .unwrap();
+}
+
+fn test_asset_info(db: Database) {
+    use crate::database::{AssetInfo, DatabaseError, InstanceStatus};
+    use crate::storage::MemoryStorage;
+    use chrono::SubsecRound;
+
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let r6 = db.get_instances(Some(r6.uuid));
+    let zoom = db.get_product_by_name("Canon 70-200mm f/2.8").unwrap();
+    let zoom = &db.get_instances(Some(zoom.uuid))[0];
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+
+    let asset = AssetInfo {
+        serial_number: Some(" R6-001234 ".to_string()),
+        purchase_date: Some(now - chrono::Duration::days(300)),
+        purchase_price: Some(250000),
+        current_value: Some(200000),
+        supplier: Some("Rajala".to_string()),
+        warranty_end: Some(now + chrono::Duration::days(430)),
+        ..AssetInfo::new(r6[0].uuid)
+    };
+    db.set_asset_info(asset.clone()).unwrap();
+    let stored = db.get_asset_info(r6[0].uuid).unwrap();
+    assert!(stored.serial_number.as_deref() == Some("R6-001234"));
+    assert!(stored.purchase_date == asset.purchase_date && stored.supplier == asset.supplier);
+    assert!(db.get_asset_info(r6[1].uuid).is_none());
+
+    // Serial numbers are unique, prices can't be negative
+    assert!(matches!(
+        db.set_asset_info(AssetInfo {
+            serial_number: Some("R6-001234".to_string()),
+            ..AssetInfo::new(r6[1].uuid)
+        }),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        db.set_asset_info(AssetInfo {
+            current_value: Some(-1),
+            ..AssetInfo::new(r6[1].uuid)
+        }),
+        Err(DatabaseError::Invalid(_))
+    ));
+    db.set_asset_info(AssetInfo {
+        serial_number: Some("R6-009999".to_string()),
+        purchase_price: Some(260000),
+        ..AssetInfo::new(r6[1].uuid)
+    })
+    .unwrap();
+    db.set_asset_info(AssetInfo {
+        serial_number: Some("Z-42".to_string()),
+        purchase_price: Some(180000),
+        current_value: Some(150000),
+        ..AssetInfo::new(zoom.uuid)
+    })
+    .unwrap();
+
+    let found = db.search_serial_numbers("r6-00");
+    assert!(found.len() == 2 && found[0].instance.uuid == r6[0].uuid);
+    assert!(found[1].instance.uuid == r6[1].uuid);
+    assert!(db.search_serial_numbers("9999").len() == 1);
+    assert!(db.search_serial_numbers("X").is_empty());
+
+    // Subtree totals include every subcategory
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let valuation = db.get_inventory_valuation(catalogue.uuid).unwrap();
+    assert!(valuation.instances == 8 && valuation.unvalued == 6);
+    assert!(valuation.purchase_price == 690000 && valuation.current_value == 350000);
+    let cameras = valuation
+        .subcategories
+        .iter()
+        .find(|v| v.category.name == "Cameras")
+        .unwrap();
+    assert!(cameras.purchase_price == 510000 && cameras.current_value == 200000);
+    assert!(db.get_inventory_valuation(uuid::Uuid::new_v4()).is_none());
+
+    // Retired instances are no longer in the inventory
+    db.set_instance_status(r6[1].uuid, InstanceStatus::Retired, None, now)
+        .unwrap();
+    let valuation = db.get_inventory_valuation(catalogue.uuid).unwrap();
+    assert!(valuation.instances == 7 && valuation.purchase_price == 430000);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_asset_info(r6[0].uuid) == Some(stored));
+    assert!(restored.search_serial_numbers("").len() == 3);
+}
+
+fn test_insurance_report(db: Database) {
+    use crate::database::{
+        AssetInfo, DatabaseError, Depreciation, DepreciationMethod, InstanceStatus,
+    };
+    use crate::storage::MemoryStorage;
+    use chrono::SubsecRound;
+
+    // PostgreSQL keeps microseconds
+    let now = chrono::Utc::now()
+        .with_timezone(&chrono_tz::Europe::Helsinki)
+        .trunc_subsecs(6);
+    let days = chrono::Duration::days;
+    let instance = |product: &str, index: usize| {
+        let product = db.get_product_by_name(product).unwrap();
+        db.get_instances(Some(product.uuid))[index].clone()
+    };
+    let (r6, new_r6) = (instance("Canon R6", 0), instance("Canon R6", 1));
+    let (hassel, retired) = (
+        instance("Hasselblad 500c", 0),
+        instance("Hasselblad 500c", 1),
+    );
+    let zoom = instance("Canon 70-200mm f/2.8", 0);
+
+    // Lenses inherit the depreciation of the catalogue
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let cameras = db.get_category("Cameras").unwrap();
+    let lenses = db.get_category("Lenses").unwrap();
+    let straight_line = Depreciation {
+        method: DepreciationMethod::StraightLine,
+        rate: 20,
+    };
+    let declining = Depreciation {
+        method: DepreciationMethod::DecliningBalance,
+        rate: 30,
+    };
+    db.set_depreciation(cameras.uuid, Some(straight_line))
+        .unwrap();
+    db.set_depreciation(catalogue.uuid, Some(declining))
+        .unwrap();
+    assert!(db.get_depreciation(lenses.uuid) == Some(declining));
+    assert!(matches!(
+        db.set_depreciation(
+            lenses.uuid,
+            Some(Depreciation {
+                rate: 0,
+                ..declining
+            })
+        ),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    // Four years at 20 % of the price, or losing 30 % of the value a year
+    let four_years_ago = Some(now - days(1461));
+    db.set_asset_info(AssetInfo {
+        purchase_date: four_years_ago,
+        purchase_price: Some(250000),
+        ..AssetInfo::new(r6.uuid)
+    })
+    .unwrap();
+    db.set_asset_info(AssetInfo {
+        purchase_date: four_years_ago,
+        purchase_price: Some(180000),
+        ..AssetInfo::new(zoom.uuid)
+    })
+    .unwrap();
+    assert!(db.get_insured_value(r6.uuid, now) == Some(50000));
+    assert!(db.get_insured_value(zoom.uuid, now) == Some(43218));
+    assert!(db.get_insured_value(retired.uuid, now).is_none());
+
+    // Bought after the date, or retired by then, is not insured
+    db.set_asset_info(AssetInfo {
+        purchase_date: Some(now + days(10)),
+        purchase_price: Some(260000),
+        ..AssetInfo::new(new_r6.uuid)
+    })
+    .unwrap();
+    db.set_asset_info(AssetInfo {
+        current_value: Some(80000),
+        ..AssetInfo::new(hassel.uuid)
+    })
+    .unwrap();
+    db.set_instance_status(retired.uuid, InstanceStatus::Retired, None, now - days(1))
+        .unwrap();
+    db.set_instance_location(r6.uuid, Some("Studio")).unwrap();
+    db.set_instance_location(hassel.uuid, Some(" Studio "))
+        .unwrap();
+    db.set_instance_location(zoom.uuid, Some("Warehouse"))
+        .unwrap();
+    assert!(db.get_instance_location(hassel.uuid).as_deref() == Some("Studio"));
+
+    let report = db.get_insurance_report(now);
+    let rows: Vec<(String, Option<&str>, i64, i64)> = report
+        .rows
+        .iter()
+        .map(|row| {
+            (
+                row.category_path.join("/"),
+                row.location.as_deref(),
+                row.instances,
+                row.insured_value,
+            )
+        })
+        .collect();
+    assert!(
+        rows == vec![
+            ("Catalogue/Cameras".to_string(), Some("Studio"), 2, 130000),
+            ("Catalogue/Lenses".to_string(), None, 3, 0),
+            ("Catalogue/Lenses".to_string(), Some("Warehouse"), 1, 43218),
+        ]
+    );
+    assert!(report.total_insured_value() == 173218);
+    let csv = report.to_csv();
+    assert!(csv.starts_with("category,location,instances,purchase_price,insured_value\n"));
+    assert!(csv.contains("Catalogue/Cameras,Studio,2,2500.00,1300.00\n"));
+
+    // The new camera is insured once bought
+    let later = db.get_insurance_report(now + days(11));
+    assert!(later.rows.len() == 4 && later.rows[0].location.is_none());
+    assert!(later.rows[0].purchase_price == 260000);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_insurance_report(now).rows == report.rows);
+
+    // Without their own depreciation cameras inherit the catalogue's
+    db.set_depreciation(cameras.uuid, None).unwrap();
+    assert!(db.get_depreciation(cameras.uuid) == Some(declining));
+    db.set_instance_location(zoom.uuid, None).unwrap();
+    assert!(db.get_instance_location(zoom.uuid).is_none());
+}
+
+fn test_product_attributes(db: Database) {
+    use crate::attribute::{AttributeFilter, AttributeType, Comparison};
+    use crate::database::{DatabaseError, ProductQueryParams};
+    use crate::storage::MemoryStorage;
+
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let lenses = db.get_category("Lenses").unwrap();
+    let cameras = db.get_category("Cameras").unwrap();
+    let zoom = db.get_product_by_name("Canon 24-70mm f/2.8").unwrap();
+    let tele = db.get_product_by_name("Canon 70-200mm f/2.8").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+
+    let weight = db
+        .add_attribute(catalogue.uuid, "Weight", AttributeType::Number, false)
+        .unwrap();
+    let mount = AttributeType::Enum(vec!["EF".to_string(), " RF ".to_string()]);
+    let mount = db.add_attribute(lenses.uuid, "Mount", mount, true).unwrap();
+    assert!(mount.kind.options() == ["EF", "RF"]);
+    db.add_attribute(lenses.uuid, "Stabilized", AttributeType::Bool, false)
+        .unwrap();
+
+    // Names are unique along each branch of the tree
+    assert!(matches!(
+        db.add_attribute(lenses.uuid, "weight", AttributeType::Text, false),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        db.add_attribute(catalogue.uuid, "Mount", AttributeType::Text, false),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    db.add_attribute(cameras.uuid, "Mount", AttributeType::Text, false)
+        .unwrap();
+    assert!(matches!(
+        db.add_attribute(lenses.uuid, "Aperture", AttributeType::Enum(vec![]), false),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let names: Vec<String> = db
+        .get_attributes(lenses.uuid)
+        .into_iter()
+        .map(|a| a.name)
+        .collect();
+    assert!(names == ["Weight", "Mount", "Stabilized"]);
+
+    // Values are checked against the types and required attributes
+    for values in [
+        vec![("Mount", "FE")],
+        vec![("Mount", "RF"), ("Weight", "heavy")],
+        vec![("Mount", "RF"), ("Colour", "white")],
+        vec![("Weight", "1480")],
+    ] {
+        assert!(matches!(
+            db.set_product_attributes(tele.uuid, &values),
+            Err(DatabaseError::Invalid(_))
+        ));
+    }
+    db.set_product_attributes(
+        tele.uuid,
+        &[("mount", "rf"), ("Weight", "1480"), ("Stabilized", "True")],
+    )
+    .unwrap();
+    db.set_product_attributes(zoom.uuid, &[("Mount", "EF"), ("Weight", "805")])
+        .unwrap();
+    db.set_product_attributes(r6.uuid, &[("Mount", "RF"), ("Weight", "680")])
+        .unwrap();
+    let attributes = db.get_product_attributes(tele.uuid);
+    let values: Vec<Option<&str>> = attributes.iter().map(|a| a.value.as_deref()).collect();
+    assert!(values == [Some("1480"), Some("RF"), Some("true")]);
+    let attributes = db.get_product_attributes(zoom.uuid);
+    assert!(attributes[2].value.is_none());
+
+    let filtered = |filters: &[&str]| {
+        let mut params = ProductQueryParams::new();
+        for filter in filters {
+            params
+                .attributes
+                .push(AttributeFilter::parse(filter).unwrap());
+        }
+        let mut names: Vec<String> = db
+            .get_products(params)
+            .into_iter()
+            .map(|p| p.name)
+            .collect();
+        names.sort();
+        names
+    };
+    assert!(filtered(&["mount = RF"]) == ["Canon 70-200mm f/2.8", "Canon R6"]);
+    assert!(filtered(&["Mount = rf", "weight < 1000"]) == ["Canon R6"]);
+    assert!(filtered(&["Weight >= 805"]) == ["Canon 24-70mm f/2.8", "Canon 70-200mm f/2.8"]);
+    assert!(filtered(&["Stabilized != false"]) == ["Canon 70-200mm f/2.8"]);
+    assert!(
+        AttributeFilter::parse("Weight <= 900").unwrap()
+            == AttributeFilter::new("Weight", Comparison::LessOrEqual, "900")
+    );
+    assert!(matches!(
+        AttributeFilter::parse("mount RF"),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_product_attributes(tele.uuid) == db.get_product_attributes(tele.uuid));
+
+    // Values go with their attribute
+    db.remove_attribute(weight.uuid).unwrap();
+    assert!(filtered(&["Weight > 0"]).is_empty());
+    assert!(db.get_product_attributes(tele.uuid).len() == 2);
+    assert!(matches!(
+        db.remove_attribute(weight.uuid),
+        Err(DatabaseError::NotFound(_))
+    ));
+}
+
+fn test_product_relations(db: Database) {
+    use crate::database::{DatabaseError, ProductRelationKind};
+    use crate::storage::MemoryStorage;
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let user = &db.get_users()[0];
+    let lenses = db.get_category("Lenses").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let zoom = db.get_product_by_name("Canon 24-70mm f/2.8").unwrap();
+    let tele = db.get_product_by_name("Canon 70-200mm f/2.8").unwrap();
+    let adapter = db
+        .add_product("Canon EF-EOS R adapter", lenses.uuid)
+        .unwrap();
+    let adapter_instance = db.add_instance("Adapter #1", adapter.uuid).unwrap();
+
+    db.add_product_relation(zoom.uuid, adapter.uuid, ProductRelationKind::Requires)
+        .unwrap();
+    db.add_product_relation(r6.uuid, tele.uuid, ProductRelationKind::CompatibleWith)
+        .unwrap();
+    db.add_product_relation(r6.uuid, adapter.uuid, ProductRelationKind::RecommendedWith)
+        .unwrap();
+    assert!(matches!(
+        db.add_product_relation(tele.uuid, r6.uuid, ProductRelationKind::CompatibleWith),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        db.add_product_relation(r6.uuid, r6.uuid, ProductRelationKind::Requires),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(db.get_product_relations(adapter.uuid).len() == 2);
+
+    // The adapter is missing rather than suggested
+    let body = db.get_instances(Some(r6.uuid))[0].uuid;
+    let lens = db.get_instances(Some(zoom.uuid))[0].uuid;
+    let check = db.check_compatibility(&[body, lens]);
+    assert!(check.missing.len() == 1);
+    assert!(check.missing[0].product.uuid == zoom.uuid);
+    assert!(check.missing[0].required.uuid == adapter.uuid);
+    let suggestions: Vec<uuid::Uuid> = check.suggestions.iter().map(|p| p.uuid).collect();
+    assert!(suggestions == [tele.uuid]);
+
+    // Compatibility holds both ways
+    let tele_instance = db.get_instances(Some(tele.uuid))[0].uuid;
+    let check = db.check_compatibility(&[tele_instance]);
+    assert!(check.missing.is_empty() && check.suggestions[0].uuid == r6.uuid);
+
+    // Loans are made anyway, with a warning
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![body, lens],
+            now,
+            now + chrono::Duration::days(2),
+        )
+        .unwrap();
+    assert!(loan.compatibility.missing.len() == 1);
+    let created = db.get_notifications(user.uuid).pop().unwrap();
+    assert!(created
+        .body
+        .contains("Canon 24-70mm f/2.8 requires Canon EF-EOS R adapter"));
+    let start = now + chrono::Duration::days(3);
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![body, lens, adapter_instance.uuid],
+            start,
+            start + chrono::Duration::days(2),
+        )
+        .unwrap();
+    let loan = db.get_loan(loan.uuid).unwrap();
+    assert!(loan.compatibility.missing.is_empty());
+    assert!(loan.compatibility.suggestions.len() == 1);
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    assert!(restored.get_product_relations(r6.uuid) == db.get_product_relations(r6.uuid));
+
+    db.remove_product_relation(tele.uuid, r6.uuid, ProductRelationKind::CompatibleWith)
+        .unwrap();
+    assert!(matches!(
+        db.remove_product_relation(tele.uuid, r6.uuid, ProductRelationKind::CompatibleWith),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(db
+        .check_compatibility(&[tele_instance])
+        .suggestions
+        .is_empty());
+}
+
+fn test_kits(db: Database) {
+    use crate::database::{DatabaseError, KitItem};
+    use crate::storage::MemoryStorage;
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let days = chrono::Duration::days;
+    let user = &db.get_users()[0];
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let zoom = db.get_product_by_name("Canon 24-70mm f/2.8").unwrap();
+    let hassel = db.get_product_by_name("Hasselblad 500c").unwrap();
+    let mic = db.add_product("Rode NTG", catalogue.uuid).unwrap();
+    let mic_instance = db.add_instance("#1", mic.uuid).unwrap();
+    let body = db.get_instances(Some(r6.uuid))[0].clone();
+    let tele = db.get_product_by_name("Canon 70-200mm f/2.8").unwrap();
+    let tele_instance = db.get_instances(Some(tele.uuid))[0].clone();
+
+    // A camera, two lenses and a microphone
+    let items = vec![
+        KitItem::Instance {
+            instance: body.uuid,
+        },
+        KitItem::Product {
+            product: zoom.uuid,
+            quantity: 2,
+        },
+        KitItem::Product {
+            product: mic.uuid,
+            quantity: 1,
+        },
+    ];
+    let kit = db.add_kit("Interview kit", items.clone()).unwrap();
+    assert!(db.get_kit(kit.uuid) == Some(kit.clone()));
+    assert!(matches!(
+        db.add_kit("Interview kit", items),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        db.add_kit("Empty kit", vec![]),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let none = KitItem::Product {
+        product: zoom.uuid,
+        quantity: 0,
+    };
+    assert!(matches!(
+        db.add_kit("Nothing", vec![none]),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    // Kits are booked as their instances, next to other instances
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![tele_instance.uuid, kit.uuid],
+            now,
+            now + days(2),
+        )
+        .unwrap();
+    assert!(loan.instaces.len() == 5);
+    assert!(loan.kits.len() == 1 && loan.kits[0].kit.name == "Interview kit");
+    let mut grouped: Vec<uuid::Uuid> = loan.kits[0].instances.iter().map(|i| i.uuid).collect();
+    grouped.sort();
+    let mut expected: Vec<uuid::Uuid> = db
+        .get_instances(Some(zoom.uuid))
+        .iter()
+        .map(|i| i.uuid)
+        .chain([body.uuid, mic_instance.uuid])
+        .collect();
+    expected.sort();
+    assert!(grouped == expected);
+
+    // Either every item is available or nothing is booked
+    let loans = db.get_loans(crate::database::LoanQueryParams::new()).len();
+    let conflict = db.add_loan(user.uuid, vec![kit.uuid], now + days(1), now + days(3));
+    assert!(matches!(conflict, Err(DatabaseError::Conflict(_))));
+    db.add_loan(
+        user.uuid,
+        vec![mic_instance.uuid],
+        now + days(5),
+        now + days(6),
+    )
+    .unwrap();
+    let conflict = db.add_loan(user.uuid, vec![kit.uuid], now + days(4), now + days(7));
+    assert!(matches!(conflict, Err(DatabaseError::Conflict(_))));
+    assert!(db.get_loans(crate::database::LoanQueryParams::new()).len() == loans + 1);
+    let zooms: Vec<uuid::Uuid> = db
+        .get_instances(Some(zoom.uuid))
+        .iter()
+        .map(|i| i.uuid)
+        .collect();
+    db.add_loan(user.uuid, zooms, now + days(4), now + days(7))
+        .unwrap();
+
+    let restored = Database::with_storage(MemoryStorage::new());
+    restored.restore_snapshot(&db.export_snapshot()).unwrap();
+    let restored_loan = restored.get_loan(loan.uuid).unwrap();
+    assert!(restored_loan.kits.len() == 1 && restored_loan.kits[0].instances.len() == 4);
+
+    // Kits and their products stay while they are in use
+    assert!(matches!(
+        db.remove_kit(kit.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    let spare = db
+        .add_kit(
+            "Medium format",
+            vec![KitItem::Product {
+                product: hassel.uuid,
+                quantity: 1,
+            }],
+        )
+        .unwrap();
+    assert!(matches!(
+        db.remove_product(hassel.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    db.remove_kit(spare.uuid).unwrap();
+    assert!(matches!(
+        db.remove_kit(spare.uuid),
+        Err(DatabaseError::NotFound(_))
+    ));
+    db.remove_product(hassel.uuid).unwrap();
+    assert!(db.get_kits().len() == 1);
+}
+
+/// Accepts SMTP sessions on a local port and passes each received message
+/// on through the channel.
+fn smtp_stand_in() -> (u16, std::sync::mpsc::Receiver<String>) {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+            let mut message = String::new();
+            let mut in_data = false;
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        sender.send(std::mem::take(&mut message)).unwrap();
+                        stream.write_all(b"250 OK\r\n").unwrap();
+                    } else {
+                        message.push_str(&line);
+                    }
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    stream.write_all(b"354 End data with .\r\n").unwrap();
+                } else if line.starts_with("QUIT") {
+                    stream.write_all(b"221 Bye\r\n").unwrap();
+                    break;
+                } else {
+                    stream.write_all(b"250 OK\r\n").unwrap();
+                }
+            }
+        }
+    });
+    (port, receiver)
+}
+
+#[test]
+fn test_smtp_delivery() {
+    use crate::notification::SmtpTransport;
+
+    let db = initialize_test_database(None);
+    let user = &db.get_users()[0];
+    let r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(r6.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    db.set_user_email(user.uuid, "alice@example.com").unwrap();
+    db.add_loan(
+        user.uuid,
+        vec![instance.uuid],
+        now,
+        now + chrono::Duration::days(1),
+    )
+    .unwrap();
+
+    let (port, messages) = smtp_stand_in();
+    let transport =
+        SmtpTransport::unencrypted("127.0.0.1", port, "Loaner <loans@example.com>").unwrap();
+    let report = db
+        .deliver_notifications(
+            &transport,
+            chrono::Utc::now().with_timezone(&now.timezone()),
+        )
+        .unwrap();
+    assert!(report.sent == 1);
+
+    let message = messages
+        .recv_timeout(std::time::Duration::from_secs(5))
+        .unwrap();
+    assert!(message.contains("To: alice@example.com"));
+    assert!(message.contains("From: Loaner <loans@example.com>"));
+    assert!(message.contains("Subject: Loan request received"));
+    assert!(message.contains("Canon R6 #1"));
+}
+
+#[test]
+fn test_concurrent_loans() {
+    use std::sync::{Arc, Barrier};
+
+    let path = temporary_database_path();
+
+    let db = initialize_test_database(Some(&path));
+    let user = db.get_users()[0].clone();
+    let product = db.get_product_by_name("Canon R6").unwrap();
+    let instance = db.get_instances(Some(product.uuid))[0].clone();
+
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    let thread_count = 8;
+    let barrier = Arc::new(Barrier::new(thread_count));
+    let handles = (0..thread_count)
+        .map(|_| {
+            let barrier = Arc::clone(&barrier);
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let db = Database::new(&path);
+                barrier.wait();
+                db.add_loan(
+                    user.uuid,
+                    vec![instance.uuid],
+                    now,
+                    now + chrono::Duration::days(7),
+                )
+                .is_ok()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let successes = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|ok| *ok)
+        .count();
+    assert_eq!(successes, 1);
+    assert_eq!(
+        db.get_loans(crate::database::LoanQueryParams::new()).len(),
+        1
+    );
+
+    drop(db);
+    remove_database_files(&path);
+}
+
+fn test_referential_integrity(db: Database) {
+    use crate::database::DatabaseError;
+
+    let missing = uuid::Uuid::new_v4();
+    assert!(matches!(
+        db.add_category("Drones", Some(missing)),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(matches!(
+        db.add_product("Mavic 2 Pro", missing),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(matches!(
+        db.add_instance("#1", missing),
+        Err(DatabaseError::NotFound(_))
+    ));
+
+    // Categories with subcategories or products are restricted
+    let catalogue = db.get_category("Catalogue").unwrap();
+    let cameras = db.get_category("Cameras").unwrap();
+    assert!(matches!(
+        db.remove_category(catalogue.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    assert!(matches!(
+        db.remove_category(cameras.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    assert!(matches!(
+        db.remove_category(missing),
+        Err(DatabaseError::NotFound(_))
+    ));
+
+    // Loaned instances and their borrowers are restricted
+    let user = &db.get_users()[0];
+    let canon_r6 = db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(canon_r6.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![instance.uuid],
+            now,
+            now + chrono::Duration::days(1),
+        )
+        .unwrap();
+    let other_instance = &db.get_instances(Some(canon_r6.uuid))[1];
+    assert!(matches!(
+        db.add_loan(
+            missing,
+            vec![other_instance.uuid],
+            now,
+            now + chrono::Duration::days(1),
+        ),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(matches!(
+        db.remove_user(user.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    assert!(matches!(
+        db.remove_product(canon_r6.uuid),
+        Err(DatabaseError::InUse(_))
+    ));
+    assert!(db.get_loan(loan.uuid).is_some());
+
+    // Instances without loans are removed with their product
+    let hassel = db.get_product_by_name("Hasselblad 500c").unwrap();
+    assert!(db.remove_product(hassel.uuid).is_ok());
+    assert!(db.get_instances(Some(hassel.uuid)).is_empty());
+}
+
+fn test_schema_constraints(db: Database) {
+    use crate::database::DatabaseError;
+
+    let cameras = db.get_category("Cameras").unwrap();
+    let canon_r6 = db.get_product_by_name("Canon R6").unwrap();
+    assert!(matches!(
+        db.add_product("Canon R6", cameras.uuid),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        db.add_instance("#1", canon_r6.uuid),
+        Err(DatabaseError::AlreadyExists(_))
+    ));
+    assert!(db.add_instance("#3", canon_r6.uuid).is_ok());
+
+    let user = &db.get_users()[0];
+    let instance = &db.get_instances(Some(canon_r6.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+    assert!(matches!(
+        db.add_loan(
+            user.uuid,
+            vec![instance.uuid],
+            now,
+            now - chrono::Duration::days(1),
+        ),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        db.add_loan(
+            user.uuid,
+            vec![instance.uuid, uuid::Uuid::new_v4()],
+            now,
+            now + chrono::Duration::days(1),
+        ),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert!(db
+        .get_loans(crate::database::LoanQueryParams::new())
+        .is_empty());
+}
+
+fn test_shared_database(db: Database) {
+    use std::sync::{Arc, Barrier};
+
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Database>();
+
+    let db = Arc::new(db);
+
+    let user = db.get_users()[0].clone();
+    let product = db.get_product_by_name("Canon R6").unwrap();
+    let instance = db.get_instances(Some(product.uuid))[0].clone();
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    // Every thread books its own week and races for the same instance
+    let thread_count = 8;
+    let barrier = Arc::new(Barrier::new(thread_count));
+    let handles = (0..thread_count)
+        .map(|i| {
+            let db = Arc::clone(&db);
+            let barrier = Arc::clone(&barrier);
+            std::thread::spawn(move || {
+                barrier.wait();
+                assert_eq!(db.get_users().len(), 3);
+                let week_start = now + chrono::Duration::weeks(i as i64 + 1);
+                let own_week = db.add_loan(
+                    user.uuid,
+                    vec![instance.uuid],
+                    week_start,
+                    week_start + chrono::Duration::days(6),
+                );
+                assert!(own_week.is_ok());
+                db.add_loan(
+                    user.uuid,
+                    vec![instance.uuid],
+                    now,
+                    now + chrono::Duration::days(6),
+                )
+                .is_ok()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let successes = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .filter(|ok| *ok)
+        .count();
+    assert_eq!(successes, 1);
+    assert_eq!(
+        db.get_loans(crate::database::LoanQueryParams::new()).len(),
+        thread_count + 1
+    );
+}
+
+#[test]
+fn test_shared_database_file() {
+    let path = temporary_database_path();
+    test_shared_database(initialize_test_database(Some(&path)));
+    remove_database_files(&path);
+}
+
+#[test]
+fn test_sqlite_constraints() {
+    use crate::database::DatabaseError;
+
+    let path = temporary_database_path();
+    let db = initialize_test_database(Some(&path));
+    let cameras = db.get_category("Cameras").unwrap();
+    let user = &db.get_users()[0];
+    let product = &db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(product.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    // The same instance twice violates the loan_instances primary key
+    // after the loan row has been inserted.
+    let loan = db.add_loan(
+        user.uuid,
+        vec![instance.uuid, instance.uuid],
+        now,
+        now + chrono::Duration::days(7),
+    );
+    assert!(loan.is_err());
+
+    // Constraints hold for writes that bypass the Database API as well
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let result = connection.execute("DELETE FROM audit_log", []);
+    assert!(matches!(
+        result.map_err(DatabaseError::from),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let result = connection.execute("UPDATE audit_log SET actor = 'nobody'", []);
+    assert!(matches!(
+        result.map_err(DatabaseError::from),
+        Err(DatabaseError::Invalid(_))
+    ));
+    let loan_rows: i64 = connection
+        .query_row("SELECT COUNT(*) FROM loan", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(loan_rows, 0);
+
+    let result = connection.execute(
+        "INSERT INTO product (uuid, name, category) VALUES (?1, ?2, ?3)",
+        rusqlite::params![uuid::Uuid::new_v4(), "Canon R6", cameras.uuid],
+    );
+    assert!(result.is_err());
+
+    let result = connection.execute(
+        "INSERT INTO loan (uuid, user, date_start, date_end, accepted)
+        VALUES (?1, ?2, ?3, ?3, 1)",
+        rusqlite::params![uuid::Uuid::new_v4(), user.uuid, now.to_rfc3339()],
+    );
+    assert!(result.is_err());
+    // The dates are ordered as instants. 08:00 UTC is after 10:30+03:00.
+    let result = connection.execute(
+        "INSERT INTO loan (uuid, user, date_start, date_end, accepted)
+        VALUES (?1, ?2, '2024-06-12T08:00:00+00:00', '2024-06-12T10:30:00+03:00', 1)",
+        rusqlite::params![uuid::Uuid::new_v4(), user.uuid],
+    );
+    assert!(result.is_err());
+    let result = connection.execute(
+        "INSERT INTO maintenance_window (uuid, instance, date_start, date_end, reason)
+        VALUES (?1, ?2, '2024-06-12T08:00:00+00:00', '2024-06-12T10:30:00+03:00', 'Service')",
+        rusqlite::params![uuid::Uuid::new_v4(), instance.uuid],
+    );
+    assert!(result.is_err());
+
+    drop(connection);
+    drop(db);
+    remove_database_files(&path);
+}
+
+#[test]
+fn test_memory_transactions() {
+    use crate::database::DatabaseError;
+    use crate::storage::{MemoryStorage, Storage};
+
+    let storage = MemoryStorage::new();
+    let (alice, bob, charlie) = (
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+    );
+    storage.insert_user(alice, "Alice").unwrap();
+    let names = |storage: &MemoryStorage| {
+        storage
+            .get_users()
+            .unwrap()
+            .into_iter()
+            .map(|user| user.name)
+            .collect::<Vec<_>>()
+    };
+
+    // Appends and removals of a failed transaction are both undone
+    let result = storage.transaction(&mut |storage| {
+        storage.insert_user(bob, "Bob")?;
+        storage.delete_user(alice)?;
+        storage.set_user_email(bob, "bob@example.com")?;
+        Err(DatabaseError::Conflict("Changed my mind.".to_string()))
+    });
+    assert!(matches!(result, Err(DatabaseError::Conflict(_))));
+    assert_eq!(names(&storage), ["Alice"]);
+    assert_eq!(storage.get_user_email(bob).unwrap(), None);
+
+    storage
+        .transaction(&mut |storage| {
+            storage.insert_user(bob, "Bob")?;
+            storage.delete_user(alice)
+        })
+        .unwrap();
+    assert_eq!(names(&storage), ["Bob"]);
+
+    // A failed transaction after a committed one only undoes its own changes
+    let result = storage.transaction(&mut |storage| {
+        storage.insert_user(charlie, "Charlie")?;
+        storage.insert_user(charlie, "Charlie")
+    });
+    assert!(matches!(result, Err(DatabaseError::AlreadyExists(_))));
+    assert_eq!(names(&storage), ["Bob"]);
+
+    // A panic in a transaction undoes it and leaves the data usable
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        storage.transaction(&mut |storage| {
+            storage.insert_user(charlie, "Charlie")?;
+            storage.delete_user(bob)?;
+            panic!("Bug in the transaction");
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(names(&storage), ["Bob"]);
+    storage.insert_user(charlie, "Charlie").unwrap();
+    assert_eq!(names(&storage), ["Bob", "Charlie"]);
+}
+
+#[test]
+fn test_backup_and_restore() {
+    use crate::database::DatabaseError;
+
+    let path = temporary_database_path();
+    let backup_path = temporary_database_path();
+    let db = initialize_test_database(Some(&path));
+
+    // The copy is taken while the database stays open
+    db.backup_to(&backup_path).unwrap();
+    db.add_user("Dave").unwrap();
+    assert_eq!(db.get_users().len(), 4);
+    drop(db);
+
+    crate::storage::SqliteStorage::validate_backup(std::path::Path::new(&backup_path)).unwrap();
+    Database::restore_backup(&backup_path, &path).unwrap();
+    let db = Database::new(&path);
+    assert_eq!(db.get_users().len(), 3);
+    assert!(db.get_user_by_name("Dave").is_none());
+    assert!(db.get_product_by_name("Canon R6").is_some());
+    drop(db);
+
+    // Backups of another schema version are refused and the database is
+    // left alone
+    let connection = rusqlite::Connection::open(&backup_path).unwrap();
+    connection
+        .pragma_update(
+            None,
+            "user_version",
+            crate::storage::sqlite::SCHEMA_VERSION + 1,
+        )
+        .unwrap();
+    drop(connection);
+    assert!(matches!(
+        Database::restore_backup(&backup_path, &path),
+        Err(DatabaseError::Invalid(_))
+    ));
+    std::fs::write(&backup_path, "not a database").unwrap();
+    assert!(matches!(
+        Database::restore_backup(&backup_path, &path),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert!(matches!(
+        Database::restore_backup(&temporary_database_path(), &path),
+        Err(DatabaseError::NotFound(_))
+    ));
+    assert_eq!(Database::new(&path).get_users().len(), 3);
+
+    // In-memory databases have no file to copy
+    let memory = Database::with_storage(crate::storage::MemoryStorage::new());
+    assert!(matches!(
+        memory.backup_to(&backup_path),
+        Err(DatabaseError::Invalid(_))
+    ));
+
+    remove_database_files(&path);
+    remove_database_files(&backup_path);
+}
+
+#[test]
+fn test_backup_rotation() {
+    use chrono::TimeZone;
+
+    let directory = std::env::temp_dir().join(format!("loaner-backups-{}", uuid::Uuid::new_v4()));
+    let directory = directory.to_str().unwrap().to_string();
+    let db = initialize_test_database(None);
+    let start = chrono_tz::Europe::Helsinki
+        .with_ymd_and_hms(2024, 6, 10, 12, 0, 0)
+        .unwrap();
+
+    // Backups named before milliseconds were added are rotated as well
+    let old_backup = std::path::Path::new(&directory).join("loaner-20240610-110000.db");
+    std::fs::create_dir_all(&directory).unwrap();
+    db.backup_to(old_backup.to_str().unwrap()).unwrap();
+
+    let mut paths = Vec::new();
+    for hour in 0..4 {
+        let path = db
+            .backup_rotating(&directory, 2, start + chrono::Duration::hours(hour))
+            .unwrap();
+        paths.push(path);
+    }
+    assert!(paths[3].ends_with("loaner-20240610-150000-000.db"));
+    assert!(!old_backup.exists());
+
+    // Backups of the same millisecond don't overwrite each other
+    let last = start + chrono::Duration::hours(3);
+    let path = db.backup_rotating(&directory, 2, last).unwrap();
+    assert!(path.ends_with("loaner-20240610-150000-001.db"));
+    paths.push(path);
+
+    // Only the newest two are kept, and files that aren't backups are left
+    std::fs::write(std::path::Path::new(&directory).join("notes.txt"), "").unwrap();
+    let backups = crate::backup::list_backups(std::path::Path::new(&directory)).unwrap();
+    let backups: Vec<String> = backups
+        .iter()
+        .map(|path| path.to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(backups, paths[3..]);
+    assert!(std::path::Path::new(&directory).join("notes.txt").exists());
+
+    let backup = Database::new(&backups[1]);
+    assert_eq!(backup.get_users().len(), 3);
+    drop(backup);
+
+    // The schedule backs up on its own thread until stopped
+    let schedule = crate::backup::BackupSchedule::start(
+        std::sync::Arc::new(db),
+        &directory,
+        std::time::Duration::from_millis(50),
+        10,
+    );
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    schedule.stop();
+    let backups = crate::backup::list_backups(std::path::Path::new(&directory)).unwrap();
+    assert!(backups.len() > 2);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_schema_migration() {
+    use crate::database::DatabaseError;
+    use crate::storage::sqlite::SCHEMA_VERSION;
+
+    // The original schema, which files were created with before they had a
+    // version
+    let path = temporary_database_path();
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "CREATE TABLE category (uuid blob NOT NULL PRIMARY KEY, name text NOT NULL,
+                supercategory blob);
+            CREATE TABLE instance (uuid blob NOT NULL PRIMARY KEY, identifier text NOT NULL,
+                product blob NOT NULL, FOREIGN KEY (product) REFERENCES product (uuid));
+            CREATE TABLE loan (uuid blob NOT NULL PRIMARY KEY, user blob NOT NULL,
+                date_start text NOT NULL, date_end text NOT NULL, accepted boolean NOT NULL,
+                description text, FOREIGN KEY (user) REFERENCES user (uuid));
+            CREATE TABLE product (uuid blob NOT NULL PRIMARY KEY, name text NOT NULL,
+                category blob NOT NULL, FOREIGN KEY (category) REFERENCES category (uuid));
+            CREATE TABLE user (uuid blob NOT NULL PRIMARY KEY, name text NOT NULL);
+            CREATE TABLE membership_payments (uuid blob NOT NULL PRIMARY KEY,
+                user blob NOT NULL, membership_type blob NOT NULL, price numeric NOT NULL,
+                date_start text NOT NULL, date_end text NOT NULL,
+                FOREIGN KEY (user) REFERENCES user (uuid),
+                FOREIGN KEY (membership_type) REFERENCES membership_type (uuid));
+            CREATE TABLE membership_type (uuid blob NOT NULL PRIMARY KEY, type text NOT NULL);
+            CREATE TABLE loan_instances (loan blob NOT NULL, instance blob NOT NULL,
+                PRIMARY KEY (loan, instance),
+                FOREIGN KEY (loan) REFERENCES loan (uuid),
+                FOREIGN KEY (instance) REFERENCES instance (uuid));
+            CREATE VIEW loan_view AS
+            SELECT loan.uuid AS loan_uuid, instance.uuid AS instance_uuid
+            FROM loan_instances
+              JOIN loan ON loan_instances.loan = loan.uuid
+              JOIN instance ON loan_instances.instance = instance.uuid;",
+        )
+        .unwrap();
+    let (user, category, product, instance, loan) = (
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+        uuid::Uuid::new_v4(),
+    );
+    connection
+        .execute("INSERT INTO user VALUES (?1, 'Alice')", [user])
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO category VALUES (?1, 'Cameras', NULL)",
+            [category],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO product VALUES (?1, 'Canon R6', ?2)",
+            [product, category],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO instance VALUES (?1, 'R6-1', ?2)",
+            [instance, product],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO loan VALUES (?1, ?2, '2024-06-10T12:00:00+03:00',
+                '2024-06-12T12:00:00+03:00', 1, 'Wedding')",
+            [loan, user],
+        )
+        .unwrap();
+    connection
+        .execute(
+            "INSERT INTO loan_instances VALUES (?1, ?2)",
+            [loan, instance],
+        )
+        .unwrap();
+    drop(connection);
+
+    let db = Database::open(&path).unwrap();
+    let loans = db.get_loans(crate::database::LoanQueryParams::new());
+    assert_eq!(loans.len(), 1);
+    assert_eq!(loans[0].uuid, loan);
+    assert_eq!(loans[0].user.name, "Alice");
+    assert_eq!(loans[0].instaces[0].uuid, instance);
+    assert_eq!(loans[0].price, 0);
+    db.add_user("Bob").unwrap();
+    assert_eq!(db.get_users().len(), 2);
+    // The delete rules of the rebuilt tables are in place
+    assert!(matches!(db.remove_user(user), Err(DatabaseError::InUse(_))));
+    drop(db);
+
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    let version: i64 = connection
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, SCHEMA_VERSION);
+
+    drop(connection);
+
+    remove_database_files(&path);
+}
+
+#[test]
+fn test_schema_version_check() {
+    use crate::database::DatabaseError;
+    use crate::storage::sqlite::SCHEMA_VERSION;
+
+    let user_version = |path: &str| -> i64 {
+        rusqlite::Connection::open(path)
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    };
+
+    // A file of version 10, before kits were added
+    let path = temporary_database_path();
+    let db = initialize_test_database(Some(&path));
+    drop(db);
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .execute_batch(
+            "DROP TABLE loan_kit;
+            DROP TABLE kit_item;
+            DROP TABLE kit;
+            PRAGMA user_version = 10;",
+        )
+        .unwrap();
+    drop(connection);
+    // Backups of older versions can be restored, they are migrated on open
+    crate::storage::SqliteStorage::validate_backup(std::path::Path::new(&path)).unwrap();
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(user_version(&path), SCHEMA_VERSION);
+    assert_eq!(db.get_users().len(), 3);
+    assert!(db.get_kits().is_empty());
+    drop(db);
+
+    // Opening a file of the current version changes nothing
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.get_users().len(), 3);
+    drop(db);
+
+    // Files of a newer version are refused and left alone
+    let connection = rusqlite::Connection::open(&path).unwrap();
+    connection
+        .pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+        .unwrap();
+    drop(connection);
+    assert!(matches!(
+        Database::open(&path),
+        Err(DatabaseError::Invalid(_))
+    ));
+    assert_eq!(user_version(&path), SCHEMA_VERSION + 1);
+
+    remove_database_files(&path);
+}
+
+#[cfg(feature = "postgres")]
+#[test]
+#[ignore = "needs a PostgreSQL server in LOANER_TEST_POSTGRES"]
+fn test_postgres_exclusion_constraint() {
+    use crate::database::DatabaseError;
+
+    let params = postgres_test_params();
+    let db = fill_test_database(Database::with_storage(
+        crate::storage::PostgresStorage::new(&params),
+    ));
+
+    let user = &db.get_users()[0];
+    let product = &db.get_product_by_name("Canon R6").unwrap();
+    let instance = &db.get_instances(Some(product.uuid))[0];
+    let now = chrono::Utc::now().with_timezone(&chrono_tz::Europe::Helsinki);
+
+    let loan = db
+        .add_loan(
+            user.uuid,
+            vec![instance.uuid],
+            now,
+            now + chrono::Duration::days(7),
+        )
+        .unwrap();
+
+    let pending = db
+        .add_loan(
+            user.uuid,
+            vec![instance.uuid],
+            now + chrono::Duration::days(8),
+            now + chrono::Duration::days(16),
+        )
+        .unwrap();
+    assert!(!pending.accepted);
+
+    // Writes that bypass the Database API are checked as well: accepting
+    // the pending loan over the first one is refused by the schema.
+    let mut client = ::postgres::Client::connect(&params, ::postgres::NoTls).unwrap();
+    let result = client
+        .execute(
+            "UPDATE loan SET accepted = true, date_start = date_start - interval '7 days'
+            WHERE uuid = $1",
+            &[&pending.uuid],
+        )
+        .map_err(DatabaseError::from);
+    assert!(matches!(result, Err(DatabaseError::Conflict(_))));
+    assert!(db.get_loan(loan.uuid).is_some());
+}
@@ -0,0 +1,79 @@
+use chrono::DateTime;
+use chrono_tz::Tz;
+
+use crate::database::{Depreciation, DepreciationMethod};
+use crate::invoice::format_money;
+
+/// Columns of insurance report CSV files, in order.
+pub const HEADER: [&str; 5] = [
+    "category",
+    "location",
+    "instances",
+    "purchase_price",
+    "insured_value",
+];
+
+/// Average length of a year, leap years included.
+const DAYS_PER_YEAR: f64 = 365.25;
+
+/// Value in cents of something bought for `price` on `purchase_date`, on
+/// `date`. Value is lost a little every day rather than once a year.
+pub fn depreciated_value(
+    price: i64,
+    purchase_date: DateTime<Tz>,
+    depreciation: &Depreciation,
+    date: DateTime<Tz>,
+) -> i64 {
+    let years = (date - purchase_date).num_days().max(0) as f64 / DAYS_PER_YEAR;
+    let rate = depreciation.rate as f64 / 100.0;
+    let remaining = match depreciation.method {
+        DepreciationMethod::StraightLine => (1.0 - rate * years).max(0.0),
+        DepreciationMethod::DecliningBalance => (1.0 - rate).powf(years),
+    };
+    (price as f64 * remaining).round() as i64
+}
+
+/// Instances of one category kept in one location. Amounts are in cents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsuranceRow {
+    pub category_path: Vec<String>,
+    pub location: Option<String>,
+    pub instances: i64,
+    pub purchase_price: i64,
+    pub insured_value: i64,
+}
+
+/// Insured value of the inventory on a date, by category and location.
+#[derive(Debug, Clone)]
+pub struct InsuranceReport {
+    pub date: DateTime<Tz>,
+    /// Sorted by category path, then location. Instances without a location
+    /// come first.
+    pub rows: Vec<InsuranceRow>,
+}
+
+impl InsuranceReport {
+    pub fn total_insured_value(&self) -> i64 {
+        self.rows.iter().map(|row| row.insured_value).sum()
+    }
+
+    /// The rows with a header, amounts in euros. Category paths are written
+    /// like in catalogue files.
+    pub fn to_csv(&self) -> String {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record(HEADER).unwrap();
+        for row in self.rows.iter() {
+            let path = crate::catalogue::join_path(&row.category_path);
+            writer
+                .write_record([
+                    path.as_str(),
+                    row.location.as_deref().unwrap_or_default(),
+                    &row.instances.to_string(),
+                    &format_money(row.purchase_price),
+                    &format_money(row.insured_value),
+                ])
+                .unwrap();
+        }
+        String::from_utf8(writer.into_inner().unwrap()).unwrap()
+    }
+}